@@ -60,6 +60,69 @@ impl UniqueBuffer {
     })
   }
 
+  /// Zeroes the buffer, hands it to `f` to be filled, and shares the result
+  pub fn fill_and_share<F: FnOnce(&mut [u8])>(self, f: F) -> SharedBytes {
+    let mut unique = match self {
+      UniqueBuffer::Uninit(mut uninit) => {
+        uninit.slice.as_out().fill(0);
+        unsafe { uninit.assume_init_slice_with_header() }
+      }
+      UniqueBuffer::Init(mut init) => {
+        init.slice.fill(0);
+        init
+      }
+    };
+    f(&mut unique.slice);
+    let shared = unique.shareable();
+    SharedBytes {
+      inner: ManuallyDrop::new(shared),
+    }
+  }
+
+  /// Zeroes the buffer if it has not been initialized
+  pub(crate) fn into_init(self) -> UniqueArc<PoolBuffer> {
+    match self {
+      UniqueBuffer::Uninit(mut uninit) => {
+        uninit.slice.as_out().fill(0);
+        unsafe { uninit.assume_init_slice_with_header() }
+      }
+      UniqueBuffer::Init(init) => init,
+    }
+  }
+
+  /// Hands the buffer to `f` to be read into and shares the result
+  pub fn read_with_and_share<F>(self, f: F) -> io::Result<SharedBytes>
+  where
+    F: FnOnce(&mut [u8]) -> io::Result<()>,
+  {
+    let mut unique = self.into_init();
+    f(&mut unique.slice)?;
+    let shared = unique.shareable();
+    Ok(SharedBytes {
+      inner: ManuallyDrop::new(shared),
+    })
+  }
+
+  /// Hands every buffer to `f` at once to be read into and shares the results in order
+  pub fn read_all_with_and_share<F>(
+    buffers: Vec<UniqueBuffer>, f: F,
+  ) -> io::Result<Vec<SharedBytes>>
+  where
+    F: FnOnce(&mut [&mut [u8]]) -> io::Result<()>,
+  {
+    let mut uniques: Vec<_> = buffers.into_iter().map(UniqueBuffer::into_init).collect();
+    let mut slices: Vec<&mut [u8]> = uniques.iter_mut().map(|u| &mut u.slice).collect();
+    f(&mut slices)?;
+    Ok(
+      uniques
+        .into_iter()
+        .map(|unique| SharedBytes {
+          inner: ManuallyDrop::new(unique.shareable()),
+        })
+        .collect(),
+    )
+  }
+
   #[cfg(target_family = "unix")]
   pub fn read_exact_at_and_share(self, file: &File, offset: u64) -> io::Result<SharedBytes> {
     use std::os::unix::fs::FileExt;
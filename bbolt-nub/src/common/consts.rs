@@ -27,8 +27,76 @@ pub const BETTER_BBOLT_RS_TAG: DbTag = DbTag {
   magic: 0x5caff01d,
 };
 
+pub const STABLE_FREE_SPACE_TAG: DbTag = DbTag {
+  version: 3,
+  magic: 0x5caff01d,
+};
+
+/// The on-disk format identified by a [`DbTag`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DbFormat {
+  /// Files written by Go BBolt
+  BBolt,
+  /// The Go BBolt layout under this project's magic
+  BBoltRs,
+  /// The version 3 layout where node page ids are mapped to disk pages by a
+  /// [`PageMapPage`](crate::io::pages::types::page_map::PageMapPage)
+  BetterBBoltRs,
+  /// Pages grouped in clusters that each track their own free space, as laid out by
+  /// [`StableFreeSpaceTranslator`](crate::common::id::StableFreeSpaceTranslator)
+  StableFreeSpace,
+}
+
+impl DbFormat {
+  pub fn tag(self) -> DbTag {
+    match self {
+      DbFormat::BBolt => BBOLT_TAG,
+      DbFormat::BBoltRs => BBOLT_RS_TAG,
+      DbFormat::BetterBBoltRs => BETTER_BBOLT_RS_TAG,
+      DbFormat::StableFreeSpace => STABLE_FREE_SPACE_TAG,
+    }
+  }
+
+  /// Whether pages are laid out as in Go BBolt, where node page ids are disk page ids
+  pub fn is_bbolt_layout(self) -> bool {
+    matches!(self, DbFormat::BBolt | DbFormat::BBoltRs)
+  }
+}
+
+impl DbTag {
+  /// The format of files written with this tag, if it is a known tag
+  pub fn format(self) -> Option<DbFormat> {
+    match self {
+      BBOLT_TAG => Some(DbFormat::BBolt),
+      BBOLT_RS_TAG => Some(DbFormat::BBoltRs),
+      BETTER_BBOLT_RS_TAG => Some(DbFormat::BetterBBoltRs),
+      STABLE_FREE_SPACE_TAG => Some(DbFormat::StableFreeSpace),
+      _ => None,
+    }
+  }
+}
+
 pub const IGNORE_NO_SYNC: bool = cfg!(target_os = "openbsd");
 
 pub const DEFAULT_MAX_BATCH_SIZE: u32 = 1000;
 pub const DEFAULT_MAX_BATCH_DELAY: Duration = Duration::from_millis(10);
 pub const DEFAULT_ALLOC_SIZE: Size = Size::from_const(16 * MiB);
+
+/// The number of free space pages each meta page has in every cluster of the
+/// [`DbFormat::StableFreeSpace`] format
+pub const FREE_SPACE_CLUSTER_LEN: usize = 1;
+
+/// The meta freelist page id marking that the freelist was not written
+pub const PGID_NO_FREELIST: u64 = u64::MAX;
+
+/// The largest key that can be stored in a bucket
+pub const MAX_KEY_SIZE: usize = 32768;
+/// The largest value that can be stored in a bucket
+pub const MAX_VALUE_SIZE: usize = (1 << 31) - 2;
+
+pub const DEFAULT_FILL_PERCENT: f64 = 0.5;
+pub const MIN_FILL_PERCENT: f64 = 0.1;
+pub const MAX_FILL_PERCENT: f64 = 1.0;
+
+/// The bytes copied in each transaction when compacting. `-tx-max-size` in Go BBolt's compact
+pub const DEFAULT_COMPACT_TX_MAX_SIZE: u64 = 65536;
@@ -84,7 +84,8 @@ impl UniqueData {
   }
 
   pub fn copy_data_and_share(mut self, data: &[u8]) -> SharedData {
-    self.0.slice.copy_from_slice(data);
+    self.0.slice.clear();
+    self.0.slice.extend_from_slice(data);
     let shared = self.0.shareable();
     SharedData {
       inner: ManuallyDrop::new(shared),
@@ -178,6 +179,28 @@ impl Debug for DataPool {
 }
 
 impl DataPool {
+  pub fn new(
+    init_size: Size, min_size: Size, max_size: Size, default_data_capacity: usize,
+    max_data_capacity: usize,
+  ) -> Self {
+    let reserve_size = init_size.bytes() as usize / default_data_capacity;
+    let inner = InnerDataPool {
+      init_size,
+      min_size,
+      max_size,
+      current_size_in_bytes: AtomicI64::new(0),
+      default_data_capacity,
+      max_data_capacity,
+      pool: Mutex::new(Vec::with_capacity(reserve_size)),
+    };
+    for _ in 0..reserve_size {
+      inner.push(inner.new_unique());
+    }
+    DataPool {
+      inner: sync::Arc::new(inner),
+    }
+  }
+
   pub fn pop(&self) -> UniqueData {
     let mut entry = self.inner.pop();
     entry.set_header(Some(self.clone()));
@@ -0,0 +1,179 @@
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::sync;
+
+/// Keeps retired values alive until every transaction that might still reference them is done.
+///
+/// Transactions [`Epochs::pin`] the current generation when they begin. Retiring a value
+/// tags it with the current generation and starts a new one. A retired value is dropped once
+/// no pinned generation is less than or equal to its tag.
+pub struct Epochs<T> {
+  state: sync::Arc<Mutex<EpochState<T>>>,
+}
+
+struct EpochState<T> {
+  generation: u64,
+  pinned: BTreeMap<u64, usize>,
+  retired: VecDeque<(u64, T)>,
+}
+
+impl<T> EpochState<T> {
+  fn collect(&mut self) {
+    let min_pinned = self.pinned.keys().next().copied().unwrap_or(u64::MAX);
+    while let Some((generation, _)) = self.retired.front() {
+      if *generation < min_pinned {
+        self.retired.pop_front();
+      } else {
+        break;
+      }
+    }
+  }
+}
+
+trait EpochRelease: Send + Sync {
+  fn release(&self, generation: u64);
+}
+
+impl<T> EpochRelease for Mutex<EpochState<T>>
+where
+  T: Send,
+{
+  fn release(&self, generation: u64) {
+    let mut state = self.lock();
+    if let Some(count) = state.pinned.get_mut(&generation) {
+      *count -= 1;
+      if *count == 0 {
+        state.pinned.remove(&generation);
+      }
+    }
+    state.collect();
+  }
+}
+
+impl<T> Default for Epochs<T> {
+  fn default() -> Self {
+    Epochs {
+      state: sync::Arc::new(Mutex::new(EpochState {
+        generation: 0,
+        pinned: BTreeMap::new(),
+        retired: VecDeque::new(),
+      })),
+    }
+  }
+}
+
+impl<T> Debug for Epochs<T> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    let state = self.state.lock();
+    f.debug_struct("Epochs")
+      .field("generation", &state.generation)
+      .field("pinned", &state.pinned)
+      .field("retired", &state.retired.len())
+      .finish()
+  }
+}
+
+impl<T> Epochs<T>
+where
+  T: Send + 'static,
+{
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Pins the current generation until the returned guard is dropped
+  pub fn pin(&self) -> EpochGuard {
+    let mut state = self.state.lock();
+    let generation = state.generation;
+    *state.pinned.entry(generation).or_default() += 1;
+    EpochGuard {
+      generation,
+      epochs: self.state.clone(),
+    }
+  }
+
+  /// Pins `generation` instead of the current one, for callers that number generations
+  /// themselves
+  pub fn pin_at(&self, generation: u64) -> EpochGuard {
+    let mut state = self.state.lock();
+    *state.pinned.entry(generation).or_default() += 1;
+    EpochGuard {
+      generation,
+      epochs: self.state.clone(),
+    }
+  }
+
+  /// The oldest generation still pinned
+  pub fn min_pinned(&self) -> Option<u64> {
+    self.state.lock().pinned.keys().next().copied()
+  }
+
+  /// Retires `value` and starts a new generation.
+  /// `value` is dropped immediately if nothing is pinned.
+  pub fn retire(&self, value: T) {
+    let mut state = self.state.lock();
+    let generation = state.generation;
+    state.retired.push_back((generation, value));
+    state.generation += 1;
+    state.collect();
+  }
+
+  /// The number of retired values still alive
+  pub fn retired_len(&self) -> usize {
+    self.state.lock().retired.len()
+  }
+}
+
+/// Holds a pinned generation. Dropping it may release retired values.
+pub struct EpochGuard {
+  generation: u64,
+  epochs: sync::Arc<dyn EpochRelease>,
+}
+
+impl EpochGuard {
+  #[inline]
+  pub fn generation(&self) -> u64 {
+    self.generation
+  }
+}
+
+impl Debug for EpochGuard {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("EpochGuard")
+      .field("generation", &self.generation)
+      .finish()
+  }
+}
+
+impl Drop for EpochGuard {
+  fn drop(&mut self) {
+    self.epochs.release(self.generation);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_retire() {
+    let epochs = Epochs::new();
+    epochs.retire(0);
+    assert_eq!(0, epochs.retired_len());
+
+    let first = epochs.pin();
+    epochs.retire(1);
+    let second = epochs.pin();
+    let also_second = epochs.pin();
+    epochs.retire(2);
+    assert_eq!(2, epochs.retired_len());
+
+    drop(second);
+    assert_eq!(2, epochs.retired_len());
+    drop(first);
+    assert_eq!(1, epochs.retired_len());
+    drop(also_second);
+    assert_eq!(0, epochs.retired_len());
+  }
+}
@@ -1,9 +1,12 @@
+use crate::common::consts::DbTag;
 use crate::common::id::{
-  DbPageId, DiskPageId, EOFPageId, FreelistPageId, MetaPageId, NodePageId, OverflowPageId,
+  BucketPageId, DbPageId, DiskPageId, EOFPageId, FreelistPageId, MetaPageId, NodePageId,
+  OverflowPageId, TxId,
 };
 use crate::common::layout::page::{PageFlag, PageHeader};
 use crate::io::pages::types::freelist::FreelistPage;
 use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -20,12 +23,24 @@ pub enum PageError {
   InvalidMetaFlag(PageFlag),
   #[error("Expected a freelist flag. Found `{0:#x}`.")]
   InvalidFreelistFlag(PageFlag),
+  #[error("Expected a page map flag. Found `{0:#x}`.")]
+  InvalidPageMapFlag(PageFlag),
+  #[error("Expected a free space flag. Found `{0:#x}`.")]
+  InvalidFreeSpaceFlag(PageFlag),
   #[error("Error reading node page `{0:?}`.")]
   InvalidNode(NodePageId),
   #[error("Error reading meta page `{0:?}`.")]
   InvalidMeta(MetaPageId),
   #[error("Error reading freelist page `{0:?}`.")]
   InvalidFreelist(FreelistPageId),
+  #[error("Error reading page map page `{0:?}`.")]
+  InvalidPageMap(FreelistPageId),
+  #[error("Error reading free space page `{0:?}`.")]
+  InvalidFreeSpace(FreelistPageId),
+  #[error("Error reading an inline bucket page.")]
+  InvalidInlineNode,
+  #[error("Checksum mismatch in page `{0:?}` overflow {1}.")]
+  ChecksumMismatch(DbPageId, u32),
 }
 
 #[derive(Debug, Error)]
@@ -38,12 +53,24 @@ pub enum IOError {
   UnexpectedEOF(DiskPageId, EOFPageId),
   #[error("ReadError: Read at `{0:?}`.")]
   ReadError(DiskPageId),
+  #[error("ReadError: Node page `{0:?}` isn't in the page map.")]
+  Unmapped(NodePageId),
   #[error("WriteError: Write at `{0:?}`.")]
   WriteError(DiskPageId),
   #[error("PageWriteError: Writing at `{0:?}`.")]
   PageWriteError(PageHeader),
   #[error("Error updating length")]
   UpdateLengthError,
+  #[error("SyncError: Unable to sync file to disk.")]
+  SyncError,
+  #[error("CryptError: Not an encrypted database, or encrypted differently.")]
+  CryptHeaderError,
+  #[error("CryptError: The key doesn't match the database.")]
+  CryptKeyError,
+  #[error("CryptError: Page `{0:?}` failed authentication.")]
+  DecryptError(DiskPageId),
+  #[error("CryptError: Unable to draw a nonce for page `{0:?}`.")]
+  EncryptError(DiskPageId),
 }
 
 #[derive(Debug, Error)]
@@ -80,6 +107,14 @@ pub enum CursorError {
 pub enum TxError {
   #[error("Tx Error: DataCopy")]
   DataCopy,
+  #[error("Tx Error: Unable to find the free pages")]
+  FreePagesError,
+  #[error("Tx Error: Unable to read page `{0:?}`")]
+  PageReadError(DiskPageId),
+  #[error("Tx Error: Unable to write the snapshot")]
+  WriteError,
+  #[error("Tx Error: Unable to copy the snapshot to `{0:?}`")]
+  CopyFileError(PathBuf),
 }
 
 #[derive(Debug, Error)]
@@ -90,10 +125,86 @@ pub enum BucketError {
   GetError,
   #[error("Bucket Error: TruBuffKvError")]
   TruBuffKvError,
+  #[error("Expected Bucket, found Bytes")]
+  ValueIsBytes,
+  #[error("Bucket Error: Bucket not found")]
+  BucketNotFound,
+  #[error("Bucket Error: Bucket already exists")]
+  BucketExists,
+  #[error("Bucket Error: Key required")]
+  KeyRequired,
+  #[error("Bucket Error: Key too large")]
+  KeyTooLarge,
+  #[error("Bucket Error: Value too large")]
+  ValueTooLarge,
+  #[error("Bucket Error: Unable to read bucket root `{0:?}`")]
+  RootReadError(BucketPageId),
+  #[error("Bucket Error: Unable to read page `{0:?}`")]
+  PageReadError(NodePageId),
+  #[error("Bucket Error: Unable to read element {1} of page `{0:?}`")]
+  ElementReadError(NodePageId, usize),
+  #[error("Bucket Error: Unable to decompress value")]
+  Decompress,
+  #[error("Bucket Error: Compression threshold {0} is too large")]
+  CompressionThreshold(usize),
+  #[error("Bucket Error: Go BBolt databases can't store compressed values")]
+  CompressionUnsupported,
+}
+
+#[derive(Debug, Error)]
+pub enum CheckError {
+  #[error("Check Error: Unable to read freelist page `{0:?}`")]
+  FreelistReadError(FreelistPageId),
+  #[error("Check Error: Page `{0:?}` is past the end of the database at `{1:?}`")]
+  OutOfBounds(DiskPageId, EOFPageId),
+  #[error("Check Error: Page `{0:?}` is freed more than once")]
+  DoubleFreed(DiskPageId),
+  #[error("Check Error: Page `{0:?}` is referenced more than once")]
+  MultipleReferences(DiskPageId),
+  #[error("Check Error: Page `{0:?}` is reachable but freed")]
+  ReachableFreed(DiskPageId),
+  #[error("Check Error: Page `{0:?}` is neither reachable nor freed")]
+  UnreachableUnfreed(DiskPageId),
+  #[error("Check Error: Unable to read node page `{0:?}`")]
+  InvalidPage(NodePageId),
+  #[error("Check Error: Unable to read element {1} of page `{0:?}`")]
+  InvalidElement(NodePageId, usize),
+  #[error("Check Error: Keys of page `{0:?}` are out of order at index {1}")]
+  UnsortedKeys(NodePageId, usize),
+  #[error("Check Error: Key {1} of page `{0:?}` is outside its parent branch's range")]
+  KeyOutOfRange(NodePageId, usize),
 }
 
 #[derive(Debug, Error)]
 pub enum DbError {
   #[error("DBError: Unspecified Failure")]
   Failure,
+  #[error("DBError: Unable to open database at `{0:?}`.")]
+  OpenError(PathBuf),
+  #[error("DBError: Unable to initialize database at `{0:?}`.")]
+  InitError(PathBuf),
+  #[error("DBError: Unable to lock database at `{0:?}`.")]
+  LockError(PathBuf),
+  #[error("DBError: Timed out after `{1:?}` waiting to lock database at `{0:?}`.")]
+  LockTimeout(PathBuf, Duration),
+  #[error("DBError: Expected database tag `{0:?}`. Found `{1:?}`.")]
+  InvalidTag(DbTag, DbTag),
+  #[error("DBError: Unknown database tag `{0:?}`.")]
+  UnknownTag(DbTag),
+  #[error("DBError: Database at `{0:?}` is read-only.")]
+  ReadOnly(PathBuf),
+  #[error("DBError: Unable to commit transaction `{0:?}`.")]
+  CommitError(TxId),
+  #[error("DBError: Batch call failed.")]
+  BatchCallError,
+  #[error("DBError: Batch was aborted before it committed.")]
+  BatchAborted,
+  #[error("DBError: Write-ahead log failure at `{0:?}`.")]
+  WalError(PathBuf),
+  #[error("DBError: Write-ahead log at `{0:?}` must be recovered by a writable open.")]
+  WalPending(PathBuf),
+  #[error("DBError: Unable to compact database into `{0:?}`.")]
+  CompactError(PathBuf),
+  #[error("DBError: The write-ahead log at `{0:?}` can't be used with an encrypted database.")]
+  EncryptedWal(PathBuf),
 }
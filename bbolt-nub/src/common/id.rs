@@ -1,7 +1,13 @@
+use crate::common::consts::FREE_SPACE_CLUSTER_LEN;
 use crate::common::layout::page::PageFlag;
+use crate::common::layout::page_map::PageMapEntry;
+use crate::io::pages::types::free_space::free_space_bitmap_len;
+use crate::io::transmogrify::{TxContext, TxIndirectContext};
 use bytemuck::{Pod, Zeroable};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::ops::{Add, AddAssign, Deref};
+use std::sync;
 // TODO: Clean this up once I'm done making sure everything is as it needs to be!
 
 pub trait DbId {
@@ -217,8 +223,10 @@ impl DiskPageTranslator for DirectPageTranslator {
 }
 
 impl SupportsContigPages for DirectPageTranslator {}
-impl SupportsNonContigPages for DirectPageTranslator {}
 
+/// Lays the file out as the two meta pages followed by clusters. Each cluster holds two free
+/// space regions of `freespace_cluster_len` pages, one per meta page, followed by the data pages
+/// those regions track, one bit per page.
 #[derive(Debug, Clone)]
 pub struct StableFreeSpaceTranslator {
   tx_id: TxId,
@@ -227,6 +235,7 @@ pub struct StableFreeSpaceTranslator {
 }
 
 impl StableFreeSpaceTranslator {
+  /// Each free space page tracks `page_size * 8` data pages
   pub fn new(tx_id: TxId, freespace_cluster_len: usize, page_size: usize) -> Self {
     StableFreeSpaceTranslator {
       tx_id,
@@ -235,10 +244,71 @@ impl StableFreeSpaceTranslator {
     }
   }
 
+  /// The layout of a database with `page_size` pages, where each free space page's bitmap fills
+  /// the page past its header and any checksum footer
+  pub fn for_pages(tx_id: TxId, page_size: usize, page_checksums: bool) -> Self {
+    let bitmap_len = free_space_bitmap_len(page_size, page_checksums);
+    StableFreeSpaceTranslator::new(tx_id, FREE_SPACE_CLUSTER_LEN, bitmap_len)
+  }
+
+  /// The same layout for the free space region of `tx_id`
+  pub fn with_tx_id(mut self, tx_id: TxId) -> Self {
+    self.tx_id = tx_id;
+    self
+  }
+
+  /// The number of data pages each free space page tracks
+  #[inline]
+  pub fn tracked_len(&self) -> u64 {
+    self.page_size as u64 * 8
+  }
+
+  /// The number of node page ids stored below `eof`
+  pub fn node_eof(&self, eof: EOFPageId) -> u64 {
+    if eof.0.0 <= 2 {
+      return 0;
+    }
+    let (cluster_idx, cluster_offset) = self.cluster_of(eof.0);
+    let freespace_len = 2 * self.freespace_cluster_len as u64;
+    cluster_idx * self.data_len() + cluster_offset.saturating_sub(freespace_len)
+  }
+
+  /// The number of free space pages each meta has for the data pages below `eof`
+  pub fn free_space_len(&self, eof: EOFPageId) -> u64 {
+    self.node_eof(eof).div_ceil(self.tracked_len())
+  }
+
+  /// Whether `disk_page_id` is in the free space regions of a cluster
+  pub fn is_free_space(&self, disk_page_id: DiskPageId) -> bool {
+    disk_page_id.0 >= 2 && self.cluster_of(disk_page_id).1 < 2 * self.freespace_cluster_len as u64
+  }
+
+  /// The first data page at or after `disk_page_id`
+  pub fn next_data_page(&self, disk_page_id: DiskPageId) -> DiskPageId {
+    let disk_page_id = disk_page_id.max(DiskPageId(2));
+    let (_, cluster_offset) = self.cluster_of(disk_page_id);
+    let freespace_len = 2 * self.freespace_cluster_len as u64;
+    disk_page_id + freespace_len.saturating_sub(cluster_offset)
+  }
+
   #[inline]
   pub fn cluster_len(&self) -> u64 {
-    (2 * self.freespace_cluster_len as u64)
-      + (self.freespace_cluster_len as u64 * self.page_size as u64 * 8)
+    (2 * self.freespace_cluster_len as u64) + self.data_len()
+  }
+
+  /// The number of data pages in each cluster
+  #[inline]
+  pub fn data_len(&self) -> u64 {
+    self.freespace_cluster_len as u64 * self.page_size as u64 * 8
+  }
+
+  /// Splits a disk page into its cluster and its offset within the cluster
+  #[inline]
+  fn cluster_of(&self, disk_page_id: DiskPageId) -> (u64, u64) {
+    assert!(disk_page_id.0 >= 2, "meta pages aren't in a cluster");
+    let cluster_page = disk_page_id.0 - 2;
+    let cluster_len = self.cluster_len();
+    (cluster_page / cluster_len, cluster_page % cluster_len)
   }
 }
 
@@ -264,8 +334,9 @@ impl DiskPageTranslator for StableFreeSpaceTranslator {
 
   fn node_to_disk(&self, page_id: NodePageId) -> DiskPageId {
     let freespace_cluster_len = self.freespace_cluster_len as u64;
-    let data_cluster_idx = page_id.0.0 / self.page_size as u64;
-    let node_offset = page_id.0.0 % self.page_size as u64;
+    let data_len = self.data_len();
+    let data_cluster_idx = page_id.0.0 / data_len;
+    let node_offset = page_id.0.0 % data_len;
     let cluster_len = self.cluster_len();
     let disk_offset = 2 + // meta pages
       data_cluster_idx * cluster_len; // the data pages for each cluster;
@@ -274,16 +345,219 @@ impl DiskPageTranslator for StableFreeSpaceTranslator {
   }
 
   fn disk_to_freelist(&self, disk_page_id: DiskPageId) -> FreelistPageId {
-    todo!("I definitely do not remember what I was planning :D")
+    let freespace_cluster_len = self.freespace_cluster_len as u64;
+    let (cluster_idx, cluster_offset) = self.cluster_of(disk_page_id);
+    assert_eq!(
+      self.tx_id.meta_offset(),
+      cluster_offset / freespace_cluster_len,
+      "{disk_page_id:?} isn't in this meta's free space region"
+    );
+    let freespace_offset = cluster_offset % freespace_cluster_len;
+    FreelistPageId(DbPageId(
+      cluster_idx * freespace_cluster_len + freespace_offset,
+    ))
   }
 
   fn disk_to_node(&self, disk_page_id: DiskPageId) -> NodePageId {
-    todo!("I definitely do not remember what I was planning :D")
+    let freespace_len = 2 * self.freespace_cluster_len as u64;
+    let (cluster_idx, cluster_offset) = self.cluster_of(disk_page_id);
+    assert!(
+      cluster_offset >= freespace_len,
+      "{disk_page_id:?} is a free space page"
+    );
+    NodePageId(DbPageId(
+      cluster_idx * self.data_len() + cluster_offset - freespace_len,
+    ))
   }
 }
 
 impl SupportsNonContigPages for StableFreeSpaceTranslator {}
 
+impl TxContext for StableFreeSpaceTranslator {
+  #[inline]
+  fn trans_meta_id(&self, meta_page_id: MetaPageId) -> DiskPageId {
+    DiskPageId(meta_page_id.0.0)
+  }
+
+  #[inline]
+  fn trans_freelist_id(&self, freelist_page_id: FreelistPageId) -> DiskPageId {
+    self.freelist_to_disk(freelist_page_id)
+  }
+
+  #[inline]
+  fn trans_node_id(&self, node_page_id: NodePageId) -> DiskPageId {
+    self.node_to_disk(node_page_id)
+  }
+}
+
+// Overflow pages may cross into the next cluster, past its free space regions
+impl TxIndirectContext for StableFreeSpaceTranslator {
+  #[inline]
+  fn trans_freelist_overflow(&self, freelist_page_id: FreelistPageId, overflow: u32) -> DiskPageId {
+    self.freelist_to_disk(freelist_page_id + overflow)
+  }
+
+  #[inline]
+  fn trans_node_overflow(&self, node_page_id: NodePageId, overflow: u32) -> DiskPageId {
+    self.node_to_disk(node_page_id + overflow)
+  }
+}
+
+/// Translates node page ids through the version 3 page map. Every page of a node, including its
+/// overflow, has its own entry so a node may be spread across scattered disk pages.
+///
+/// Moving a node only changes its entry, so the pages referencing it are left untouched.
+#[derive(Debug, Clone)]
+pub struct PageMapTranslator {
+  tx_id: TxId,
+  to_disk: sync::Arc<BTreeMap<NodePageId, DiskPageId>>,
+  to_node: sync::Arc<BTreeMap<DiskPageId, NodePageId>>,
+}
+
+impl PageMapTranslator {
+  pub fn new<I: IntoIterator<Item = PageMapEntry>>(tx_id: TxId, entries: I) -> Self {
+    let to_disk: BTreeMap<_, _> = entries
+      .into_iter()
+      .map(|entry| (entry.node(), entry.disk()))
+      .collect();
+    let to_node = to_disk.iter().map(|(node, disk)| (*disk, *node)).collect();
+    PageMapTranslator {
+      tx_id,
+      to_disk: sync::Arc::new(to_disk),
+      to_node: sync::Arc::new(to_node),
+    }
+  }
+
+  /// The same mapping for the transaction `tx_id`
+  pub fn with_tx_id(mut self, tx_id: TxId) -> Self {
+    self.tx_id = tx_id;
+    self
+  }
+
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.to_disk.len()
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.to_disk.is_empty()
+  }
+
+  #[inline]
+  pub fn get(&self, page_id: NodePageId) -> Option<DiskPageId> {
+    self.to_disk.get(&page_id).copied()
+  }
+
+  /// The node page stored at `disk_page_id`
+  #[inline]
+  pub fn node_at(&self, disk_page_id: DiskPageId) -> Option<NodePageId> {
+    self.to_node.get(&disk_page_id).copied()
+  }
+
+  /// The lowest node page id after every mapped page
+  pub fn next_node_id(&self) -> NodePageId {
+    self
+      .to_disk
+      .last_key_value()
+      .map(|(page_id, _)| *page_id + 1)
+      .unwrap_or_default()
+  }
+
+  /// Stores `page_id` at `disk_page_id`, returning the disk page it was stored at before
+  pub fn remap(&mut self, page_id: NodePageId, disk_page_id: DiskPageId) -> Option<DiskPageId> {
+    let to_node = sync::Arc::make_mut(&mut self.to_node);
+    assert!(
+      !to_node.contains_key(&disk_page_id),
+      "{disk_page_id:?} is already mapped"
+    );
+    let old = sync::Arc::make_mut(&mut self.to_disk).insert(page_id, disk_page_id);
+    if let Some(old) = old {
+      to_node.remove(&old);
+    }
+    to_node.insert(disk_page_id, page_id);
+    old
+  }
+
+  /// Removes `page_id`, returning the disk page it was stored at
+  pub fn unmap(&mut self, page_id: NodePageId) -> Option<DiskPageId> {
+    let old = sync::Arc::make_mut(&mut self.to_disk).remove(&page_id);
+    if let Some(old) = old {
+      sync::Arc::make_mut(&mut self.to_node).remove(&old);
+    }
+    old
+  }
+
+  /// The entries sorted by node page id, as they are written to the page map page
+  pub fn entries(&self) -> impl ExactSizeIterator<Item = PageMapEntry> + '_ {
+    self
+      .to_disk
+      .iter()
+      .map(|(node, disk)| PageMapEntry::new(*node, *disk))
+  }
+}
+
+impl DiskPageTranslator for PageMapTranslator {
+  #[inline]
+  fn meta(&self) -> DiskPageId {
+    DiskPageId(self.tx_id.meta_offset())
+  }
+
+  // The page map page itself isn't mapped
+  #[inline]
+  fn freelist_to_disk(&self, page_id: FreelistPageId) -> DiskPageId {
+    DiskPageId(page_id.0.0)
+  }
+
+  fn node_to_disk(&self, page_id: NodePageId) -> DiskPageId {
+    self
+      .get(page_id)
+      .unwrap_or_else(|| panic!("{page_id:?} isn't mapped"))
+  }
+
+  #[inline]
+  fn disk_to_freelist(&self, disk_page_id: DiskPageId) -> FreelistPageId {
+    FreelistPageId(DbPageId(disk_page_id.0))
+  }
+
+  fn disk_to_node(&self, disk_page_id: DiskPageId) -> NodePageId {
+    self
+      .node_at(disk_page_id)
+      .unwrap_or_else(|| panic!("{disk_page_id:?} isn't mapped"))
+  }
+}
+
+impl SupportsNonContigPages for PageMapTranslator {}
+
+impl TxContext for PageMapTranslator {
+  #[inline]
+  fn trans_meta_id(&self, meta_page_id: MetaPageId) -> DiskPageId {
+    DiskPageId(meta_page_id.0.0)
+  }
+
+  #[inline]
+  fn trans_freelist_id(&self, freelist_page_id: FreelistPageId) -> DiskPageId {
+    self.freelist_to_disk(freelist_page_id)
+  }
+
+  #[inline]
+  fn trans_node_id(&self, node_page_id: NodePageId) -> DiskPageId {
+    self.node_to_disk(node_page_id)
+  }
+}
+
+impl TxIndirectContext for PageMapTranslator {
+  #[inline]
+  fn trans_freelist_overflow(&self, freelist_page_id: FreelistPageId, overflow: u32) -> DiskPageId {
+    self.freelist_to_disk(freelist_page_id + overflow)
+  }
+
+  #[inline]
+  fn trans_node_overflow(&self, node_page_id: NodePageId, overflow: u32) -> DiskPageId {
+    self.node_to_disk(node_page_id + overflow)
+  }
+}
+
 #[derive(Default, Debug, Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Hash)]
 pub struct WipNodeId(pub u64);
 
@@ -298,7 +572,7 @@ impl WipNodeGenerator {
     }
   }
 
-  pub fn root(&self) ->  WipNodeId {
+  pub fn root(&self) -> WipNodeId {
     WipNodeId(0)
   }
 
@@ -311,9 +585,12 @@ impl WipNodeGenerator {
 #[cfg(test)]
 mod tests {
   use crate::common::id::{
-    DirectPageTranslator, DiskPageTranslator, FreelistPageId, NodePageId,
-    StableFreeSpaceTranslator, TxId,
+    DbId, DirectPageTranslator, DiskPageId, DiskPageTranslator, EOFPageId, FreelistPageId,
+    NodePageId, PageMapTranslator, StableFreeSpaceTranslator, TxId,
   };
+  use crate::common::layout::page_map::PageMapEntry;
+  use crate::io::transmogrify::TxIndirectContext;
+  use proptest::prelude::*;
 
   #[test]
   fn test() {
@@ -335,4 +612,99 @@ mod tests {
     let node_page = tr.node_to_disk(NodePageId::of(0));
     println!("{:#?}", meta);
   }
+
+  #[test]
+  fn test_page_map() {
+    let entries = [(0, 9), (1, 4), (2, 5), (5, 12)]
+      .map(|(node, disk)| PageMapEntry::new(NodePageId::of(node), DiskPageId(disk)));
+    let mut tr = PageMapTranslator::new(TxId::of(3), entries);
+    assert_eq!(DiskPageId(1), tr.meta());
+    assert_eq!(DiskPageId(4), tr.node_to_disk(NodePageId::of(1)));
+    assert_eq!(DiskPageId(5), tr.trans_node_overflow(NodePageId::of(1), 1));
+    assert_eq!(NodePageId::of(5), tr.disk_to_node(DiskPageId(12)));
+    assert_eq!(NodePageId::of(6), tr.next_node_id());
+
+    // Moving a node leaves the previous translator untouched
+    let before = tr.clone();
+    assert_eq!(
+      Some(DiskPageId(4)),
+      tr.remap(NodePageId::of(1), DiskPageId(20))
+    );
+    assert_eq!(DiskPageId(20), tr.node_to_disk(NodePageId::of(1)));
+    assert_eq!(NodePageId::of(1), tr.disk_to_node(DiskPageId(20)));
+    assert_eq!(DiskPageId(4), before.node_to_disk(NodePageId::of(1)));
+    assert_eq!(Some(DiskPageId(12)), tr.unmap(NodePageId::of(5)));
+    assert_eq!(None, tr.get(NodePageId::of(5)));
+    assert_eq!(
+      vec![(0, 9), (1, 20), (2, 5)],
+      tr.entries()
+        .map(|entry| (entry.node().0.0, entry.disk().0))
+        .collect::<Vec<_>>()
+    );
+  }
+
+  fn translators() -> impl Strategy<Value = StableFreeSpaceTranslator> {
+    (
+      1..=2u64,
+      1..=4usize,
+      prop_oneof![Just(512usize), Just(4096)],
+    )
+      .prop_map(|(tx_id, freespace_cluster_len, page_size)| {
+        StableFreeSpaceTranslator::new(TxId::of(tx_id), freespace_cluster_len, page_size)
+      })
+  }
+
+  proptest! {
+    #[test]
+    fn test_node_round_trip(tr in translators(), page_id in 0..1u64 << 40) {
+      let disk_page_id = tr.node_to_disk(NodePageId::of(page_id));
+      let (_, cluster_offset) = tr.cluster_of(disk_page_id);
+      prop_assert!(cluster_offset >= 2 * tr.freespace_cluster_len as u64);
+      prop_assert_eq!(NodePageId::of(page_id), tr.disk_to_node(disk_page_id));
+    }
+
+    #[test]
+    fn test_freelist_round_trip(tr in translators(), page_id in 0..1u64 << 30) {
+      let disk_page_id = tr.freelist_to_disk(FreelistPageId::of(page_id));
+      let (_, cluster_offset) = tr.cluster_of(disk_page_id);
+      prop_assert!(cluster_offset < 2 * tr.freespace_cluster_len as u64);
+      prop_assert_eq!(FreelistPageId::of(page_id), tr.disk_to_freelist(disk_page_id));
+    }
+
+    #[test]
+    fn test_disk_round_trip(tr in translators(), disk_page_id in 2..1u64 << 40) {
+      let disk_page_id = DiskPageId(disk_page_id);
+      let (_, cluster_offset) = tr.cluster_of(disk_page_id);
+      let freespace_cluster_len = tr.freespace_cluster_len as u64;
+      if cluster_offset >= 2 * freespace_cluster_len {
+        prop_assert_eq!(disk_page_id, tr.node_to_disk(tr.disk_to_node(disk_page_id)));
+      } else if cluster_offset / freespace_cluster_len == tr.tx_id.meta_offset() {
+        prop_assert_eq!(disk_page_id, tr.freelist_to_disk(tr.disk_to_freelist(disk_page_id)));
+      }
+    }
+
+    #[test]
+    fn test_node_order(tr in translators(), page_id in 0..1u64 << 40) {
+      let disk_page_id = tr.node_to_disk(NodePageId::of(page_id));
+      let next_disk_page_id = tr.node_to_disk(NodePageId::of(page_id + 1));
+      prop_assert!(disk_page_id < next_disk_page_id);
+    }
+
+    #[test]
+    fn test_node_eof(tr in translators(), page_id in 0..1u64 << 40) {
+      let disk_page_id = tr.node_to_disk(NodePageId::of(page_id));
+      prop_assert_eq!(page_id, tr.node_eof(EOFPageId(disk_page_id)));
+      prop_assert_eq!(page_id + 1, tr.node_eof(EOFPageId(disk_page_id + 1)));
+    }
+
+    #[test]
+    fn test_next_data_page(tr in translators(), disk_page_id in 0..1u64 << 40) {
+      let data_page_id = tr.next_data_page(DiskPageId(disk_page_id));
+      prop_assert!(!tr.is_free_space(data_page_id));
+      prop_assert!(data_page_id.0 >= disk_page_id);
+      prop_assert!((disk_page_id.max(2)..data_page_id.0)
+        .all(|page_id| tr.is_free_space(DiskPageId(page_id))));
+      prop_assert_eq!(data_page_id, tr.node_to_disk(tr.disk_to_node(data_page_id)));
+    }
+  }
 }
@@ -1,4 +1,6 @@
 use crate::common::id::BucketPageId;
+use crate::common::layout::node::{LeafElement, LeafFlag};
+use crate::common::layout::page::PageHeader;
 use bytemuck::{Pod, Zeroable};
 use getset::CopyGetters;
 
@@ -28,6 +30,36 @@ impl BucketHeader {
   }
 }
 
+/// The flag, key and value of a leaf element
+pub type LeafKv<'a> = (LeafFlag, &'a [u8], &'a [u8]);
+
+/// The leaf page of an inline bucket, whose leaf `value` is its header followed by the page.
+/// `None` if the page header doesn't fit in `value` or the page claims an overflow.
+pub fn inline_page(value: &[u8]) -> Option<&[u8]> {
+  let page = value.get(size_of::<BucketHeader>()..)?;
+  let header: PageHeader = bytemuck::pod_read_unaligned(page.get(0..size_of::<PageHeader>())?);
+  (header.get_overflow() == 0).then_some(page)
+}
+
+/// The flag, key and value of every element of an inline bucket, whose leaf `value` is its
+/// header followed by its leaf page. `None` if the page doesn't fit in `value`.
+pub fn inline_elements(value: &[u8]) -> Option<Vec<LeafKv<'_>>> {
+  let page = inline_page(value)?;
+  let header: PageHeader = bytemuck::pod_read_unaligned(&page[0..size_of::<PageHeader>()]);
+  let mut elements = Vec::with_capacity(header.count() as usize);
+  for index in 0..header.count() as usize {
+    let elem_start = size_of::<PageHeader>() + index * size_of::<LeafElement>();
+    let elem: LeafElement =
+      bytemuck::pod_read_unaligned(page.get(elem_start..elem_start + size_of::<LeafElement>())?);
+    let key_start = elem_start + elem.key_dist() as usize;
+    let value_start = key_start + elem.key_len() as usize;
+    let key = page.get(key_start..value_start)?;
+    let value = page.get(value_start..value_start + elem.value_len() as usize)?;
+    elements.push((elem.flags(), key, value));
+  }
+  Some(elements)
+}
+
 impl From<BucketHeader> for String {
   fn from(value: BucketHeader) -> Self {
     format!("<pgid={:?},seq={}>", value.root, value.sequence)
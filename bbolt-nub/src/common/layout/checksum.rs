@@ -0,0 +1,115 @@
+use crate::common::errors::PageError;
+use crate::common::layout::page::PageHeader;
+use std::ops::Range;
+
+/// The length of one page checksum
+pub const PAGE_CHECKSUM_LEN: usize = size_of::<u32>();
+
+/// The length of the checksum footer of a page with `overflow` overflow pages.
+///
+/// The footer holds a CRC32C for each page on disk, root first, and is stored in the last bytes
+/// of the last page. It must fit in that page.
+#[inline]
+pub fn page_footer_len(overflow: u32) -> usize {
+  (overflow as usize + 1) * PAGE_CHECKSUM_LEN
+}
+
+/// The number of pages `byte_size` bytes are stored in, along with their checksum footer when
+/// `page_checksums` is set
+pub fn page_count(byte_size: usize, page_size: usize, page_checksums: bool) -> usize {
+  let mut count = byte_size.div_ceil(page_size).max(1);
+  while page_checksums && byte_size + page_footer_len(count as u32 - 1) > count * page_size {
+    count += 1;
+  }
+  count
+}
+
+/// The bytes of disk page `index` covered by its checksum, relative to the start of that page.
+/// The footer isn't covered.
+fn covered(index: u32, overflow: u32, page_size: usize) -> Range<usize> {
+  if index == overflow {
+    0..page_size - page_footer_len(overflow)
+  } else {
+    0..page_size
+  }
+}
+
+fn stored_sum(last_page: &[u8], index: u32, overflow: u32) -> u32 {
+  let page_size = last_page.len();
+  let start = page_size - page_footer_len(overflow) + index as usize * PAGE_CHECKSUM_LEN;
+  bytemuck::pod_read_unaligned(&last_page[start..start + PAGE_CHECKSUM_LEN])
+}
+
+/// Fills in the checksum footer of `page`, which spans the whole page including its overflow
+pub fn write_page_checksums(page: &mut [u8], page_size: usize) {
+  assert_eq!(0, page.len() % page_size);
+  let overflow = (page.len() / page_size - 1) as u32;
+  assert!(page_footer_len(overflow) <= page_size);
+  let footer_start = page.len() - page_footer_len(overflow);
+  for index in 0..=overflow {
+    let page_start = index as usize * page_size;
+    let range = covered(index, overflow, page_size);
+    let sum = crc32c::crc32c(&page[page_start + range.start..page_start + range.end]);
+    let sum_start = footer_start + index as usize * PAGE_CHECKSUM_LEN;
+    page[sum_start..sum_start + PAGE_CHECKSUM_LEN].copy_from_slice(&sum.to_ne_bytes());
+  }
+}
+
+/// Verifies disk page `index` of a page against the footer in `last_page`
+pub fn verify_page_checksum(
+  header: &PageHeader, index: u32, page: &[u8], last_page: &[u8],
+) -> Result<(), PageError> {
+  let overflow = header.get_overflow();
+  let page_size = page.len();
+  if page_footer_len(overflow) > page_size {
+    return Err(PageError::ChecksumMismatch(header.id(), index));
+  }
+  let range = covered(index, overflow, page_size);
+  if crc32c::crc32c(&page[range]) == stored_sum(last_page, index, overflow) {
+    Ok(())
+  } else {
+    Err(PageError::ChecksumMismatch(header.id(), index))
+  }
+}
+
+/// Verifies every disk page of `page`, which spans the whole page including its overflow
+pub fn verify_page_checksums(page: &[u8], page_size: usize) -> Result<(), PageError> {
+  let header: PageHeader = bytemuck::pod_read_unaligned(&page[0..size_of::<PageHeader>()]);
+  let overflow = header.get_overflow();
+  let last_page = &page[overflow as usize * page_size..(overflow as usize + 1) * page_size];
+  for (index, disk_page) in page.chunks_exact(page_size).enumerate() {
+    verify_page_checksum(&header, index as u32, disk_page, last_page)?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::id::DbPageId;
+
+  #[test]
+  fn test_page_checksums() {
+    let page_size = 512;
+    let mut page = vec![0u8; page_size * 3];
+    let mut header = PageHeader::init_leaf(DbPageId(4));
+    unsafe { header.set_overflow(2) };
+    page[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+    page[size_of::<PageHeader>()..page_size * 3 - page_footer_len(2)].fill(7);
+    write_page_checksums(&mut page, page_size);
+    assert!(verify_page_checksums(&page, page_size).is_ok());
+
+    page[page_size + 10] ^= 1;
+    assert!(matches!(
+      verify_page_checksums(&page, page_size),
+      Err(PageError::ChecksumMismatch(DbPageId(4), 1))
+    ));
+    let last_page = &page[page_size * 2..];
+    assert!(verify_page_checksum(&header, 2, last_page, last_page).is_ok());
+
+    // The footer of a full page spills into an overflow page
+    assert_eq!(1, page_count(page_size - 4, page_size, true));
+    assert_eq!(2, page_count(page_size - 3, page_size, true));
+    assert_eq!(1, page_count(page_size, page_size, false));
+  }
+}
@@ -0,0 +1,128 @@
+use crate::common::consts::MAX_VALUE_SIZE;
+use crate::common::errors::BucketError;
+use crate::common::layout::node::{LeafElement, LeafFlag};
+use crate::common::layout::page::PageHeader;
+use error_stack::{Report, ResultExt};
+use std::borrow::Cow;
+
+/// Compresses `value` if it's at least `threshold` bytes long and compressing makes it smaller.
+///
+/// The compressed value is prefixed with its uncompressed length.
+pub fn compress_value(value: &[u8], threshold: usize) -> Option<Vec<u8>> {
+  if value.len() < threshold {
+    return None;
+  }
+  let compressed = lz4_flex::compress_prepend_size(value);
+  if compressed.len() < value.len() {
+    Some(compressed)
+  } else {
+    None
+  }
+}
+
+/// Decompresses a value written by `compress_value`. The prepended length is checked against
+/// [`MAX_VALUE_SIZE`] before anything is allocated, so a corrupt value can't claim more.
+pub fn decompress_value(value: &[u8]) -> crate::Result<Vec<u8>, BucketError> {
+  let (len, compressed) = value
+    .split_first_chunk::<4>()
+    .ok_or(BucketError::Decompress)?;
+  let len = u32::from_le_bytes(*len) as usize;
+  if len > MAX_VALUE_SIZE {
+    return Err(Report::new(BucketError::Decompress));
+  }
+  lz4_flex::decompress(compressed, len).change_context(BucketError::Decompress)
+}
+
+/// Whether `page` is a leaf with [`LeafFlag::COMPRESSED`] values. `None` if its elements don't
+/// all fit in `page`, such as when it's only the first page of a node.
+pub fn has_compressed_values(page: &[u8]) -> Option<bool> {
+  let header_len = size_of::<PageHeader>();
+  let header: PageHeader = bytemuck::pod_read_unaligned(page.get(0..header_len)?);
+  if !header.is_leaf() {
+    return Some(false);
+  }
+  let elements_end = header_len + header.count() as usize * size_of::<LeafElement>();
+  let compressed = page
+    .get(header_len..elements_end)?
+    .chunks_exact(size_of::<LeafElement>())
+    .map(bytemuck::pod_read_unaligned::<LeafElement>)
+    .any(|element| element.flags().contains(LeafFlag::COMPRESSED));
+  Some(compressed)
+}
+
+/// Rebuilds a leaf page with every [`LeafFlag::COMPRESSED`] value decompressed. The header and
+/// flags are kept, so the page still describes how it's stored. `None` if `page` isn't a leaf or
+/// has nothing compressed.
+pub fn decompress_leaf(page: &[u8]) -> crate::Result<Option<Vec<u8>>, BucketError> {
+  if !has_compressed_values(page).ok_or(BucketError::Decompress)? {
+    return Ok(None);
+  }
+  let header_len = size_of::<PageHeader>();
+  let header: PageHeader = bytemuck::pod_read_unaligned(&page[0..header_len]);
+  let elements_end = header_len + header.count() as usize * size_of::<LeafElement>();
+  let elements: Vec<LeafElement> = page[header_len..elements_end]
+    .chunks_exact(size_of::<LeafElement>())
+    .map(bytemuck::pod_read_unaligned)
+    .collect();
+  let mut kvs = Vec::with_capacity(elements.len());
+  for (index, element) in elements.iter().enumerate() {
+    let key_start = header_len + index * size_of::<LeafElement>() + element.key_dist() as usize;
+    let value_start = key_start + element.key_len() as usize;
+    let value_end = value_start + element.value_len() as usize;
+    let (Some(key), Some(value)) = (
+      page.get(key_start..value_start),
+      page.get(value_start..value_end),
+    ) else {
+      return Err(Report::new(BucketError::Decompress));
+    };
+    let value = if element.flags().contains(LeafFlag::COMPRESSED) {
+      Cow::Owned(decompress_value(value)?)
+    } else {
+      Cow::Borrowed(value)
+    };
+    kvs.push((key, value));
+  }
+  let data_len: usize = kvs.iter().map(|(key, value)| key.len() + value.len()).sum();
+  let mut leaf = Vec::with_capacity(elements_end + data_len);
+  leaf.extend_from_slice(&page[0..header_len]);
+  let mut data_start = elements_end;
+  for (index, (element, (key, value))) in elements.iter().zip(&kvs).enumerate() {
+    let element_start = header_len + index * size_of::<LeafElement>();
+    let element = LeafElement::new(
+      element.flags(),
+      (data_start - element_start) as u32,
+      key.len() as u32,
+      value.len() as u32,
+    );
+    leaf.extend_from_slice(bytemuck::bytes_of(&element));
+    data_start += key.len() + value.len();
+  }
+  for (key, value) in &kvs {
+    leaf.extend_from_slice(key);
+    leaf.extend_from_slice(value);
+  }
+  Ok(Some(leaf))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_compress_value() {
+    let value = br#"{"name":"widget","color":"blue","size":"large"}"#.repeat(20);
+    assert_eq!(None, compress_value(&value, value.len() + 1));
+    let compressed = compress_value(&value, 64).unwrap();
+    assert!(compressed.len() < value.len());
+    assert_eq!(value, decompress_value(&compressed).unwrap());
+    // Incompressible values are stored as is
+    let noise: Vec<u8> = (0..256u32).map(|i| (i * 167 % 251) as u8).collect();
+    assert_eq!(None, compress_value(&noise, 64));
+    assert!(decompress_value(&value).is_err());
+    // A corrupt length is rejected rather than allocated
+    let mut huge = compressed.clone();
+    huge[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(decompress_value(&huge).is_err());
+    assert!(decompress_value(&[0; 3]).is_err());
+  }
+}
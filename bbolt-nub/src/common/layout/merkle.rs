@@ -0,0 +1,147 @@
+use crate::common::errors::BucketError;
+use crate::common::id::NodePageId;
+use crate::common::layout::bucket::BucketHeader;
+use crate::common::layout::node::{BranchElement, LeafFlag};
+use crate::common::layout::page::PageHeader;
+use bytemuck::{Pod, Zeroable};
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Formatter};
+use std::ops::Range;
+
+const LEAF_TAG: u8 = 0x00;
+const BRANCH_TAG: u8 = 0x01;
+const VALUE_TAG: u8 = 0x02;
+const BUCKET_TAG: u8 = 0x03;
+
+/// A SHA-256 hash of a page and everything reachable from it.
+///
+/// A leaf's hash covers each element's key and value. A nested bucket's value is covered by its
+/// sequence and the hash of its root instead. A branch's hash covers each element's key and the
+/// hash of its child. Page ids aren't covered, so the same tree written to different pages has
+/// the same hash.
+#[repr(transparent)]
+#[derive(Default, Copy, Clone, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct MerkleHash(pub [u8; 32]);
+
+impl Debug for MerkleHash {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    for byte in self.0 {
+      write!(f, "{byte:02x}")?;
+    }
+    Ok(())
+  }
+}
+
+impl MerkleHash {
+  #[inline]
+  pub fn is_zero(&self) -> bool {
+    *self == MerkleHash::default()
+  }
+
+  fn finish(hasher: Sha256) -> MerkleHash {
+    MerkleHash(hasher.finalize().into())
+  }
+}
+
+/// Hashes a node from its elements, in order
+pub struct NodeHasher(Sha256);
+
+impl NodeHasher {
+  pub fn leaf() -> NodeHasher {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_TAG]);
+    NodeHasher(hasher)
+  }
+
+  pub fn branch() -> NodeHasher {
+    let mut hasher = Sha256::new();
+    hasher.update([BRANCH_TAG]);
+    NodeHasher(hasher)
+  }
+
+  /// Adds an element's key and the hash of its value or child
+  pub fn update(&mut self, key: &[u8], hash: MerkleHash) {
+    self.0.update((key.len() as u32).to_le_bytes());
+    self.0.update(key);
+    self.0.update(hash.0);
+  }
+
+  pub fn finish(self) -> MerkleHash {
+    MerkleHash::finish(self.0)
+  }
+}
+
+/// Hashes a leaf element's value, replacing a nested bucket's root with the hash of its tree.
+/// Leaves are read with their values decompressed, so `value` is hashed decompressed.
+pub fn value_hash<F>(
+  flag: LeafFlag, value: &[u8], root_hash: F,
+) -> crate::Result<MerkleHash, BucketError>
+where
+  F: FnOnce(NodePageId) -> crate::Result<MerkleHash, BucketError>,
+{
+  let mut hasher = Sha256::new();
+  if flag.contains(LeafFlag::BUCKET) {
+    let header: BucketHeader = bytemuck::pod_read_unaligned(
+      value
+        .get(0..size_of::<BucketHeader>())
+        .ok_or(BucketError::ValueIsBytes)?,
+    );
+    hasher.update([BUCKET_TAG]);
+    hasher.update(header.sequence().to_le_bytes());
+    if header.root().0.0 == 0 {
+      // Inline buckets store their page in the value
+      hasher.update(&value[size_of::<BucketHeader>()..]);
+    } else {
+      hasher.update(root_hash(header.root().into())?.0);
+    }
+  } else {
+    hasher.update([VALUE_TAG]);
+    hasher.update(value);
+  }
+  Ok(MerkleHash::finish(hasher))
+}
+
+/// Where the hash of the child of element `index` is stored in a branch page of `count`
+/// elements. The version 3 format stores the hashes between the elements and the keys.
+pub fn branch_hash_range(count: usize, index: usize) -> Range<usize> {
+  let start =
+    size_of::<PageHeader>() + count * size_of::<BranchElement>() + index * size_of::<MerkleHash>();
+  start..start + size_of::<MerkleHash>()
+}
+
+/// The hash of a nested bucket's root, which the version 3 format stores after its header
+pub fn bucket_root_hash(value: &[u8]) -> Option<MerkleHash> {
+  let start = size_of::<BucketHeader>();
+  value
+    .get(start..start + size_of::<MerkleHash>())
+    .map(bytemuck::pod_read_unaligned)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::id::{BucketPageId, DbPageId};
+
+  #[test]
+  fn test_value_hash() {
+    let value = value_hash(LeafFlag::empty(), b"value", |_| unreachable!()).unwrap();
+    assert_ne!(
+      value,
+      value_hash(LeafFlag::empty(), b"other", |_| unreachable!()).unwrap()
+    );
+
+    // A nested bucket is hashed by its root's hash, wherever the root is
+    let mut bucket = bytemuck::bytes_of(&BucketHeader::new(BucketPageId(DbPageId(3)), 1)).to_vec();
+    let root = MerkleHash([7; 32]);
+    bucket.extend_from_slice(&root.0);
+    assert_eq!(Some(root), bucket_root_hash(&bucket));
+    let hash = value_hash(LeafFlag::BUCKET, &bucket, |_| Ok(root)).unwrap();
+    let moved = BucketHeader::new(BucketPageId(DbPageId(9)), 1);
+    let moved = bytemuck::bytes_of(&moved);
+    assert_eq!(
+      hash,
+      value_hash(LeafFlag::BUCKET, moved, |_| Ok(root)).unwrap()
+    );
+    assert_eq!(None, bucket_root_hash(moved));
+  }
+}
@@ -1,9 +1,12 @@
 use crate::common::id::{EOFPageId, FreelistPageId, TxId};
 use crate::common::layout::bucket::BucketHeader;
+use crate::common::layout::merkle::MerkleHash;
+use crate::common::layout::node::LeafFlag;
 use crate::common::layout::page::PageHeader;
 use bytemuck::{Pod, Zeroable};
 use fnv_rs::{Fnv64, FnvHasher};
 use std::hash::Hasher;
+use std::mem::offset_of;
 
 /// `Meta` represents the on-file layout of a database's metadata
 ///
@@ -28,13 +31,21 @@ pub struct Meta {
   pub tx_id: TxId,
   /// Checksum of the previous Meta fields using the 64-bit version of the Fowler-Noll-Vo hash function
   pub checksum: u64,
+  /// The Merkle hash of the root bucket, stored by the version 3 format. Go BBolt's meta ends
+  /// at the checksum, so it's zero in its files.
+  pub root_hash: MerkleHash,
 }
 
 impl Meta {
+  /// The checksum of every field but `checksum`. A zero root hash isn't covered, so Go BBolt
+  /// agrees on the checksum of its files.
   pub fn sum64(&self) -> u64 {
     let mut h = Fnv64::new();
-    let bytes = &bytemuck::bytes_of(self)[0..size_of::<Meta>() - size_of::<u64>()];
+    let bytes = &bytemuck::bytes_of(self)[0..offset_of!(Meta, checksum)];
     h.update(bytes);
+    if !self.root_hash.is_zero() {
+      h.update(&self.root_hash.0);
+    }
     h.finish()
   }
 
@@ -46,6 +57,18 @@ impl Meta {
   pub fn update_checksum(&mut self) {
     self.checksum = self.sum64();
   }
+
+  /// The root bucket's compression threshold, stored in `flags` the way a nested bucket's is
+  /// stored in its [`LeafFlag`]
+  pub fn compression_threshold(&self) -> Option<usize> {
+    LeafFlag::from_bits_retain(self.flags).compression_threshold()
+  }
+
+  pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+    self.flags = LeafFlag::from_bits_retain(self.flags)
+      .with_compression_threshold(threshold)
+      .bits();
+  }
 }
 
 #[repr(C)]
@@ -54,3 +77,15 @@ pub struct HeaderMetaPage {
   pub(crate) header: PageHeader,
   pub(crate) meta: Meta,
 }
+
+impl HeaderMetaPage {
+  #[inline]
+  pub fn header(&self) -> &PageHeader {
+    &self.header
+  }
+
+  #[inline]
+  pub fn meta(&self) -> &Meta {
+    &self.meta
+  }
+}
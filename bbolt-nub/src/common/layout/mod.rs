@@ -1,4 +1,8 @@
 pub mod bucket;
+pub mod checksum;
+pub mod compression;
+pub mod merkle;
 pub mod meta;
 pub mod node;
 pub mod page;
+pub mod page_map;
@@ -18,6 +18,16 @@ pub struct BranchElement {
   page_id: NodePageId,
 }
 
+impl BranchElement {
+  pub fn new(key_dist: u32, key_len: u32, page_id: NodePageId) -> BranchElement {
+    BranchElement {
+      key_dist,
+      key_len,
+      page_id,
+    }
+  }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct LeafFlag(u32);
@@ -25,6 +35,36 @@ pub struct LeafFlag(u32);
 bitflags::bitflags! {
   impl LeafFlag: u32 {
     const BUCKET = 0x01;
+    /// The value is LZ4 compressed. Go BBolt ignores this flag and sees the compressed bytes.
+    const COMPRESSED = 0x02;
+  }
+}
+
+impl LeafFlag {
+  /// A bucket element's compression threshold is stored above the flag bits, plus one so 0 means
+  /// none. Go BBolt only checks [`LeafFlag::BUCKET`] and ignores it.
+  const COMPRESSION_SHIFT: u32 = 8;
+
+  /// The largest compression threshold a bucket can store
+  pub const MAX_COMPRESSION_THRESHOLD: usize = (u32::MAX >> Self::COMPRESSION_SHIFT) as usize - 1;
+
+  /// The compression threshold of the bucket this element stores, if it has one
+  pub fn compression_threshold(self) -> Option<usize> {
+    match self.bits() >> Self::COMPRESSION_SHIFT {
+      0 => None,
+      threshold => Some(threshold as usize - 1),
+    }
+  }
+
+  /// These flags with the compression threshold replaced by `threshold`, which can't be larger
+  /// than [`LeafFlag::MAX_COMPRESSION_THRESHOLD`]
+  pub fn with_compression_threshold(self, threshold: Option<usize>) -> LeafFlag {
+    let flags = self.bits() & ((1 << Self::COMPRESSION_SHIFT) - 1);
+    let threshold = threshold.map_or(0, |threshold| {
+      debug_assert!(threshold <= Self::MAX_COMPRESSION_THRESHOLD);
+      threshold as u32 + 1
+    });
+    LeafFlag::from_bits_retain(flags | threshold << Self::COMPRESSION_SHIFT)
   }
 }
 
@@ -46,3 +86,14 @@ pub struct LeafElement {
   /// Value length
   value_len: u32,
 }
+
+impl LeafElement {
+  pub fn new(flags: LeafFlag, key_dist: u32, key_len: u32, value_len: u32) -> LeafElement {
+    LeafElement {
+      flags,
+      key_dist,
+      key_len,
+      value_len,
+    }
+  }
+}
@@ -14,8 +14,10 @@ bitflags::bitflags! {
     const LEAF = 0x02;
     const META = 0x04;
     const FREELIST = 0x10;
+    const PAGE_MAP = 0x20;
+    const FREE_SPACE = 0x40;
     const NODE_TYPE_MASK = 0x01 | 0x02;
-    const PAGE_TYPE_MASK = 0x01 | 0x02 | 0x04 | 0x10;
+    const PAGE_TYPE_MASK = 0x01 | 0x02 | 0x04 | 0x10 | 0x20 | 0x40;
   }
 }
 
@@ -37,9 +39,11 @@ bitflags::bitflags! {
 pub struct PageHeader {
   /// This Page's ID
   id: DbPageId,
-  /// Page's type. Branch(0x01), Leaf(0x02), Meta(0x04), or FreeList(0x10)
+  /// Page's type. Branch(0x01), Leaf(0x02), Meta(0x04), FreeList(0x10), PageMap(0x20), or
+  /// FreeSpace(0x40)
   flags: PageFlag,
-  /// Defines the number of items in the Branch, Leaf, and Freelist pages
+  /// Defines the number of items in the Branch, Leaf, Freelist, and PageMap pages, and the
+  /// length of the FreeSpace bitmap
   count: u16,
   #[getset(skip)]
   /// How many additional meta.page_size pages are included in this page
@@ -83,6 +87,16 @@ impl PageHeader {
     (self.flags & PageFlag::PAGE_TYPE_MASK) == PageFlag::FREELIST
   }
 
+  #[inline(always)]
+  pub fn is_page_map(&self) -> bool {
+    (self.flags & PageFlag::PAGE_TYPE_MASK) == PageFlag::PAGE_MAP
+  }
+
+  #[inline(always)]
+  pub fn is_free_space(&self) -> bool {
+    (self.flags & PageFlag::PAGE_TYPE_MASK) == PageFlag::FREE_SPACE
+  }
+
   #[inline(always)]
   pub fn init_meta(id: DbPageId) -> Self {
     PageHeader {
@@ -103,6 +117,26 @@ impl PageHeader {
     }
   }
 
+  #[inline(always)]
+  pub fn init_page_map(id: DbPageId) -> Self {
+    PageHeader {
+      id,
+      flags: PageFlag::PAGE_MAP,
+      count: 0,
+      overflow: 0,
+    }
+  }
+
+  #[inline(always)]
+  pub fn init_free_space(id: DbPageId) -> Self {
+    PageHeader {
+      id,
+      flags: PageFlag::FREE_SPACE,
+      count: 0,
+      overflow: 0,
+    }
+  }
+
   #[inline(always)]
   pub fn init_leaf(id: DbPageId) -> Self {
     PageHeader {
@@ -145,7 +179,8 @@ impl PageHeader {
   pub fn overflow_page_id(&self) -> Option<OverflowPageId> {
     if self.is_node() {
       Some(OverflowPageId::Node(NodePageId(self.id)))
-    } else if self.is_freelist() {
+    } else if self.is_freelist() || self.is_page_map() {
+      // The page map is stored where the freelist would be
       Some(OverflowPageId::Freelist(FreelistPageId(self.id)))
     } else {
       None
@@ -159,6 +194,8 @@ impl PageHeader {
       PageFlag::LEAF => Cow::Borrowed("leaf"),
       PageFlag::META => Cow::Borrowed("meta"),
       PageFlag::FREELIST => Cow::Borrowed("freelist"),
+      PageFlag::PAGE_MAP => Cow::Borrowed("page map"),
+      PageFlag::FREE_SPACE => Cow::Borrowed("free space"),
       _ => Cow::Owned(format!("unknown<{:#x}>", self.flags.bits())),
     }
   }
@@ -0,0 +1,19 @@
+use crate::common::id::{DiskPageId, NodePageId};
+use bytemuck::{Pod, Zeroable};
+use getset::CopyGetters;
+
+/// `PageMapEntry` represents the on-file layout of a page map entry, the disk page a node page
+/// is stored at.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, CopyGetters, Zeroable, Pod, Eq, PartialEq)]
+#[getset(get_copy = "pub")]
+pub struct PageMapEntry {
+  node: NodePageId,
+  disk: DiskPageId,
+}
+
+impl PageMapEntry {
+  pub fn new(node: NodePageId, disk: DiskPageId) -> PageMapEntry {
+    PageMapEntry { node, disk }
+  }
+}
@@ -1,5 +1,6 @@
 pub mod buffer_pool;
 pub mod data_pool;
+pub mod epoch;
 pub mod errors;
 pub mod id;
 pub mod vec_pool;
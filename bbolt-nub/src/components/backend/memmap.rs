@@ -1,37 +1,2570 @@
-use crate::common::errors::DbError;
+use crate::api::tx::TxStats;
+use crate::common::buffer_pool::BufferPool;
+use crate::common::consts::{
+  BBOLT_RS_TAG, DEFAULT_ALLOC_SIZE, DEFAULT_COMPACT_TX_MAX_SIZE, DEFAULT_FILL_PERCENT,
+  DEFAULT_MAX_BATCH_DELAY, DEFAULT_MAX_BATCH_SIZE, DbFormat, DbTag, MAX_FILL_PERCENT,
+  MIN_FILL_PERCENT, PGID_NO_FREELIST,
+};
+use crate::common::data_pool::DataPool;
+use crate::common::epoch::Epochs;
+use crate::common::errors::{BucketError, DbError, TxError};
+use crate::common::id::{
+  DbPageId, DirectPageTranslator, DiskPageId, DiskPageTranslator, EOFPageId, FreelistPageId,
+  NodePageId, PageMapTranslator, StableFreeSpaceTranslator, TxId,
+};
+use crate::common::layout::bucket::BucketHeader;
+use crate::common::layout::checksum::{page_count, write_page_checksums};
+use crate::common::layout::merkle::MerkleHash;
+use crate::common::layout::meta::Meta;
+use crate::components::backend::{
+  init_db_file, init_db_io, lock_db_file, meta_page, read_db_meta, write_meta_page,
+};
+use crate::components::bucket_path::BucketPathBuf;
+use crate::components::check::TxCheck;
+use crate::components::commit::phase2::commit_tx;
+use crate::components::commit::wal::{Wal, WalOptions, WriteAheadLog, wal_path};
+use crate::components::compact::compact;
+use crate::components::free_index::{FreeIndex, NodeAllocator, PendingPages};
+use crate::components::tx::{CoreTxHandle, MutTxHandle, RefTxHandle, TxPageMap};
+use crate::io::backends::crypt::{CryptMemMapIO, PageEncryption, crypt_page_size};
+use crate::io::backends::file_lock::FileLockType;
+use crate::io::backends::memmap::{MemMapIO, MemMapReadOptions, MemMapWriteOptions};
+use crate::io::backends::meta_reader::MetaReader;
+use crate::io::backends::{
+  DirectReadHandler, IOBackend, IOWriter, NewIOReadWriter, NewIOReader, RHandler,
+};
+use crate::io::bytes::shared_bytes::SharedBytes;
+use crate::io::pages::types::free_space::{free_space_bitmap_len, write_free_space_page};
+use crate::io::pages::types::freelist::{HasFreelist, freelist_page_len, write_freelist_page};
+use crate::io::pages::types::page_map::{HasPageMap, page_map_page_len, write_page_map_page};
+use crate::io::pages::{Page, TxReadPageIO};
+use crate::io::transmogrify::direct::DirectTransmogrify;
 use bon::{bon, builder};
+use crossbeam_channel::Sender;
+use error_stack::ResultExt;
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use size::Size;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync;
+use std::time::{Duration, Instant};
+use std::{iter, mem};
 
 pub struct MemmapOptions {
   //MMap Specific
-  preload_freelist: bool,
   use_mlock: bool,
   // Common
   disable_growth_sync: bool,
   disable_freelist_sync: bool,
+  page_checksums: bool,
+  encryption: Option<PageEncryption>,
 }
 
-pub struct MemmapDb {}
+pub type MemmapReader = DirectReadHandler<DirectTransmogrify, CryptMemMapIO>;
+
+pub type MemmapTx<'tx> = RefTxHandle<'tx, RHandler<MemmapReader>>;
+
+type BatchFn = Box<dyn Fn(&MemmapMutTx<'_>) -> crate::Result<(), DbError> + Send>;
+
+/// A closure waiting to run in the next batch
+struct BatchCall {
+  f: BatchFn,
+  result: Sender<BatchResult>,
+}
+
+enum BatchResult {
+  Committed,
+  Failed(error_stack::Report<DbError>),
+  /// The closure failed inside the batch and is handed back to be retried on its own
+  TrySolo(BatchFn),
+}
+
+/// Closures collected for the next batch along with when the batch should run
+struct PendingBatch {
+  calls: Mutex<Vec<BatchCall>>,
+  full: Condvar,
+  max_size: usize,
+  max_delay: Duration,
+}
+
+/// The pages the writer can reuse as of the last commit
+#[derive(Default)]
+struct FreePages {
+  free: Vec<DiskPageId>,
+  pending: PendingPages,
+}
+
+pub struct MemmapDb {
+  path: sync::Arc<PathBuf>,
+  db_tag: DbTag,
+  format: DbFormat,
+  read_only: bool,
+  options: MemmapOptions,
+  meta: RwLock<Meta>,
+  /// Where the node pages are stored as of `meta` in the version 3 format. Updated under the
+  /// meta lock.
+  page_map: RwLock<Option<PageMapTranslator>>,
+  stats: sync::Arc<TxStats>,
+  io: RwLock<RHandler<MemmapReader>>,
+  writer: Mutex<FreePages>,
+  /// Pins the id of every open read transaction
+  readers: Epochs<()>,
+  buffer_pool: BufferPool,
+  data_pool: DataPool,
+  wal: Option<Wal>,
+  batch: PendingBatch,
+}
 
 #[bon]
 impl MemmapDb {
+  /// Opens the database at `path`, creating it if needed.
+  ///
+  /// The format is detected from the file's tag. `db_tag` sets the tag of new files, defaulting
+  /// to [`BBOLT_RS_TAG`], and when set requires existing files to match it.
+  ///
+  /// With `no_freelist_sync` the freelist is never written, and the free pages are found by
+  /// walking every bucket when the database is opened for writing. Files in the version 3
+  /// format, [`DbFormat::BetterBBoltRs`], always write their page map in its place, and files
+  /// in the clustered format, [`DbFormat::StableFreeSpace`], their free space pages.
+  ///
+  /// With `no_grow_sync` the file isn't synced after it grows, which is only safe on file
+  /// systems other than ext3 and ext4. `NoGrowSync` in Go BBolt.
+  ///
+  /// With `page_checksums` every page but the meta pages ends in a CRC32C footer that's
+  /// verified as the page is read, so bit rot is reported instead of read back. Nothing in the
+  /// file records it, so a file must always be opened with the setting it was created with.
+  ///
+  /// With `encryption` every page is encrypted through a
+  /// [`CryptShell`](crate::io::backends::crypt::CryptShell), and `page_size` is the size of the
+  /// pages on disk, so the database's own pages are `CRYPT_FOOTER_LEN` bytes smaller. It can't
+  /// be combined with a write-ahead log, whose pages aren't encrypted. [`MemmapDb::write_to`]
+  /// and [`MemmapDb::copy_file`] write the decrypted pages.
   #[builder(finish_fn = open_path)]
   pub fn new(
     #[builder(finish_fn)]
     #[builder(into)]
     path: PathBuf, page_size: Option<usize>,
     file_lock_timeout: Option<Duration>, #[builder(default)] use_mlock: bool,
+    db_tag: Option<DbTag>, #[builder(default)] read_only: bool, wal: Option<WalOptions>,
+    #[builder(default)] no_freelist_sync: bool, #[builder(default)] no_grow_sync: bool,
+    #[builder(default)] page_checksums: bool, encryption: Option<PageEncryption>,
+    #[builder(default = DEFAULT_MAX_BATCH_SIZE)] max_batch_size: u32,
+    #[builder(default = DEFAULT_MAX_BATCH_DELAY)] max_batch_delay: Duration,
   ) -> crate::Result<Self, DbError> {
-    todo!()
+    if let Some(db_tag) = db_tag {
+      db_tag.format().ok_or(DbError::UnknownTag(db_tag))?;
+    }
+    let mut options = OpenOptions::new();
+    if read_only {
+      options.read(true);
+    } else {
+      options.read(true).write(true).create(true).truncate(false);
+    }
+    let mut file = options
+      .open(&path)
+      .change_context_lazy(|| DbError::OpenError(path.clone()))?;
+    let lock_type = if read_only {
+      FileLockType::Shared
+    } else {
+      FileLockType::Exclusive
+    };
+    lock_db_file(&path, &file, lock_type, file_lock_timeout)?;
+
+    let file_len = file
+      .metadata()
+      .change_context_lazy(|| DbError::OpenError(path.clone()))?
+      .len();
+
+    let log_path = wal_path(&path);
+    if encryption.is_some() && (wal.is_some() || WriteAheadLog::is_pending(&log_path)) {
+      return Err(DbError::EncryptedWal(log_path).into());
+    }
+    let new_file = file_len == 0 && !read_only;
+    let init_meta = if new_file && encryption.is_none() {
+      let page_size = page_size.unwrap_or_else(page_size::get);
+      let meta_page = init_db_file(
+        &mut file,
+        page_size,
+        db_tag.unwrap_or(BBOLT_RS_TAG),
+        page_checksums,
+      )
+      .change_context_lazy(|| DbError::InitError(path.clone()))?;
+      Some(meta_page.meta)
+    } else {
+      None
+    };
+    // Committed transactions left in the log are replayed before the meta is read
+    let wal = if read_only {
+      if WriteAheadLog::is_pending(&log_path) {
+        return Err(DbError::WalPending(log_path).into());
+      }
+      None
+    } else {
+      let db_file = file
+        .try_clone()
+        .change_context_lazy(|| DbError::OpenError(path.clone()))?;
+      match wal {
+        Some(options) => Some(
+          Wal::open(log_path.clone(), db_file, options)
+            .change_context_lazy(|| DbError::WalError(log_path.clone()))?
+            .0,
+        ),
+        None if WriteAheadLog::is_pending(&log_path) => {
+          WriteAheadLog::open(log_path.clone(), &db_file)
+            .change_context_lazy(|| DbError::WalError(log_path.clone()))?;
+          None
+        }
+        None => None,
+      }
+    };
+
+    let read_options =
+      MemMapReadOptions::new(false, use_mlock, true).with_page_checksums(page_checksums);
+    let path = sync::Arc::new(path);
+    let (meta, io) = match &encryption {
+      Some(encryption) => {
+        let disk_page_size = if new_file {
+          page_size.unwrap_or_else(page_size::get)
+        } else {
+          crypt_page_size(&file).change_context_lazy(|| DbError::OpenError((*path).clone()))?
+        };
+        let io =
+          MemmapDb::open_memmap(&path, disk_page_size, read_options, read_only, no_grow_sync)?;
+        MemmapDb::open_encrypted(
+          &path,
+          io,
+          encryption,
+          new_file.then(|| db_tag.unwrap_or(BBOLT_RS_TAG)),
+          page_checksums,
+        )?
+      }
+      None => {
+        let meta = match init_meta {
+          Some(meta) => meta,
+          None => {
+            let meta_file = file
+              .try_clone()
+              .change_context_lazy(|| DbError::OpenError((*path).clone()))?;
+            MetaReader::new(BufReader::new(meta_file))
+              .determine_file_meta()
+              .change_context_lazy(|| DbError::OpenError((*path).clone()))?
+              .meta
+          }
+        };
+        let io = MemmapDb::open_memmap(
+          &path,
+          meta.page_size as usize,
+          read_options,
+          read_only,
+          no_grow_sync,
+        )?;
+        (meta, CryptMemMapIO::Plain(io))
+      }
+    };
+
+    let file_tag = DbTag {
+      version: meta.version,
+      magic: meta.magic,
+    };
+    if let Some(db_tag) = db_tag {
+      if file_tag != db_tag {
+        return Err(DbError::InvalidTag(db_tag, file_tag).into());
+      }
+    }
+    let format = file_tag.format().ok_or(DbError::UnknownTag(file_tag))?;
+
+    let reader = DirectReadHandler {
+      tx_context: DirectTransmogrify,
+      io,
+    };
+    let handler = RHandler::new((*path).clone(), file, reader);
+    let page_size = meta.page_size as usize;
+    let buffer_pool = BufferPool::new(
+      page_size,
+      Size::from_kibibytes(256),
+      Size::from_kibibytes(256),
+      Size::from_mebibytes(16),
+    );
+    let data_pool = DataPool::new(
+      Size::from_kibibytes(64),
+      Size::from_kibibytes(64),
+      Size::from_mebibytes(16),
+      64,
+      page_size,
+    );
+
+    let db = MemmapDb {
+      path,
+      db_tag: file_tag,
+      format,
+      read_only,
+      options: MemmapOptions {
+        use_mlock,
+        disable_growth_sync: no_grow_sync,
+        disable_freelist_sync: no_freelist_sync,
+        page_checksums,
+        encryption,
+      },
+      meta: RwLock::new(meta),
+      page_map: RwLock::new(None),
+      stats: sync::Arc::new(TxStats::default()),
+      io: RwLock::new(handler),
+      writer: Mutex::new(FreePages::default()),
+      readers: Epochs::new(),
+      buffer_pool,
+      data_pool,
+      wal,
+      batch: PendingBatch {
+        calls: Mutex::new(Vec::new()),
+        full: Condvar::new(),
+        max_size: max_batch_size as usize,
+        max_delay: max_batch_delay,
+      },
+    };
+    if format == DbFormat::BetterBBoltRs {
+      *db.page_map.write() = Some(db.load_page_map()?);
+    }
+    if !read_only {
+      db.writer.lock().free = db.load_free_pages()?;
+    }
+    Ok(db)
+  }
+
+  fn open_memmap(
+    path: &sync::Arc<PathBuf>, page_size: usize, read_options: MemMapReadOptions, read_only: bool,
+    no_grow_sync: bool,
+  ) -> crate::Result<MemMapIO, DbError> {
+    if read_only {
+      MemMapIO::new_ro(path.clone(), page_size, read_options).map(|io| io.into_inner())
+    } else {
+      let write_options = MemMapWriteOptions::new(DEFAULT_ALLOC_SIZE.bytes() as u64, no_grow_sync);
+      MemMapIO::new_rw(path.clone(), page_size, read_options, write_options)
+    }
+    .change_context_lazy(|| DbError::OpenError((**path).clone()))
+  }
+
+  /// Opens `io` through a [`CryptShell`](crate::io::backends::crypt::CryptShell) and reads the
+  /// meta through it. A new file is given a bootstrap header and the initial layout for
+  /// `init_tag`.
+  fn open_encrypted(
+    path: &sync::Arc<PathBuf>, io: MemMapIO, encryption: &PageEncryption, init_tag: Option<DbTag>,
+    page_checksums: bool,
+  ) -> crate::Result<(Meta, CryptMemMapIO), DbError> {
+    let Some(db_tag) = init_tag else {
+      let io = CryptMemMapIO::open(io, encryption)
+        .change_context_lazy(|| DbError::OpenError((**path).clone()))?;
+      // Decrypted pages only live as long as the map is pinned
+      let epoch = io.memmap().pin();
+      let meta = read_db_meta(&io).change_context_lazy(|| DbError::OpenError((**path).clone()))?;
+      drop(epoch);
+      return Ok((meta, io));
+    };
+    let io = CryptMemMapIO::create(io, encryption)
+      .change_context_lazy(|| DbError::InitError((**path).clone()))?;
+    let meta_page = init_db_io(&io, db_tag, page_checksums)
+      .change_context_lazy(|| DbError::InitError((**path).clone()))?;
+    let memmap = io.memmap();
+    memmap
+      .remap(memmap.file_len() as usize)
+      .change_context_lazy(|| DbError::InitError((**path).clone()))?;
+    Ok((meta_page.meta, io))
+  }
+
+  /// Reads the version 3 page map from the page the meta records in place of the freelist
+  fn load_page_map(&self) -> crate::Result<PageMapTranslator, DbError> {
+    let (tx, meta) = self.begin_with_meta();
+    let page_map = tx
+      .read_page_map_page(meta.free_list)
+      .change_context_lazy(|| DbError::OpenError((*self.path).clone()))?;
+    Ok(PageMapTranslator::new(meta.tx_id, page_map.page_map_iter()))
+  }
+
+  /// Reads the free pages from the freelist page. When the freelist wasn't written every page
+  /// not reachable from the root bucket is free. See [`RefTxHandle::free_page_ids`]
+  fn load_free_pages(&self) -> crate::Result<Vec<DiskPageId>, DbError> {
+    let (tx, meta) = self.begin_with_meta();
+    tx.free_page_ids(&meta)
+      .change_context_lazy(|| DbError::OpenError((*self.path).clone()))
+  }
+
+  #[inline]
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  #[inline]
+  pub fn db_tag(&self) -> DbTag {
+    self.db_tag
+  }
+
+  /// The on-disk format, as detected from the file's tag
+  #[inline]
+  pub fn format(&self) -> DbFormat {
+    self.format
+  }
+
+  /// Where the node pages are stored as of the most recently committed meta, in the version 3
+  /// format
+  pub fn page_map(&self) -> Option<PageMapTranslator> {
+    self.page_map.read().clone()
+  }
+
+  /// Where transactions reading through `meta` find the node pages. Called under the meta lock
+  /// so the page map matches `meta`.
+  fn tx_page_map(&self, meta: &Meta) -> Option<TxPageMap> {
+    match self.format {
+      DbFormat::StableFreeSpace => {
+        Some(TxPageMap::Clustered(StableFreeSpaceTranslator::for_pages(
+          meta.tx_id,
+          meta.page_size as usize,
+          self.options.page_checksums,
+        )))
+      }
+      _ => self.page_map.read().clone().map(TxPageMap::PageMap),
+    }
+  }
+
+  #[inline]
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  /// The meta of the most recently committed transaction
+  #[inline]
+  pub fn meta(&self) -> Meta {
+    *self.meta.read()
+  }
+
+  #[inline]
+  pub fn page_size(&self) -> usize {
+    self.meta.read().page_size as usize
+  }
+
+  pub fn stats(&self) -> &TxStats {
+    &self.stats
+  }
+
+  /// Syncs the database file and recycles the write-ahead log, if there is one
+  pub fn checkpoint(&self) -> crate::Result<(), DbError> {
+    match &self.wal {
+      Some(wal) => wal
+        .checkpoint()
+        .change_context_lazy(|| DbError::WalError(wal_path(&self.path))),
+      None => Ok(()),
+    }
+  }
+
+  /// Begins a read-only transaction against the most recently committed meta
+  pub fn begin(&self) -> sync::Arc<MemmapTx<'_>> {
+    self.begin_with_meta().0
+  }
+
+  /// Begins a read-only transaction, along with the meta it reads through
+  #[allow(clippy::arc_with_non_send_sync)]
+  fn begin_with_meta(&self) -> (sync::Arc<MemmapTx<'_>>, Meta) {
+    let io = self.io.read();
+    // Pinned under the meta lock so the next commit can't miss this reader
+    let (meta, page_map, reader) = {
+      let meta = self.meta.read();
+      let page_map = self.tx_page_map(&meta);
+      (*meta, page_map, self.readers.pin_at(meta.tx_id.0.0))
+    };
+    let epoch = io.reader().io.memmap().pin();
+    let handle = CoreTxHandle {
+      io: io.into(),
+      stats: self.stats.clone(),
+      tx_id: meta.tx_id,
+      epoch: Some(epoch),
+      reader: Some(reader),
+      page_map,
+    };
+    (sync::Arc::new(RefTxHandle::new(handle)), meta)
+  }
+
+  /// Checks the consistency of every page in a new read-only transaction, yielding each problem
+  /// as it's found. `tx.Check()` in Go BBolt
+  pub fn check(&self) -> TxCheck<'_, MemmapTx<'_>> {
+    let (tx, meta) = self.begin_with_meta();
+    match &tx.handle.page_map {
+      Some(TxPageMap::PageMap(page_map)) => {
+        let page_map = page_map.clone();
+        let page_map_overflow = tx
+          .read_page_map_page(meta.free_list)
+          .map(|page| page.page_header().get_overflow());
+        TxCheck::with_page_map(tx, &meta, page_map, page_map_overflow)
+      }
+      Some(TxPageMap::Clustered(clusters)) => {
+        let clusters = clusters.clone();
+        let free = tx.free_page_ids(&meta);
+        TxCheck::with_clusters(tx, &meta, clusters, free)
+      }
+      None => TxCheck::new(tx, &meta),
+    }
+  }
+
+  /// Copies a snapshot of the database into a new file at `dst_path` with every page packed to
+  /// `fill_percent`. Writers can carry on while it's copied. See
+  /// [`compact`](crate::components::compact::compact)
+  #[builder(finish_fn = compact_into)]
+  pub fn compact(
+    &self, #[builder(finish_fn)]
+    #[builder(into)]
+    dst_path: PathBuf,
+    #[builder(default = DEFAULT_COMPACT_TX_MAX_SIZE)] tx_max_size: u64,
+    #[builder(default = MAX_FILL_PERCENT)] fill_percent: f64,
+  ) -> crate::Result<(), DbError> {
+    if dst_path.exists() {
+      return Err(DbError::CompactError(dst_path).into());
+    }
+    // The size of the pages on disk, which are larger than the meta's when encrypted
+    let disk_page_size = self.io.read().reader().io.memmap().page_size();
+    let (tx, meta) = self.begin_with_meta();
+    let dst = MemmapDb::builder()
+      .page_size(disk_page_size)
+      .db_tag(self.db_tag)
+      .no_freelist_sync(self.options.disable_freelist_sync)
+      .no_grow_sync(self.options.disable_growth_sync)
+      .page_checksums(self.options.page_checksums)
+      .maybe_encryption(self.options.encryption.clone())
+      .open_path(dst_path.clone())
+      .change_context_lazy(|| DbError::CompactError(dst_path.clone()))?;
+    compact(&tx, &meta, &dst, tx_max_size, fill_percent)?;
+    drop(dst);
+    File::open(&dst_path)
+      .and_then(|file| file.sync_all())
+      .change_context_lazy(|| DbError::CompactError(dst_path.clone()))
+  }
+
+  /// Compacts the database into a file beside it, then renames that over the database.
+  ///
+  /// The write-ahead log is checkpointed first so none of it is replayed onto the new file.
+  /// The database is closed, so it must be reopened afterwards.
+  #[builder]
+  pub fn compact_in_place(
+    self, #[builder(default = DEFAULT_COMPACT_TX_MAX_SIZE)] tx_max_size: u64,
+    #[builder(default = MAX_FILL_PERCENT)] fill_percent: f64,
+  ) -> crate::Result<(), DbError> {
+    if self.read_only {
+      return Err(DbError::ReadOnly((*self.path).clone()).into());
+    }
+    self.checkpoint()?;
+    let path = (*self.path).clone();
+    let mut compact_path = path.clone().into_os_string();
+    compact_path.push(".compact");
+    let compact_path = PathBuf::from(compact_path);
+    // Left behind by a compaction that didn't finish
+    let _ = fs::remove_file(&compact_path);
+    if let Err(err) = self
+      .compact()
+      .tx_max_size(tx_max_size)
+      .fill_percent(fill_percent)
+      .compact_into(compact_path.clone())
+    {
+      let _ = fs::remove_file(&compact_path);
+      return Err(err);
+    }
+    drop(self);
+    fs::rename(&compact_path, &path).change_context_lazy(|| DbError::CompactError(path.clone()))?;
+    let dir = match path.parent() {
+      Some(dir) if !dir.as_os_str().is_empty() => dir,
+      _ => Path::new("."),
+    };
+    File::open(dir)
+      .and_then(|dir| dir.sync_all())
+      .change_context_lazy(|| DbError::CompactError(path.clone()))
+  }
+
+  /// Writes a consistent snapshot of the database to `w` while writers carry on, returning the
+  /// number of bytes written. See [`RefTxHandle::write_to`]
+  pub fn write_to<W: Write>(&self, w: W, skip_free: bool) -> crate::Result<u64, TxError> {
+    let (tx, meta) = self.begin_with_meta();
+    tx.write_to(&meta, w, skip_free)
+  }
+
+  /// Copies a consistent snapshot of the database to a new file at `path` while writers carry
+  /// on. See [`RefTxHandle::copy_file`]
+  pub fn copy_file<P: AsRef<Path>>(&self, path: P, skip_free: bool) -> crate::Result<(), TxError> {
+    let (tx, meta) = self.begin_with_meta();
+    tx.copy_file(&meta, path, skip_free)
+  }
+
+  /// Begins a read-write transaction, waiting for any other writer to finish
+  #[allow(clippy::arc_with_non_send_sync)]
+  pub fn begin_mut(&self) -> crate::Result<MemmapMutTx<'_>, DbError> {
+    if self.read_only {
+      return Err(DbError::ReadOnly((*self.path).clone()).into());
+    }
+    let free_pages = self.writer.lock();
+    let io = self.io.upgradable_read();
+    let meta = *self.meta.read();
+    let page_map = self.tx_page_map(&meta);
+    let epoch = io.reader().io.memmap().pin();
+    let handle = CoreTxHandle {
+      io: io.into(),
+      stats: self.stats.clone(),
+      tx_id: meta.tx_id,
+      epoch: Some(epoch),
+      reader: None,
+      page_map,
+    };
+    let tx = sync::Arc::new(RefTxHandle::new(handle));
+    let mut_tx = MutTxHandle::new(
+      tx,
+      self.data_pool.clone(),
+      meta.root,
+      meta.compression_threshold(),
+    );
+    Ok(MemmapMutTx {
+      db: self,
+      meta,
+      tx: sync::Arc::new(mut_tx),
+      free_pages,
+      fill_percent: DEFAULT_FILL_PERCENT,
+    })
+  }
+
+  /// Runs `f` in a read-write transaction shared with other concurrent `batch` calls, like
+  /// `DB.Batch` in Go BBolt.
+  ///
+  /// A batch commits once `max_batch_size` calls have joined or `max_batch_delay` has passed.
+  /// If `f` fails the batch is rolled back and retried without it, and `f` is retried in its
+  /// own transaction. `f` may run more than once so it must be idempotent.
+  pub fn batch<F, E>(&self, f: F) -> crate::Result<(), DbError>
+  where
+    F: Fn(&MemmapMutTx<'_>) -> crate::Result<(), E> + Send + 'static,
+    E: error_stack::Context,
+  {
+    if self.read_only {
+      return Err(DbError::ReadOnly((*self.path).clone()).into());
+    }
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let call = BatchCall {
+      f: Box::new(move |tx| f(tx).change_context(DbError::BatchCallError)),
+      result: sender,
+    };
+    let is_leader = {
+      let mut calls = self.batch.calls.lock();
+      calls.push(call);
+      if calls.len() >= self.batch.max_size {
+        self.batch.full.notify_one();
+      }
+      calls.len() == 1
+    };
+    if is_leader {
+      self.run_batch();
+    }
+    match receiver.recv() {
+      Ok(BatchResult::Committed) => Ok(()),
+      Ok(BatchResult::Failed(report)) => Err(report),
+      Ok(BatchResult::TrySolo(f)) => {
+        let tx = self.begin_mut()?;
+        f(&tx)?;
+        tx.commit()
+      }
+      // The leader panicked while running another call
+      Err(_) => Err(DbError::BatchAborted.into()),
+    }
+  }
+
+  /// Waits for the batch to fill or time out, then runs it
+  fn run_batch(&self) {
+    let deadline = Instant::now() + self.batch.max_delay;
+    let mut calls = {
+      let mut calls = self.batch.calls.lock();
+      while calls.len() < self.batch.max_size {
+        if self.batch.full.wait_until(&mut calls, deadline).timed_out() {
+          break;
+        }
+      }
+      mem::take(&mut *calls)
+    };
+    while !calls.is_empty() {
+      let tx = match self.begin_mut() {
+        Ok(tx) => tx,
+        Err(report) => {
+          for call in calls {
+            let report = error_stack::Report::new(DbError::BatchAborted)
+              .attach_printable(format!("{:?}", report.current_context()));
+            let _ = call.result.send(BatchResult::Failed(report));
+          }
+          return;
+        }
+      };
+      let tx_id = tx.tx_id();
+      match calls.iter().position(|call| (call.f)(&tx).is_err()) {
+        Some(failed) => {
+          tx.rollback();
+          let call = calls.remove(failed);
+          let _ = call.result.send(BatchResult::TrySolo(call.f));
+        }
+        None => {
+          let result = tx.commit();
+          for call in calls {
+            let result = match &result {
+              Ok(()) => BatchResult::Committed,
+              Err(report) => BatchResult::Failed(
+                error_stack::Report::new(DbError::CommitError(tx_id))
+                  .attach_printable(format!("{:?}", report.current_context())),
+              ),
+            };
+            let _ = call.result.send(result);
+          }
+          return;
+        }
+      }
+    }
   }
 }
 
+/// A read-write transaction. Dropping it without calling [`MemmapMutTx::commit`] rolls it back.
+pub struct MemmapMutTx<'db> {
+  db: &'db MemmapDb,
+  meta: Meta,
+  tx: sync::Arc<MutTxHandle<MemmapTx<'db>>>,
+  free_pages: MutexGuard<'db, FreePages>,
+  fill_percent: f64,
+}
+
+impl<'db> Deref for MemmapMutTx<'db> {
+  type Target = sync::Arc<MutTxHandle<MemmapTx<'db>>>;
+
+  fn deref(&self) -> &Self::Target {
+    &self.tx
+  }
+}
+
+impl<'db> MemmapMutTx<'db> {
+  /// The id the transaction is committed as
+  #[inline]
+  pub fn tx_id(&self) -> TxId {
+    TxId::of(self.meta.tx_id.0.0 + 1)
+  }
+
+  /// Sets how full split pages are left, between [`MIN_FILL_PERCENT`] and
+  /// [`MAX_FILL_PERCENT`]. `Bucket.FillPercent` in Go BBolt
+  pub fn set_fill_percent(&mut self, fill_percent: f64) {
+    self.fill_percent = fill_percent.clamp(MIN_FILL_PERCENT, MAX_FILL_PERCENT);
+  }
+
+  /// Compresses values put into the bucket at `path` that are at least `threshold` bytes long,
+  /// from now on. `None` stops compressing. Go BBolt can't read compressed values, so it's an
+  /// error in its format. See [`MutTxHandle::set_compression`]
+  pub fn set_compression(
+    &self, path: &BucketPathBuf, threshold: Option<usize>,
+  ) -> crate::Result<(), BucketError> {
+    if threshold.is_some() && self.db.format() == DbFormat::BBolt {
+      return Err(BucketError::CompressionUnsupported.into());
+    }
+    self.tx.set_compression(path, threshold)
+  }
+
+  /// Discards every change made in the transaction
+  pub fn rollback(self) {}
+
+  /// Writes the transaction's dirty pages, then the new meta page.
+  ///
+  /// Pages are always written to unused space so a crash before the meta page is synced
+  /// leaves the previous transaction intact. With a write-ahead log only the log is synced, and
+  /// the database file catches up at the next checkpoint.
+  pub fn commit(self) -> crate::Result<(), DbError> {
+    let tx_id = self.tx_id();
+    let MemmapMutTx {
+      db,
+      meta,
+      tx,
+      mut free_pages,
+      fill_percent,
+    } = self;
+    let page_size = meta.page_size as usize;
+    let root_compression = tx.root_compression();
+    let committed = match &tx.tx().handle.page_map {
+      Some(TxPageMap::PageMap(page_map)) => {
+        commit_page_map(db, &tx, meta, &free_pages, fill_percent, page_map)
+      }
+      Some(TxPageMap::Clustered(clusters)) => {
+        commit_clustered(db, &tx, meta, &free_pages, fill_percent, clusters)
+      }
+      None => commit_freelist(db, &tx, meta, &free_pages, fill_percent),
+    }?;
+
+    let mut new_meta = meta;
+    new_meta.tx_id = tx_id;
+    new_meta.root = committed.root;
+    if let Some(root_hash) = committed.root_hash {
+      new_meta.root_hash = root_hash;
+    }
+    new_meta.free_list = committed.free_list;
+    new_meta.eof_id = committed.eof_id;
+    new_meta.set_compression_threshold(root_compression);
+    new_meta.update_checksum();
+    {
+      let io = &tx.tx().handle.io;
+      match &db.wal {
+        Some(wal) => {
+          let meta_page = meta_page(&db.buffer_pool, new_meta);
+          wal
+            .commit(
+              tx_id,
+              page_size,
+              committed.pages,
+              meta_page,
+              |pages, meta_page| {
+                io.write_disk_pages(pages)?;
+                io.write_single_page(meta_page.0, meta_page.1)
+              },
+            )
+            .change_context(DbError::CommitError(tx_id))?;
+        }
+        None => {
+          io.write_disk_pages(committed.pages)
+            .change_context(DbError::CommitError(tx_id))?;
+          io.sync().change_context(DbError::CommitError(tx_id))?;
+          write_meta_page(&**io, &db.buffer_pool, new_meta)
+            .change_context(DbError::CommitError(tx_id))?;
+        }
+      }
+      // Readers pinned to the current mapping keep it alive so remapping doesn't wait on them
+      let memmap = io.reader().io.memmap();
+      memmap
+        .remap(memmap.file_len() as usize)
+        .change_context(DbError::CommitError(tx_id))?;
+    }
+    drop(tx);
+    *free_pages = committed.free_pages;
+    let mut db_meta = db.meta.write();
+    *db.page_map.write() = committed.page_map;
+    *db_meta = new_meta;
+    Ok(())
+  }
+}
+
+/// The pages a commit writes before its meta page, and what the writer carries on with
+struct CommittedPages {
+  root: BucketHeader,
+  root_hash: Option<MerkleHash>,
+  pages: Vec<(DiskPageId, SharedBytes)>,
+  free_list: FreelistPageId,
+  eof_id: EOFPageId,
+  free_pages: FreePages,
+  page_map: Option<PageMapTranslator>,
+}
+
+/// Commits `tx` in the Go BBolt layout, where node page ids are disk page ids and the free pages
+/// are written to the freelist
+fn commit_freelist<'db>(
+  db: &'db MemmapDb, tx: &sync::Arc<MutTxHandle<MemmapTx<'db>>>, meta: Meta,
+  free_pages: &FreePages, fill_percent: f64,
+) -> crate::Result<CommittedPages, DbError> {
+  let tx_id = TxId::of(meta.tx_id.0.0 + 1);
+  let page_size = meta.page_size as usize;
+  let translator = DirectPageTranslator::new(tx_id);
+  let mut free_index = FreeIndex::new(
+    translator.clone(),
+    free_pages.free.iter().copied(),
+    meta.eof_id,
+  )
+  .with_pending(free_pages.pending.clone());
+  free_index.release_pending(db.readers.min_pinned().map(TxId::of));
+  let page_checksums = db.options.page_checksums;
+  let tx_commit = commit_tx(
+    tx,
+    &mut free_index,
+    &db.buffer_pool,
+    fill_percent,
+    page_checksums,
+    false,
+  )
+  .change_context(DbError::CommitError(tx_id))?;
+
+  // The previous freelist page and every page the transaction released stay pending until
+  // no reader can see them
+  if meta.free_list.0.0 != PGID_NO_FREELIST {
+    let freelist = tx
+      .tx()
+      .read_freelist_page(meta.free_list)
+      .change_context(DbError::CommitError(tx_id))?;
+    free_index.free_pending(
+      tx_id,
+      translator.freelist_to_disk(meta.free_list),
+      freelist.page_header().get_overflow() as u64 + 1,
+    );
+  }
+  for (page_id, overflow) in tx_commit.freed {
+    free_index.free_node(tx_id, page_id, overflow as u64 + 1);
+  }
+  let freelist = if db.options.disable_freelist_sync {
+    None
+  } else {
+    // Assigning the freelist page can only shrink the freelist
+    let count = free_index.free_count() + free_index.pending().len();
+    let freelist_len = page_count(freelist_page_len(count), page_size, page_checksums);
+    let freelist_id = free_index.assign_freelist(meta.free_list, freelist_len as u64);
+    // Pending pages are free once the database is reopened
+    let mut free_ids = free_index.free_ids();
+    free_ids.extend(free_index.pending().iter());
+    free_ids.sort_unstable();
+    let freelist_page = db
+      .buffer_pool
+      .pop_with_len(freelist_len * page_size)
+      .fill_and_share(|buffer| {
+        write_freelist_page(buffer, freelist_id, page_size, free_ids.into_iter());
+        if page_checksums {
+          write_page_checksums(buffer, page_size);
+        }
+      });
+    Some((freelist_id, freelist_page))
+  };
+
+  let free_list = freelist
+    .as_ref()
+    .map(|(freelist_id, _)| *freelist_id)
+    .unwrap_or(FreelistPageId(DbPageId(PGID_NO_FREELIST)));
+  let pages = tx_commit
+    .pages
+    .into_iter()
+    .map(|(page_id, page)| (translator.node_to_disk(page_id), page))
+    .chain(freelist.map(|(freelist_id, freelist_page)| {
+      (translator.freelist_to_disk(freelist_id), freelist_page)
+    }))
+    .collect();
+  Ok(CommittedPages {
+    root: tx_commit.root,
+    root_hash: tx_commit.root_hash,
+    pages,
+    free_list,
+    eof_id: free_index.current_eof(),
+    free_pages: FreePages {
+      free: free_index.free_ids(),
+      pending: free_index.into_pending(),
+    },
+    page_map: None,
+  })
+}
+
+/// Commits `tx` in the version 3 format. Rewritten nodes keep their ids where they can while
+/// their pages move, possibly scattered, to free space, and the page map is written in place of
+/// the freelist.
+fn commit_page_map<'db>(
+  db: &'db MemmapDb, tx: &sync::Arc<MutTxHandle<MemmapTx<'db>>>, meta: Meta,
+  free_pages: &FreePages, fill_percent: f64, page_map: &PageMapTranslator,
+) -> crate::Result<CommittedPages, DbError> {
+  let tx_id = TxId::of(meta.tx_id.0.0 + 1);
+  let page_size = meta.page_size as usize;
+  let translator = page_map.clone().with_tx_id(tx_id);
+  let mut free_index = FreeIndex::new(translator, free_pages.free.iter().copied(), meta.eof_id)
+    .with_pending(free_pages.pending.clone());
+  free_index.release_pending(db.readers.min_pinned().map(TxId::of));
+  let page_checksums = db.options.page_checksums;
+  let tx_commit = commit_tx(
+    tx,
+    &mut free_index,
+    &db.buffer_pool,
+    fill_percent,
+    page_checksums,
+    true,
+  )
+  .change_context(DbError::CommitError(tx_id))?;
+
+  // The previous page map page and every page the transaction released stay pending until no
+  // reader can see them
+  let page_map_page = tx
+    .tx()
+    .read_page_map_page(meta.free_list)
+    .change_context(DbError::CommitError(tx_id))?;
+  free_index.free_pending(
+    tx_id,
+    free_index.translator().freelist_to_disk(meta.free_list),
+    page_map_page.page_header().get_overflow() as u64 + 1,
+  );
+  for (page_id, overflow) in tx_commit.freed {
+    free_index.free_node(tx_id, page_id, overflow as u64 + 1);
+  }
+  let translator = free_index.translator().clone();
+  let page_map_len = page_count(
+    page_map_page_len(translator.len()),
+    page_size,
+    page_checksums,
+  );
+  let page_map_id = free_index.assign_freelist(meta.free_list, page_map_len as u64);
+  let page_map_page = db
+    .buffer_pool
+    .pop_with_len(page_map_len * page_size)
+    .fill_and_share(|buffer| {
+      write_page_map_page(buffer, page_map_id, page_size, translator.entries());
+      if page_checksums {
+        write_page_checksums(buffer, page_size);
+      }
+    });
+
+  let mut pages = node_disk_pages(db, page_size, tx_commit.pages, |page_id| {
+    translator.node_to_disk(page_id)
+  });
+  pages.push((translator.freelist_to_disk(page_map_id), page_map_page));
+  Ok(CommittedPages {
+    root: tx_commit.root,
+    root_hash: tx_commit.root_hash,
+    pages,
+    free_list: page_map_id,
+    eof_id: free_index.current_eof(),
+    free_pages: FreePages {
+      free: free_index.free_ids(),
+      pending: free_index.into_pending(),
+    },
+    page_map: Some(translator),
+  })
+}
+
+/// Commits `tx` in the clustered format. Nodes are assigned free data pages, and the free pages
+/// are written to the free space pages of the new meta's region in every cluster, so the
+/// previous meta's stay intact until the new meta is.
+fn commit_clustered<'db>(
+  db: &'db MemmapDb, tx: &sync::Arc<MutTxHandle<MemmapTx<'db>>>, meta: Meta,
+  free_pages: &FreePages, fill_percent: f64, clusters: &StableFreeSpaceTranslator,
+) -> crate::Result<CommittedPages, DbError> {
+  let tx_id = TxId::of(meta.tx_id.0.0 + 1);
+  let page_size = meta.page_size as usize;
+  let translator = clusters.clone().with_tx_id(tx_id);
+  let mut free_index = FreeIndex::new(
+    translator.clone(),
+    free_pages.free.iter().copied(),
+    meta.eof_id,
+  )
+  .with_pending(free_pages.pending.clone());
+  free_index.release_pending(db.readers.min_pinned().map(TxId::of));
+  let page_checksums = db.options.page_checksums;
+  let tx_commit = commit_tx(
+    tx,
+    &mut free_index,
+    &db.buffer_pool,
+    fill_percent,
+    page_checksums,
+    false,
+  )
+  .change_context(DbError::CommitError(tx_id))?;
+  for (page_id, overflow) in tx_commit.freed {
+    free_index.free_node(tx_id, page_id, overflow as u64 + 1);
+  }
+
+  // Pending pages are free once the database is reopened
+  let eof_id = free_index.current_eof();
+  let tracked_len = translator.tracked_len();
+  let bitmap_len = free_space_bitmap_len(page_size, page_checksums);
+  let mut free_nodes = vec![Vec::new(); translator.free_space_len(eof_id) as usize];
+  for page_id in free_index
+    .free_ids()
+    .into_iter()
+    .chain(free_index.pending().iter())
+  {
+    let node_page_id = translator.disk_to_node(page_id).0.0;
+    free_nodes[(node_page_id / tracked_len) as usize].push(node_page_id % tracked_len);
+  }
+  let mut pages = node_disk_pages(db, page_size, tx_commit.pages, |page_id| {
+    translator.node_to_disk(page_id)
+  });
+  for (index, free_offsets) in free_nodes.into_iter().enumerate() {
+    let page_id = FreelistPageId(DbPageId(index as u64));
+    let free_space_page = db.buffer_pool.pop().fill_and_share(|buffer| {
+      write_free_space_page(buffer, page_id, bitmap_len, free_offsets.into_iter());
+      if page_checksums {
+        write_page_checksums(buffer, page_size);
+      }
+    });
+    pages.push((translator.freelist_to_disk(page_id), free_space_page));
+  }
+  Ok(CommittedPages {
+    root: tx_commit.root,
+    root_hash: tx_commit.root_hash,
+    pages,
+    free_list: meta.free_list,
+    eof_id,
+    free_pages: FreePages {
+      free: free_index.free_ids(),
+      pending: free_index.into_pending(),
+    },
+    page_map: None,
+  })
+}
+
+/// Pairs each node page with the disk page it's written to. Nodes whose pages aren't contiguous
+/// on disk are written page by page.
+fn node_disk_pages(
+  db: &MemmapDb, page_size: usize, pages: Vec<(NodePageId, SharedBytes)>,
+  node_to_disk: impl Fn(NodePageId) -> DiskPageId,
+) -> Vec<(DiskPageId, SharedBytes)> {
+  let mut disk_pages = Vec::with_capacity(pages.len() + 1);
+  for (page_id, page) in pages {
+    let disk_page_id = node_to_disk(page_id);
+    let page_count = page.len() / page_size;
+    let is_contig = (1..page_count)
+      .all(|overflow| node_to_disk(page_id + overflow as u32) == disk_page_id + overflow as u64);
+    if is_contig {
+      disk_pages.push((disk_page_id, page));
+      continue;
+    }
+    for (overflow, chunk) in page.chunks_exact(page_size).enumerate() {
+      let chunk_page = db
+        .buffer_pool
+        .pop()
+        .fill_and_share(|buffer| buffer.copy_from_slice(chunk));
+      disk_pages.push((node_to_disk(page_id + overflow as u32), chunk_page));
+    }
+  }
+  disk_pages
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
-  /*  #[test]
-  fn test() {
-    let b = MemmapDb::builder().open_path("Test");
-  }*/
+  use crate::common::consts::BBOLT_TAG;
+  use crate::common::errors::{IOError, PageError};
+  use crate::common::id::{BucketPageId, NodePageId, TxId};
+  use crate::common::layout::node::{LeafElement, LeafFlag};
+  use crate::common::layout::page::PageHeader;
+  use crate::common::vec_pool::VecPool;
+  use crate::components::backend::{
+    INIT_EOF_PAGE_ID, INIT_FREELIST_PAGE_ID, INIT_ROOT_PAGE_ID, temp_db_path,
+  };
+  use crate::components::bucket::OnDiskBucket;
+  use crate::components::bucket_path::BucketPathBuf;
+  use crate::components::cursor::{CoreCursor, CoreCursorApi, CoreCursorMoveApi};
+  use crate::components::tx::{LazyTxHandle, SharedTxHandle, walk_node_tree};
+  use crate::io::backends::CachedReadHandler;
+  use crate::io::backends::crypt::{CRYPT_FOOTER_LEN, CryptKey};
+  use crate::io::backends::file::{FileReadOptions, SingleFileIO};
+  use crate::io::pages::types::node::NodePage;
+  use crate::io::pages::types::node::leaf::HasValues;
+  use moka::sync::Cache;
+  use std::io::{Seek, SeekFrom};
+  use std::{fs, thread};
+
+  /// The free and pending pages known to the writer, sorted
+  fn all_free_pages(db: &MemmapDb) -> Vec<DiskPageId> {
+    let free_pages = db.writer.lock();
+    let mut ids = free_pages.free.clone();
+    ids.extend(free_pages.pending.iter());
+    ids.sort_unstable();
+    ids
+  }
+
+  /// Lays out a leaf page holding `elements`, like `node.write` in Go BBolt
+  fn leaf_page(page_id: u64, elements: &[(LeafFlag, &[u8], &[u8])]) -> Vec<u8> {
+    let mut header = PageHeader::init_leaf(DbPageId(page_id));
+    header.set_count(elements.len() as u16);
+    let mut page = bytemuck::bytes_of(&header).to_vec();
+    let mut data = Vec::new();
+    for (index, (flags, key, value)) in elements.iter().enumerate() {
+      let key_dist = (elements.len() - index) * size_of::<LeafElement>() + data.len();
+      let element = LeafElement::new(
+        *flags,
+        key_dist as u32,
+        key.len() as u32,
+        value.len() as u32,
+      );
+      page.extend_from_slice(bytemuck::bytes_of(&element));
+      data.extend_from_slice(key);
+      data.extend_from_slice(value);
+    }
+    page.extend_from_slice(&data);
+    page
+  }
+
+  /// Rewrites the root leaf of the Go BBolt database at `path` to hold the bucket "gadgets",
+  /// stored inline the way Go BBolt stores small buckets, with "a" and "b" in it
+  fn write_inline_bucket(path: &Path) {
+    let (root, page_size) = {
+      let db = MemmapDb::builder().open_path(path.to_path_buf()).unwrap();
+      let meta = db.meta();
+      (meta.root.root().0.0, meta.page_size as u64)
+    };
+    let inline = leaf_page(
+      0,
+      &[
+        (LeafFlag::empty(), b"a", b"1"),
+        (LeafFlag::empty(), b"b", b"2"),
+      ],
+    );
+    let header = BucketHeader::new(BucketPageId(DbPageId(0)), 7);
+    let mut value = bytemuck::bytes_of(&header).to_vec();
+    value.extend_from_slice(&inline);
+    let page = leaf_page(root, &[(LeafFlag::BUCKET, b"gadgets", &value)]);
+    let mut file = OpenOptions::new().write(true).open(path).unwrap();
+    file.seek(SeekFrom::Start(root * page_size)).unwrap();
+    file.write_all(&page).unwrap();
+    file.sync_all().unwrap();
+  }
+
+  #[test]
+  fn test_create_and_reopen() {
+    let path = temp_db_path("create_and_reopen");
+    {
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .open_path(path.clone())
+        .unwrap();
+      let meta = db.meta();
+      assert_eq!(TxId::of(1), meta.tx_id);
+      assert_eq!(4096, meta.page_size);
+      assert_eq!(INIT_FREELIST_PAGE_ID, meta.free_list.0.0);
+      assert_eq!(INIT_ROOT_PAGE_ID, meta.root.root().0.0);
+      assert_eq!(INIT_EOF_PAGE_ID, meta.eof_id.0.0);
+      assert_eq!(4096 * INIT_EOF_PAGE_ID, fs::metadata(&path).unwrap().len());
+    }
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    let meta = db.meta();
+    assert_eq!(TxId::of(1), meta.tx_id);
+    assert_eq!(4096, meta.page_size);
+    let tx = db.begin();
+    let root = tx.read_node_page(meta.root.root().into()).unwrap();
+    assert!(root.is_leaf());
+    assert_eq!(0, root.element_count());
+    drop(tx);
+    drop(db);
+
+    let err = MemmapDb::builder()
+      .db_tag(BBOLT_TAG)
+      .open_path(path.clone())
+      .err()
+      .unwrap();
+    assert!(matches!(err.current_context(), DbError::InvalidTag(_, _)));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_detect_format() {
+    let path = temp_db_path("detect_format");
+    let bucket = BucketPathBuf::new();
+    {
+      let db = MemmapDb::builder()
+        .db_tag(BBOLT_TAG)
+        .open_path(path.clone())
+        .unwrap();
+      assert_eq!(DbFormat::BBolt, db.format());
+      let tx = db.begin_mut().unwrap();
+      tx.put(&bucket, b"foo", b"bar").unwrap();
+      tx.commit().unwrap();
+    }
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(BBOLT_TAG, db.db_tag());
+    assert_eq!(DbFormat::BBolt, db.format());
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(b"bar", tx.get(&bucket, b"foo").unwrap().unwrap().as_ref());
+    tx.put(&bucket, b"foo", b"baz").unwrap();
+    tx.commit().unwrap();
+    assert_eq!(BBOLT_TAG.magic, db.meta().magic);
+    drop(db);
+
+    fs::remove_file(&path).unwrap();
+    let unknown = DbTag {
+      version: 1,
+      magic: 0xDEADBEEF,
+    };
+    let err = MemmapDb::builder()
+      .db_tag(unknown)
+      .open_path(path.clone())
+      .err()
+      .unwrap();
+    assert!(matches!(err.current_context(), DbError::UnknownTag(_)));
+    assert!(!path.exists());
+    let mut file = fs::File::create_new(&path).unwrap();
+    init_db_file(&mut file, 4096, unknown, false).unwrap();
+    drop(file);
+    let err = MemmapDb::builder().open_path(path.clone()).err().unwrap();
+    assert!(matches!(err.current_context(), DbError::UnknownTag(_)));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_file_lock() {
+    let path = temp_db_path("file_lock");
+    let timeout = Duration::from_millis(100);
+    let db = MemmapDb::builder()
+      .file_lock_timeout(timeout)
+      .open_path(path.clone())
+      .unwrap();
+    let err = MemmapDb::builder()
+      .file_lock_timeout(timeout)
+      .read_only(true)
+      .open_path(path.clone())
+      .err()
+      .unwrap();
+    assert!(matches!(err.current_context(), DbError::LockTimeout(_, _)));
+    drop(db);
+
+    let ro_db = MemmapDb::builder()
+      .file_lock_timeout(timeout)
+      .read_only(true)
+      .open_path(path.clone())
+      .unwrap();
+    let other_ro_db = MemmapDb::builder()
+      .file_lock_timeout(timeout)
+      .read_only(true)
+      .open_path(path.clone())
+      .unwrap();
+    assert!(ro_db.is_read_only());
+    let err = MemmapDb::builder()
+      .file_lock_timeout(timeout)
+      .open_path(path.clone())
+      .err()
+      .unwrap();
+    assert!(matches!(err.current_context(), DbError::LockTimeout(_, _)));
+    drop(ro_db);
+    drop(other_ro_db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_commit_and_reopen() {
+    let path = temp_db_path("commit_and_reopen");
+    let root = BucketPathBuf::new();
+    let widgets = BucketPathBuf::from(["widgets"]);
+    let gears = BucketPathBuf::from(["widgets", "gears"]);
+    {
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .open_path(path.clone())
+        .unwrap();
+      let tx = db.begin_mut().unwrap();
+      assert_eq!(TxId::of(2), tx.tx_id());
+      tx.create_bucket(&widgets).unwrap();
+      tx.create_bucket(&gears).unwrap();
+      tx.put(&widgets, b"foo", b"bar").unwrap();
+      tx.put(&gears, b"teeth", b"32").unwrap();
+      assert_eq!(b"bar", tx.get(&widgets, b"foo").unwrap().unwrap().as_ref());
+      tx.commit().unwrap();
+      assert_eq!(TxId::of(2), db.meta().tx_id);
+
+      let tx = db.begin_mut().unwrap();
+      for i in 0..2000u32 {
+        let key = format!("key-{:08}", i);
+        tx.put(&widgets, key.as_bytes(), &i.to_be_bytes()).unwrap();
+      }
+      tx.delete(&widgets, b"foo").unwrap();
+      assert!(tx.get(&widgets, b"foo").unwrap().is_none());
+      tx.commit().unwrap();
+
+      let tx = db.begin_mut().unwrap();
+      tx.put(&root, b"ignored", b"value").unwrap();
+      tx.rollback();
+    }
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(TxId::of(3), db.meta().tx_id);
+    let tx = db.begin_mut().unwrap();
+    assert!(tx.get(&root, b"ignored").unwrap().is_none());
+    assert!(tx.get(&widgets, b"foo").unwrap().is_none());
+    assert_eq!(b"32", tx.get(&gears, b"teeth").unwrap().unwrap().as_ref());
+    for i in 0..2000u32 {
+      let key = format!("key-{:08}", i);
+      let value = tx.get(&widgets, key.as_bytes()).unwrap().unwrap();
+      assert_eq!(&i.to_be_bytes(), value.as_ref());
+    }
+    tx.delete_bucket(&widgets).unwrap();
+    assert!(!tx.bucket_exists(&gears).unwrap());
+    tx.commit().unwrap();
+    assert!(!db.begin_mut().unwrap().bucket_exists(&widgets).unwrap());
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_rebalance() {
+    let widgets = BucketPathBuf::from(["widgets"]);
+    for db_tag in [BBOLT_RS_TAG, DbFormat::BetterBBoltRs.tag()] {
+      let path = temp_db_path("rebalance");
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .db_tag(db_tag)
+        .open_path(path.clone())
+        .unwrap();
+      let node_count = |db: &MemmapDb| {
+        let tx = db.begin();
+        let bucket = OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), db.meta().root).unwrap();
+        let root = bucket.bucket_header(b"widgets").unwrap().unwrap().root();
+        drop(bucket);
+        let mut count = 0;
+        walk_node_tree(&tx, root.into(), &mut |_, _| count += 1).unwrap();
+        count
+      };
+      let key = |i: u32| format!("key-{:08}", i);
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&widgets).unwrap();
+      for i in 0..4000u32 {
+        tx.put(&widgets, key(i).as_bytes(), &[i as u8; 100])
+          .unwrap();
+      }
+      tx.commit().unwrap();
+      let filled = node_count(&db);
+
+      // Every leaf in the first half loses most of its elements, so each is merged with its
+      // siblings
+      let tx = db.begin_mut().unwrap();
+      for i in (0..2000u32).filter(|i| i % 10 != 0) {
+        tx.delete(&widgets, key(i).as_bytes()).unwrap();
+      }
+      tx.commit().unwrap();
+      let rebalanced = db.stats().rebalance();
+      assert!(rebalanced > 0);
+      assert!(node_count(&db) * 3 < filled * 2);
+      assert_eq!(0, db.check().count());
+
+      // The leaves around the deleted range are merged with siblings still on disk
+      let tx = db.begin_mut().unwrap();
+      for i in 3005..3095u32 {
+        tx.delete(&widgets, key(i).as_bytes()).unwrap();
+      }
+      tx.commit().unwrap();
+      assert!(db.stats().rebalance() > rebalanced);
+      assert_eq!(0, db.check().count());
+      drop(db);
+
+      let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+      assert_eq!(0, db.check().count());
+      let tx = db.begin_mut().unwrap();
+      for i in 0..4000u32 {
+        let value = tx.get(&widgets, key(i).as_bytes()).unwrap();
+        let kept = if i < 2000 {
+          i % 10 == 0
+        } else {
+          !(3005..3095).contains(&i)
+        };
+        assert_eq!(kept, value.is_some());
+      }
+      drop(tx);
+      drop(db);
+      fs::remove_file(&path).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_page_map_commit_and_reopen() {
+    let path = temp_db_path("page_map_commit_and_reopen");
+    let root = BucketPathBuf::new();
+    let gears = BucketPathBuf::from(["gears"]);
+    {
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .db_tag(DbFormat::BetterBBoltRs.tag())
+        .open_path(path.clone())
+        .unwrap();
+      assert_eq!(DbFormat::BetterBBoltRs, db.format());
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&gears).unwrap();
+      tx.commit().unwrap();
+      for i in 0..200u32 {
+        let tx = db.begin_mut().unwrap();
+        let key = format!("key-{:08}", i);
+        tx.put(&root, key.as_bytes(), &i.to_be_bytes()).unwrap();
+        // Every tenth value overflows its page
+        let len = if i % 10 == 0 { 10000 } else { 64 };
+        tx.put(&gears, key.as_bytes(), &vec![i as u8; len]).unwrap();
+        tx.commit().unwrap();
+      }
+      assert_eq!(0, db.check().count());
+
+      // The root keeps its id as its pages move, so only the page map records the move
+      let root_id = db.meta().root.root();
+      let tx = db.begin_mut().unwrap();
+      tx.put(&root, b"key-00000000", b"moved").unwrap();
+      tx.commit().unwrap();
+      assert_eq!(root_id, db.meta().root.root());
+    }
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(DbFormat::BetterBBoltRs, db.format());
+    assert_eq!(0, db.check().count());
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(
+      b"moved",
+      tx.get(&root, b"key-00000000").unwrap().unwrap().as_ref()
+    );
+    for i in 1..200u32 {
+      let key = format!("key-{:08}", i);
+      let value = tx.get(&root, key.as_bytes()).unwrap().unwrap();
+      assert_eq!(&i.to_be_bytes(), value.as_ref());
+      let value = tx.get(&gears, key.as_bytes()).unwrap().unwrap();
+      assert_eq!(if i % 10 == 0 { 10000 } else { 64 }, value.len());
+      assert!(value.iter().all(|byte| *byte == i as u8));
+    }
+    tx.delete_bucket(&gears).unwrap();
+    tx.commit().unwrap();
+    drop(db);
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(0, db.check().count());
+    assert!(!db.begin_mut().unwrap().bucket_exists(&gears).unwrap());
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_page_map_scattered_overflow() {
+    let path = temp_db_path("page_map_scattered_overflow");
+    let big_path = BucketPathBuf::from(["big"]);
+    let big = vec![7u8; 20000];
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .db_tag(DbFormat::BetterBBoltRs.tag())
+      .open_path(path.clone())
+      .unwrap();
+    // Each bucket's value fills its own leaf, so deleting every other bucket frees single pages
+    let tx = db.begin_mut().unwrap();
+    for i in 0..40u32 {
+      let bucket = BucketPathBuf::from([format!("bucket-{:02}", i)]);
+      tx.create_bucket(&bucket).unwrap();
+      tx.put(&bucket, b"value", &[i as u8; 3000]).unwrap();
+    }
+    tx.commit().unwrap();
+    let tx = db.begin_mut().unwrap();
+    for i in (0..40u32).step_by(2) {
+      let bucket = BucketPathBuf::from([format!("bucket-{:02}", i)]);
+      tx.delete_bucket(&bucket).unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&big_path).unwrap();
+    tx.put(&big_path, b"big", &big).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(0, db.check().count());
+
+    // No free run fits the bucket's leaf and its overflow, so its pages are scattered
+    let tx = db.begin();
+    let bucket = OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), db.meta().root).unwrap();
+    let root_id: NodePageId = bucket.bucket_header(b"big").unwrap().unwrap().root().into();
+    drop(bucket);
+    drop(tx);
+    let page_map = db.page_map().unwrap();
+    let disk: Vec<_> = (0..5)
+      .map(|overflow| page_map.get(root_id + overflow).unwrap().0)
+      .collect();
+    assert!(disk.windows(2).any(|pair| pair[1] != pair[0] + 1));
+    drop(db);
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(0, db.check().count());
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(
+      &big[..],
+      tx.get(&big_path, b"big").unwrap().unwrap().as_ref()
+    );
+    for i in (1..40u32).step_by(2) {
+      let bucket = BucketPathBuf::from([format!("bucket-{:02}", i)]);
+      let value = tx.get(&bucket, b"value").unwrap().unwrap();
+      assert_eq!(&[i as u8; 3000][..], value.as_ref());
+    }
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_clustered_commit_and_reopen() {
+    let root = BucketPathBuf::new();
+    let gears = BucketPathBuf::from(["gears"]);
+    for page_checksums in [false, true] {
+      let path = temp_db_path("clustered_commit_and_reopen");
+      // Each 1024 byte free space page tracks about 8000 data pages, so the values span clusters
+      let free_pages = {
+        let db = MemmapDb::builder()
+          .page_size(1024)
+          .page_checksums(page_checksums)
+          .db_tag(DbFormat::StableFreeSpace.tag())
+          .open_path(path.clone())
+          .unwrap();
+        assert_eq!(DbFormat::StableFreeSpace, db.format());
+        let tx = db.begin_mut().unwrap();
+        tx.create_bucket(&gears).unwrap();
+        tx.commit().unwrap();
+        for i in 0..200u32 {
+          let tx = db.begin_mut().unwrap();
+          let key = format!("key-{:08}", i);
+          tx.put(&root, key.as_bytes(), &i.to_be_bytes()).unwrap();
+          tx.put(&gears, key.as_bytes(), &vec![i as u8; 50000])
+            .unwrap();
+          tx.commit().unwrap();
+        }
+        let tx = db.begin_mut().unwrap();
+        tx.delete(&gears, b"key-00000000").unwrap();
+        tx.commit().unwrap();
+        assert_eq!(0, db.check().count());
+        let clusters = StableFreeSpaceTranslator::for_pages(TxId::of(0), 1024, page_checksums);
+        assert!(clusters.free_space_len(db.meta().eof_id) > 1);
+        all_free_pages(&db)
+      };
+      assert!(!free_pages.is_empty());
+
+      let db = MemmapDb::builder()
+        .page_checksums(page_checksums)
+        .open_path(path.clone())
+        .unwrap();
+      assert_eq!(DbFormat::StableFreeSpace, db.format());
+      assert_eq!(0, db.check().count());
+      assert_eq!(free_pages, all_free_pages(&db));
+      let tx = db.begin_mut().unwrap();
+      assert!(tx.get(&gears, b"key-00000000").unwrap().is_none());
+      for i in 1..200u32 {
+        let key = format!("key-{:08}", i);
+        let value = tx.get(&root, key.as_bytes()).unwrap().unwrap();
+        assert_eq!(&i.to_be_bytes(), value.as_ref());
+        let value = tx.get(&gears, key.as_bytes()).unwrap().unwrap();
+        assert_eq!(50000, value.len());
+        assert!(value.iter().all(|byte| *byte == i as u8));
+      }
+      tx.delete_bucket(&gears).unwrap();
+      tx.commit().unwrap();
+      drop(db);
+
+      let db = MemmapDb::builder()
+        .page_checksums(page_checksums)
+        .open_path(path.clone())
+        .unwrap();
+      assert_eq!(0, db.check().count());
+      assert!(!db.begin_mut().unwrap().bucket_exists(&gears).unwrap());
+      drop(db);
+      fs::remove_file(&path).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_compressed_values() {
+    let path = temp_db_path("compressed_values");
+    let root = BucketPathBuf::new();
+    let blob = br#"{"name":"widget","color":"blue","size":"large"}"#.repeat(40);
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.set_compression(&root, Some(256)).unwrap();
+    tx.put(&root, b"blob", &blob).unwrap();
+    tx.put(&root, b"small", b"value").unwrap();
+    assert_eq!(&blob[..], tx.get(&root, b"blob").unwrap().unwrap().as_ref());
+    tx.commit().unwrap();
+
+    let stored_compressed = |db: &MemmapDb| {
+      let page_id = db.meta().root.root().0.0 as usize;
+      let file = fs::read(&path).unwrap();
+      let page = &file[page_id * 4096..(page_id + 1) * 4096];
+      !page.windows(blob.len()).any(|window| window == &blob[..])
+    };
+    assert!(stored_compressed(&db));
+    let tx = db.begin();
+    let node = tx.read_node_page(db.meta().root.root().into()).unwrap();
+    let NodePage::Leaf(leaf) = node else {
+      panic!("expected a leaf root");
+    };
+    // Leaves and cursors see the value that was put
+    assert_eq!(Some(LeafFlag::COMPRESSED), leaf.leaf_flag(0));
+    assert_eq!(&blob[..], &*leaf.value(0).unwrap());
+    assert_eq!(Some(LeafFlag::empty()), leaf.leaf_flag(1));
+    let bucket = OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), db.meta().root).unwrap();
+    let mut cursor = CoreCursor::new_with_stack(&bucket, VecPool::new(0, 0, 16).pop());
+    assert!(cursor.move_to_first_element().unwrap().is_some());
+    let (key, value) = cursor.key_value().unwrap();
+    assert_eq!((&b"blob"[..], &blob[..]), (&*key, &*value));
+    drop(cursor);
+    assert_eq!(&blob[..], bucket.get(b"blob").unwrap().unwrap().as_ref());
+    assert_eq!(b"value", bucket.get(b"small").unwrap().unwrap().as_ref());
+    drop(bucket);
+    drop(tx);
+
+    // The threshold is stored with the bucket, and rewriting the leaf keeps the untouched value
+    // compressed
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(Some(256), tx.compression(&root).unwrap());
+    tx.put(&root, b"other", &blob).unwrap();
+    tx.commit().unwrap();
+    drop(db);
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert!(stored_compressed(&db));
+    let tx = db.begin();
+    let node = tx.read_node_page(db.meta().root.root().into()).unwrap();
+    let NodePage::Leaf(leaf) = node else {
+      panic!("expected a leaf root");
+    };
+    assert_eq!(Some(LeafFlag::COMPRESSED), leaf.leaf_flag(1));
+    drop(tx);
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(&blob[..], tx.get(&root, b"blob").unwrap().unwrap().as_ref());
+    assert_eq!(
+      &blob[..],
+      tx.get(&root, b"other").unwrap().unwrap().as_ref()
+    );
+    tx.set_compression(&root, None).unwrap();
+    tx.commit().unwrap();
+    assert_eq!(None, db.meta().compression_threshold());
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_nested_bucket_compression() {
+    let path = temp_db_path("nested_bucket_compression");
+    let gears = BucketPathBuf::from(["gears"]);
+    let blob = br#"{"name":"widget","color":"blue","size":"large"}"#.repeat(40);
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&gears).unwrap();
+    tx.set_compression(&gears, Some(64)).unwrap();
+    tx.commit().unwrap();
+
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(None, tx.compression(&BucketPathBuf::new()).unwrap());
+    assert_eq!(Some(64), tx.compression(&gears).unwrap());
+    tx.put(&gears, b"blob", &blob).unwrap();
+    tx.commit().unwrap();
+    let tx = db.begin();
+    let root = OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), db.meta().root).unwrap();
+    let (flag, _) = root.bucket_element(b"gears").unwrap().unwrap();
+    assert_eq!(Some(64), flag.compression_threshold());
+    let bucket = root.bucket(b"gears").unwrap().unwrap();
+    let node = tx.read_node_page(bucket.header().root().into()).unwrap();
+    let NodePage::Leaf(leaf) = node else {
+      panic!("expected a leaf root");
+    };
+    assert_eq!(Some(LeafFlag::COMPRESSED), leaf.leaf_flag(0));
+    assert_eq!(&blob[..], bucket.get(b"blob").unwrap().unwrap().as_ref());
+    drop(bucket);
+    drop(root);
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+
+    // Go BBolt would read the compressed bytes
+    let path = temp_db_path("nested_bucket_compression_go");
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .db_tag(BBOLT_TAG)
+      .open_path(path.clone())
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    let Err(report) = tx.set_compression(&BucketPathBuf::new(), Some(64)) else {
+      panic!("compression is unsupported in the Go BBolt format");
+    };
+    assert!(matches!(
+      report.current_context(),
+      BucketError::CompressionUnsupported
+    ));
+    tx.set_compression(&BucketPathBuf::new(), None).unwrap();
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_compressed_values_every_handle() {
+    let path = temp_db_path("compressed_handles");
+    let root = BucketPathBuf::new();
+    let blob = br#"{"name":"widget","color":"blue","size":"large"}"#.repeat(40);
+    // Too noisy to compress, so the leaf overflows its first page
+    let mut state = 0x2545f4914f6cdd1du64;
+    let noise: Vec<u8> = (0..6000)
+      .map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+      })
+      .collect();
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.set_compression(&root, Some(256)).unwrap();
+    tx.put(&root, b"blob", &blob).unwrap();
+    tx.put(&root, b"noise", &noise).unwrap();
+    tx.commit().unwrap();
+    let meta = db.meta();
+    let root_page = NodePageId::from(meta.root.root());
+
+    // The memory map's handle decompresses each leaf once however often it's read
+    let tx = db.begin();
+    let read_leaf = || {
+      let NodePage::Leaf(leaf) = tx.read_node_page(root_page).unwrap() else {
+        panic!("expected a leaf root");
+      };
+      assert_eq!(&blob[..], &*leaf.value(0).unwrap());
+      leaf.root_page().as_ptr()
+    };
+    assert_eq!(read_leaf(), read_leaf());
+    drop(tx);
+    drop(db);
+
+    let buffer_pool = BufferPool::new(
+      4096,
+      Size::from_kibibytes(64),
+      Size::from_kibibytes(32),
+      Size::from_kibibytes(256),
+    );
+    let new_handler = || {
+      let options = FileReadOptions::new(buffer_pool.clone());
+      let io = SingleFileIO::new_ro(sync::Arc::new(path.clone()), 4096, options).unwrap();
+      DirectReadHandler {
+        tx_context: DirectTransmogrify {},
+        io,
+      }
+    };
+    macro_rules! core_tx {
+      ($handler:expr) => {
+        CoreTxHandle {
+          io: $handler.read().into(),
+          stats: Default::default(),
+          tx_id: meta.tx_id,
+          epoch: None,
+          reader: None,
+          page_map: None,
+        }
+      };
+    }
+    // Shared pages hold the whole node
+    let handler = RwLock::new(new_handler());
+    let tx = sync::Arc::new(SharedTxHandle {
+      handle: core_tx!(handler),
+    });
+    let NodePage::Leaf(leaf) = tx.read_node_page(root_page).unwrap() else {
+      panic!("expected a leaf root");
+    };
+    assert_eq!(&blob[..], &*leaf.value(0).unwrap());
+    assert_eq!(&noise[..], &*leaf.value(1).unwrap());
+    drop((leaf, tx));
+    // Lazy pages read their overflow as it's needed
+    let handler = RwLock::new(CachedReadHandler {
+      handler: new_handler(),
+      page_cache: Cache::new(16),
+    });
+    let tx = sync::Arc::new(LazyTxHandle {
+      handle: core_tx!(handler),
+    });
+    let NodePage::Leaf(leaf) = tx.read_node_page(root_page).unwrap() else {
+      panic!("expected a leaf root");
+    };
+    assert!(leaf.value(0).unwrap() == blob[..]);
+    assert!(leaf.value(1).unwrap() == noise[..]);
+    drop((leaf, tx));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_inline_bucket() {
+    let path = temp_db_path("inline_bucket");
+    let gadgets = BucketPathBuf::from(["gadgets"]);
+    drop(
+      MemmapDb::builder()
+        .page_size(4096)
+        .db_tag(BBOLT_TAG)
+        .open_path(path.clone())
+        .unwrap(),
+    );
+    write_inline_bucket(&path);
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    let tx = db.begin();
+    let root = OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), db.meta().root).unwrap();
+    let bucket = root.bucket(b"gadgets").unwrap().unwrap();
+    assert_eq!(0, bucket.header().root().0.0);
+    assert_eq!(7, bucket.header().sequence());
+    assert_eq!(b"1", bucket.get(b"a").unwrap().unwrap().as_ref());
+    assert!(bucket.get(b"c").unwrap().is_none());
+    drop(bucket);
+    drop(root);
+    drop(tx);
+
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(b"2", tx.get(&gadgets, b"b").unwrap().unwrap().as_ref());
+    tx.put(&gadgets, b"c", b"3").unwrap();
+    tx.delete(&gadgets, b"a").unwrap();
+    tx.commit().unwrap();
+
+    // The bucket is written to pages of its own
+    let tx = db.begin_mut().unwrap();
+    assert!(tx.get(&gadgets, b"a").unwrap().is_none());
+    assert_eq!(b"2", tx.get(&gadgets, b"b").unwrap().unwrap().as_ref());
+    assert_eq!(b"3", tx.get(&gadgets, b"c").unwrap().unwrap().as_ref());
+    assert_eq!(8, tx.next_sequence(&gadgets).unwrap());
+    tx.rollback();
+    let tx = db.begin();
+    let root = OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), db.meta().root).unwrap();
+    assert_ne!(
+      0,
+      root.bucket_header(b"gadgets").unwrap().unwrap().root().0.0
+    );
+    drop(root);
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_freelist_commit() {
+    let path = temp_db_path("freelist_commit");
+    let root = BucketPathBuf::new();
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let tx = db.begin();
+    assert_eq!(
+      0,
+      tx.read_freelist_page(db.meta().free_list)
+        .unwrap()
+        .free_count()
+    );
+    drop(tx);
+
+    let tx = db.begin_mut().unwrap();
+    tx.put(&root, b"foo", b"bar").unwrap();
+    tx.commit().unwrap();
+    let first = db.meta();
+    let tx = db.begin();
+    let freelist = tx.read_freelist_page(first.free_list).unwrap();
+    let free: Vec<_> = freelist.freelist_iter().collect();
+    assert_eq!(
+      vec![
+        DiskPageId(INIT_FREELIST_PAGE_ID),
+        DiskPageId(INIT_ROOT_PAGE_ID)
+      ],
+      free
+    );
+    drop(tx);
+
+    let tx = db.begin_mut().unwrap();
+    tx.put(&root, b"foo", b"baz").unwrap();
+    tx.commit().unwrap();
+    drop(db);
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    let second = db.meta();
+    assert_ne!(first.free_list, second.free_list);
+    let tx = db.begin();
+    let freelist = tx.read_freelist_page(second.free_list).unwrap();
+    let free: Vec<_> = freelist.freelist_iter().collect();
+    // The pages freed by the first commit were reused
+    let mut expected = vec![
+      DiskPageId(first.free_list.0.0),
+      DiskPageId(first.root.root().0.0),
+    ];
+    expected.sort();
+    assert_eq!(expected, free);
+    assert_eq!(first.eof_id, second.eof_id);
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_pending_pages() {
+    let path = temp_db_path("pending_pages");
+    let root = BucketPathBuf::new();
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.put(&root, b"foo", b"0").unwrap();
+    tx.commit().unwrap();
+
+    let reader = db.begin();
+    let reader_root = db.meta().root.root();
+    for i in 1..10u32 {
+      let tx = db.begin_mut().unwrap();
+      tx.put(&root, b"foo", i.to_string().as_bytes()).unwrap();
+      tx.commit().unwrap();
+      assert_ne!(reader_root, db.meta().root.root());
+    }
+    // Nothing the reader can see was reused
+    let page = reader.read_node_page(reader_root.into()).unwrap();
+    let NodePage::Leaf(leaf) = page else {
+      panic!("expected a leaf")
+    };
+    assert_eq!(b"0", leaf.value(0).unwrap().as_ref());
+    let eof_with_reader = db.meta().eof_id;
+    drop(reader);
+
+    for i in 10..20u32 {
+      let tx = db.begin_mut().unwrap();
+      tx.put(&root, b"foo", i.to_string().as_bytes()).unwrap();
+      tx.commit().unwrap();
+    }
+    assert_eq!(eof_with_reader, db.meta().eof_id);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_no_freelist_sync() {
+    let path = temp_db_path("no_freelist_sync");
+    let widgets = BucketPathBuf::from(["widgets"]);
+    let free_pages = {
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .no_freelist_sync(true)
+        .open_path(path.clone())
+        .unwrap();
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&widgets).unwrap();
+      for i in 0..500u32 {
+        let key = format!("key-{:08}", i);
+        tx.put(&widgets, key.as_bytes(), &i.to_be_bytes()).unwrap();
+      }
+      tx.commit().unwrap();
+      let tx = db.begin_mut().unwrap();
+      tx.put(&widgets, b"key-00000001", b"updated").unwrap();
+      tx.commit().unwrap();
+      assert_eq!(PGID_NO_FREELIST, db.meta().free_list.0.0);
+      all_free_pages(&db)
+    };
+    assert!(!free_pages.is_empty());
+
+    // The free pages are rebuilt from the reachable pages
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(free_pages, all_free_pages(&db));
+    let tx = db.begin_mut().unwrap();
+    tx.put(&widgets, b"key-00000002", b"updated").unwrap();
+    tx.commit().unwrap();
+    let meta = db.meta();
+    assert_ne!(PGID_NO_FREELIST, meta.free_list.0.0);
+    let tx = db.begin();
+    let freelist = tx.read_freelist_page(meta.free_list).unwrap();
+    assert_eq!(
+      all_free_pages(&db),
+      freelist.freelist_iter().collect::<Vec<_>>()
+    );
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_no_freelist_sync_inline_bucket() {
+    let path = temp_db_path("no_freelist_sync_inline_bucket");
+    let widgets = BucketPathBuf::from(["widgets"]);
+    let gadgets = BucketPathBuf::from(["gadgets"]);
+    {
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .db_tag(BBOLT_TAG)
+        .no_freelist_sync(true)
+        .open_path(path.clone())
+        .unwrap();
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&widgets).unwrap();
+      for i in 0..500u32 {
+        let key = format!("key-{:08}", i);
+        tx.put(&widgets, key.as_bytes(), &i.to_be_bytes()).unwrap();
+      }
+      tx.commit().unwrap();
+      assert_eq!(PGID_NO_FREELIST, db.meta().free_list.0.0);
+    }
+    // The root now holds only the inline bucket, so the pages of "widgets" are free
+    write_inline_bucket(&path);
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    let meta = db.meta();
+    let root = DiskPageId(meta.root.root().0.0);
+    let free_pages: Vec<_> = (2..meta.eof_id.0.0)
+      .map(DiskPageId)
+      .filter(|page_id| *page_id != root)
+      .collect();
+    assert_eq!(free_pages, all_free_pages(&db));
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(b"1", tx.get(&gadgets, b"a").unwrap().unwrap().as_ref());
+    tx.delete_bucket(&gadgets).unwrap();
+    tx.commit().unwrap();
+    assert!(!db.begin_mut().unwrap().bucket_exists(&gadgets).unwrap());
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_no_grow_sync() {
+    let path = temp_db_path("no_grow_sync");
+    let widgets = BucketPathBuf::from(["widgets"]);
+    let big = vec![5u8; 1 << 20];
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .no_grow_sync(true)
+      .open_path(path.clone())
+      .unwrap();
+    assert!(db.options.disable_growth_sync);
+    // The value is larger than the file, so committing it grows the file
+    let file_len = fs::metadata(&path).unwrap().len();
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&widgets).unwrap();
+    tx.put(&widgets, b"big", &big).unwrap();
+    tx.commit().unwrap();
+    assert!(fs::metadata(&path).unwrap().len() > file_len);
+    drop(db);
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(0, db.check().count());
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(
+      &big[..],
+      tx.get(&widgets, b"big").unwrap().unwrap().as_ref()
+    );
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_page_checksums() {
+    let widgets = BucketPathBuf::from(["widgets"]);
+    for db_tag in [BBOLT_TAG, DbFormat::BetterBBoltRs.tag()] {
+      let path = temp_db_path("page_checksums");
+      let open = || {
+        MemmapDb::builder()
+          .page_size(4096)
+          .page_checksums(true)
+          .open_path(path.clone())
+          .unwrap()
+      };
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .db_tag(db_tag)
+        .page_checksums(true)
+        .open_path(path.clone())
+        .unwrap();
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&widgets).unwrap();
+      for i in 0..500u32 {
+        let key = format!("key-{:08}", i);
+        tx.put(&widgets, key.as_bytes(), &i.to_be_bytes()).unwrap();
+      }
+      tx.put(&widgets, b"big", &[3; 10000]).unwrap();
+      tx.commit().unwrap();
+      let tx = db.begin_mut().unwrap();
+      tx.put(&widgets, b"key-00000001", b"updated").unwrap();
+      tx.commit().unwrap();
+      assert_eq!(0, db.check().count());
+      drop(db);
+
+      let db = open();
+      assert_eq!(0, db.check().count());
+      let tx = db.begin_mut().unwrap();
+      assert_eq!(
+        &[3; 10000][..],
+        tx.get(&widgets, b"big").unwrap().unwrap().as_ref()
+      );
+      for i in 2..500u32 {
+        let key = format!("key-{:08}", i);
+        let value = tx.get(&widgets, key.as_bytes()).unwrap().unwrap();
+        assert_eq!(&i.to_be_bytes(), value.as_ref());
+      }
+      drop(tx);
+      let root_id: NodePageId = db.meta().root.root().into();
+      let disk_page_id = match db.page_map() {
+        Some(page_map) => page_map.get(root_id).unwrap(),
+        None => DiskPageId(root_id.0.0),
+      };
+      drop(db);
+
+      // Flip a bit in the root bucket's page past its elements
+      let mut bytes = fs::read(&path).unwrap();
+      bytes[disk_page_id.0 as usize * 4096 + 1024] ^= 1;
+      fs::write(&path, bytes).unwrap();
+      let db = open();
+      let tx = db.begin_mut().unwrap();
+      let Err(report) = tx.get(&widgets, b"big") else {
+        panic!("the flipped bit wasn't caught");
+      };
+      assert!(report.frames().any(|frame| matches!(
+        frame.downcast_ref::<PageError>(),
+        Some(PageError::ChecksumMismatch(_, 0))
+      )));
+      drop(tx);
+      drop(db);
+      fs::remove_file(&path).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_encryption() {
+    let path = temp_db_path("encryption");
+    let compact_path = temp_db_path("encryption-compact");
+    let key = CryptKey::new([3; 32]);
+    let encryption = PageEncryption::ChaCha20Poly1305(key.clone());
+    let secrets = BucketPathBuf::from(["secrets"]);
+    let assert_secrets = |db: &MemmapDb| {
+      assert_eq!(0, db.check().count());
+      let tx = db.begin();
+      let bucket = OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), db.meta().root).unwrap();
+      let bucket = bucket.bucket(b"secrets").unwrap().unwrap();
+      for i in 0..200u32 {
+        let key = format!("secret-{:08}", i);
+        let value = bucket.get(key.as_bytes()).unwrap().unwrap();
+        assert_eq!(&[b's'; 300][..], &value[..]);
+      }
+      let value = bucket.get(b"overflow").unwrap().unwrap();
+      assert_eq!(&[b'o'; 10000][..], &value[..]);
+    };
+    {
+      let db = MemmapDb::builder()
+        .page_size(4096)
+        .encryption(encryption.clone())
+        .open_path(path.clone())
+        .unwrap();
+      assert_eq!(4096 - CRYPT_FOOTER_LEN, db.page_size());
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&secrets).unwrap();
+      tx.put(&secrets, b"overflow", &[b'o'; 10000]).unwrap();
+      tx.commit().unwrap();
+      for batch in 0..20u32 {
+        let tx = db.begin_mut().unwrap();
+        for i in batch * 10..(batch + 1) * 10 {
+          let key = format!("secret-{:08}", i);
+          tx.put(&secrets, key.as_bytes(), &[b's'; 300]).unwrap();
+        }
+        tx.commit().unwrap();
+      }
+      assert_secrets(&db);
+    }
+    let on_disk = fs::read(&path).unwrap();
+    assert!(
+      !on_disk
+        .windows(7)
+        .any(|w| w == b"secrets" || w == b"secret-")
+    );
+    assert!(!on_disk.windows(32).any(|w| w.iter().all(|b| *b == b's')));
+
+    let db = MemmapDb::builder()
+      .encryption(encryption.clone())
+      .read_only(true)
+      .open_path(path.clone())
+      .unwrap();
+    assert_secrets(&db);
+    // Decrypted pages are kept by id, so reading them again under a pinned reader keeps no more
+    let pinned = db.begin();
+    let kept_len = || db.io.read().reader().io.memmap().kept_len();
+    let kept = kept_len();
+    assert_ne!(0, kept);
+    assert_secrets(&db);
+    assert_secrets(&db);
+    assert_eq!(kept, kept_len());
+    drop(pinned);
+    drop(db);
+
+    // The wrong key, the wrong cipher, or no key at all is refused
+    let err = MemmapDb::builder()
+      .encryption(PageEncryption::ChaCha20Poly1305(CryptKey::new([4; 32])))
+      .open_path(path.clone())
+      .err()
+      .unwrap();
+    assert!(matches!(
+      err.downcast_ref::<IOError>(),
+      Some(IOError::CryptKeyError)
+    ));
+    let err = MemmapDb::builder()
+      .encryption(PageEncryption::Aes256Gcm(key.clone()))
+      .open_path(path.clone())
+      .err()
+      .unwrap();
+    assert!(matches!(
+      err.downcast_ref::<IOError>(),
+      Some(IOError::CryptHeaderError)
+    ));
+    assert!(MemmapDb::builder().open_path(path.clone()).is_err());
+    let err = MemmapDb::builder()
+      .encryption(encryption.clone())
+      .wal(WalOptions::default())
+      .open_path(path.clone())
+      .err()
+      .unwrap();
+    assert!(matches!(err.current_context(), DbError::EncryptedWal(_)));
+
+    // Compaction keeps the copy encrypted with the same key
+    let db = MemmapDb::builder()
+      .encryption(encryption.clone())
+      .open_path(path.clone())
+      .unwrap();
+    db.compact().compact_into(compact_path.clone()).unwrap();
+    drop(db);
+    assert!(MemmapDb::builder().open_path(compact_path.clone()).is_err());
+    let db = MemmapDb::builder()
+      .encryption(encryption)
+      .open_path(compact_path.clone())
+      .unwrap();
+    assert_eq!(4096 - CRYPT_FOOTER_LEN, db.page_size());
+    assert_secrets(&db);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&compact_path).unwrap();
+  }
+
+  #[test]
+  fn test_begin_mut_read_only() {
+    let path = temp_db_path("begin_mut_read_only");
+    drop(MemmapDb::builder().open_path(path.clone()).unwrap());
+    let db = MemmapDb::builder()
+      .read_only(true)
+      .open_path(path.clone())
+      .unwrap();
+    let err = db.begin_mut().err().unwrap();
+    assert!(matches!(err.current_context(), DbError::ReadOnly(_)));
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_reader_survives_remap() {
+    let path = temp_db_path("reader_survives_remap");
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let root_id = db.meta().root.root();
+    let tx = db.begin();
+    let root = tx.read_node_page(root_id.into()).unwrap();
+
+    let mut_tx = db.begin_mut().unwrap();
+    let bucket = BucketPathBuf::new();
+    for i in 0..5000u32 {
+      let key = format!("key-{:08}", i);
+      mut_tx.put(&bucket, key.as_bytes(), &[0u8; 64]).unwrap();
+    }
+    mut_tx.commit().unwrap();
+    assert!(db.io.read().reader().io.memmap().mmap_len() > 4096 * INIT_EOF_PAGE_ID as usize);
+
+    assert!(root.is_leaf());
+    assert_eq!(0, root.element_count());
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_wal_recovery() {
+    let path = temp_db_path("wal_recovery");
+    let log_path = wal_path(&path);
+    let base_path = path.with_extension("base");
+    let saved_log_path = path.with_extension("saved-wal");
+    let bucket = BucketPathBuf::from(["widgets"]);
+    drop(
+      MemmapDb::builder()
+        .page_size(4096)
+        .open_path(path.clone())
+        .unwrap(),
+    );
+    fs::copy(&path, &base_path).unwrap();
+    {
+      let db = MemmapDb::builder()
+        .wal(WalOptions::new(u64::MAX, Duration::from_secs(3600)))
+        .open_path(path.clone())
+        .unwrap();
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&bucket).unwrap();
+      tx.commit().unwrap();
+      let tx = db.begin_mut().unwrap();
+      for i in 0..500u32 {
+        tx.put(
+          &bucket,
+          format!("key-{:08}", i).as_bytes(),
+          &i.to_be_bytes(),
+        )
+        .unwrap();
+      }
+      tx.commit().unwrap();
+      assert!(db.wal.as_ref().unwrap().frames_len() > 0);
+      assert_eq!(TxId::of(3), db.meta().tx_id);
+      // Keep the log as it was before the checkpoint on close recycles it
+      fs::copy(&log_path, &saved_log_path).unwrap();
+    }
+
+    // Crash with none of the commits in the database file and a torn frame at the end of the log
+    fs::rename(&base_path, &path).unwrap();
+    fs::rename(&saved_log_path, &log_path).unwrap();
+    let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+    std::io::Write::write_all(&mut log, &[0xAB; 100]).unwrap();
+    drop(log);
+    assert!(matches!(
+      MemmapDb::builder()
+        .read_only(true)
+        .open_path(path.clone())
+        .map(|_| ())
+        .unwrap_err()
+        .current_context(),
+      DbError::WalPending(_)
+    ));
+
+    let db = MemmapDb::builder().open_path(path.clone()).unwrap();
+    assert_eq!(TxId::of(3), db.meta().tx_id);
+    let tx = db.begin_mut().unwrap();
+    for i in 0..500u32 {
+      let value = tx
+        .get(&bucket, format!("key-{:08}", i).as_bytes())
+        .unwrap()
+        .unwrap();
+      assert_eq!(&i.to_be_bytes(), value.as_ref());
+    }
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&log_path).unwrap();
+  }
+
+  #[test]
+  fn test_batch() {
+    let path = temp_db_path("batch");
+    let db = MemmapDb::builder()
+      .max_batch_size(16)
+      .max_batch_delay(Duration::from_millis(50))
+      .open_path(path.clone())
+      .unwrap();
+    let bucket = BucketPathBuf::from(["widgets"]);
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&bucket).unwrap();
+    tx.commit().unwrap();
+    let start_tx_id = db.meta().tx_id;
+
+    let threads = 8u32;
+    let calls = 16u32;
+    thread::scope(|scope| {
+      for t in 0..threads {
+        let db = &db;
+        let bucket = bucket.clone();
+        scope.spawn(move || {
+          for i in 0..calls {
+            let bucket = bucket.clone();
+            let key = format!("key-{:04}-{:04}", t, i);
+            db.batch(move |tx| tx.put(&bucket, key.as_bytes(), &i.to_be_bytes()))
+              .unwrap();
+          }
+          // An empty key fails even when retried on its own
+          let bucket = bucket.clone();
+          let err = db
+            .batch(move |tx| tx.put(&bucket, b"", b"value"))
+            .unwrap_err();
+          assert!(matches!(err.current_context(), DbError::BatchCallError));
+        });
+      }
+    });
+
+    let committed = db.meta().tx_id.0.0 - start_tx_id.0.0;
+    assert!(committed < (threads * calls) as u64);
+    let tx = db.begin_mut().unwrap();
+    for t in 0..threads {
+      for i in 0..calls {
+        let key = format!("key-{:04}-{:04}", t, i);
+        let value = tx.get(&bucket, key.as_bytes()).unwrap().unwrap();
+        assert_eq!(&i.to_be_bytes(), value.as_ref());
+      }
+    }
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_cursor_prefetch() {
+    let path = temp_db_path("cursor_prefetch");
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let mut_tx = db.begin_mut().unwrap();
+    let bucket = BucketPathBuf::new();
+    for i in 0..5000u32 {
+      let key = format!("key-{:08}", i);
+      mut_tx.put(&bucket, key.as_bytes(), &[0u8; 64]).unwrap();
+    }
+    mut_tx.commit().unwrap();
+
+    let tx = db.begin();
+    let stack_pool = VecPool::new(4, 4, 16);
+    let bucket = OnDiskBucket::new(tx.clone(), stack_pool.clone(), db.meta().root).unwrap();
+    assert!(bucket.root.is_branch());
+    for prefetch in [0, 1, 8, 64] {
+      let mut cursor = CoreCursor::new_with_stack(&bucket, stack_pool.pop());
+      cursor.set_prefetch(prefetch);
+      let mut count = 0u32;
+      let mut flag = cursor.move_to_first_element().unwrap();
+      while flag.is_some() {
+        let key = format!("key-{:08}", count);
+        assert_eq!(key.as_bytes(), cursor.key().unwrap().as_ref());
+        count += 1;
+        flag = cursor.move_to_next_element().unwrap();
+      }
+      assert_eq!(5000, count);
+    }
+    drop(bucket);
+    drop(tx);
+    drop(db);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_write_to() {
+    let path = temp_db_path("write_to");
+    let backup_path = temp_db_path("write_to-backup");
+    let copy_path = temp_db_path("write_to-copy");
+    let widgets = BucketPathBuf::from(["widgets"]);
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(path.clone())
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&widgets).unwrap();
+    for i in 0..500u32 {
+      let key = format!("key-{:08}", i);
+      tx.put(&widgets, key.as_bytes(), &i.to_be_bytes()).unwrap();
+    }
+    tx.commit().unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.put(&widgets, b"key-00000001", b"updated").unwrap();
+    tx.commit().unwrap();
+
+    // Commits after the snapshot don't show up in it
+    let (snapshot, meta) = db.begin_with_meta();
+    for i in 0..100u32 {
+      let tx = db.begin_mut().unwrap();
+      tx.put(&widgets, format!("later-{:08}", i).as_bytes(), b"later")
+        .unwrap();
+      tx.commit().unwrap();
+    }
+    let mut backup = Vec::new();
+    let written = snapshot.write_to(&meta, &mut backup, false).unwrap();
+    assert_eq!(backup.len() as u64, written);
+    assert_eq!(meta.eof_id.0.0 * 4096, written);
+    drop(snapshot);
+    fs::write(&backup_path, &backup).unwrap();
+
+    db.copy_file(&copy_path, true).unwrap();
+    assert!(db.copy_file(&copy_path, true).is_err());
+    let copy_meta = db.meta();
+    drop(db);
+
+    let backup = MemmapDb::builder().open_path(&backup_path).unwrap();
+    assert_eq!(meta.tx_id, backup.meta().tx_id);
+    assert_eq!(0, backup.check().count());
+    let tx = backup.begin_mut().unwrap();
+    assert_eq!(
+      Some(&b"updated"[..]),
+      tx.get(&widgets, b"key-00000001").unwrap().as_deref()
+    );
+    assert!(tx.get(&widgets, b"later-00000000").unwrap().is_none());
+    drop(tx);
+    drop(backup);
+
+    let copy = MemmapDb::builder().open_path(&copy_path).unwrap();
+    assert_eq!(copy_meta.tx_id, copy.meta().tx_id);
+    assert_eq!(0, copy.check().count());
+    let tx = copy.begin_mut().unwrap();
+    assert_eq!(
+      Some(&b"later"[..]),
+      tx.get(&widgets, b"later-00000099").unwrap().as_deref()
+    );
+    drop(tx);
+    drop(copy);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&backup_path).unwrap();
+    fs::remove_file(&copy_path).unwrap();
+  }
 }
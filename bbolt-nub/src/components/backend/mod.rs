@@ -1,2 +1,280 @@
+use crate::common::buffer_pool::BufferPool;
+use crate::common::consts::{DbFormat, DbTag};
+use crate::common::errors::{DbError, IOError};
+use crate::common::id::{
+  BucketPageId, DbPageId, DiskPageId, DiskPageTranslator, EOFPageId, FreelistPageId, NodePageId,
+  StableFreeSpaceTranslator, TxId,
+};
+use crate::common::layout::bucket::BucketHeader;
+use crate::common::layout::checksum::write_page_checksums;
+use crate::common::layout::merkle::NodeHasher;
+use crate::common::layout::meta::{HeaderMetaPage, Meta};
+use crate::common::layout::page::PageHeader;
+use crate::common::layout::page_map::PageMapEntry;
+use crate::io::backends::{IOReader, IOWriter};
+use crate::io::bytes::shared_bytes::SharedBytes;
+use crate::io::backends::file_lock::{FileLockType, try_lock_file};
+use crate::io::pages::types::free_space::{free_space_bitmap_len, write_free_space_page};
+use crate::io::pages::types::page_map::write_page_map_page;
+use error_stack::ResultExt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::iter;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
 pub mod file;
 pub mod memmap;
+
+pub(crate) const INIT_FREELIST_PAGE_ID: u64 = 2;
+pub(crate) const INIT_ROOT_PAGE_ID: u64 = 3;
+pub(crate) const INIT_EOF_PAGE_ID: u64 = 4;
+
+/// How long to wait between attempts to lock the database file
+pub(crate) const FILE_LOCK_RETRY: Duration = Duration::from_millis(50);
+
+/// Locks the database file, retrying until `timeout` elapses.
+///
+/// A `timeout` of `None` waits indefinitely, matching Go BBolt's default `Timeout` of 0.
+pub(crate) fn lock_db_file(
+  path: &Path, file: &File, lock_type: FileLockType, timeout: Option<Duration>,
+) -> crate::Result<(), DbError> {
+  let start = Instant::now();
+  loop {
+    let locked =
+      try_lock_file(file, lock_type).change_context_lazy(|| DbError::LockError(path.into()))?;
+    if locked {
+      return Ok(());
+    }
+    if let Some(timeout) = timeout {
+      let elapsed = start.elapsed();
+      if elapsed >= timeout {
+        return Err(DbError::LockTimeout(path.into(), timeout).into());
+      }
+      thread::sleep(FILE_LOCK_RETRY.min(timeout - elapsed));
+    } else {
+      thread::sleep(FILE_LOCK_RETRY);
+    }
+  }
+}
+
+/// The root bucket's node page in a new file in the clustered format. Node page 0 is never
+/// assigned, as a bucket rooted there is inline.
+pub(crate) const INIT_CLUSTERED_ROOT_PAGE_ID: u64 = 1;
+
+/// Writes the initial layout of a brand-new database file and syncs it to disk.
+///
+/// The layout matches `db.init()` in Go BBolt:
+/// * Pages 0 and 1 are meta pages for transactions 0 and 1
+/// * Page 2 is an empty freelist, or in the version 3 format a page map holding the root
+/// * Page 3 is an empty leaf page acting as the root bucket
+///
+/// In the clustered format the meta pages are followed by the first cluster, whose free space
+/// pages are empty and whose second data page is the root bucket.
+///
+/// With `page_checksums` every page but the meta pages ends in its checksum footer.
+///
+/// Returns the meta page of the most recent transaction.
+pub(crate) fn init_db_file(
+  file: &mut File, page_size: usize, db_tag: DbTag, page_checksums: bool,
+) -> io::Result<HeaderMetaPage> {
+  let mut buffer = vec![0u8; page_size * init_eof(page_size, db_tag, page_checksums) as usize];
+  let last_meta_page = init_db_pages(&mut buffer, page_size, db_tag, page_checksums);
+  file.write_all(&buffer)?;
+  file.sync_all()?;
+  Ok(last_meta_page)
+}
+
+/// Writes the initial layout of a brand-new database through `io` and syncs it, for files that
+/// can't be written directly. See [`init_db_file`]
+pub(crate) fn init_db_io<W: IOWriter>(
+  io: &W, db_tag: DbTag, page_checksums: bool,
+) -> crate::Result<HeaderMetaPage, IOError> {
+  let page_size = io.page_size();
+  let mut last_meta_page = HeaderMetaPage::default();
+  let init_len = page_size * init_eof(page_size, db_tag, page_checksums) as usize;
+  let pages = BufferPool::new_unbound(init_len).fill_and_share(|buffer| {
+    last_meta_page = init_db_pages(buffer, page_size, db_tag, page_checksums)
+  });
+  io.write_disk_page(DiskPageId(0), pages)?;
+  io.sync()?;
+  Ok(last_meta_page)
+}
+
+/// The number of pages in a new file
+fn init_eof(page_size: usize, db_tag: DbTag, page_checksums: bool) -> u64 {
+  if db_tag.format() == Some(DbFormat::StableFreeSpace) {
+    let clusters = StableFreeSpaceTranslator::for_pages(TxId::of(0), page_size, page_checksums);
+    let root = NodePageId(DbPageId(INIT_CLUSTERED_ROOT_PAGE_ID));
+    clusters.node_to_disk(root).0 + 1
+  } else {
+    INIT_EOF_PAGE_ID
+  }
+}
+
+/// Lays out the initial pages in `buffer`, returning the meta page of the most recent
+/// transaction
+fn init_db_pages(
+  buffer: &mut [u8], page_size: usize, db_tag: DbTag, page_checksums: bool,
+) -> HeaderMetaPage {
+  if db_tag.format() == Some(DbFormat::StableFreeSpace) {
+    return init_clustered_pages(buffer, page_size, db_tag, page_checksums);
+  }
+  let mut last_meta_page = HeaderMetaPage::default();
+  for (i, page) in buffer.chunks_exact_mut(page_size).enumerate() {
+    let page_id = DbPageId(i as u64);
+    match page_id.0 {
+      0 | 1 => {
+        let mut meta = Meta {
+          magic: db_tag.magic,
+          version: db_tag.version,
+          page_size: page_size as u32,
+          flags: 0,
+          root: BucketHeader::new(BucketPageId(DbPageId(INIT_ROOT_PAGE_ID)), 0),
+          free_list: FreelistPageId(DbPageId(INIT_FREELIST_PAGE_ID)),
+          eof_id: EOFPageId(DiskPageId(INIT_EOF_PAGE_ID)),
+          tx_id: TxId::of(page_id.0),
+          checksum: 0,
+          root_hash: Default::default(),
+        };
+        if db_tag.format() == Some(DbFormat::BetterBBoltRs) {
+          // The root bucket starts as an empty leaf
+          meta.root_hash = NodeHasher::leaf().finish();
+        }
+        meta.update_checksum();
+        last_meta_page = HeaderMetaPage {
+          header: PageHeader::init_meta(page_id),
+          meta,
+        };
+        let meta_bytes = bytemuck::bytes_of(&last_meta_page);
+        page[0..meta_bytes.len()].copy_from_slice(meta_bytes);
+      }
+      INIT_FREELIST_PAGE_ID if db_tag.format() == Some(DbFormat::BetterBBoltRs) => {
+        let root = DbPageId(INIT_ROOT_PAGE_ID);
+        let entry = PageMapEntry::new(NodePageId(root), DiskPageId(root.0));
+        write_page_map_page(page, FreelistPageId(page_id), page_size, iter::once(entry));
+      }
+      INIT_FREELIST_PAGE_ID => {
+        let header = PageHeader::init_freelist(page_id);
+        page[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+      }
+      INIT_ROOT_PAGE_ID => {
+        let header = PageHeader::init_leaf(page_id);
+        page[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+      }
+      _ => unreachable!("init only writes {} pages", INIT_EOF_PAGE_ID),
+    }
+    if page_checksums && page_id.0 >= INIT_FREELIST_PAGE_ID {
+      write_page_checksums(page, page_size);
+    }
+  }
+  last_meta_page
+}
+
+/// Lays out the initial pages of the clustered format in `buffer`. See [`init_db_pages`]
+fn init_clustered_pages(
+  buffer: &mut [u8], page_size: usize, db_tag: DbTag, page_checksums: bool,
+) -> HeaderMetaPage {
+  let clusters = StableFreeSpaceTranslator::for_pages(TxId::of(0), page_size, page_checksums);
+  let bitmap_len = free_space_bitmap_len(page_size, page_checksums);
+  let root = DbPageId(INIT_CLUSTERED_ROOT_PAGE_ID);
+  let eof = buffer.len() / page_size;
+  let mut last_meta_page = HeaderMetaPage::default();
+  for (i, page) in buffer.chunks_exact_mut(page_size).enumerate() {
+    let disk_page_id = DiskPageId(i as u64);
+    if i < 2 {
+      let mut meta = Meta {
+        magic: db_tag.magic,
+        version: db_tag.version,
+        page_size: page_size as u32,
+        flags: 0,
+        root: BucketHeader::new(BucketPageId(root), 0),
+        free_list: FreelistPageId(DbPageId(0)),
+        eof_id: EOFPageId(DiskPageId(eof as u64)),
+        tx_id: TxId::of(i as u64),
+        checksum: 0,
+        root_hash: Default::default(),
+      };
+      meta.update_checksum();
+      last_meta_page = HeaderMetaPage {
+        header: PageHeader::init_meta(DbPageId(i as u64)),
+        meta,
+      };
+      let meta_bytes = bytemuck::bytes_of(&last_meta_page);
+      page[0..meta_bytes.len()].copy_from_slice(meta_bytes);
+      continue;
+    }
+    if clusters.is_free_space(disk_page_id) {
+      let page_id = clusters
+        .clone()
+        .with_tx_id(TxId::of(i as u64))
+        .disk_to_freelist(disk_page_id);
+      write_free_space_page(page, page_id, bitmap_len, iter::empty());
+    } else if clusters.disk_to_node(disk_page_id) == NodePageId(root) {
+      let header = PageHeader::init_leaf(root);
+      page[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+    } else {
+      // The reserved node page is never read
+      continue;
+    }
+    if page_checksums {
+      write_page_checksums(page, page_size);
+    }
+  }
+  last_meta_page
+}
+
+/// Reads the meta of the most recent transaction from meta pages 0 and 1 through `io`, for
+/// files that can't be read directly. A meta page that can't be read or fails its checksum was
+/// torn by a crash and is skipped.
+pub(crate) fn read_db_meta<R: IOReader>(io: &R) -> crate::Result<Meta, IOError> {
+  let mut latest: Option<Meta> = None;
+  for page_id in 0..2 {
+    let Ok(page) = io.read_single_page(DiskPageId(page_id)) else {
+      continue;
+    };
+    let meta_page: HeaderMetaPage =
+      bytemuck::pod_read_unaligned(&page[0..size_of::<HeaderMetaPage>()]);
+    let meta = meta_page.meta;
+    if meta.is_valid() && latest.is_none_or(|latest| meta.tx_id > latest.tx_id) {
+      latest = Some(meta);
+    }
+  }
+  latest.ok_or_else(|| IOError::MetaError.into())
+}
+
+/// Lays out `meta` in a full page destined for its alternating meta page
+pub(crate) fn meta_page(buffer_pool: &BufferPool, meta: Meta) -> (DiskPageId, SharedBytes) {
+  let page_id = DbPageId(meta.tx_id.meta_offset());
+  let meta_page = HeaderMetaPage {
+    header: PageHeader::init_meta(page_id),
+    meta,
+  };
+  let page = buffer_pool.pop().fill_and_share(|buffer| {
+    let meta_bytes = bytemuck::bytes_of(&meta_page);
+    buffer[0..meta_bytes.len()].copy_from_slice(meta_bytes);
+  });
+  (DiskPageId(page_id.0), page)
+}
+
+/// Writes `meta` into its alternating meta page and syncs it to disk.
+///
+/// Once this returns the transaction is durable.
+pub(crate) fn write_meta_page<W: IOWriter>(
+  io: &W, buffer_pool: &BufferPool, meta: Meta,
+) -> crate::Result<(), IOError> {
+  let (disk_page_id, page) = meta_page(buffer_pool, meta);
+  io.write_single_page(disk_page_id, page)?;
+  io.sync()
+}
+
+/// A path in the temp directory unique to `name` and the test process, with any leftover file
+/// removed
+#[cfg(test)]
+pub(crate) fn temp_db_path(name: &str) -> std::path::PathBuf {
+  let path = std::env::temp_dir().join(format!("bbolt-nub-{}-{}.db", name, std::process::id()));
+  let _ = std::fs::remove_file(&path);
+  path
+}
@@ -29,6 +29,7 @@ use std::hash::{Hash, Hasher};
 use std::iter::FusedIterator;
 use std::ops::{Deref, Range};
 use std::sync;
+use std::sync::atomic::{self, AtomicU64};
 
 pub struct OnDiskBucket<B, L, TX> {
   pub(crate) tx: sync::Arc<TX>,
@@ -41,6 +42,48 @@ impl<B, L, TX> OnDiskBucket<B, L, TX> {
   fn sequence(&self) -> u64 {
     self.header.sequence()
   }
+
+  #[inline]
+  pub fn header(&self) -> BucketHeader {
+    self.header
+  }
+}
+
+impl<'tx, TX> OnDiskBucket<TX::BranchType, TX::LeafType, TX>
+where
+  TX: TheTx<'tx>,
+{
+  pub fn new(
+    tx: sync::Arc<TX>, stack_pool: VecPool<StackEntry<TX::BranchType, TX::LeafType>>,
+    header: BucketHeader,
+  ) -> crate::Result<Self, BucketError> {
+    let root = tx
+      .read_node_page(header.root().into())
+      .change_context(BucketError::RootReadError(header.root()))?;
+    Ok(OnDiskBucket {
+      tx,
+      stack_pool,
+      header,
+      root,
+    })
+  }
+
+  /// The bucket stored inline in `value`, which owns no pages. Its root leaf follows `header`
+  /// in `value`.
+  pub fn new_inline(
+    tx: sync::Arc<TX>, stack_pool: VecPool<StackEntry<TX::BranchType, TX::LeafType>>,
+    header: BucketHeader, value: <TX::LeafType as HasKeys<'tx>>::TxKv,
+  ) -> crate::Result<Self, BucketError> {
+    let root = tx
+      .read_inline_node(value)
+      .change_context(BucketError::RootReadError(header.root()))?;
+    Ok(OnDiskBucket {
+      tx,
+      stack_pool,
+      header,
+      root,
+    })
+  }
 }
 
 impl<'tx, TX> OnDiskBucket<TX::BranchType, TX::LeafType, TX>
@@ -48,15 +91,17 @@ where
   TX: TheTx<'tx>,
   for<'b> <TX::BranchType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
   for<'b> <TX::LeafType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]> + AsRef<[u8]>,
 {
-  fn get(
+  /// The value stored under `key`
+  pub fn get(
     &self, key: &[u8],
-  ) -> crate::Result<Option<<TX::LeafType as HasKeys<'tx>>::TxKv>, BucketError> {
+  ) -> crate::Result<Option<DeltaKv<<TX::LeafType as HasKeys<'tx>>::TxKv>>, BucketError> {
     let stack = self.stack_pool.pop();
     let core_cursor = CoreCursor::new_with_stack(self, stack);
     let mut c = LeafFlagFilterCursor::new(core_cursor, LeafFlag::default());
     match c.seek(key) {
-      Ok(v) => Ok(v.map(|_| c.value()).flatten()),
+      Ok(v) => Ok(v.and_then(|_| c.value()).map(DeltaKv::OnDisk)),
       Err(err) => {
         let e = match err.current_context() {
           CursorError::ValueIsABucket => err.change_context(BucketError::ValueIsABucket),
@@ -68,6 +113,66 @@ where
   }
 }
 
+impl<'tx, TX> OnDiskBucket<TX::BranchType, TX::LeafType, TX>
+where
+  TX: TheTx<'tx>,
+  for<'b> <TX::BranchType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
+  for<'b> <TX::LeafType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  /// The header of the child bucket stored under `key`
+  pub fn bucket_header(&self, key: &[u8]) -> crate::Result<Option<BucketHeader>, BucketError> {
+    Ok(self.bucket_element(key)?.map(|(_, header)| header))
+  }
+
+  /// The flags and header of the child bucket stored under `key`
+  pub fn bucket_element(
+    &self, key: &[u8],
+  ) -> crate::Result<Option<(LeafFlag, BucketHeader)>, BucketError> {
+    Ok(self.bucket_value(key)?.map(|(flag, value)| {
+      (
+        flag,
+        bytemuck::pod_read_unaligned(&value[0..size_of::<BucketHeader>()]),
+      )
+    }))
+  }
+
+  /// The flags and value of the child bucket stored under `key`
+  #[allow(clippy::type_complexity)]
+  fn bucket_value(
+    &self, key: &[u8],
+  ) -> crate::Result<Option<(LeafFlag, <TX::LeafType as HasKeys<'tx>>::TxKv)>, BucketError> {
+    let stack = self.stack_pool.pop();
+    let core_cursor = CoreCursor::new_with_stack(self, stack);
+    let mut c = LeafFlagFilterCursor::new(core_cursor, LeafFlag::BUCKET);
+    match c.seek(key) {
+      Ok(v) => Ok(v.and_then(|flag| c.value().map(|value| (flag, value)))),
+      Err(err) => {
+        let e = match err.current_context() {
+          CursorError::ValueIsBytes => err.change_context(BucketError::ValueIsBytes),
+          _ => err.change_context(BucketError::GetError),
+        };
+        Err(e)
+      }
+    }
+  }
+
+  /// The child bucket stored under `key`. A bucket Go BBolt stored inline is read from its
+  /// value.
+  pub fn bucket(&self, key: &[u8]) -> crate::Result<Option<Self>, BucketError> {
+    let Some((_, value)) = self.bucket_value(key)? else {
+      return Ok(None);
+    };
+    let header: BucketHeader = bytemuck::pod_read_unaligned(&value[0..size_of::<BucketHeader>()]);
+    let (tx, stack_pool) = (self.tx.clone(), self.stack_pool.clone());
+    if header.root().0.0 == 0 {
+      OnDiskBucket::new_inline(tx, stack_pool, header, value).map(Some)
+    } else {
+      OnDiskBucket::new(tx, stack_pool, header).map(Some)
+    }
+  }
+}
+
 impl<'tx, TX> OnDiskBucket<TX::BranchType, TX::LeafType, TX>
 where
   TX: TheTx<'tx>,
@@ -93,9 +198,13 @@ where
   }
 }
 
+#[derive(Clone)]
 pub enum ValueDelta {
   UValue(SharedData),
-  UBucket(SharedData),
+  /// A value compressed with `compress_value`
+  UCompressed(SharedData),
+  /// A bucket header, with the bucket's compression threshold in its flags
+  UBucket(SharedData, LeafFlag),
   Delete,
 }
 
@@ -116,6 +225,89 @@ impl<B, L, T> BucketType<B, L, T> {
 #[derive(Clone)]
 pub struct BucketDelta {
   delta: sync::Arc<Mutex<BTreeMap<SharedData, ValueDelta>>>,
+  on_disk: Option<BucketHeader>,
+  sequence: sync::Arc<AtomicU64>,
+  on_disk_compression: Option<usize>,
+  compression: sync::Arc<Mutex<Option<usize>>>,
+}
+
+impl BucketDelta {
+  /// A delta against a bucket that exists on disk, which compresses values at least
+  /// `compression` bytes long
+  pub fn new(on_disk: BucketHeader, compression: Option<usize>) -> Self {
+    BucketDelta {
+      delta: Default::default(),
+      on_disk: Some(on_disk),
+      sequence: sync::Arc::new(AtomicU64::new(on_disk.sequence())),
+      on_disk_compression: compression,
+      compression: sync::Arc::new(Mutex::new(compression)),
+    }
+  }
+
+  /// A delta for a bucket created in this transaction
+  pub fn new_bucket() -> Self {
+    BucketDelta {
+      delta: Default::default(),
+      on_disk: None,
+      sequence: Default::default(),
+      on_disk_compression: None,
+      compression: Default::default(),
+    }
+  }
+
+  /// The bucket's sequence as of this transaction
+  #[inline]
+  pub fn sequence(&self) -> u64 {
+    self.sequence.load(atomic::Ordering::Acquire)
+  }
+
+  #[inline]
+  pub fn set_sequence(&self, sequence: u64) {
+    self.sequence.store(sequence, atomic::Ordering::Release);
+  }
+
+  /// Increments the bucket's sequence, returning the new value
+  #[inline]
+  pub fn next_sequence(&self) -> u64 {
+    self.sequence.fetch_add(1, atomic::Ordering::AcqRel) + 1
+  }
+
+  /// The size values put into the bucket are compressed from, as of this transaction
+  #[inline]
+  pub fn compression(&self) -> Option<usize> {
+    *self.compression.lock()
+  }
+
+  #[inline]
+  pub fn set_compression(&self, threshold: Option<usize>) {
+    *self.compression.lock() = threshold;
+  }
+
+  /// Whether the compression threshold is different from the one on disk
+  #[inline]
+  pub fn compression_changed(&self) -> bool {
+    self.compression() != self.on_disk_compression
+  }
+
+  /// The bucket's header as of the start of the transaction
+  #[inline]
+  pub fn on_disk(&self) -> Option<BucketHeader> {
+    self.on_disk
+  }
+
+  #[inline]
+  pub fn is_new(&self) -> bool {
+    self.on_disk.is_none()
+  }
+
+  #[inline]
+  pub fn lock(&self) -> MutexGuard<'_, BTreeMap<SharedData, ValueDelta>> {
+    self.delta.lock()
+  }
+
+  pub fn take(&self) -> BTreeMap<SharedData, ValueDelta> {
+    std::mem::take(&mut *self.delta.lock())
+  }
 }
 
 pub struct DeltaBucket<B, L, T> {
@@ -127,6 +319,7 @@ pub struct DeltaBucket<B, L, T> {
 pub enum DeltaKv<O: AsRef<[u8]>> {
   OnDisk(O),
   Delta(SharedData),
+  Decompressed(sync::Arc<[u8]>),
 }
 
 impl<O: AsRef<[u8]>> AsRef<[u8]> for DeltaKv<O> {
@@ -134,6 +327,7 @@ impl<O: AsRef<[u8]>> AsRef<[u8]> for DeltaKv<O> {
     match self {
       DeltaKv::OnDisk(d) => d.as_ref(),
       DeltaKv::Delta(u) => u.as_ref(),
+      DeltaKv::Decompressed(d) => d,
     }
   }
 }
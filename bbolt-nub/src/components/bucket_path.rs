@@ -50,6 +50,20 @@ impl BucketPathBuf {
   pub fn len(&self) -> usize {
     self.partitions.len()
   }
+
+  pub fn is_empty(&self) -> bool {
+    self.partitions.is_empty()
+  }
+
+  /// The last key in the path
+  pub fn last(&self) -> Option<&[u8]> {
+    self.into_iter().last()
+  }
+
+  /// Whether `prefix` is an ancestor of, or equal to, this path
+  pub fn starts_with(&self, prefix: &BucketPathBuf) -> bool {
+    self.len() >= prefix.len() && self.into_iter().zip(prefix).all(|(a, b)| a == b)
+  }
 }
 
 impl<A> Extend<A> for BucketPathBuf
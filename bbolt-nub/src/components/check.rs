@@ -0,0 +1,454 @@
+use crate::common::consts::PGID_NO_FREELIST;
+use crate::common::errors::{CheckError, PageError, TxError};
+use crate::common::id::{
+  DbPageId, DiskPageId, DiskPageTranslator, EOFPageId, NodePageId, PageMapTranslator,
+  StableFreeSpaceTranslator,
+};
+use crate::common::layout::bucket::BucketHeader;
+use crate::common::layout::meta::Meta;
+use crate::common::layout::node::LeafFlag;
+use crate::components::tx::TxPageMap;
+use crate::io::TxSlot;
+use crate::io::pages::types::freelist::HasFreelist;
+use crate::io::pages::types::node::branch::HasNodes;
+use crate::io::pages::types::node::leaf::HasValues;
+use crate::io::pages::types::node::{HasElements, HasKeys, NodePage};
+use crate::io::pages::{Page, TxReadPageIO};
+use error_stack::{Report, ResultExt};
+use hashbrown::HashSet;
+use std::collections::VecDeque;
+use std::ops::{Deref, Range};
+use std::sync;
+
+/// A node page waiting to be checked, with the bounds its keys must fall in
+struct PendingPage {
+  page_id: NodePageId,
+  /// The keys must be at least `min`
+  min: Option<Vec<u8>>,
+  /// The keys must be less than `max`
+  max: Option<Vec<u8>>,
+}
+
+/// Checks the consistency of a transaction's pages, yielding each problem as it's found.
+///
+/// Every bucket is walked from `Meta.root`. Each node page must pass
+/// [`PageHeader::fast_check`](crate::common::layout::page::PageHeader::fast_check), its keys must
+/// be sorted and within the range its parent branch gives it, and it must be referenced once and
+/// not be free. Once the walk is done, every page below `Meta.eof_id` must have been reached or
+/// be free. Without a freelist every unreached page is free, so that last step is skipped.
+///
+/// In the version 3 format, see [`TxCheck::with_page_map`], node pages are reached where the page
+/// map stores them, and in the clustered format, see [`TxCheck::with_clusters`], where the layout
+/// puts them. `tx.Check()` in Go BBolt
+pub struct TxCheck<'tx, TX> {
+  tx: sync::Arc<TX>,
+  eof: EOFPageId,
+  page_map: Option<TxPageMap>,
+  free: HashSet<DiskPageId>,
+  reached: HashSet<DiskPageId>,
+  pending: Vec<PendingPage>,
+  unreached: Option<Range<u64>>,
+  errors: VecDeque<Report<CheckError>>,
+  tx_slot: TxSlot<'tx>,
+}
+
+impl<'tx, TX> TxCheck<'tx, TX>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  /// Checks the pages `tx` can see through `meta`
+  pub fn new(tx: sync::Arc<TX>, meta: &Meta) -> Self {
+    let mut check = Self::unchecked(tx, meta, None);
+    if meta.free_list.0.0 != PGID_NO_FREELIST {
+      match check.tx.read_freelist_page(meta.free_list) {
+        Ok(freelist) => {
+          check.reach(
+            DiskPageId(meta.free_list.0.0),
+            freelist.page_header().get_overflow(),
+          );
+          for page_id in freelist.freelist_iter() {
+            if page_id.0 >= check.eof.0.0 {
+              let error = CheckError::OutOfBounds(page_id, check.eof);
+              check.errors.push_back(Report::new(error));
+            } else if !check.free.insert(page_id) {
+              check
+                .errors
+                .push_back(Report::new(CheckError::DoubleFreed(page_id)));
+            }
+          }
+        }
+        Err(err) => {
+          let error = CheckError::FreelistReadError(meta.free_list);
+          check.errors.push_back(err.change_context(error));
+        }
+      }
+      check.unreached = Some(0..check.eof.0.0);
+    }
+    check
+  }
+
+  /// Checks the pages of a version 3 transaction, whose node pages are stored where `page_map`
+  /// says and whose page map page has the `page_map_overflow` read from its header. Every page
+  /// that isn't mapped is free, so unreached pages aren't looked for.
+  pub fn with_page_map(
+    tx: sync::Arc<TX>, meta: &Meta, page_map: PageMapTranslator,
+    page_map_overflow: crate::Result<u32, PageError>,
+  ) -> Self {
+    let mut check = Self::unchecked(tx, meta, Some(TxPageMap::PageMap(page_map)));
+    match page_map_overflow {
+      Ok(overflow) => check.reach(DiskPageId(meta.free_list.0.0), overflow),
+      Err(err) => {
+        let error = CheckError::FreelistReadError(meta.free_list);
+        check.errors.push_back(err.change_context(error));
+      }
+    }
+    check
+  }
+
+  /// Checks the pages of a clustered transaction, whose node pages are stored where `clusters`
+  /// lays them out and whose `free` pages were read from its free space pages. Node page 0 is
+  /// never assigned, so it's reached along with the free space regions.
+  pub fn with_clusters(
+    tx: sync::Arc<TX>, meta: &Meta, clusters: StableFreeSpaceTranslator,
+    free: crate::Result<Vec<DiskPageId>, TxError>,
+  ) -> Self {
+    let reserved = clusters.node_to_disk(NodePageId(DbPageId(0)));
+    let mut check = Self::unchecked(tx, meta, None);
+    for page_id in (2..check.eof.0.0).map(DiskPageId) {
+      if clusters.is_free_space(page_id) || page_id == reserved {
+        check.reach(page_id, 0);
+      }
+    }
+    match free {
+      Ok(free) => {
+        for page_id in free {
+          if check.reached.contains(&page_id) {
+            let error = CheckError::ReachableFreed(page_id);
+            check.errors.push_back(Report::new(error));
+          } else if !check.free.insert(page_id) {
+            let error = CheckError::DoubleFreed(page_id);
+            check.errors.push_back(Report::new(error));
+          }
+        }
+      }
+      Err(err) => {
+        let error = CheckError::FreelistReadError(meta.free_list);
+        check.errors.push_back(err.change_context(error));
+      }
+    }
+    check.page_map = Some(TxPageMap::Clustered(clusters));
+    check.unreached = Some(0..check.eof.0.0);
+    check
+  }
+
+  /// Starts at the root bucket with only the meta pages reached
+  fn unchecked(tx: sync::Arc<TX>, meta: &Meta, page_map: Option<TxPageMap>) -> Self {
+    let mut check = TxCheck {
+      tx,
+      eof: meta.eof_id,
+      page_map,
+      free: HashSet::new(),
+      reached: HashSet::new(),
+      pending: vec![PendingPage {
+        page_id: meta.root.root().into(),
+        min: None,
+        max: None,
+      }],
+      unreached: None,
+      errors: VecDeque::new(),
+      tx_slot: TxSlot::default(),
+    };
+    check.reach(DiskPageId(0), 0);
+    check.reach(DiskPageId(1), 0);
+    check
+  }
+
+  /// Marks the page and its overflow as reached
+  fn reach(&mut self, page_id: DiskPageId, overflow: u32) {
+    for index in 0..=overflow as u64 {
+      let page_id = page_id + index;
+      let error = if page_id.0 >= self.eof.0.0 {
+        CheckError::OutOfBounds(page_id, self.eof)
+      } else if !self.reached.insert(page_id) {
+        CheckError::MultipleReferences(page_id)
+      } else if self.free.contains(&page_id) {
+        CheckError::ReachableFreed(page_id)
+      } else {
+        continue;
+      };
+      self.errors.push_back(Report::new(error));
+    }
+  }
+
+  /// Checks that `keys` are sorted and within `min..max`
+  fn check_keys<'a>(
+    &mut self, page_id: NodePageId, keys: impl Iterator<Item = &'a [u8]>, min: Option<&[u8]>,
+    max: Option<&[u8]>,
+  ) {
+    let mut prev: Option<&[u8]> = None;
+    for (index, key) in keys.enumerate() {
+      if prev.is_some_and(|prev| prev >= key) {
+        let error = CheckError::UnsortedKeys(page_id, index);
+        self.errors.push_back(Report::new(error));
+      }
+      if min.is_some_and(|min| key < min) || max.is_some_and(|max| key >= max) {
+        let error = CheckError::KeyOutOfRange(page_id, index);
+        self.errors.push_back(Report::new(error));
+      }
+      prev = Some(key);
+    }
+  }
+
+  fn check_page(&mut self, pending: PendingPage) {
+    let PendingPage { page_id, min, max } = pending;
+    // Unmapped pages fail to be read
+    let disk_page_id = match &self.page_map {
+      None => Some(DiskPageId(page_id.0.0)),
+      Some(TxPageMap::Clustered(clusters)) => Some(clusters.node_to_disk(page_id)),
+      Some(TxPageMap::PageMap(_)) => None,
+    };
+    if let Some(disk_page_id) = disk_page_id.filter(|disk_page_id| *disk_page_id >= self.eof.0) {
+      let error = CheckError::OutOfBounds(disk_page_id, self.eof);
+      self.errors.push_back(Report::new(error));
+      return;
+    }
+    let node = match self.tx.read_node_page(page_id).and_then(|node| {
+      node.page_header().fast_check(page_id)?;
+      Ok(node)
+    }) {
+      Ok(node) => node,
+      Err(err) => {
+        self
+          .errors
+          .push_back(err.change_context(CheckError::InvalidPage(page_id)));
+        return;
+      }
+    };
+    let overflow = node.page_header().get_overflow();
+    match &self.page_map {
+      Some(page_map) => {
+        let disk_page_ids: Vec<_> = (0..=overflow)
+          .filter_map(|overflow| page_map.get(page_id + overflow))
+          .collect();
+        for disk_page_id in disk_page_ids {
+          self.reach(disk_page_id, 0);
+        }
+      }
+      None => self.reach(DiskPageId(page_id.0.0), overflow),
+    }
+    let start = self.pending.len();
+    match &node {
+      NodePage::Branch(branch) => {
+        let mut elements = Vec::with_capacity(branch.element_count());
+        for index in 0..branch.element_count() {
+          match (branch.key(index), branch.node(index)) {
+            (Some(key), Some(child)) => elements.push((key, child)),
+            _ => {
+              let error = CheckError::InvalidElement(page_id, index);
+              self.errors.push_back(Report::new(error));
+              return;
+            }
+          }
+        }
+        self.check_keys(
+          page_id,
+          elements.iter().map(|(key, _)| &**key),
+          min.as_deref(),
+          max.as_deref(),
+        );
+        for (index, (key, child)) in elements.iter().enumerate() {
+          self.pending.push(PendingPage {
+            page_id: *child,
+            min: Some(key.to_vec()),
+            max: match elements.get(index + 1) {
+              Some((next, _)) => Some(next.to_vec()),
+              None => max.clone(),
+            },
+          });
+        }
+      }
+      NodePage::Leaf(leaf) => {
+        let mut elements = Vec::with_capacity(leaf.element_count());
+        for index in 0..leaf.element_count() {
+          match (leaf.leaf_flag(index), leaf.key_value(index)) {
+            (Some(flag), Some((key, value))) => elements.push((flag, key, value)),
+            _ => {
+              let error = CheckError::InvalidElement(page_id, index);
+              self.errors.push_back(Report::new(error));
+              return;
+            }
+          }
+        }
+        self.check_keys(
+          page_id,
+          elements.iter().map(|(_, key, _)| &**key),
+          min.as_deref(),
+          max.as_deref(),
+        );
+        for (index, (flag, _, value)) in elements.iter().enumerate() {
+          if !flag.contains(LeafFlag::BUCKET) {
+            continue;
+          }
+          let Some(header) = value.get(0..size_of::<BucketHeader>()) else {
+            let error = CheckError::InvalidElement(page_id, index);
+            self.errors.push_back(Report::new(error));
+            continue;
+          };
+          let header: BucketHeader = bytemuck::pod_read_unaligned(header);
+          // Inline buckets are stored in this page
+          if header.root().0.0 != 0 {
+            self.pending.push(PendingPage {
+              page_id: header.root().into(),
+              min: None,
+              max: None,
+            });
+          }
+        }
+      }
+    }
+    // Visit the children in key order
+    self.pending[start..].reverse();
+  }
+}
+
+impl<'tx, TX> Iterator for TxCheck<'tx, TX>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  type Item = Report<CheckError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some(error) = self.errors.pop_front() {
+        return Some(error);
+      }
+      if let Some(pending) = self.pending.pop() {
+        self.check_page(pending);
+        continue;
+      }
+      let unreached = self.unreached.as_mut()?;
+      for page_id in unreached.by_ref().map(DiskPageId) {
+        if !self.reached.contains(&page_id) && !self.free.contains(&page_id) {
+          return Some(Report::new(CheckError::UnreachableUnfreed(page_id)));
+        }
+      }
+      self.unreached = None;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::consts::DbFormat;
+  use crate::common::layout::checksum::page_count;
+  use crate::components::backend::memmap::MemmapDb;
+  use crate::components::backend::temp_db_path;
+  use crate::components::bucket_path::BucketPathBuf;
+  use crate::components::tx::walk_node_tree;
+  use crate::io::pages::types::page_map::page_map_page_len;
+  use std::fs;
+  use std::os::unix::fs::FileExt;
+
+  #[test]
+  fn test_tx_check() {
+    let path = temp_db_path("check");
+    let root = BucketPathBuf::new();
+    let gears = BucketPathBuf::from(["gears"]);
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(&path)
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&gears).unwrap();
+    tx.commit().unwrap();
+    for i in 0..1000u32 {
+      let tx = db.begin_mut().unwrap();
+      tx.put(&root, format!("key-{:08}", i).as_bytes(), &i.to_be_bytes())
+        .unwrap();
+      tx.put(&gears, format!("gear-{:08}", i % 10).as_bytes(), b"teeth")
+        .unwrap();
+      tx.commit().unwrap();
+    }
+    assert_eq!(0, db.check().count());
+
+    // Shrinking the end of the database strands the last page
+    let mut meta = db.meta();
+    meta.eof_id = EOFPageId(DiskPageId(meta.eof_id.0.0 - 1));
+    let errors: Vec<_> = TxCheck::new(db.begin(), &meta).collect();
+    assert!(
+      errors
+        .iter()
+        .any(|error| matches!(error.current_context(), CheckError::OutOfBounds(..)))
+    );
+
+    let mut leaf = None;
+    walk_node_tree(
+      &db.begin(),
+      db.meta().root.root().into(),
+      &mut |page_id, _| {
+        leaf.get_or_insert(page_id);
+      },
+    )
+    .unwrap();
+    let leaf = leaf.unwrap();
+    drop(db);
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[0u8; 16], leaf.0.0 * 4096).unwrap();
+    drop(file);
+
+    let db = MemmapDb::builder()
+      .read_only(true)
+      .open_path(&path)
+      .unwrap();
+    let errors: Vec<_> = db
+      .check()
+      .map(|error| format!("{:?}", error.current_context()))
+      .collect();
+    assert_eq!(
+      vec![
+        format!("{:?}", CheckError::InvalidPage(leaf)),
+        format!("{:?}", CheckError::UnreachableUnfreed(DiskPageId(leaf.0.0))),
+      ],
+      errors
+    );
+    drop(db);
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_page_map_checksum_footer() {
+    let path = temp_db_path("check_page_map_checksum_footer");
+    let db = MemmapDb::builder()
+      .page_size(512)
+      .db_tag(DbFormat::BetterBBoltRs.tag())
+      .page_checksums(true)
+      .open_path(&path)
+      .unwrap();
+    // Each bucket's value fills its own leaf, adding an entry to the page map, until the entries
+    // fill whole pages and the checksum footer spills onto another
+    let page_map_len = |db: &MemmapDb| page_map_page_len(db.page_map().unwrap().len());
+    for i in 0..100u32 {
+      let tx = db.begin_mut().unwrap();
+      let bucket = BucketPathBuf::from([format!("bucket-{:02}", i)]);
+      tx.create_bucket(&bucket).unwrap();
+      tx.put(&bucket, b"value", &[i as u8; 300]).unwrap();
+      tx.commit().unwrap();
+      if page_map_len(&db) % 512 == 0 {
+        break;
+      }
+    }
+    assert_eq!(0, page_map_len(&db) % 512);
+    let page_map_pages = page_count(page_map_len(&db), 512, true) as u64;
+    let last_page = DiskPageId(db.meta().free_list.0.0 + page_map_pages - 1);
+    let check = db.check();
+    assert!(check.reached.contains(&last_page));
+    assert_eq!(0, check.count());
+    drop(db);
+    fs::remove_file(path).unwrap();
+  }
+}
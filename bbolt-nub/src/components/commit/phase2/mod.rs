@@ -1,34 +1,70 @@
 mod doc;
 
+use crate::common::buffer_pool::BufferPool;
 use crate::common::data_pool::SharedData;
-use crate::common::errors::CursorError;
-use crate::common::id::{NodePageId, WipNodeGenerator, WipNodeId};
-use crate::common::layout::node::{LeafElement, LeafFlag};
+use crate::common::errors::{BucketError, CursorError};
+use crate::common::id::{BucketPageId, NodePageId, WipNodeGenerator, WipNodeId};
+use crate::common::layout::bucket::BucketHeader;
+use crate::common::layout::checksum::{page_count, page_footer_len, write_page_checksums};
+use crate::common::layout::compression::{compress_value, decompress_value};
+use crate::common::layout::merkle::{
+  MerkleHash, NodeHasher, branch_hash_range, bucket_root_hash, value_hash,
+};
+use crate::common::layout::node::{BranchElement, LeafElement, LeafFlag};
+use crate::common::layout::page::PageHeader;
 use crate::components::bucket::ValueDelta;
-use crate::components::cursor::CoreCursor;
-use crate::components::tx::TheTx;
-use crate::io::TxSlot;
-use crate::io::pages::{GatKvRef, GetKvTxSlice, TxPageType};
+use crate::components::bucket_path::BucketPathBuf;
+use crate::components::cursor::{CoreCursor, CoreCursorSeekApi, StackEntry};
+use crate::components::free_index::NodeAllocator;
+use crate::components::merkle::stored_page_hash;
+use crate::components::tx::{MutTxHandle, TheMutTx, TheTx};
+use crate::io::bytes::shared_bytes::SharedBytes;
+use crate::io::pages::types::node::branch::HasNodes;
+use crate::io::pages::types::node::leaf::HasValues;
+use crate::io::pages::types::node::{HasElements, HasKeys, NodePage};
+use crate::io::pages::{GatKvRef, Page};
+use error_stack::ResultExt;
 use hashbrown::HashMap;
+use std::cmp::{Ordering, Reverse};
 use std::collections::BTreeMap;
-use std::marker::PhantomData;
-use std::ops::Deref;
+use std::ops::{Deref, Range};
+use std::sync;
 use thiserror::Error;
-use crate::common::layout::page::PageHeader;
+
+/// The fewest elements a node may hold after a split
+const MIN_KEYS_PER_PAGE: usize = 2;
+
+/// The fewest elements a rewritten leaf may hold before it's merged with a sibling
+const MIN_LEAF_KEYS: usize = 1;
+
+/// The fewest elements a rewritten branch may hold before it's merged with a sibling
+const MIN_BRANCH_KEYS: usize = 2;
 
 #[derive(Debug, Error)]
 pub enum CommitError {
   #[error("CommitError: Unspecified Failure")]
   Failure,
+  #[error("CommitError: Unable to locate delta key")]
+  Seek,
+  #[error("CommitError: Unable to load bucket `{0}`")]
+  Bucket(BucketPathBuf),
+  #[error("CommitError: Unable to read element {1} of page `{0:?}`")]
+  Element(NodePageId, usize),
+  #[error("CommitError: Unable to hash page `{0:?}`")]
+  Hash(NodePageId),
+  #[error("CommitError: Unable to read page `{0:?}`")]
+  Page(NodePageId),
 }
 
-
 pub enum LeafData<D> {
   OnDisk(D),
   Upsert(SharedData),
 }
 
-impl<D> Deref for LeafData<D> where D: Deref<Target = [u8]> {
+impl<D> Deref for LeafData<D>
+where
+  D: Deref<Target = [u8]>,
+{
   type Target = [u8];
   fn deref(&self) -> &Self::Target {
     match self {
@@ -38,7 +74,10 @@ impl<D> Deref for LeafData<D> where D: Deref<Target = [u8]> {
   }
 }
 
-impl<D> AsRef<[u8]> for LeafData<D> where D: Deref<Target = [u8]> {
+impl<D> AsRef<[u8]> for LeafData<D>
+where
+  D: Deref<Target = [u8]>,
+{
   fn as_ref(&self) -> &[u8] {
     self.deref()
   }
@@ -46,10 +85,28 @@ impl<D> AsRef<[u8]> for LeafData<D> where D: Deref<Target = [u8]> {
 
 pub struct LeafValue<D> {
   data: LeafData<D>,
-  is_bucket: bool,
+  flags: LeafFlag,
 }
 
-impl<D> Deref for LeafValue<D> where D: Deref<Target = [u8]>
+impl<D> LeafValue<D> {
+  /// The value a delta writes, or `None` if it deletes the key
+  fn from_delta(value_delta: ValueDelta) -> Option<Self> {
+    let (flags, value) = match value_delta {
+      ValueDelta::UValue(value) => (LeafFlag::empty(), value),
+      ValueDelta::UCompressed(value) => (LeafFlag::COMPRESSED, value),
+      ValueDelta::UBucket(bucket, flags) => (flags, bucket),
+      ValueDelta::Delete => return None,
+    };
+    Some(LeafValue {
+      data: LeafData::Upsert(value),
+      flags,
+    })
+  }
+}
+
+impl<D> Deref for LeafValue<D>
+where
+  D: Deref<Target = [u8]>,
 {
   type Target = [u8];
 
@@ -58,151 +115,941 @@ impl<D> Deref for LeafValue<D> where D: Deref<Target = [u8]>
   }
 }
 
-impl<D> AsRef<[u8]> for LeafValue<D> where D: Deref<Target = [u8]> {
+impl<D> AsRef<[u8]> for LeafValue<D>
+where
+  D: Deref<Target = [u8]>,
+{
   fn as_ref(&self) -> &[u8] {
     self.data.deref()
   }
 }
 
+/// A child of a branch, either rewritten in this commit or left untouched on disk
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WipChild {
+  Wip(WipNodeId),
+  OnDisk(NodePageId),
+}
+
 pub struct WipBranch {
   wip_id: WipNodeId,
-  parent_wip_id: WipNodeId,
-  first_key: SharedData,
-  entries: BTreeMap<SharedData, WipNodeId>,
+  /// The page this node replaces, used as an allocation hint
+  origin: Option<NodePageId>,
+  entries: Vec<(SharedData, WipChild)>,
 }
 
-pub struct WipLeaf<D> {
-  wip_id: WipNodeId,
-  first_key: SharedData,
-  entries: BTreeMap<SharedData, LeafValue<D>>,
+impl WipBranch {
+  fn byte_size(&self, merkle_hashes: bool) -> usize {
+    size_of::<PageHeader>()
+      + self
+        .entries
+        .iter()
+        .map(|(key, _)| branch_element_size(key, merkle_hashes))
+        .sum::<usize>()
+  }
+
+  /// Writes the branch pointing at `children`. The version 3 format stores each child's
+  /// `hashes` between the elements and the keys.
+  fn write_page(
+    &self, page_id: NodePageId, overflow: u32, children: &[NodePageId],
+    hashes: Option<&[MerkleHash]>, page: &mut [u8],
+  ) {
+    let mut header = PageHeader::init_branch(page_id.0);
+    header.set_count(self.entries.len() as u16);
+    unsafe {
+      header.set_overflow(overflow);
+    }
+    page[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+    let elements_start = size_of::<PageHeader>();
+    let mut data_start = elements_start + size_of::<BranchElement>() * self.entries.len();
+    if let Some(hashes) = hashes {
+      let hashes = bytemuck::cast_slice(hashes);
+      page[data_start..data_start + hashes.len()].copy_from_slice(hashes);
+      data_start += hashes.len();
+    }
+    for (index, ((key, _), child)) in self.entries.iter().zip(children).enumerate() {
+      let element_start = elements_start + size_of::<BranchElement>() * index;
+      let element = BranchElement::new(
+        (data_start - element_start) as u32,
+        key.len() as u32,
+        *child,
+      );
+      page[element_start..element_start + size_of::<BranchElement>()]
+        .copy_from_slice(bytemuck::bytes_of(&element));
+      page[data_start..data_start + key.len()].copy_from_slice(key);
+      data_start += key.len();
+    }
+  }
+}
+
+fn branch_element_size(key: &[u8], merkle_hashes: bool) -> usize {
+  let hash_size = if merkle_hashes {
+    size_of::<MerkleHash>()
+  } else {
+    0
+  };
+  size_of::<BranchElement>() + key.len() + hash_size
 }
 
-pub struct WipLeafBuilder<D> {
+type LeafEntry<D> = (LeafData<D>, LeafValue<D>);
+
+pub struct WipLeaf<D> {
   wip_id: WipNodeId,
-  leaf: Option<WipLeaf<D>>,
-  goal_byte_size: usize,
-  current_byte_size: usize,
+  /// The page this node replaces, used as an allocation hint
+  origin: Option<NodePageId>,
+  entries: Vec<LeafEntry<D>>,
 }
 
-impl<D> WipLeafBuilder<D> {
-  pub fn new(wip_id: WipNodeId, goal_page_size: usize) -> Self {
-    WipLeafBuilder {
-      wip_id,
-      leaf: None,
-      goal_byte_size: goal_page_size,
-      current_byte_size: size_of::<PageHeader>(),
+impl<D> WipLeaf<D>
+where
+  D: Deref<Target = [u8]>,
+{
+  fn byte_size(&self) -> usize {
+    size_of::<PageHeader>()
+      + self
+        .entries
+        .iter()
+        .map(|(key, value)| size_of::<LeafElement>() + key.len() + value.len())
+        .sum::<usize>()
+  }
+
+  /// Hashes the leaf the same way [`crate::components::merkle::MerkleTree`] does, reading
+  /// nested buckets' hashes from their values
+  fn merkle_hash(&self) -> crate::Result<MerkleHash, BucketError> {
+    let mut hasher = NodeHasher::leaf();
+    for (key, value) in &self.entries {
+      let decompressed;
+      let bytes: &[u8] = if value.flags.contains(LeafFlag::COMPRESSED) {
+        decompressed = decompress_value(value)?;
+        &decompressed
+      } else {
+        value
+      };
+      let hash = value_hash(value.flags, bytes, |root| {
+        bucket_root_hash(bytes).ok_or(BucketError::PageReadError(root).into())
+      })?;
+      hasher.update(key, hash);
     }
+    Ok(hasher.finish())
   }
 
-  #[inline]
-  fn element_size(&self, key: &[u8], value: &[u8]) -> usize {
-    size_of::<LeafElement>() + key.len() + value.len()
+  fn write_page(&self, page_id: NodePageId, overflow: u32, page: &mut [u8]) {
+    let mut header = PageHeader::init_leaf(page_id.0);
+    header.set_count(self.entries.len() as u16);
+    unsafe {
+      header.set_overflow(overflow);
+    }
+    page[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+    let elements_start = size_of::<PageHeader>();
+    let mut data_start = elements_start + size_of::<LeafElement>() * self.entries.len();
+    for (index, (key, value)) in self.entries.iter().enumerate() {
+      let element_start = elements_start + size_of::<LeafElement>() * index;
+      let element = LeafElement::new(
+        value.flags,
+        (data_start - element_start) as u32,
+        key.len() as u32,
+        value.len() as u32,
+      );
+      page[element_start..element_start + size_of::<LeafElement>()]
+        .copy_from_slice(bytemuck::bytes_of(&element));
+      page[data_start..data_start + key.len()].copy_from_slice(key);
+      data_start += key.len();
+      page[data_start..data_start + value.len()].copy_from_slice(value);
+      data_start += value.len();
+    }
   }
+}
 
-  pub fn can_fit(&self, key: &[u8], value: &[u8]) -> bool {
-    if self.leaf.is_none() {
-      true
-    } else {
-      if self.current_byte_size + self.element_size(key, value) <= self.goal_byte_size {
-        true
-      } else {
-        false
+/// Splits a node's elements into runs that each become a node, mirroring `node.split` in
+/// Go BBolt. A node that fits in a single page is left whole. Otherwise each node is filled
+/// up to `threshold` bytes while keeping at least `MIN_KEYS_PER_PAGE` elements.
+fn split_ranges(element_sizes: &[usize], page_size: usize, threshold: usize) -> Vec<Range<usize>> {
+  let header_size = size_of::<PageHeader>();
+  let len = element_sizes.len();
+  let mut remaining: usize = element_sizes.iter().sum();
+  if len <= MIN_KEYS_PER_PAGE * 2 || header_size + remaining <= page_size {
+    #[allow(clippy::single_range_in_vec_init)]
+    return vec![0..len];
+  }
+  let mut ranges = Vec::new();
+  let mut start = 0;
+  let mut node_size = header_size;
+  for (index, element_size) in element_sizes.iter().enumerate() {
+    if index - start >= MIN_KEYS_PER_PAGE
+      && len - index >= MIN_KEYS_PER_PAGE
+      && node_size + element_size > threshold
+    {
+      ranges.push(start..index);
+      start = index;
+      node_size = header_size;
+      // Whatever is left fits in a page of its own
+      if header_size + remaining <= page_size {
+        break;
       }
     }
+    node_size += element_size;
+    remaining -= element_size;
   }
+  ranges.push(start..len);
+  ranges
+}
 
+/// The elements of a node taken out of the commit to be merged with a sibling, along with the
+/// page it replaces
+enum TakenNode<D> {
+  Branch(Option<NodePageId>, Vec<(SharedData, WipChild)>),
+  Leaf(Option<NodePageId>, Vec<LeafEntry<D>>),
+}
 
-  pub fn build(self) -> Option<WipLeaf<D>> {
-    self.leaf
-  }
+/// The pages a bucket touched by a delta passes through on disk
+enum DirtyNode<B, L> {
+  Branch(B, BTreeMap<usize, DirtyNode<B, L>>),
+  Leaf(L, Vec<(SharedData, ValueDelta)>),
 }
 
-impl<D> WipLeafBuilder<D> where D: Deref<Target = [u8]> {
+impl<B, L> DirtyNode<B, L>
+where
+  B: Clone,
+  L: Clone,
+{
+  fn new(page: &NodePage<B, L>) -> Self {
+    match page {
+      NodePage::Branch(branch) => DirtyNode::Branch(branch.clone(), BTreeMap::new()),
+      NodePage::Leaf(leaf) => DirtyNode::Leaf(leaf.clone(), Vec::new()),
+    }
+  }
 
-  pub fn insert(&mut self, key: SharedData, value: LeafValue<D>) {
-    self.current_byte_size += self.element_size(&key, &value);
-    self.leaf = match self.leaf.take() {
-      None => {
-        let mut entries = BTreeMap::new();
-        entries.insert(key.clone(), value);
-        Some(WipLeaf {
-          wip_id: self.wip_id,
-          first_key: key,
-          entries,
-        })
-      }
-      Some(mut leaf) => {
-        leaf.entries.insert(key, value);
-        Some(leaf)
+  /// Records a delta entry on the leaf at the end of `path`, where `path[0]` is this node
+  fn insert(&mut self, path: &[StackEntry<B, L>], key: SharedData, value_delta: ValueDelta) {
+    match self {
+      DirtyNode::Branch(_, children) => {
+        let child_path = &path[1..];
+        children
+          .entry(path[0].index())
+          .or_insert_with(|| DirtyNode::new(child_path[0].page()))
+          .insert(child_path, key, value_delta);
       }
+      DirtyNode::Leaf(_, delta) => delta.push((key, value_delta)),
     }
   }
 }
 
+pub struct WipPages {
+  pub root: NodePageId,
+  /// The hash of the bucket's tree when the commit stores Merkle hashes
+  pub root_hash: Option<MerkleHash>,
+  pub pages: Vec<(NodePageId, SharedBytes)>,
+  /// The on-disk pages replaced by this commit
+  pub freed: Vec<(NodePageId, u32)>,
+}
+
 pub struct WipCommit<'tx, TX: TheTx<'tx>> {
-  wip_leaves: BTreeMap<SharedData, WipLeaf<<TX::TxPageType as GetKvTxSlice<'tx>>::KvTx>>,
-  tx_type: PhantomData<&'tx TX>,
+  tx: sync::Arc<TX>,
+  page_size: usize,
+  /// Whether each node ends in a checksum footer
+  page_checksums: bool,
+  /// Whether branches store their children's Merkle hashes
+  merkle_hashes: bool,
+  /// The bytes of a page left for elements once the footer of a single page is reserved
+  split_size: usize,
+  fill_threshold: usize,
+  wip_node_generator: WipNodeGenerator,
+  wip_branches: BTreeMap<WipNodeId, WipBranch>,
+  wip_leaves: BTreeMap<WipNodeId, WipLeaf<<TX::LeafType as HasKeys<'tx>>::TxKv>>,
+  root: Option<WipChild>,
+  freed: Vec<(NodePageId, u32)>,
+  /// The stored hashes of the on-disk children kept by rewritten branches
+  on_disk_hashes: HashMap<NodePageId, MerkleHash>,
 }
 
-impl<'tx, TX: TheTx<'tx>> WipCommit<'tx, TX>
+impl<'tx, TX> WipCommit<'tx, TX>
+where
+  TX: TheMutTx<'tx>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
 {
-  pub fn from_new_index<F>(goal_page_size: usize,
-    mut delta: BTreeMap<SharedData, ValueDelta>) {
-    let mut wip_node_generator = WipNodeGenerator::new();
-    let root_node = wip_node_generator.root();
-    let mut child_parent_map = HashMap::new();
-    let mut wip_leaves = BTreeMap::new();
-
-    let mut leaf_builder = WipLeafBuilder::new(wip_node_generator.gen_next(), goal_page_size);
+  fn new(
+    tx: sync::Arc<TX>, page_size: usize, fill_percent: f64, page_checksums: bool,
+    merkle_hashes: bool,
+  ) -> Self {
+    let split_size = if page_checksums {
+      page_size - page_footer_len(0)
+    } else {
+      page_size
+    };
+    WipCommit {
+      tx,
+      page_size,
+      page_checksums,
+      merkle_hashes,
+      split_size,
+      fill_threshold: (split_size as f64 * fill_percent) as usize,
+      wip_node_generator: WipNodeGenerator::new(),
+      wip_branches: BTreeMap::new(),
+      wip_leaves: BTreeMap::new(),
+      root: None,
+      freed: Vec::new(),
+      on_disk_hashes: HashMap::new(),
+    }
+  }
+
+  /// Builds a bucket that has no pages on disk from its delta alone
+  pub fn from_new_index(
+    tx: sync::Arc<TX>, page_size: usize, fill_percent: f64, page_checksums: bool,
+    merkle_hashes: bool, delta: BTreeMap<SharedData, ValueDelta>,
+  ) -> Self {
+    let mut commit = WipCommit::new(tx, page_size, fill_percent, page_checksums, merkle_hashes);
+    let mut entries = Vec::with_capacity(delta.len());
     for (key, value_delta) in delta {
-      let (is_bucket, value) = match value_delta {
-        ValueDelta::UValue(value) => (false, value),
-        ValueDelta::UBucket(bucket) => (true, bucket),
-        ValueDelta::Delete => continue,
+      let Some(value) = LeafValue::from_delta(value_delta) else {
+        continue;
       };
-      if !leaf_builder.can_fit(&key, &value) {
-        let leaf = leaf_builder.build().expect("Leaf builder building failed");
-        child_parent_map.insert(leaf.wip_id, root_node);
-        wip_leaves.insert(leaf.first_key.clone(), leaf);
-        leaf_builder = WipLeafBuilder::new(wip_node_generator.gen_next(), goal_page_size);
-      }
-      leaf_builder.insert(key, LeafValue { data: LeafData::Upsert(value), is_bucket });
-    }
-    if let Some(leaf) = leaf_builder.build() {
-      child_parent_map.insert(leaf.wip_id, root_node);
-      wip_leaves.insert(leaf.first_key.clone(), leaf);
+      entries.push((LeafData::Upsert(key), value));
     }
+    let level = commit.split_leaves(None, entries);
+    commit.root = Some(commit.build_root(level));
+    commit
+  }
 
-    if wip_leaves.is_empty() {
-      todo!("We should never have gotten here!")
-    } else if wip_leaves.len() == 1 {
-      todo!("The leaf is root!")
-    } else {
-      todo!("Build the tree")
-    }
+  /// Builds a bucket stored inline from its root `leaf` and `delta`. The leaf owns no pages, so
+  /// nothing is freed and the bucket is written to pages of its own.
+  pub fn from_inline_bucket(
+    tx: sync::Arc<TX>, leaf: &TX::LeafType, delta: BTreeMap<SharedData, ValueDelta>,
+    page_size: usize, fill_percent: f64, page_checksums: bool, merkle_hashes: bool,
+  ) -> crate::Result<Self, CommitError> {
+    let mut commit = WipCommit::new(tx, page_size, fill_percent, page_checksums, merkle_hashes);
+    let entries = commit.merge_leaf(leaf, delta.into_iter().collect())?;
+    let level = commit.split_leaves(None, entries);
+    commit.root = Some(commit.build_root(level));
+    Ok(commit)
   }
 
+  /// Copy-on-write merges `delta` into an existing bucket. Only the pages on the path from the
+  /// root to each touched leaf are rewritten, along with the siblings underfilled nodes are
+  /// merged with; untouched subtrees are shared with the old tree.
   pub fn upsert_bucket<F>(
-    mut bucket_cursor: CoreCursor<TX::BranchType, TX::LeafType, TX>,
-    mut delta: BTreeMap<SharedData, ValueDelta>, seek: F,
-  ) -> crate::Result<Self, CursorError>
+    mut bucket_cursor: CoreCursor<'tx, TX::BranchType, TX::LeafType, TX>,
+    delta: BTreeMap<SharedData, ValueDelta>, page_size: usize, fill_percent: f64,
+    page_checksums: bool, merkle_hashes: bool, mut seek: F,
+  ) -> crate::Result<Self, CommitError>
   where
     F: FnMut(
-      &mut CoreCursor<TX::BranchType, TX::LeafType, TX>,
+      &mut CoreCursor<'tx, TX::BranchType, TX::LeafType, TX>,
       &[u8],
     ) -> crate::Result<Option<LeafFlag>, CursorError>,
   {
-    let mut wip_node_generator = WipNodeGenerator::new();
-    let wip_nodes = HashMap::new();
-    let wip_leaves = BTreeMap::new();
-    let tx_slot = TxSlot::default();
-
-    // TODO: Don't die here
-    let (last_key, _) = delta.last_key_value().unwrap();
-    let seek_r = seek(&mut bucket_cursor, last_key)?;
-    match seek_r {
-      None => {todo!("all new bucket!")}
-      Some(_) => {todo!("existing bucket!")}
+    let mut dirty_root = None;
+    for (key, value_delta) in delta {
+      seek(&mut bucket_cursor, &key).change_context(CommitError::Seek)?;
+      let stack = bucket_cursor.stack();
+      dirty_root
+        .get_or_insert_with(|| DirtyNode::new(stack[0].page()))
+        .insert(stack, key, value_delta);
+    }
+    let mut commit = WipCommit::new(
+      bucket_cursor.tx().clone(),
+      page_size,
+      fill_percent,
+      page_checksums,
+      merkle_hashes,
+    );
+    let root = match dirty_root {
+      Some(dirty_root) => {
+        let level = commit.rewrite_node(dirty_root)?;
+        commit.build_root(level)
+      }
+      None => WipChild::OnDisk(NodePageId(bucket_cursor.root().page_header().id())),
+    };
+    commit.root = Some(root);
+    Ok(commit)
+  }
+
+  fn free_page<P: Page>(&mut self, page: &P) {
+    let header = page.page_header();
+    self
+      .freed
+      .push((NodePageId(header.id()), header.get_overflow()));
+  }
+
+  /// Rewrites a dirty node, returning the (first key, node) entries that replace it in its parent
+  fn rewrite_node(
+    &mut self, node: DirtyNode<TX::BranchType, TX::LeafType>,
+  ) -> crate::Result<Vec<(SharedData, WipChild)>, CommitError> {
+    match node {
+      DirtyNode::Branch(branch, mut children) => {
+        self.free_page(&branch);
+        let origin = NodePageId(branch.page_header().id());
+        let mut entries = Vec::with_capacity(branch.element_count());
+        let mut underfilled = Vec::new();
+        for index in 0..branch.element_count() {
+          match children.remove(&index) {
+            Some(child) => {
+              let rewritten = self.rewrite_node(child)?;
+              // A child that was split is already filled, so only whole children are merged
+              if let [(_, WipChild::Wip(wip_id))] = rewritten.as_slice() {
+                if self.is_underfilled(*wip_id) {
+                  underfilled.push(*wip_id);
+                }
+              }
+              entries.extend(rewritten);
+            }
+            None => entries.push(self.on_disk_child(&branch, index)?),
+          }
+        }
+        let entries = self.rebalance(entries, &underfilled)?;
+        Ok(self.split_branches(Some(origin), entries))
+      }
+      DirtyNode::Leaf(leaf, delta) => {
+        self.free_page(&leaf);
+        let origin = NodePageId(leaf.page_header().id());
+        let entries = self.merge_leaf(&leaf, delta)?;
+        Ok(self.split_leaves(Some(origin), entries))
+      }
     }
   }
+
+  /// The element of an on-disk `branch` at `index`, kept as it is
+  fn on_disk_child(
+    &mut self, branch: &TX::BranchType, index: usize,
+  ) -> crate::Result<(SharedData, WipChild), CommitError> {
+    let origin = NodePageId(branch.page_header().id());
+    let (Some(key), Some(child)) = (branch.key(index), branch.node(index)) else {
+      return Err(CommitError::Element(origin, index).into());
+    };
+    if self.merkle_hashes {
+      let count = branch.element_count();
+      let hash = branch
+        .root_page()
+        .get(branch_hash_range(count, index))
+        .map(bytemuck::pod_read_unaligned)
+        .ok_or(CommitError::Element(origin, index))?;
+      self.on_disk_hashes.insert(child, hash);
+    }
+    Ok((self.tx.clone_key(&key), WipChild::OnDisk(child)))
+  }
+
+  /// Whether a rewritten node fills less than a quarter of a page or holds too few elements to
+  /// stand on its own, `node.rebalance` in Go BBolt
+  fn is_underfilled(&self, wip_id: WipNodeId) -> bool {
+    let threshold = self.page_size / 4;
+    match (self.wip_leaves.get(&wip_id), self.wip_branches.get(&wip_id)) {
+      (Some(leaf), _) => leaf.byte_size() < threshold || leaf.entries.len() < MIN_LEAF_KEYS,
+      (None, Some(branch)) => {
+        branch.byte_size(self.merkle_hashes) < threshold || branch.entries.len() < MIN_BRANCH_KEYS
+      }
+      (None, None) => false,
+    }
+  }
+
+  /// Merges each `underfilled` child of a branch with a sibling, the next one for the first
+  /// child and the previous one otherwise, and splits the result again. `node.rebalance` in
+  /// Go BBolt.
+  fn rebalance(
+    &mut self, mut entries: Vec<(SharedData, WipChild)>, underfilled: &[WipNodeId],
+  ) -> crate::Result<Vec<(SharedData, WipChild)>, CommitError> {
+    let mut index = 0;
+    while index < entries.len() {
+      let is_underfilled = match entries[index].1 {
+        WipChild::Wip(wip_id) => underfilled.contains(&wip_id),
+        WipChild::OnDisk(_) => false,
+      };
+      if !is_underfilled || entries.len() < 2 {
+        index += 1;
+        continue;
+      }
+      let left = index.saturating_sub(1);
+      let pair: Vec<_> = entries.drain(left..left + 2).collect();
+      let merged = self.merge_children(pair[0].1, pair[1].1)?;
+      self.tx.stats().inc_rebalance(1);
+      index = left + merged.len();
+      entries.splice(left..left, merged);
+    }
+    Ok(entries)
+  }
+
+  /// Merges sibling nodes `left` and `right` into as few nodes as they fit in
+  fn merge_children(
+    &mut self, left: WipChild, right: WipChild,
+  ) -> crate::Result<Vec<(SharedData, WipChild)>, CommitError> {
+    match (self.take_node(left)?, self.take_node(right)?) {
+      (TakenNode::Leaf(left_origin, mut entries), TakenNode::Leaf(right_origin, right)) => {
+        entries.extend(right);
+        Ok(self.split_leaves(left_origin.or(right_origin), entries))
+      }
+      (TakenNode::Branch(left_origin, mut entries), TakenNode::Branch(right_origin, right)) => {
+        entries.extend(right);
+        Ok(self.split_branches(left_origin.or(right_origin), entries))
+      }
+      // Siblings are always at the same depth
+      _ => Err(CommitError::Failure.into()),
+    }
+  }
+
+  /// Takes the elements of `child` out of the commit, freeing its page if it's on disk
+  fn take_node(
+    &mut self, child: WipChild,
+  ) -> crate::Result<TakenNode<<TX::LeafType as HasKeys<'tx>>::TxKv>, CommitError> {
+    let page_id = match child {
+      WipChild::Wip(wip_id) => {
+        if let Some(leaf) = self.wip_leaves.remove(&wip_id) {
+          return Ok(TakenNode::Leaf(leaf.origin, leaf.entries));
+        }
+        let branch = self
+          .wip_branches
+          .remove(&wip_id)
+          .expect("every wip node is a leaf or a branch");
+        return Ok(TakenNode::Branch(branch.origin, branch.entries));
+      }
+      WipChild::OnDisk(page_id) => page_id,
+    };
+    let page = self
+      .tx
+      .read_node_page(page_id)
+      .change_context(CommitError::Page(page_id))?;
+    match page {
+      NodePage::Branch(branch) => {
+        self.free_page(&branch);
+        let entries = (0..branch.element_count())
+          .map(|index| self.on_disk_child(&branch, index))
+          .collect::<crate::Result<_, _>>()?;
+        Ok(TakenNode::Branch(Some(page_id), entries))
+      }
+      NodePage::Leaf(leaf) => {
+        self.free_page(&leaf);
+        let entries = self.merge_leaf(&leaf, Vec::new())?;
+        Ok(TakenNode::Leaf(Some(page_id), entries))
+      }
+    }
+  }
+
+  /// Merges `delta` into the elements of `leaf`, in key order
+  fn merge_leaf(
+    &self, leaf: &TX::LeafType, delta: Vec<(SharedData, ValueDelta)>,
+  ) -> crate::Result<Vec<LeafEntry<<TX::LeafType as HasKeys<'tx>>::TxKv>>, CommitError> {
+    let origin = NodePageId(leaf.page_header().id());
+    let mut entries = Vec::with_capacity(leaf.element_count() + delta.len());
+    let mut on_disk = (0..leaf.element_count()).peekable();
+    for (key, value_delta) in delta {
+      // Keep the on-disk entries before `key`, dropping the one it replaces
+      while let Some(&index) = on_disk.peek() {
+        let (Some((disk_key, disk_value)), Some(flags)) =
+          (leaf.key_value(index), leaf.leaf_flag(index))
+        else {
+          return Err(CommitError::Element(origin, index).into());
+        };
+        match disk_key.deref().cmp(&key) {
+          Ordering::Less => {
+            let value = self.on_disk_value(disk_value, flags);
+            entries.push((LeafData::OnDisk(disk_key), value));
+            on_disk.next();
+          }
+          Ordering::Equal => {
+            on_disk.next();
+            break;
+          }
+          Ordering::Greater => break,
+        }
+      }
+      let Some(value) = LeafValue::from_delta(value_delta) else {
+        continue;
+      };
+      entries.push((LeafData::Upsert(key), value));
+    }
+    for index in on_disk {
+      let (Some((disk_key, disk_value)), Some(flags)) =
+        (leaf.key_value(index), leaf.leaf_flag(index))
+      else {
+        return Err(CommitError::Element(origin, index).into());
+      };
+      let value = self.on_disk_value(disk_value, flags);
+      entries.push((LeafData::OnDisk(disk_key), value));
+    }
+    Ok(entries)
+  }
+
+  /// A value kept from a rewritten leaf. Leaves are read with their values decompressed, so
+  /// compressed values are compressed again.
+  fn on_disk_value(
+    &self, value: <TX::LeafType as HasKeys<'tx>>::TxKv, flags: LeafFlag,
+  ) -> LeafValue<<TX::LeafType as HasKeys<'tx>>::TxKv> {
+    if !flags.contains(LeafFlag::COMPRESSED) {
+      return LeafValue {
+        data: LeafData::OnDisk(value),
+        flags,
+      };
+    }
+    match compress_value(&value, 0) {
+      Some(compressed) => LeafValue {
+        data: LeafData::Upsert(self.tx.clone_value(&compressed)),
+        flags,
+      },
+      None => LeafValue {
+        data: LeafData::OnDisk(value),
+        flags: flags - LeafFlag::COMPRESSED,
+      },
+    }
+  }
+
+  fn split_leaves(
+    &mut self, origin: Option<NodePageId>,
+    mut entries: Vec<LeafEntry<<TX::LeafType as HasKeys<'tx>>::TxKv>>,
+  ) -> Vec<(SharedData, WipChild)> {
+    if entries.is_empty() {
+      return Vec::new();
+    }
+    let element_sizes: Vec<usize> = entries
+      .iter()
+      .map(|(key, value)| size_of::<LeafElement>() + key.len() + value.len())
+      .collect();
+    let ranges = split_ranges(&element_sizes, self.split_size, self.fill_threshold);
+    self.tx.stats().inc_split(ranges.len() as i64 - 1);
+    let mut nodes = Vec::with_capacity(ranges.len());
+    for range in ranges.into_iter().rev() {
+      let leaf_entries = entries.split_off(range.start);
+      let wip_id = self.wip_node_generator.gen_next();
+      let first_key = self.tx.clone_key(&leaf_entries[0].0);
+      let leaf = WipLeaf {
+        wip_id,
+        origin,
+        entries: leaf_entries,
+      };
+      self.wip_leaves.insert(wip_id, leaf);
+      nodes.push((first_key, WipChild::Wip(wip_id)));
+    }
+    nodes.reverse();
+    nodes
+  }
+
+  fn split_branches(
+    &mut self, origin: Option<NodePageId>, mut entries: Vec<(SharedData, WipChild)>,
+  ) -> Vec<(SharedData, WipChild)> {
+    if entries.is_empty() {
+      return Vec::new();
+    }
+    let element_sizes: Vec<usize> = entries
+      .iter()
+      .map(|(key, _)| branch_element_size(key, self.merkle_hashes))
+      .collect();
+    let ranges = split_ranges(&element_sizes, self.split_size, self.fill_threshold);
+    self.tx.stats().inc_split(ranges.len() as i64 - 1);
+    let mut nodes = Vec::with_capacity(ranges.len());
+    for range in ranges.into_iter().rev() {
+      let branch_entries = entries.split_off(range.start);
+      let wip_id = self.wip_node_generator.gen_next();
+      let first_key = branch_entries[0].0.clone();
+      let branch = WipBranch {
+        wip_id,
+        origin,
+        entries: branch_entries,
+      };
+      self.wip_branches.insert(wip_id, branch);
+      nodes.push((first_key, WipChild::Wip(wip_id)));
+    }
+    nodes.reverse();
+    nodes
+  }
+
+  /// Stacks branches on top of `level` until a single root remains
+  fn build_root(&mut self, mut level: Vec<(SharedData, WipChild)>) -> WipChild {
+    loop {
+      match level.len() {
+        0 => {
+          let wip_id = self.wip_node_generator.gen_next();
+          let leaf = WipLeaf {
+            wip_id,
+            origin: None,
+            entries: Vec::new(),
+          };
+          self.wip_leaves.insert(wip_id, leaf);
+          return WipChild::Wip(wip_id);
+        }
+        1 => break,
+        _ => level = self.split_branches(None, level),
+      }
+    }
+    let (_, mut root) = level.pop().expect("level has a single node");
+    // Deletes can leave a branch with a single child. Promote the child in its place.
+    while let WipChild::Wip(wip_id) = root {
+      match self.wip_branches.get(&wip_id) {
+        Some(branch) if branch.entries.len() == 1 => {
+          let child = branch.entries[0].1;
+          self.wip_branches.remove(&wip_id);
+          root = child;
+        }
+        _ => break,
+      }
+    }
+    root
+  }
+
+  /// Assigns page ids to every node through `allocator` and serializes them, sealing each with
+  /// its checksum footer when the commit has them. Merkle hashes are computed children first,
+  /// alongside the pages.
+  pub fn write_pages<A: NodeAllocator>(
+    self, allocator: &mut A, buffer_pool: &BufferPool,
+  ) -> crate::Result<WipPages, CommitError> {
+    let page_size = self.page_size;
+    let merkle_hashes = self.merkle_hashes;
+    let page_checksums = self.page_checksums;
+    let seal = |page: &mut [u8]| {
+      if page_checksums {
+        write_page_checksums(page, page_size);
+      }
+    };
+    // Every origin was freed when its node was rewritten
+    let origin_lens: HashMap<NodePageId, u64> = self
+      .freed
+      .iter()
+      .map(|(page_id, overflow)| (*page_id, *overflow as u64 + 1))
+      .collect();
+    let mut assign_page = |origin: Option<NodePageId>, page_count: usize| {
+      let origin = origin.map(|origin| (origin, origin_lens.get(&origin).copied().unwrap_or(0)));
+      allocator.allocate_node(origin, page_count as u64)
+    };
+    let mut assigned = HashMap::with_capacity(self.wip_leaves.len() + self.wip_branches.len());
+    let mut hashes = HashMap::new();
+    let mut pages = Vec::with_capacity(self.wip_leaves.len() + self.wip_branches.len());
+    for (wip_id, leaf) in &self.wip_leaves {
+      let page_count = page_count(leaf.byte_size(), page_size, page_checksums);
+      let page_id = assign_page(leaf.origin, page_count);
+      if merkle_hashes {
+        let hash = leaf
+          .merkle_hash()
+          .change_context(CommitError::Hash(page_id))?;
+        hashes.insert(*wip_id, hash);
+      }
+      let overflow = (page_count - 1) as u32;
+      let bytes = buffer_pool
+        .pop_with_len(page_count * page_size)
+        .fill_and_share(|page| {
+          leaf.write_page(page_id, overflow, page);
+          seal(page);
+        });
+      assigned.insert(*wip_id, page_id);
+      pages.push((page_id, bytes));
+    }
+    // Branches are always created after their children, so walking them in id order
+    // guarantees every child already has a page
+    let child_id = |assigned: &HashMap<WipNodeId, NodePageId>, child: WipChild| match child {
+      WipChild::Wip(wip_id) => assigned[&wip_id],
+      WipChild::OnDisk(page_id) => page_id,
+    };
+    let child_hash = |hashes: &HashMap<WipNodeId, MerkleHash>, child: WipChild| match child {
+      WipChild::Wip(wip_id) => hashes.get(&wip_id).copied(),
+      WipChild::OnDisk(page_id) => self.on_disk_hashes.get(&page_id).copied(),
+    };
+    for (wip_id, branch) in &self.wip_branches {
+      let page_count = page_count(branch.byte_size(merkle_hashes), page_size, page_checksums);
+      let page_id = assign_page(branch.origin, page_count);
+      let overflow = (page_count - 1) as u32;
+      let children: Vec<NodePageId> = branch
+        .entries
+        .iter()
+        .map(|(_, child)| child_id(&assigned, *child))
+        .collect();
+      let child_hashes = if merkle_hashes {
+        let child_hashes = branch
+          .entries
+          .iter()
+          .map(|(_, child)| child_hash(&hashes, *child))
+          .collect::<Option<Vec<MerkleHash>>>()
+          .ok_or(CommitError::Hash(page_id))?;
+        let mut hasher = NodeHasher::branch();
+        for ((key, _), hash) in branch.entries.iter().zip(&child_hashes) {
+          hasher.update(key, *hash);
+        }
+        hashes.insert(*wip_id, hasher.finish());
+        Some(child_hashes)
+      } else {
+        None
+      };
+      let bytes = buffer_pool
+        .pop_with_len(page_count * page_size)
+        .fill_and_share(|page| {
+          branch.write_page(page_id, overflow, &children, child_hashes.as_deref(), page);
+          seal(page);
+        });
+      assigned.insert(*wip_id, page_id);
+      pages.push((page_id, bytes));
+    }
+    self.tx.stats().inc_spill(pages.len() as i64);
+    let root_child = self.root.expect("root is always built");
+    let root = child_id(&assigned, root_child);
+    let root_hash = match (merkle_hashes, child_hash(&hashes, root_child)) {
+      (false, _) => None,
+      (true, Some(hash)) => Some(hash),
+      // The root's pages were kept whole
+      (true, None) => {
+        Some(stored_page_hash(&self.tx, root).change_context(CommitError::Hash(root))?)
+      }
+    };
+    Ok(WipPages {
+      root,
+      root_hash,
+      pages,
+      freed: self.freed,
+    })
+  }
+}
+
+/// The pages produced by committing every bucket delta in a transaction
+pub struct TxCommit {
+  pub root: BucketHeader,
+  /// The hash of the root bucket's tree when the commit stores Merkle hashes
+  pub root_hash: Option<MerkleHash>,
+  pub pages: Vec<(NodePageId, SharedBytes)>,
+  /// The on-disk pages no longer reachable from `root`
+  pub freed: Vec<(NodePageId, u32)>,
+}
+
+/// Commits every bucket delta in `tx`. Buckets are written deepest first so each bucket's new
+/// header is folded into its parent's delta before the parent is written. With
+/// `page_checksums` every page ends in a checksum footer. With `merkle_hashes` branches store
+/// their children's hashes and each nested bucket's value stores its root's hash after its header.
+pub fn commit_tx<'tx, TX, A>(
+  tx: &sync::Arc<MutTxHandle<TX>>, allocator: &mut A, buffer_pool: &BufferPool, fill_percent: f64,
+  page_checksums: bool, merkle_hashes: bool,
+) -> crate::Result<TxCommit, CommitError>
+where
+  TX: TheTx<'tx>,
+  A: NodeAllocator,
+  for<'b> <TX::BranchType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
+  for<'b> <TX::LeafType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]> + AsRef<[u8]>,
+{
+  let page_size = buffer_pool.page_size();
+  let deltas = tx.take_deltas();
+  let mut paths: Vec<&BucketPathBuf> = deltas.keys().collect();
+  paths.sort_by_key(|path| Reverse(path.len()));
+
+  // The hash of a bucket whose pages are kept
+  let stored_hash = |root: BucketPageId| {
+    if !merkle_hashes {
+      return Ok(None);
+    }
+    let root = NodePageId::from(root);
+    stored_page_hash(tx, root)
+      .map(Some)
+      .change_context(CommitError::Hash(root))
+  };
+  let mut root = None;
+  let mut root_hash = None;
+  let mut pages = Vec::new();
+  let mut freed = tx.take_freed();
+  for path in paths {
+    let bucket_delta = &deltas[path];
+    let delta = bucket_delta.take();
+    let sequence = bucket_delta.sequence();
+    let unchanged = delta.is_empty() && !bucket_delta.compression_changed();
+    let wip = match bucket_delta.on_disk() {
+      Some(header) if unchanged && header.sequence() == sequence => {
+        if path.is_empty() {
+          root = Some(header);
+          root_hash = stored_hash(header.root())?;
+        }
+        continue;
+      }
+      // An inline bucket owns no pages, so it's written out whole
+      Some(header) if header.root().0.0 == 0 => {
+        let bucket = tx
+          .on_disk_bucket(path, header)
+          .change_context_lazy(|| CommitError::Bucket(path.clone()))?;
+        let NodePage::Leaf(leaf) = &bucket.root else {
+          return Err(CommitError::Bucket(path.clone()).into());
+        };
+        Some(WipCommit::from_inline_bucket(
+          tx.clone(),
+          leaf,
+          delta,
+          page_size,
+          fill_percent,
+          page_checksums,
+          merkle_hashes,
+        )?)
+      }
+      // Only the sequence or compression threshold changed, so the pages are kept
+      Some(_) if delta.is_empty() => None,
+      Some(header) => {
+        let bucket = tx
+          .on_disk_bucket(path, header)
+          .change_context_lazy(|| CommitError::Bucket(path.clone()))?;
+        let cursor = CoreCursor::new_with_stack(&bucket, bucket.stack_pool.pop());
+        Some(WipCommit::upsert_bucket(
+          cursor,
+          delta,
+          page_size,
+          fill_percent,
+          page_checksums,
+          merkle_hashes,
+          |c, key| c.seek(key),
+        )?)
+      }
+      None => Some(WipCommit::from_new_index(
+        tx.clone(),
+        page_size,
+        fill_percent,
+        page_checksums,
+        merkle_hashes,
+        delta,
+      )),
+    };
+    let (bucket_root, bucket_hash) = match wip {
+      Some(wip) => {
+        let wip_pages = wip.write_pages(allocator, buffer_pool)?;
+        pages.extend(wip_pages.pages);
+        freed.extend(wip_pages.freed);
+        (BucketPageId(wip_pages.root.0), wip_pages.root_hash)
+      }
+      None => {
+        let bucket_root = bucket_delta.on_disk().expect("matched above").root();
+        (bucket_root, stored_hash(bucket_root)?)
+      }
+    };
+    let header = BucketHeader::new(bucket_root, sequence);
+    match path.last() {
+      Some(key) => {
+        let mut parent_path = path.clone();
+        parent_path.pop();
+        let parent = deltas
+          .get(&parent_path)
+          .expect("parent buckets always have a delta");
+        let key = tx.clone_key(key);
+        let mut value = bytemuck::bytes_of(&header).to_vec();
+        if let Some(hash) = bucket_hash {
+          value.extend_from_slice(bytemuck::bytes_of(&hash));
+        }
+        let header = tx.clone_value(&value);
+        let flags = LeafFlag::BUCKET.with_compression_threshold(bucket_delta.compression());
+        parent
+          .lock()
+          .insert(key, ValueDelta::UBucket(header, flags));
+      }
+      None => {
+        root = Some(header);
+        root_hash = bucket_hash;
+      }
+    }
+  }
+  Ok(TxCommit {
+    root: root.expect("the root bucket always has a delta"),
+    root_hash,
+    pages,
+    freed,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::split_ranges;
+
+  #[test]
+  fn test_split_ranges() {
+    // Everything fits in a single page
+    assert_eq!(vec![0..10], split_ranges(&[100; 10], 4096, 2048));
+    // Too few elements to split
+    assert_eq!(vec![0..4], split_ranges(&[2000; 4], 4096, 2048));
+    // The remainder is left whole once it fits in a page
+    assert_eq!(vec![0..2, 2..4, 4..8], split_ranges(&[1000; 8], 4096, 2048));
+  }
 }
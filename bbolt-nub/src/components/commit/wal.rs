@@ -1 +1,495 @@
+use crate::common::errors::IOError;
+use crate::common::id::{DiskPageId, TxId};
+use crate::io::bytes::shared_bytes::SharedBytes;
+use bytemuck::{Pod, Zeroable};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use error_stack::ResultExt;
+use fnv_rs::{Fnv64, FnvHasher};
+use parking_lot::Mutex;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{BufReader, ErrorKind, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use thiserror::Error;
 
+const WAL_MAGIC: u32 = 0x5caff01d;
+const WAL_VERSION: u32 = 1;
+const WAL_HEADER_LEN: u64 = size_of::<WalHeader>() as u64;
+/// Marks the frame that carries a transaction's meta page
+const FRAME_COMMIT: u32 = 0x01;
+
+#[derive(Debug, Error)]
+pub enum WalError {
+  #[error("WAL Error: Unable to open log at `{0:?}`")]
+  OpenFailure(PathBuf),
+  #[error("WAL Error: Unable to read log")]
+  ReadFailure,
+  #[error("WAL Error: Unable to append transaction `{0:?}`")]
+  AppendFailure(TxId),
+  #[error("WAL Error: Unable to apply transaction `{0:?}`")]
+  ApplyFailure(TxId),
+  #[error("WAL Error: Unable to sync")]
+  SyncFailure,
+  #[error("WAL Error: Unable to checkpoint")]
+  CheckpointFailure,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+  /// Checkpoint once the log grows past this many bytes
+  checkpoint_size: u64,
+  /// Checkpoint at least this often while the log has frames
+  checkpoint_interval: Duration,
+}
+
+impl WalOptions {
+  pub fn new(checkpoint_size: u64, checkpoint_interval: Duration) -> Self {
+    Self {
+      checkpoint_size,
+      checkpoint_interval,
+    }
+  }
+}
+
+impl Default for WalOptions {
+  fn default() -> Self {
+    Self::new(4 << 20, Duration::from_secs(1))
+  }
+}
+
+/// The path of the log kept alongside the database at `path`
+pub fn wal_path(path: &Path) -> PathBuf {
+  let mut wal_path = OsString::from(path.as_os_str());
+  wal_path.push("-wal");
+  wal_path.into()
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
+struct WalHeader {
+  magic: u32,
+  version: u32,
+  /// Frames written before the log was last recycled carry an older salt
+  salt: u64,
+  checksum: u64,
+}
+
+impl WalHeader {
+  fn new(salt: u64) -> Self {
+    let mut header = WalHeader {
+      magic: WAL_MAGIC,
+      version: WAL_VERSION,
+      salt,
+      checksum: 0,
+    };
+    header.checksum = header.sum64();
+    header
+  }
+
+  fn sum64(&self) -> u64 {
+    let mut h = Fnv64::new();
+    h.update(&bytemuck::bytes_of(self)[0..size_of::<WalHeader>() - size_of::<u64>()]);
+    h.finish()
+  }
+
+  fn is_valid(&self) -> bool {
+    self.magic == WAL_MAGIC && self.version == WAL_VERSION && self.checksum == self.sum64()
+  }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Pod, Zeroable)]
+struct WalFrameHeader {
+  salt: u64,
+  tx_id: TxId,
+  /// Where the frame's data belongs in the database file
+  offset: u64,
+  len: u64,
+  flags: u32,
+  _reserved: u32,
+  checksum: u64,
+}
+
+impl WalFrameHeader {
+  fn sum64(&self, data: &[u8]) -> u64 {
+    let mut h = Fnv64::new();
+    h.update(&bytemuck::bytes_of(self)[0..size_of::<WalFrameHeader>() - size_of::<u64>()]);
+    h.update(data);
+    h.finish()
+  }
+
+  #[inline]
+  fn is_commit(&self) -> bool {
+    self.flags & FRAME_COMMIT != 0
+  }
+}
+
+/// The sidecar log file.
+///
+/// Each commit appends one frame per dirty page followed by a commit frame holding the meta page.
+/// Only the log is synced when committing. Checkpointing syncs the database file and recycles
+/// the log by bumping its salt, so stale frames are never replayed.
+pub struct WriteAheadLog {
+  path: PathBuf,
+  file: File,
+  salt: u64,
+  /// Where the next frame is appended
+  offset: u64,
+}
+
+impl WriteAheadLog {
+  /// Opens, or creates, the log at `path`. Replays committed transactions into `db_file`, then
+  /// recycles the log. Returns the log and the number of transactions replayed.
+  pub fn open(path: PathBuf, db_file: &File) -> crate::Result<(Self, usize), WalError> {
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)
+      .change_context_lazy(|| WalError::OpenFailure(path.clone()))?;
+    let mut header = WalHeader::default();
+    let header_valid = match file.read_exact_at(bytemuck::bytes_of_mut(&mut header), 0) {
+      Ok(_) => header.is_valid(),
+      Err(err) if err.kind() == ErrorKind::UnexpectedEof => false,
+      Err(err) => return Err(err).change_context(WalError::OpenFailure(path)),
+    };
+    let mut wal = WriteAheadLog {
+      path,
+      file,
+      salt: header.salt,
+      offset: WAL_HEADER_LEN,
+    };
+    let replayed = if header_valid {
+      wal.replay(db_file)?
+    } else {
+      // Without a valid header stale frames can't be told apart, so drop them all
+      wal
+        .file
+        .set_len(0)
+        .change_context_lazy(|| WalError::OpenFailure(wal.path.clone()))?;
+      0
+    };
+    if replayed > 0 {
+      db_file.sync_all().change_context(WalError::SyncFailure)?;
+    }
+    wal.recycle()?;
+    Ok((wal, replayed))
+  }
+
+  /// Whether the log at `path` has frames that have not been checkpointed
+  pub fn is_pending(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+      return false;
+    };
+    let mut header = WalHeader::default();
+    let mut frame = WalFrameHeader::default();
+    file
+      .read_exact_at(bytemuck::bytes_of_mut(&mut header), 0)
+      .is_ok()
+      && header.is_valid()
+      && file
+        .read_exact_at(bytemuck::bytes_of_mut(&mut frame), WAL_HEADER_LEN)
+        .is_ok()
+      && frame.salt == header.salt
+  }
+
+  #[inline]
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// The number of bytes of frames since the last checkpoint
+  #[inline]
+  pub fn frames_len(&self) -> u64 {
+    self.offset - WAL_HEADER_LEN
+  }
+
+  /// Applies every committed transaction to `db_file`, stopping at the first torn or
+  /// corrupt frame
+  fn replay(&mut self, db_file: &File) -> crate::Result<usize, WalError> {
+    let file_len = self
+      .file
+      .metadata()
+      .change_context(WalError::ReadFailure)?
+      .len();
+    let mut reader = BufReader::new(&self.file);
+    let mut skip = [0u8; WAL_HEADER_LEN as usize];
+    reader
+      .read_exact(&mut skip)
+      .change_context(WalError::ReadFailure)?;
+    let mut offset = WAL_HEADER_LEN;
+    let mut pending = Vec::new();
+    let mut replayed = 0;
+    loop {
+      let mut frame = WalFrameHeader::default();
+      if reader
+        .read_exact(bytemuck::bytes_of_mut(&mut frame))
+        .is_err()
+      {
+        break;
+      }
+      offset += size_of::<WalFrameHeader>() as u64;
+      if frame.salt != self.salt || frame.len > file_len - offset {
+        break;
+      }
+      let mut data = vec![0u8; frame.len as usize];
+      if reader.read_exact(&mut data).is_err() || frame.checksum != frame.sum64(&data) {
+        break;
+      }
+      offset += frame.len;
+      let is_commit = frame.is_commit();
+      pending.push((frame.offset, data));
+      if is_commit {
+        for (db_offset, data) in pending.drain(..) {
+          db_file
+            .write_all_at(&data, db_offset)
+            .change_context(WalError::ApplyFailure(frame.tx_id))?;
+        }
+        replayed += 1;
+      }
+    }
+    Ok(replayed)
+  }
+
+  /// Appends a transaction's pages and its commit frame, then syncs the log
+  pub fn append(
+    &mut self, tx_id: TxId, page_size: usize, pages: &[(DiskPageId, SharedBytes)],
+    meta_page: &(DiskPageId, SharedBytes),
+  ) -> crate::Result<(), WalError> {
+    let frames_len: usize = pages
+      .iter()
+      .chain(Some(meta_page))
+      .map(|(_, page)| size_of::<WalFrameHeader>() + page.len())
+      .sum();
+    let mut buffer = Vec::with_capacity(frames_len);
+    for (i, (disk_page_id, page)) in pages.iter().chain(Some(meta_page)).enumerate() {
+      let mut frame = WalFrameHeader {
+        salt: self.salt,
+        tx_id,
+        offset: disk_page_id.0 * page_size as u64,
+        len: page.len() as u64,
+        flags: if i == pages.len() { FRAME_COMMIT } else { 0 },
+        _reserved: 0,
+        checksum: 0,
+      };
+      frame.checksum = frame.sum64(page);
+      buffer.extend_from_slice(bytemuck::bytes_of(&frame));
+      buffer.extend_from_slice(page);
+    }
+    self
+      .file
+      .write_all_at(&buffer, self.offset)
+      .change_context(WalError::AppendFailure(tx_id))?;
+    self
+      .file
+      .sync_data()
+      .change_context(WalError::AppendFailure(tx_id))?;
+    self.offset += buffer.len() as u64;
+    Ok(())
+  }
+
+  /// Syncs `db_file` and recycles the log.
+  ///
+  /// Every transaction in the log must already have been written to `db_file`.
+  pub fn checkpoint(&mut self, db_file: &File) -> crate::Result<(), WalError> {
+    if self.frames_len() == 0 {
+      return Ok(());
+    }
+    db_file
+      .sync_data()
+      .change_context(WalError::CheckpointFailure)?;
+    self.recycle().change_context(WalError::CheckpointFailure)
+  }
+
+  /// Starts a new salt and rewinds to the start of the log, reusing its space
+  fn recycle(&mut self) -> crate::Result<(), WalError> {
+    self.salt = self.salt.wrapping_add(1);
+    let header = WalHeader::new(self.salt);
+    self
+      .file
+      .write_all_at(bytemuck::bytes_of(&header), 0)
+      .change_context(WalError::SyncFailure)?;
+    self
+      .file
+      .sync_data()
+      .change_context(WalError::SyncFailure)?;
+    self.offset = WAL_HEADER_LEN;
+    Ok(())
+  }
+}
+
+enum CheckpointSignal {
+  Checkpoint,
+  Stop,
+}
+
+/// A [`WriteAheadLog`] shared with a background checkpointer
+pub struct Wal {
+  log: sync::Arc<Mutex<WriteAheadLog>>,
+  db_file: sync::Arc<File>,
+  options: WalOptions,
+  signal: Sender<CheckpointSignal>,
+  checkpointer: Option<JoinHandle<()>>,
+}
+
+impl Wal {
+  /// Opens the log for the database file, recovering it first, and starts the checkpointer
+  pub fn open(
+    path: PathBuf, db_file: File, options: WalOptions,
+  ) -> crate::Result<(Self, usize), WalError> {
+    let (log, replayed) = WriteAheadLog::open(path.clone(), &db_file)?;
+    let log = sync::Arc::new(Mutex::new(log));
+    let db_file = sync::Arc::new(db_file);
+    let (signal, receiver) = crossbeam_channel::bounded(1);
+    let checkpointer = {
+      let log = log.clone();
+      let db_file = db_file.clone();
+      let interval = options.checkpoint_interval;
+      thread::Builder::new()
+        .name("bbolt-wal-checkpoint".into())
+        .spawn(move || run_checkpointer(&log, &db_file, &receiver, interval))
+        .change_context(WalError::OpenFailure(path))?
+    };
+    Ok((
+      Wal {
+        log,
+        db_file,
+        options,
+        signal,
+        checkpointer: Some(checkpointer),
+      },
+      replayed,
+    ))
+  }
+
+  /// Appends the transaction to the log and, while still holding the log, writes it to the
+  /// database file with `apply`. The database file isn't synced until the next checkpoint.
+  pub fn commit<F>(
+    &self, tx_id: TxId, page_size: usize, pages: Vec<(DiskPageId, SharedBytes)>,
+    meta_page: (DiskPageId, SharedBytes), apply: F,
+  ) -> crate::Result<(), WalError>
+  where
+    F: FnOnce(
+      Vec<(DiskPageId, SharedBytes)>,
+      (DiskPageId, SharedBytes),
+    ) -> crate::Result<(), IOError>,
+  {
+    let frames_len = {
+      let mut log = self.log.lock();
+      log.append(tx_id, page_size, &pages, &meta_page)?;
+      apply(pages, meta_page).change_context(WalError::ApplyFailure(tx_id))?;
+      log.frames_len()
+    };
+    if frames_len >= self.options.checkpoint_size {
+      let _ = self.signal.try_send(CheckpointSignal::Checkpoint);
+    }
+    Ok(())
+  }
+
+  /// Checkpoints immediately
+  pub fn checkpoint(&self) -> crate::Result<(), WalError> {
+    self.log.lock().checkpoint(&self.db_file)
+  }
+
+  /// The number of bytes of frames since the last checkpoint
+  pub fn frames_len(&self) -> u64 {
+    self.log.lock().frames_len()
+  }
+}
+
+impl Drop for Wal {
+  fn drop(&mut self) {
+    let _ = self.signal.send(CheckpointSignal::Stop);
+    if let Some(checkpointer) = self.checkpointer.take() {
+      let _ = checkpointer.join();
+    }
+  }
+}
+
+fn run_checkpointer(
+  log: &Mutex<WriteAheadLog>, db_file: &File, receiver: &Receiver<CheckpointSignal>,
+  interval: Duration,
+) {
+  loop {
+    let stop = match receiver.recv_timeout(interval) {
+      Ok(CheckpointSignal::Checkpoint) | Err(RecvTimeoutError::Timeout) => false,
+      Ok(CheckpointSignal::Stop) | Err(RecvTimeoutError::Disconnected) => true,
+    };
+    // A failed checkpoint leaves the log intact. It's retried on the next signal and
+    // replayed on the next open otherwise.
+    let _ = log.lock().checkpoint(db_file);
+    if stop {
+      return;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::buffer_pool::BufferPool;
+  use std::fs;
+
+  #[test]
+  fn test_replay_discards_torn_tail() {
+    let page_size = 512;
+    let dir = std::env::temp_dir();
+    let db_path = dir.join(format!("bbolt-nub-wal-replay-{}.db", std::process::id()));
+    let log_path = wal_path(&db_path);
+    let _ = fs::remove_file(&log_path);
+    let db_file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&db_path)
+      .unwrap();
+    let page = |fill: u8| BufferPool::new_unbound(page_size).fill_and_share(|b| b.fill(fill));
+
+    let (mut log, replayed) = WriteAheadLog::open(log_path.clone(), &db_file).unwrap();
+    assert_eq!(0, replayed);
+    log
+      .append(
+        TxId::of(2),
+        page_size,
+        &[(DiskPageId(2), page(2))],
+        &(DiskPageId(0), page(0xA)),
+      )
+      .unwrap();
+    let first_len = log.offset;
+    log
+      .append(
+        TxId::of(3),
+        page_size,
+        &[(DiskPageId(3), page(3))],
+        &(DiskPageId(1), page(0xB)),
+      )
+      .unwrap();
+    drop(log);
+    // Tear the second transaction's commit frame
+    let log_file = OpenOptions::new().write(true).open(&log_path).unwrap();
+    log_file.set_len(first_len + 700).unwrap();
+
+    let (log, replayed) = WriteAheadLog::open(log_path.clone(), &db_file).unwrap();
+    assert_eq!(1, replayed);
+    assert_eq!(0, log.frames_len());
+    let mut read = vec![0u8; page_size * 3];
+    db_file.read_exact_at(&mut read, 0).unwrap();
+    assert!(read[..page_size].iter().all(|b| *b == 0xA));
+    assert!(read[page_size..page_size * 2].iter().all(|b| *b == 0));
+    assert!(read[page_size * 2..].iter().all(|b| *b == 2));
+    drop(log);
+
+    // Recycling bumps the salt so nothing is replayed again
+    let (_, replayed) = WriteAheadLog::open(log_path.clone(), &db_file).unwrap();
+    assert_eq!(0, replayed);
+    fs::remove_file(&db_path).unwrap();
+    fs::remove_file(&log_path).unwrap();
+  }
+}
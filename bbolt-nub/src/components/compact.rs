@@ -0,0 +1,251 @@
+use crate::common::errors::DbError;
+use crate::common::id::NodePageId;
+use crate::common::layout::bucket::{BucketHeader, inline_elements};
+use crate::common::layout::meta::Meta;
+use crate::common::layout::node::LeafFlag;
+use crate::components::backend::memmap::{MemmapDb, MemmapMutTx};
+use crate::components::bucket_path::BucketPathBuf;
+use crate::io::pages::TxReadPageIO;
+use crate::io::pages::types::node::branch::HasNodes;
+use crate::io::pages::types::node::leaf::HasValues;
+use crate::io::pages::types::node::{HasElements, HasKeys, NodePage};
+use error_stack::{Report, ResultExt};
+use std::ops::Deref;
+use std::sync;
+
+fn compact_error(dst: &MemmapDb) -> DbError {
+  DbError::CompactError(dst.path().to_path_buf())
+}
+
+/// Copies buckets into a database, committing whenever a transaction grows past `tx_max_size`
+struct Compactor<'db> {
+  dst: &'db MemmapDb,
+  tx: Option<MemmapMutTx<'db>>,
+  tx_size: u64,
+  tx_max_size: u64,
+  fill_percent: f64,
+}
+
+impl<'db> Compactor<'db> {
+  /// The transaction to copy `size` more bytes in
+  fn tx(&mut self, size: u64) -> crate::Result<&MemmapMutTx<'db>, DbError> {
+    if self.tx_max_size != 0 && self.tx_size + size > self.tx_max_size {
+      self.commit()?;
+    }
+    self.tx_size += size;
+    if self.tx.is_none() {
+      let mut tx = self.dst.begin_mut()?;
+      tx.set_fill_percent(self.fill_percent);
+      self.tx = Some(tx);
+    }
+    Ok(self.tx.as_ref().expect("transaction was just begun"))
+  }
+
+  fn commit(&mut self) -> crate::Result<(), DbError> {
+    self.tx_size = 0;
+    match self.tx.take() {
+      Some(tx) => tx.commit(),
+      None => Ok(()),
+    }
+  }
+
+  /// Copies every element under `page_id` into the bucket at `path`, in key order
+  fn copy_node<'tx, TX>(
+    &mut self, src_tx: &sync::Arc<TX>, page_id: NodePageId, path: &mut BucketPathBuf,
+  ) -> crate::Result<(), DbError>
+  where
+    TX: TxReadPageIO<'tx>,
+    <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+    <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  {
+    let node = src_tx
+      .read_node_page(page_id)
+      .change_context_lazy(|| compact_error(self.dst))?;
+    match &node {
+      NodePage::Branch(branch) => {
+        for index in 0..branch.element_count() {
+          let child = branch
+            .node(index)
+            .ok_or_else(|| Report::new(compact_error(self.dst)))?;
+          self.copy_node(src_tx, child, path)?;
+        }
+      }
+      NodePage::Leaf(leaf) => {
+        for index in 0..leaf.element_count() {
+          let (Some(flag), Some((key, value))) = (leaf.leaf_flag(index), leaf.key_value(index))
+          else {
+            return Err(Report::new(compact_error(self.dst)));
+          };
+          self.copy_element(src_tx, path, flag, &key, &value)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Copies one leaf element, and the whole tree of a nested bucket, into the bucket at `path`
+  fn copy_element<'tx, TX>(
+    &mut self, src_tx: &sync::Arc<TX>, path: &mut BucketPathBuf, flag: LeafFlag, key: &[u8],
+    value: &[u8],
+  ) -> crate::Result<(), DbError>
+  where
+    TX: TxReadPageIO<'tx>,
+    <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+    <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  {
+    let dst = self.dst;
+    if !flag.contains(LeafFlag::BUCKET) {
+      // Values are read decompressed, and compressed again by the bucket's threshold
+      return self
+        .tx((key.len() + value.len()) as u64)?
+        .put(path, key, value)
+        .change_context_lazy(|| compact_error(dst));
+    }
+    let header: BucketHeader = bytemuck::pod_read_unaligned(
+      value
+        .get(0..size_of::<BucketHeader>())
+        .ok_or_else(|| Report::new(compact_error(dst)))?,
+    );
+    path.push(key);
+    let tx = self.tx(key.len() as u64)?;
+    tx.create_bucket(path)
+      .change_context_lazy(|| compact_error(dst))?;
+    tx.set_sequence(path, header.sequence())
+      .change_context_lazy(|| compact_error(dst))?;
+    tx.set_compression(path, flag.compression_threshold())
+      .change_context_lazy(|| compact_error(dst))?;
+    if header.root().0.0 == 0 {
+      // Inline buckets store their leaf page in the value
+      let elements = inline_elements(value).ok_or_else(|| Report::new(compact_error(dst)))?;
+      for (flag, key, value) in elements {
+        self.copy_element(src_tx, path, flag, key, value)?;
+      }
+    } else {
+      self.copy_node(src_tx, header.root().into(), path)?;
+    }
+    path.pop();
+    Ok(())
+  }
+}
+
+/// Copies every bucket under the root of `meta` in `src_tx` into `dst`, preserving nested
+/// buckets and their sequences and compression thresholds. A transaction is committed whenever
+/// it grows past `tx_max_size` bytes, or only once at the end when it's 0, and its pages are
+/// split at `fill_percent`.
+///
+/// `bbolt compact` in Go BBolt
+pub fn compact<'tx, TX>(
+  src_tx: &sync::Arc<TX>, meta: &Meta, dst: &MemmapDb, tx_max_size: u64, fill_percent: f64,
+) -> crate::Result<(), DbError>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  let mut compactor = Compactor {
+    dst,
+    tx: None,
+    tx_size: 0,
+    tx_max_size,
+    fill_percent,
+  };
+  let mut path = BucketPathBuf::new();
+  let root = meta.root;
+  if root.sequence() != 0 {
+    compactor
+      .tx(0)?
+      .set_sequence(&path, root.sequence())
+      .change_context_lazy(|| compact_error(dst))?;
+  }
+  if let Some(threshold) = meta.compression_threshold() {
+    compactor
+      .tx(0)?
+      .set_compression(&path, Some(threshold))
+      .change_context_lazy(|| compact_error(dst))?;
+  }
+  compactor.copy_node(src_tx, root.root().into(), &mut path)?;
+  compactor.commit()
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::components::backend::memmap::MemmapDb;
+  use crate::components::backend::temp_db_path;
+  use crate::components::bucket_path::BucketPathBuf;
+  use std::fs;
+
+  fn assert_compacted(db: &MemmapDb) {
+    let gears = BucketPathBuf::from(["gears"]);
+    let sprockets = BucketPathBuf::from(["gears", "sprockets"]);
+    assert_eq!(0, db.check().count());
+    let tx = db.begin_mut().unwrap();
+    assert_eq!(7, tx.sequence(&gears).unwrap());
+    assert_eq!(1, tx.sequence(&sprockets).unwrap());
+    assert_eq!(Some(64), tx.compression(&gears).unwrap());
+    assert_eq!(None, tx.compression(&sprockets).unwrap());
+    for i in 0..500u32 {
+      let key = format!("key-{:08}", i);
+      let value = tx.get(&gears, key.as_bytes()).unwrap();
+      if i % 4 == 0 {
+        assert_eq!(Some(&[b'g'; 256][..]), value.as_deref());
+      } else {
+        assert!(value.is_none());
+      }
+      let value = tx.get(&sprockets, key.as_bytes()).unwrap();
+      assert_eq!(Some(&i.to_be_bytes()[..]), value.as_deref());
+    }
+  }
+
+  #[test]
+  fn test_compact() {
+    let path = temp_db_path("compact");
+    let dst_path = temp_db_path("compact-dst");
+    let gears = BucketPathBuf::from(["gears"]);
+    let sprockets = BucketPathBuf::from(["gears", "sprockets"]);
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .open_path(&path)
+      .unwrap();
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&gears).unwrap();
+    tx.create_bucket(&sprockets).unwrap();
+    tx.set_sequence(&gears, 6).unwrap();
+    tx.next_sequence(&gears).unwrap();
+    tx.next_sequence(&sprockets).unwrap();
+    tx.set_compression(&gears, Some(64)).unwrap();
+    tx.commit().unwrap();
+    for i in 0..500u32 {
+      let tx = db.begin_mut().unwrap();
+      let key = format!("key-{:08}", i);
+      tx.put(&gears, key.as_bytes(), &[b'g'; 256]).unwrap();
+      tx.put(&sprockets, key.as_bytes(), &i.to_be_bytes())
+        .unwrap();
+      tx.commit().unwrap();
+    }
+    let tx = db.begin_mut().unwrap();
+    for i in (0..500u32).filter(|i| i % 4 != 0) {
+      tx.delete(&gears, format!("key-{:08}", i).as_bytes())
+        .unwrap();
+    }
+    tx.commit().unwrap();
+
+    db.compact()
+      .tx_max_size(4096)
+      .compact_into(dst_path.clone())
+      .unwrap();
+    assert!(db.compact().compact_into(dst_path.clone()).is_err());
+    let dst = MemmapDb::builder().open_path(&dst_path).unwrap();
+    assert_compacted(&dst);
+    assert!(dst.meta().eof_id.0.0 < db.meta().eof_id.0.0);
+    drop(dst);
+
+    let eof_id = db.meta().eof_id;
+    db.compact_in_place().call().unwrap();
+    let db = MemmapDb::builder().open_path(&path).unwrap();
+    assert_compacted(&db);
+    assert!(db.meta().eof_id.0.0 < eof_id.0.0);
+    drop(db);
+    fs::remove_file(path).unwrap();
+    fs::remove_file(dst_path).unwrap();
+  }
+}
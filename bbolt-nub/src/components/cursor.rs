@@ -1,5 +1,6 @@
 use crate::common::data_pool::SharedData;
 use crate::common::errors::CursorError;
+use crate::common::id::NodePageId;
 use crate::common::layout::node::LeafFlag;
 use crate::common::vec_pool::UniqueVec;
 use crate::components::bucket::OnDiskBucket;
@@ -19,6 +20,7 @@ use crate::io::pages::{
   GatKvRef, GetGatKvRefSlice, GetKvTxSlice, Page, TxPageType, TxReadLazyPageIO, TxReadPageIO,
 };
 use error_stack::ResultExt;
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::sync;
@@ -40,6 +42,16 @@ impl<B, L> StackEntry<B, L> {
     Self { page, index }
   }
 
+  #[inline]
+  pub fn page(&self) -> &NodePage<B, L> {
+    &self.page
+  }
+
+  #[inline]
+  pub fn index(&self) -> usize {
+    self.index
+  }
+
   #[inline]
   pub fn is_leaf(&self) -> bool {
     self.page.is_leaf()
@@ -138,6 +150,9 @@ pub struct CoreCursor<'tx, B, L, TX> {
   stack: UniqueVec<StackEntry<B, L>>,
   location: CursorLocation,
   tx_slot: TxSlot<'tx>,
+  /// How many following siblings to read along with each child page
+  prefetch: usize,
+  prefetched: VecDeque<(NodePageId, NodePage<B, L>)>,
 }
 
 impl<'tx, B, L, TX> Clone for CoreCursor<'tx, B, L, TX>
@@ -152,6 +167,8 @@ where
       stack: self.stack.clone(),
       location: self.location,
       tx_slot: self.tx_slot,
+      prefetch: self.prefetch,
+      prefetched: self.prefetched.clone(),
     }
   }
 }
@@ -171,7 +188,67 @@ where
       stack,
       location: CursorLocation::Begin,
       tx_slot: TxSlot::default(),
+      prefetch: 0,
+      prefetched: VecDeque::new(),
+    }
+  }
+
+  /// Reads up to `prefetch` following sibling pages in one batch whenever the cursor moves into
+  /// a child page, so walking forward through the leaves needs fewer round trips.
+  /// 0 disables prefetching.
+  pub fn set_prefetch(&mut self, prefetch: usize) {
+    self.prefetch = prefetch;
+    self.prefetched.clear();
+  }
+
+  /// Reads the child at `index` of `branch`, prefetching its following siblings if enabled
+  fn read_child(
+    &mut self, branch: &TX::BranchType, index: usize,
+  ) -> crate::Result<NodePage<TX::BranchType, TX::LeafType>, CursorError> {
+    let node_page_id = branch.node(index).unwrap();
+    if let Some((prefetched_id, _)) = self.prefetched.front() {
+      if *prefetched_id == node_page_id {
+        let (_, node) = self.prefetched.pop_front().unwrap();
+        return Ok(node);
+      }
+      self.prefetched.clear();
+    }
+    if self.prefetch == 0 {
+      return self
+        .tx
+        .read_node_page(node_page_id)
+        .change_context(CursorError::GoToFirstElement);
     }
+    let end = (index + 1 + self.prefetch).min(branch.element_count());
+    let node_page_ids: Vec<_> = (index..end)
+      .map(|index| branch.node(index).unwrap())
+      .collect();
+    let mut nodes = self
+      .tx
+      .read_node_pages(&node_page_ids)
+      .change_context(CursorError::GoToFirstElement)?
+      .into_iter();
+    let node = nodes.next().expect("read at least one page");
+    self
+      .prefetched
+      .extend(node_page_ids[1..].iter().copied().zip(nodes));
+    Ok(node)
+  }
+
+  #[inline]
+  pub(crate) fn tx(&self) -> &sync::Arc<TX> {
+    &self.tx
+  }
+
+  #[inline]
+  pub(crate) fn root(&self) -> &NodePage<TX::BranchType, TX::LeafType> {
+    &self.root
+  }
+
+  /// The path from the bucket root to the current leaf
+  #[inline]
+  pub(crate) fn stack(&self) -> &[StackEntry<TX::BranchType, TX::LeafType>] {
+    &self.stack
   }
 
   fn move_to_first_element_on_stack(&mut self) -> crate::Result<(), CursorError> {
@@ -182,15 +259,12 @@ where
         break;
       }
 
-      let node_page_id = match &entry.page {
-        NodePage::Branch(branch) => branch.node(entry.index).unwrap(),
+      let (branch, index) = match &entry.page {
+        NodePage::Branch(branch) => (branch.clone(), entry.index),
         NodePage::Leaf(_) => unreachable!("Cannot be leaf"),
       };
 
-      let node = self
-        .tx
-        .read_node_page(node_page_id)
-        .change_context(CursorError::GoToFirstElement)?;
+      let node = self.read_child(&branch, index)?;
       self.stack.push(StackEntry::new(node));
     }
     self.location = CursorLocation::Inside;
@@ -599,6 +673,12 @@ impl<C> LeafFlagFilterCursor<C> {
   pub fn new(cursor: C, leaf_flag: LeafFlag) -> Self {
     Self { cursor, leaf_flag }
   }
+
+  /// Whether `flag` is the same kind of element. Compressed values are still values.
+  #[inline]
+  fn matches(&self, flag: LeafFlag) -> bool {
+    flag.contains(LeafFlag::BUCKET) == self.leaf_flag.contains(LeafFlag::BUCKET)
+  }
 }
 
 impl<C> CoreCursorMoveApi for LeafFlagFilterCursor<C>
@@ -607,7 +687,7 @@ where
 {
   fn move_to_first_element(&mut self) -> crate::Result<Option<LeafFlag>, CursorError> {
     if let Some(flag) = self.cursor.move_to_first_element()? {
-      if flag == self.leaf_flag {
+      if self.matches(flag) {
         Ok(Some(flag))
       } else {
         self.move_to_next_element()
@@ -619,7 +699,7 @@ where
 
   fn move_to_next_element(&mut self) -> crate::Result<Option<LeafFlag>, CursorError> {
     while let Some(flag) = self.cursor.move_to_next_element()? {
-      if flag == self.leaf_flag {
+      if self.matches(flag) {
         return Ok(Some(flag));
       }
     }
@@ -628,7 +708,7 @@ where
 
   fn move_to_prev_element(&mut self) -> crate::Result<Option<LeafFlag>, CursorError> {
     while let Some(flag) = self.cursor.move_to_prev_element()? {
-      if flag == self.leaf_flag {
+      if self.matches(flag) {
         return Ok(Some(flag));
       }
     }
@@ -637,7 +717,7 @@ where
 
   fn move_to_last_element(&mut self) -> crate::Result<Option<LeafFlag>, CursorError> {
     if let Some(flag) = self.cursor.move_to_last_element()? {
-      if flag == self.leaf_flag {
+      if self.matches(flag) {
         Ok(Some(flag))
       } else {
         self.move_to_prev_element()
@@ -656,13 +736,13 @@ where
     match self.cursor.seek(v)? {
       None => Ok(None),
       Some(flag) => {
-        if flag == self.leaf_flag {
+        if self.matches(flag) {
           Ok(Some(flag))
         } else {
           if self.leaf_flag == LeafFlag::BUCKET {
-            Err(CursorError::ValueIsABucket.into())
-          } else {
             Err(CursorError::ValueIsBytes.into())
+          } else {
+            Err(CursorError::ValueIsABucket.into())
           }
         }
       }
@@ -678,13 +758,13 @@ where
     match self.cursor.try_seek(v)? {
       None => Ok(None),
       Some(flag) => {
-        if flag == self.leaf_flag {
+        if self.matches(flag) {
           Ok(Some(flag))
         } else {
           if self.leaf_flag == LeafFlag::BUCKET {
-            Err(CursorError::ValueIsABucket.into())
-          } else {
             Err(CursorError::ValueIsBytes.into())
+          } else {
+            Err(CursorError::ValueIsABucket.into())
           }
         }
       }
@@ -1174,7 +1254,13 @@ mod tests {
   use super::*;
   use crate::api::tx::TxStats;
   use crate::common::buffer_pool::BufferPool;
+  use crate::common::errors::PageError;
+  use crate::common::id::{DbPageId, DiskPageId, PageMapTranslator, TxId};
   use crate::common::layout::bucket::BucketHeader;
+  use crate::common::layout::checksum::{page_footer_len, write_page_checksums};
+  use crate::common::layout::node::LeafElement;
+  use crate::common::layout::page::PageHeader;
+  use crate::common::layout::page_map::PageMapEntry;
   use crate::common::vec_pool::VecPool;
   use crate::components::tx::{CoreTxHandle, LazyTxHandle, RefTxHandle};
   use crate::io::backends::file::{
@@ -1186,6 +1272,7 @@ mod tests {
   use crate::io::backends::{CachedReadHandler, DirectReadHandler, NewIOReader, ROShell};
   use crate::io::pages::lazy::ops::RefIntoTryBuf;
   use crate::io::pages::lazy::ops::TryBuf;
+  use crate::io::pages::lazy::ops::TryPartialEq;
   use crate::io::transmogrify::direct::DirectTransmogrify;
   use bytemuck::bytes_of_mut;
   use memmap2::{Advice, Mmap, MmapOptions};
@@ -1231,6 +1318,9 @@ mod tests {
       io: read_lock.into(),
       stats: tx_stats.clone(),
       tx_id,
+      epoch: None,
+      reader: None,
+      page_map: None,
     };
     let tx = sync::Arc::new(LazyTxHandle { handle: core_tx });
     let root = tx.read_node_page(root_page.into()).unwrap();
@@ -1352,6 +1442,9 @@ mod tests {
       io: read_lock.into(),
       stats: tx_stats.clone(),
       tx_id,
+      epoch: None,
+      reader: None,
+      page_map: None,
     };
     let tx = sync::Arc::new(LazyTxHandle { handle: core_tx });
     let root = tx.read_node_page(root_page.into()).unwrap();
@@ -1472,6 +1565,9 @@ mod tests {
       io: read_lock.into(),
       stats: tx_stats.clone(),
       tx_id,
+      epoch: None,
+      reader: None,
+      page_map: None,
     };
     let tx = sync::Arc::new(LazyTxHandle { handle: core_tx });
     let root = tx.read_node_page(root_page.into()).unwrap();
@@ -1591,8 +1687,11 @@ mod tests {
       io: read_lock.into(),
       stats: tx_stats.clone(),
       tx_id,
+      epoch: None,
+      reader: None,
+      page_map: None,
     };
-    let tx = sync::Arc::new(RefTxHandle { handle: core_tx });
+    let tx = sync::Arc::new(RefTxHandle::new(core_tx));
     let root = tx.read_node_page(root_page.into()).unwrap();
     let stack_pool = VecPool::new(10, 5, 5_000);
     let bucket = OnDiskBucket {
@@ -1677,4 +1776,100 @@ mod tests {
     write.flush().unwrap();
     println!("memmap: {:?}", now.elapsed());*/
   }
+
+  #[test]
+  #[allow(clippy::arc_with_non_send_sync)]
+  fn test_scattered_overflow() {
+    let page_size = 4096;
+    let key = b"k";
+    let header_len = size_of::<PageHeader>() + size_of::<LeafElement>();
+    let path = std::env::temp_dir().join(format!("bbolt-nub-scattered-{}.db", std::process::id()));
+    for (page_checksums, corrupt) in [(false, false), (true, false), (true, true)] {
+      let footer_len = if page_checksums {
+        page_footer_len(2)
+      } else {
+        0
+      };
+      let value: Vec<u8> = (0..page_size * 3 - header_len - key.len() - footer_len)
+        .map(|i| (i % 251) as u8)
+        .collect();
+      let mut node = vec![0u8; page_size * 3];
+      let mut header = PageHeader::init_leaf(DbPageId(0));
+      header.set_count(1);
+      unsafe { header.set_overflow(2) };
+      node[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+      let element = LeafElement::new(
+        LeafFlag::empty(),
+        size_of::<LeafElement>() as u32,
+        key.len() as u32,
+        value.len() as u32,
+      );
+      node[size_of::<PageHeader>()..header_len].copy_from_slice(bytemuck::bytes_of(&element));
+      node[header_len..header_len + key.len()].copy_from_slice(key);
+      node[header_len + key.len()..header_len + key.len() + value.len()].copy_from_slice(&value);
+      if page_checksums {
+        write_page_checksums(&mut node, page_size);
+      }
+
+      // The node and its two overflow pages are stored out of order on disk
+      let disk_pages = [7u64, 3, 5];
+      let mut file = vec![0u8; page_size * 8];
+      for (chunk, disk) in node.chunks(page_size).zip(disk_pages) {
+        let offset = disk as usize * page_size;
+        file[offset..offset + page_size].copy_from_slice(chunk);
+      }
+      if corrupt {
+        file[3 * page_size + 100] ^= 1;
+      }
+      std::fs::write(&path, &file).unwrap();
+
+      let buffer_pool = BufferPool::new(
+        page_size,
+        Size::from_kibibytes(64),
+        Size::from_kibibytes(64),
+        Size::from_kibibytes(256),
+      );
+      let read_options = FileReadOptions::new(buffer_pool).with_page_checksums(page_checksums);
+      let backend =
+        SingleFileIO::new_ro(sync::Arc::new(path.clone()), page_size, read_options).unwrap();
+      let tx_context = PageMapTranslator::new(
+        TxId::of(1),
+        disk_pages.iter().enumerate().map(|(node, disk)| {
+          PageMapEntry::new(NodePageId(DbPageId(node as u64)), DiskPageId(*disk))
+        }),
+      );
+      let cached_read_handler = RwLock::new(CachedReadHandler {
+        handler: DirectReadHandler {
+          tx_context,
+          io: backend,
+        },
+        page_cache: Cache::new(16),
+      });
+      let read_lock = cached_read_handler.read();
+      let core_tx = CoreTxHandle {
+        io: read_lock.into(),
+        stats: sync::Arc::new(TxStats::default()),
+        tx_id: TxId::of(1),
+        epoch: None,
+        reader: None,
+        page_map: None,
+      };
+      let tx = sync::Arc::new(LazyTxHandle { handle: core_tx });
+      let NodePage::Leaf(leaf) = tx.read_node_page(NodePageId(DbPageId(0))).unwrap() else {
+        panic!("expected a leaf");
+      };
+      assert!(leaf.key_ref(0).unwrap().try_eq(key.as_slice()).unwrap());
+      let value_eq = leaf.value_ref(0).unwrap().try_eq(value.as_slice());
+      if corrupt {
+        let report = value_eq.unwrap_err();
+        assert!(report.frames().any(|frame| matches!(
+          frame.downcast_ref::<PageError>(),
+          Some(PageError::ChecksumMismatch(_, 1))
+        )));
+      } else {
+        assert!(value_eq.unwrap());
+      }
+    }
+    std::fs::remove_file(&path).unwrap();
+  }
 }
@@ -1,19 +1,83 @@
 use crate::common::id::{
-  DbId, DiskPageId, DiskPageTranslator, EOFPageId, FreelistPageId, NodePageId,
+  DbId, DiskPageId, DiskPageTranslator, EOFPageId, FreelistPageId, NodePageId, PageMapTranslator,
+  StableFreeSpaceTranslator, SupportsContigPages, SupportsNonContigPages, TxId,
 };
 use crate::io::transmogrify::direct::DirectTransmogrify;
 use rangemap::RangeSet;
 use std::cmp::min_by;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::iter::FusedIterator;
+use std::mem;
 use std::ops::{AddAssign, Range};
 
 pub struct FreeIndex<T> {
   page_translator: T,
   ranges: RangeSet<DiskPageId>,
   singles: BTreeSet<DiskPageId>,
+  pending: PendingPages,
   original_eof: EOFPageId,
   current_eof: EOFPageId,
+  /// The disk pages of nodes that kept their id when they were remapped, until they're freed
+  remapped: BTreeMap<NodePageId, Vec<DiskPageId>>,
+}
+
+/// Assigns pages to the nodes written by a commit and releases the pages of the nodes they
+/// replace
+pub trait NodeAllocator {
+  /// Assigns `len` pages for a node split from `origin`, which spanned `origin_len` pages, or for
+  /// a node with no prior location
+  fn allocate_node(&mut self, origin: Option<(NodePageId, u64)>, len: u64) -> NodePageId;
+
+  /// Releases the `len` pages of `page_id` that `tx_id` replaced. They aren't assigned until
+  /// no reader can see them.
+  fn free_node(&mut self, tx_id: TxId, page_id: NodePageId, len: u64);
+}
+
+/// Pages released by committed transactions that open readers may still see, keyed by the
+/// transaction that released them
+#[derive(Debug, Default, Clone)]
+pub struct PendingPages {
+  by_tx: BTreeMap<TxId, Vec<DiskPageId>>,
+}
+
+impl PendingPages {
+  /// Records that `tx_id` released `len` pages starting at `start`
+  pub fn push(&mut self, tx_id: TxId, start: DiskPageId, len: u64) {
+    self
+      .by_tx
+      .entry(tx_id)
+      .or_default()
+      .extend((start.0..start.0 + len).map(DiskPageId));
+  }
+
+  /// The number of pending pages
+  pub fn len(&self) -> usize {
+    self.by_tx.values().map(Vec::len).sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.by_tx.values().all(Vec::is_empty)
+  }
+
+  /// Every pending page, unsorted
+  pub fn iter(&self) -> impl Iterator<Item = DiskPageId> + '_ {
+    self.by_tx.values().flatten().copied()
+  }
+
+  /// Removes the pages no reader can see anymore.
+  ///
+  /// Pages released by transaction `N` are reachable from the meta of `N - 1`, so they stay
+  /// pending while any reader older than `N` is open. `min_reader` is the oldest open reader.
+  pub fn release(&mut self, min_reader: Option<TxId>) -> Vec<DiskPageId> {
+    let pending = match min_reader {
+      Some(min_reader) => {
+        let still_pending = self.by_tx.split_off(&TxId::of(min_reader.0.0 + 1));
+        mem::replace(&mut self.by_tx, still_pending)
+      }
+      None => mem::take(&mut self.by_tx),
+    };
+    pending.into_values().flatten().collect()
+  }
 }
 
 #[derive(Debug, Clone)]
@@ -102,11 +166,33 @@ where
       page_translator,
       ranges,
       singles,
+      pending: PendingPages::default(),
       original_eof: eof_page_id,
       current_eof: eof_page_id,
+      remapped: BTreeMap::new(),
     }
   }
 
+  /// Adds pages released by earlier transactions. They aren't assigned until released.
+  pub fn with_pending(mut self, pending: PendingPages) -> Self {
+    self.pending = pending;
+    self
+  }
+
+  #[inline]
+  pub fn pending(&self) -> &PendingPages {
+    &self.pending
+  }
+
+  pub fn into_pending(self) -> PendingPages {
+    self.pending
+  }
+
+  #[inline]
+  pub fn translator(&self) -> &D {
+    &self.page_translator
+  }
+
   #[inline]
   pub fn original_eof(&self) -> EOFPageId {
     self.original_eof
@@ -133,8 +219,82 @@ where
     self.page_translator.disk_to_freelist(assigned_disk)
   }
 
+  /// Assigns `len` contiguous pages for a node that has no prior location, preferring the
+  /// lowest free pages
+  pub fn assign_new_node(&mut self, len: u64) -> NodePageId {
+    let assigned_disk = self.assign_disk(DiskPageId(0), len);
+    self.page_translator.disk_to_node(assigned_disk)
+  }
+
+  /// Returns `len` contiguous pages starting at `start` to the index
+  pub fn free(&mut self, start: DiskPageId, len: u64) {
+    let mut range = start..start + len;
+    if range.start.0 > 0 && self.singles.remove(&DiskPageId(range.start.0 - 1)) {
+      range.start = DiskPageId(range.start.0 - 1);
+    }
+    if self.singles.remove(&range.end) {
+      range.end += 1;
+    }
+    let joins_range = (range.start.0 > 0 && self.ranges.contains(&DiskPageId(range.start.0 - 1)))
+      || self.ranges.contains(&range.end);
+    if range.end.0 - range.start.0 == 1 && !joins_range {
+      self.singles.insert(range.start);
+    } else {
+      self.ranges.insert(range);
+    }
+  }
+
+  /// Records that `tx_id` released `len` pages starting at `start`. They can't be assigned
+  /// until [`FreeIndex::release_pending`] finds no reader that can see them.
+  pub fn free_pending(&mut self, tx_id: TxId, start: DiskPageId, len: u64) {
+    self.pending.push(tx_id, start, len);
+  }
+
+  /// Makes the pending pages no reader can see anymore assignable. `min_reader` is the oldest
+  /// open reader.
+  pub fn release_pending(&mut self, min_reader: Option<TxId>) {
+    for page_id in self.pending.release(min_reader) {
+      self.free(page_id, 1);
+    }
+  }
+
+  /// The number of assignable pages in the index
+  pub fn free_count(&self) -> usize {
+    self.singles.len()
+      + self
+        .ranges
+        .iter()
+        .map(|range| (range.end.0 - range.start.0) as usize)
+        .sum::<usize>()
+  }
+
+  /// Every assignable page in the index, sorted
+  pub fn free_ids(&self) -> Vec<DiskPageId> {
+    let mut ids = Vec::with_capacity(self.free_count());
+    let mut singles = self.singles.iter().copied().peekable();
+    for range in self.ranges.iter() {
+      while let Some(single) = singles.next_if(|single| *single < range.start) {
+        ids.push(single);
+      }
+      ids.extend((range.start.0..range.end.0).map(DiskPageId));
+    }
+    ids.extend(singles);
+    ids
+  }
+
   fn assign_disk(&mut self, desired: DiskPageId, len: u64) -> DiskPageId {
-    let min_disk = |x: &DiskPageId, y: &DiskPageId| (&(x.0 - desired.0)).cmp(&(y.0 - desired.0));
+    self.take_free(desired, len).unwrap_or_else(|| {
+      let new_disk_page = self.current_eof.0;
+      self.current_eof.0 += len;
+      new_disk_page
+    })
+  }
+
+  /// Takes the `len` contiguous free pages nearest `desired`, if there are any
+  fn take_free(&mut self, desired: DiskPageId, len: u64) -> Option<DiskPageId> {
+    let desired = desired.min(self.current_eof.0);
+    let min_disk =
+      |x: &DiskPageId, y: &DiskPageId| x.0.abs_diff(desired.0).cmp(&y.0.abs_diff(desired.0));
     let min_option = |left_entry: Option<DiskPageId>, right_entry: Option<DiskPageId>| match (
       left_entry,
       right_entry,
@@ -197,27 +357,251 @@ where
 
     match (single_entry, range_entry) {
       (Some(single_entry), Some(range_entry)) => {
-        if single_entry.0 - desired.0 <= range_entry.0 - desired.0 {
+        if single_entry.0.abs_diff(desired.0) <= range_entry.0.abs_diff(desired.0) {
           self.singles.remove(&single_entry);
-          single_entry
+          Some(single_entry)
         } else {
           self.ranges.remove(range_entry..range_entry + len);
-          range_entry
+          Some(range_entry)
         }
       }
       (Some(single_entry), None) => {
         self.singles.remove(&single_entry);
-        single_entry
+        Some(single_entry)
       }
       (None, Some(range_entry)) => {
         self.ranges.remove(range_entry..range_entry + len);
-        range_entry
+        Some(range_entry)
       }
-      (None, None) => {
-        let new_disk_page = self.current_eof.0;
-        self.current_eof.0 += len;
-        new_disk_page
+      (None, None) => None,
+    }
+  }
+}
+
+impl<D> FreeIndex<D>
+where
+  D: DiskPageTranslator + SupportsNonContigPages,
+{
+  /// Assigns `len` pages that needn't be contiguous. A contiguous run is used when one is free,
+  /// otherwise the free pages nearest `desired` are taken and the rest are added at the end of
+  /// the file.
+  pub fn assign_scattered(&mut self, desired: DiskPageId, len: u64) -> Vec<DiskPageId> {
+    let has_run = (len == 1 && !self.singles.is_empty())
+      || self
+        .ranges
+        .iter()
+        .any(|range| range.end.0 - range.start.0 >= len);
+    if has_run {
+      let start = self.assign_disk(desired, len);
+      return (start.0..start.0 + len).map(DiskPageId).collect();
+    }
+    let mut free_ids = self.free_ids();
+    free_ids.sort_by_key(|page_id| page_id.0.abs_diff(desired.0));
+    free_ids.truncate(len as usize);
+    for page_id in &free_ids {
+      if !self.singles.remove(page_id) {
+        self.ranges.remove(*page_id..*page_id + 1);
       }
     }
+    free_ids.sort_unstable();
+    let remaining = len - free_ids.len() as u64;
+    let eof = self.current_eof.0;
+    self.current_eof.0 += remaining;
+    free_ids.extend((eof.0..eof.0 + remaining).map(DiskPageId));
+    free_ids
+  }
+}
+
+impl<D> NodeAllocator for FreeIndex<D>
+where
+  D: DiskPageTranslator + SupportsContigPages,
+{
+  fn allocate_node(&mut self, origin: Option<(NodePageId, u64)>, len: u64) -> NodePageId {
+    match origin {
+      Some((origin, _)) => self.assign_node(origin, len),
+      None => self.assign_new_node(len),
+    }
+  }
+
+  fn free_node(&mut self, tx_id: TxId, page_id: NodePageId, len: u64) {
+    let disk_page_id = self.page_translator.node_to_disk(page_id);
+    self.free_pending(tx_id, disk_page_id, len);
+  }
+}
+
+impl FreeIndex<PageMapTranslator> {
+  /// Stores the `len` pages of `page_id` and its overflow, which had `old_len` pages, at newly
+  /// assigned, possibly scattered, disk pages. Pages past `len` are unmapped. Returns every disk
+  /// page the node was stored at before, which the caller frees.
+  pub fn remap_node(&mut self, page_id: NodePageId, old_len: u64, len: u64) -> Vec<DiskPageId> {
+    assert!(
+      (old_len..len).all(|overflow| self
+        .page_translator
+        .get(page_id + overflow as u32)
+        .is_none()),
+      "{page_id:?} can't grow into mapped pages"
+    );
+    let desired = self.page_translator.get(page_id).unwrap_or_default();
+    let assigned = self.assign_scattered(desired, len);
+    let mut replaced = Vec::new();
+    for (overflow, disk_page_id) in assigned.into_iter().enumerate() {
+      let node_page_id = page_id + overflow as u32;
+      replaced.extend(self.page_translator.remap(node_page_id, disk_page_id));
+    }
+    for overflow in len..old_len {
+      replaced.extend(self.page_translator.unmap(page_id + overflow as u32));
+    }
+    replaced
+  }
+
+  /// Assigns `len` pages for a node that has no prior location, after every mapped node
+  pub fn map_new_node(&mut self, len: u64) -> NodePageId {
+    let page_id = self.page_translator.next_node_id();
+    self.remap_node(page_id, 0, len);
+    page_id
+  }
+}
+
+/// The first node split from an origin that fits in its pages keeps its id, so its parent's
+/// reference doesn't change. Every other node is mapped after the mapped nodes.
+impl NodeAllocator for FreeIndex<PageMapTranslator> {
+  fn allocate_node(&mut self, origin: Option<(NodePageId, u64)>, len: u64) -> NodePageId {
+    match origin {
+      Some((origin, origin_len)) if len <= origin_len && !self.remapped.contains_key(&origin) => {
+        let replaced = self.remap_node(origin, origin_len, len);
+        self.remapped.insert(origin, replaced);
+        origin
+      }
+      _ => self.map_new_node(len),
+    }
+  }
+
+  fn free_node(&mut self, tx_id: TxId, page_id: NodePageId, len: u64) {
+    let replaced = match self.remapped.get_mut(&page_id) {
+      Some(replaced) => mem::take(replaced),
+      None => (0..len)
+        .filter_map(|overflow| self.page_translator.unmap(page_id + overflow as u32))
+        .collect(),
+    };
+    for disk_page_id in replaced {
+      self.free_pending(tx_id, disk_page_id, 1);
+    }
+  }
+}
+
+impl FreeIndex<StableFreeSpaceTranslator> {
+  /// Assigns `len` pages for a node, preferring the free pages nearest `desired`. Free pages are
+  /// only ever data pages, so a free run never spans a cluster's free space regions. Nodes added
+  /// at the end of the file may continue into the next cluster's data pages past its regions.
+  pub fn assign_clustered(&mut self, desired: DiskPageId, len: u64) -> NodePageId {
+    if let Some(disk_page_id) = self.take_free(desired, len) {
+      return self.page_translator.disk_to_node(disk_page_id);
+    }
+    let start = self.page_translator.next_data_page(self.current_eof.0);
+    let page_id = self.page_translator.disk_to_node(start);
+    let last = self
+      .page_translator
+      .node_to_disk(page_id + (len - 1) as u32);
+    self.current_eof = EOFPageId(last + 1);
+    page_id
+  }
+}
+
+/// Nodes are contiguous in node page ids, though not always on disk
+impl NodeAllocator for FreeIndex<StableFreeSpaceTranslator> {
+  fn allocate_node(&mut self, origin: Option<(NodePageId, u64)>, len: u64) -> NodePageId {
+    let desired = match origin {
+      Some((origin, _)) => self.page_translator.node_to_disk(origin),
+      None => DiskPageId(0),
+    };
+    self.assign_clustered(desired, len)
+  }
+
+  fn free_node(&mut self, tx_id: TxId, page_id: NodePageId, len: u64) {
+    for overflow in 0..len {
+      let disk_page_id = self.page_translator.node_to_disk(page_id + overflow as u32);
+      self.free_pending(tx_id, disk_page_id, 1);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::id::DbPageId;
+  use crate::common::layout::page_map::PageMapEntry;
+
+  #[test]
+  fn test_remap_node() {
+    let entries = [(0, 2), (1, 4)]
+      .map(|(node, disk)| PageMapEntry::new(NodePageId(DbPageId(node)), DiskPageId(disk)));
+    let translator = PageMapTranslator::new(TxId::of(1), entries);
+    let free_ids = [3, 6, 9].map(DiskPageId);
+    let mut free_index = FreeIndex::new(translator, free_ids, EOFPageId(DiskPageId(10)));
+
+    // No run of 4 is free, so the scattered singles are used before growing the file
+    let page_id = free_index.map_new_node(4);
+    assert_eq!(NodePageId(DbPageId(2)), page_id);
+    assert_eq!(0, free_index.free_count());
+    assert_eq!(1, free_index.required_file_growth());
+    let disk: Vec<_> = (0..4)
+      .map(|overflow| free_index.translator().get(page_id + overflow).unwrap().0)
+      .collect();
+    assert_eq!(vec![3, 6, 9, 10], disk);
+
+    // A free run is still preferred
+    free_index.free(DiskPageId(7), 2);
+    let replaced = free_index.remap_node(NodePageId(DbPageId(0)), 2, 2);
+    assert_eq!(vec![DiskPageId(2), DiskPageId(4)], replaced);
+    assert_eq!(
+      Some(DiskPageId(8)),
+      free_index.translator().get(NodePageId(DbPageId(1)))
+    );
+
+    // Shrinking unmaps the old tail and returns its pages too
+    let replaced = free_index.remap_node(page_id, 4, 1);
+    assert_eq!(
+      vec![DiskPageId(3), DiskPageId(6), DiskPageId(9), DiskPageId(10)],
+      replaced
+    );
+    assert_eq!(None, free_index.translator().get(page_id + 1));
+    assert_eq!(3, free_index.translator().len());
+  }
+
+  #[test]
+  fn test_assign_clustered() {
+    // Clusters of 2 free space pages then 8 data pages, so nodes 0 to 7 are at disk pages 4 to
+    // 11 and nodes 8 to 15 at 14 to 21
+    let translator = StableFreeSpaceTranslator::new(TxId::of(1), 1, 1);
+    let free_ids = [5, 6].map(DiskPageId);
+    let mut free_index = FreeIndex::new(translator.clone(), free_ids, EOFPageId(DiskPageId(10)));
+
+    assert_eq!(NodePageId(DbPageId(1)), free_index.allocate_node(None, 2));
+    assert_eq!(0, free_index.free_count());
+
+    // Growing past the end of a cluster skips the next cluster's free space pages
+    let page_id = free_index.allocate_node(None, 4);
+    assert_eq!(NodePageId(DbPageId(6)), page_id);
+    assert_eq!(EOFPageId(DiskPageId(16)), free_index.current_eof());
+    free_index.free_node(TxId::of(2), page_id, 4);
+    free_index.release_pending(None);
+    assert_eq!(
+      vec![10, 11, 14, 15],
+      free_index
+        .free_ids()
+        .into_iter()
+        .map(|page_id| page_id.0)
+        .collect::<Vec<_>>()
+    );
+
+    // The split runs aren't contiguous on disk, so neither is assigned to a node of 4
+    let page_id = free_index.allocate_node(Some((NodePageId(DbPageId(6)), 4)), 4);
+    assert_eq!(NodePageId(DbPageId(10)), page_id);
+    assert_eq!(EOFPageId(DiskPageId(20)), free_index.current_eof());
+
+    // Nor is the file grown into free space pages
+    let mut free_index = FreeIndex::new(translator, [], EOFPageId(DiskPageId(12)));
+    assert_eq!(NodePageId(DbPageId(8)), free_index.allocate_node(None, 1));
+    assert_eq!(EOFPageId(DiskPageId(15)), free_index.current_eof());
   }
 }
@@ -0,0 +1,449 @@
+use crate::common::errors::BucketError;
+use crate::common::id::NodePageId;
+use crate::common::layout::merkle::{NodeHasher, branch_hash_range, bucket_root_hash, value_hash};
+use crate::io::pages::types::node::branch::HasNodes;
+use crate::io::pages::types::node::leaf::HasValues;
+use crate::io::pages::types::node::{HasElements, HasKeys, NodePage};
+use crate::io::pages::{Page, TxReadPageIO};
+use error_stack::ResultExt;
+use hashbrown::HashMap;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync;
+
+pub use crate::common::layout::merkle::MerkleHash;
+
+/// The Merkle hashes of a bucket's tree, including nested buckets. See [`MerkleHash`] for what
+/// each hash covers.
+///
+/// The version 3 format stores the hashes as it commits. Each branch stores its children's
+/// hashes, each nested bucket's value stores its root's hash and `Meta` stores the root bucket's
+/// hash, so [`MerkleTree::stored`] reads them as the tree is walked. Go BBolt's layout has no room
+/// for them, so [`MerkleTree::new`] computes every hash from the pages. Comparing the two checks
+/// the stored hashes against the data.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+  root: NodePageId,
+  root_hash: MerkleHash,
+  /// Every page's hash, or `None` when the hashes are read from the pages
+  hashes: Option<HashMap<NodePageId, MerkleHash>>,
+}
+
+impl MerkleTree {
+  /// Computes the hash of every page reachable from `root`
+  pub fn new<'tx, TX>(tx: &sync::Arc<TX>, root: NodePageId) -> crate::Result<Self, BucketError>
+  where
+    TX: TxReadPageIO<'tx>,
+    <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+    <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  {
+    let mut hashes = HashMap::new();
+    let root_hash = hash_page(tx, root, &mut hashes)?;
+    Ok(MerkleTree {
+      root,
+      root_hash,
+      hashes: Some(hashes),
+    })
+  }
+
+  /// A tree whose hashes are stored in its pages, such as the root bucket of a version 3
+  /// database with `Meta::root_hash`
+  pub fn stored(root: NodePageId, root_hash: MerkleHash) -> Self {
+    MerkleTree {
+      root,
+      root_hash,
+      hashes: None,
+    }
+  }
+
+  #[inline]
+  pub fn root(&self) -> NodePageId {
+    self.root
+  }
+
+  /// The hash of the whole tree
+  #[inline]
+  pub fn root_hash(&self) -> MerkleHash {
+    self.root_hash
+  }
+
+  /// The hash of `page_id` if it was computed with the tree
+  #[inline]
+  pub fn get(&self, page_id: NodePageId) -> Option<MerkleHash> {
+    match &self.hashes {
+      Some(hashes) => hashes.get(&page_id).copied(),
+      None => (page_id == self.root).then_some(self.root_hash),
+    }
+  }
+
+  /// Checks the tree against a previously recorded root hash
+  #[inline]
+  pub fn verify(&self, expected: MerkleHash) -> bool {
+    self.root_hash == expected
+  }
+
+  /// The keys of the bucket whose values, or nested buckets, differ from `other`'s, sorted.
+  ///
+  /// Subtrees with the same hash are skipped, so only the pages leading to a difference are
+  /// read while both trees branch on the same keys.
+  pub fn diff<'a, 'b, A, B>(
+    &self, tx: &sync::Arc<A>, other: &MerkleTree, other_tx: &sync::Arc<B>,
+  ) -> crate::Result<Vec<Vec<u8>>, BucketError>
+  where
+    A: TxReadPageIO<'a>,
+    <A::LeafType as HasKeys<'a>>::TxKv: Deref<Target = [u8]>,
+    <A::BranchType as HasKeys<'a>>::TxKv: Deref<Target = [u8]>,
+    B: TxReadPageIO<'b>,
+    <B::LeafType as HasKeys<'b>>::TxKv: Deref<Target = [u8]>,
+    <B::BranchType as HasKeys<'b>>::TxKv: Deref<Target = [u8]>,
+  {
+    let mut keys = Vec::new();
+    diff_pages(
+      (tx, self, self.root, self.root_hash),
+      (other_tx, other, other.root, other.root_hash),
+      &mut keys,
+    )?;
+    keys.sort_unstable();
+    Ok(keys)
+  }
+
+  /// The hash of the child of element `index` of `branch`
+  fn child_hash<B: Page>(
+    &self, branch: &B, index: usize, child: NodePageId,
+  ) -> crate::Result<MerkleHash, BucketError> {
+    match &self.hashes {
+      Some(hashes) => hashes
+        .get(&child)
+        .copied()
+        .ok_or(BucketError::PageReadError(child).into()),
+      None => stored_child_hash(branch, index),
+    }
+  }
+
+  /// The hash of the tree of the nested bucket stored in `value` with its root at `root`
+  fn nested_hash(&self, root: NodePageId, value: &[u8]) -> crate::Result<MerkleHash, BucketError> {
+    match &self.hashes {
+      Some(hashes) => hashes.get(&root).copied(),
+      None => bucket_root_hash(value),
+    }
+    .ok_or(BucketError::PageReadError(root).into())
+  }
+}
+
+fn stored_child_hash<B: Page>(branch: &B, index: usize) -> crate::Result<MerkleHash, BucketError> {
+  let count = branch.page_header().count() as usize;
+  branch
+    .root_page()
+    .get(branch_hash_range(count, index))
+    .map(bytemuck::pod_read_unaligned)
+    .ok_or(BucketError::ElementReadError(NodePageId(branch.page_header().id()), index).into())
+}
+
+/// Hashes `page_id` from its elements and the hashes stored in the version 3 format, reading
+/// only that page
+pub fn stored_page_hash<'tx, TX>(
+  tx: &sync::Arc<TX>, page_id: NodePageId,
+) -> crate::Result<MerkleHash, BucketError>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  let node = tx
+    .read_node_page(page_id)
+    .change_context(BucketError::PageReadError(page_id))?;
+  match &node {
+    NodePage::Branch(branch) => {
+      let mut hasher = NodeHasher::branch();
+      for index in 0..branch.element_count() {
+        let key = branch
+          .key(index)
+          .ok_or(BucketError::ElementReadError(page_id, index))?;
+        hasher.update(&key, stored_child_hash(branch, index)?);
+      }
+      Ok(hasher.finish())
+    }
+    NodePage::Leaf(leaf) => {
+      let mut hasher = NodeHasher::leaf();
+      for index in 0..leaf.element_count() {
+        let (Some((key, value)), Some(flag)) = (leaf.key_value(index), leaf.leaf_flag(index))
+        else {
+          return Err(BucketError::ElementReadError(page_id, index).into());
+        };
+        let value_hash = value_hash(flag, &value, |root| {
+          bucket_root_hash(&value).ok_or(BucketError::PageReadError(root).into())
+        })?;
+        hasher.update(&key, value_hash);
+      }
+      Ok(hasher.finish())
+    }
+  }
+}
+
+/// Hashes `page_id` and every page below it, children first
+fn hash_page<'tx, TX>(
+  tx: &sync::Arc<TX>, page_id: NodePageId, hashes: &mut HashMap<NodePageId, MerkleHash>,
+) -> crate::Result<MerkleHash, BucketError>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  if let Some(hash) = hashes.get(&page_id) {
+    return Ok(*hash);
+  }
+  let node = tx
+    .read_node_page(page_id)
+    .change_context(BucketError::PageReadError(page_id))?;
+  let hash = match &node {
+    NodePage::Branch(branch) => {
+      let mut hasher = NodeHasher::branch();
+      for index in 0..branch.element_count() {
+        let (Some(key), Some(child)) = (branch.key(index), branch.node(index)) else {
+          return Err(BucketError::ElementReadError(page_id, index).into());
+        };
+        let child_hash = hash_page(tx, child, hashes)?;
+        hasher.update(&key, child_hash);
+      }
+      hasher.finish()
+    }
+    NodePage::Leaf(leaf) => {
+      let mut hasher = NodeHasher::leaf();
+      for index in 0..leaf.element_count() {
+        let (Some((key, value)), Some(flag)) = (leaf.key_value(index), leaf.leaf_flag(index))
+        else {
+          return Err(BucketError::ElementReadError(page_id, index).into());
+        };
+        let value_hash = value_hash(flag, &value, |root| hash_page(tx, root, hashes))?;
+        hasher.update(&key, value_hash);
+      }
+      hasher.finish()
+    }
+  };
+  hashes.insert(page_id, hash);
+  Ok(hash)
+}
+
+/// A page of a tree to diff: the transaction it's read from, its tree, its id and its hash
+type DiffPage<'a, TX> = (&'a sync::Arc<TX>, &'a MerkleTree, NodePageId, MerkleHash);
+
+fn diff_pages<'a, 'b, A, B>(
+  (tx, tree, page_id, hash): DiffPage<'_, A>,
+  (other_tx, other, other_page_id, other_hash): DiffPage<'_, B>, keys: &mut Vec<Vec<u8>>,
+) -> crate::Result<(), BucketError>
+where
+  A: TxReadPageIO<'a>,
+  <A::LeafType as HasKeys<'a>>::TxKv: Deref<Target = [u8]>,
+  <A::BranchType as HasKeys<'a>>::TxKv: Deref<Target = [u8]>,
+  B: TxReadPageIO<'b>,
+  <B::LeafType as HasKeys<'b>>::TxKv: Deref<Target = [u8]>,
+  <B::BranchType as HasKeys<'b>>::TxKv: Deref<Target = [u8]>,
+{
+  if hash == other_hash {
+    return Ok(());
+  }
+  let node = tx
+    .read_node_page(page_id)
+    .change_context(BucketError::PageReadError(page_id))?;
+  let other_node = other_tx
+    .read_node_page(other_page_id)
+    .change_context(BucketError::PageReadError(other_page_id))?;
+  if let (NodePage::Branch(branch), NodePage::Branch(other_branch)) = (&node, &other_node) {
+    // Elements that can't be read fail below when every element is collected
+    let same_keys = branch.element_count() == other_branch.element_count()
+      && (0..branch.element_count()).all(|index| {
+        match (branch.key(index), other_branch.key(index)) {
+          (Some(key), Some(other_key)) => *key == *other_key,
+          _ => false,
+        }
+      });
+    if same_keys {
+      for index in 0..branch.element_count() {
+        let child = branch
+          .node(index)
+          .ok_or(BucketError::ElementReadError(page_id, index))?;
+        let other_child = other_branch
+          .node(index)
+          .ok_or(BucketError::ElementReadError(other_page_id, index))?;
+        diff_pages(
+          (tx, tree, child, tree.child_hash(branch, index, child)?),
+          (
+            other_tx,
+            other,
+            other_child,
+            other.child_hash(other_branch, index, other_child)?,
+          ),
+          keys,
+        )?;
+      }
+      return Ok(());
+    }
+  }
+  // The trees split differently here, so compare every element below both pages
+  let mut values = BTreeMap::new();
+  collect_values(tx, tree, page_id, &mut values)?;
+  let mut other_values = BTreeMap::new();
+  collect_values(other_tx, other, other_page_id, &mut other_values)?;
+  for (key, hash) in &values {
+    if other_values.get(key) != Some(hash) {
+      keys.push(key.clone());
+    }
+  }
+  keys.extend(
+    other_values
+      .into_keys()
+      .filter(|key| !values.contains_key(key)),
+  );
+  Ok(())
+}
+
+fn collect_values<'tx, TX>(
+  tx: &sync::Arc<TX>, tree: &MerkleTree, page_id: NodePageId,
+  values: &mut BTreeMap<Vec<u8>, MerkleHash>,
+) -> crate::Result<(), BucketError>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  <TX::BranchType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  let node = tx
+    .read_node_page(page_id)
+    .change_context(BucketError::PageReadError(page_id))?;
+  match &node {
+    NodePage::Branch(branch) => {
+      for index in 0..branch.element_count() {
+        let child = branch
+          .node(index)
+          .ok_or(BucketError::ElementReadError(page_id, index))?;
+        collect_values(tx, tree, child, values)?;
+      }
+    }
+    NodePage::Leaf(leaf) => {
+      for index in 0..leaf.element_count() {
+        let (Some((key, value)), Some(flag)) = (leaf.key_value(index), leaf.leaf_flag(index))
+        else {
+          return Err(BucketError::ElementReadError(page_id, index).into());
+        };
+        // Nested buckets' hashes are known, so their pages aren't read
+        let value_hash = value_hash(flag, &value, |root| tree.nested_hash(root, &value))?;
+        values.insert(key.to_vec(), value_hash);
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::consts::DbFormat;
+  use crate::components::backend::memmap::{MemmapDb, MemmapTx};
+  use crate::components::backend::temp_db_path;
+  use crate::components::bucket_path::BucketPathBuf;
+  use std::fs;
+
+  fn open_tree(db: &MemmapDb) -> (sync::Arc<MemmapTx<'_>>, MerkleTree) {
+    let tx = db.begin();
+    let tree = MerkleTree::new(&tx, db.meta().root.root().into()).unwrap();
+    (tx, tree)
+  }
+
+  #[test]
+  fn test_merkle_diff() {
+    let root = BucketPathBuf::new();
+    let gears = BucketPathBuf::from(["gears"]);
+    let paths = [temp_db_path("merkle_a"), temp_db_path("merkle_b")];
+    let dbs = paths.clone().map(|path| {
+      let db = MemmapDb::builder().page_size(4096).open_path(path).unwrap();
+      let tx = db.begin_mut().unwrap();
+      tx.create_bucket(&gears).unwrap();
+      for i in 0..1000u32 {
+        let key = format!("key-{:08}", i);
+        tx.put(&root, key.as_bytes(), &i.to_be_bytes()).unwrap();
+      }
+      tx.put(&gears, b"teeth", b"32").unwrap();
+      tx.commit().unwrap();
+      db
+    });
+
+    let [(tx_a, tree_a), (tx_b, tree_b)] = dbs.each_ref().map(open_tree);
+    assert_eq!(tree_a.root_hash(), tree_b.root_hash());
+    assert!(tree_b.verify(tree_a.root_hash()));
+    assert!(tree_a.diff(&tx_a, &tree_b, &tx_b).unwrap().is_empty());
+    drop((tx_a, tx_b));
+
+    let tx = dbs[1].begin_mut().unwrap();
+    tx.put(&root, b"key-00000500", b"changed").unwrap();
+    tx.put(&gears, b"teeth", b"33").unwrap();
+    tx.commit().unwrap();
+    let [(tx_a, tree_a), (tx_b, tree_b)] = dbs.each_ref().map(open_tree);
+    assert_ne!(tree_a.root_hash(), tree_b.root_hash());
+    assert_eq!(
+      vec![b"gears".to_vec(), b"key-00000500".to_vec()],
+      tree_a.diff(&tx_a, &tree_b, &tx_b).unwrap()
+    );
+    drop((tx_a, tx_b));
+    drop(dbs);
+    for path in paths {
+      fs::remove_file(path).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_stored_merkle_hashes() {
+    let root = BucketPathBuf::new();
+    let gears = BucketPathBuf::from(["gears"]);
+    let path = temp_db_path("merkle_stored");
+    let db = MemmapDb::builder()
+      .page_size(4096)
+      .db_tag(DbFormat::BetterBBoltRs.tag())
+      .open_path(path.clone())
+      .unwrap();
+    // Checks the hashes written on commit against the pages they cover
+    let check_meta = |db: &MemmapDb| {
+      let (tx, tree) = open_tree(db);
+      assert_eq!(tree.root_hash(), db.meta().root_hash);
+      let root = tree.root();
+      assert_eq!(tree.root_hash(), stored_page_hash(&tx, root).unwrap());
+      MerkleTree::stored(root, db.meta().root_hash)
+    };
+    check_meta(&db);
+
+    let tx = db.begin_mut().unwrap();
+    tx.create_bucket(&gears).unwrap();
+    for i in 0..1000u32 {
+      let key = format!("key-{:08}", i);
+      tx.put(&root, key.as_bytes(), &i.to_be_bytes()).unwrap();
+      tx.put(&gears, key.as_bytes(), &i.to_be_bytes()).unwrap();
+    }
+    tx.commit().unwrap();
+    let tree_a = check_meta(&db);
+    let tx_a = db.begin();
+
+    let tx = db.begin_mut().unwrap();
+    tx.put(&root, b"key-00000500", b"changed").unwrap();
+    tx.put(&gears, b"key-00000007", b"changed").unwrap();
+    tx.commit().unwrap();
+    let tree_b = check_meta(&db);
+    let tx_b = db.begin();
+    assert_eq!(
+      vec![b"gears".to_vec(), b"key-00000500".to_vec()],
+      tree_a.diff(&tx_a, &tree_b, &tx_b).unwrap()
+    );
+    drop(tx_b);
+
+    // Bumping the sequence keeps the bucket's pages and their hashes
+    let tx = db.begin_mut().unwrap();
+    tx.next_sequence(&gears).unwrap();
+    tx.commit().unwrap();
+    let tree_c = check_meta(&db);
+    let tx_c = db.begin();
+    assert_eq!(
+      vec![b"gears".to_vec(), b"key-00000500".to_vec()],
+      tree_a.diff(&tx_a, &tree_c, &tx_c).unwrap()
+    );
+    drop((tx_a, tx_c));
+    drop(db);
+    fs::remove_file(path).unwrap();
+  }
+}
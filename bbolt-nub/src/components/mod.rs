@@ -6,6 +6,9 @@ pub mod tx;
 
 pub mod backend;
 pub mod bucket_path;
+pub mod check;
+pub mod compact;
 
 pub mod commit;
 pub mod free_index;
+pub mod merkle;
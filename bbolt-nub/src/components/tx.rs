@@ -1,10 +1,23 @@
 use crate::api::tx::TxStats;
+use crate::common::buffer_pool::BufferPool;
+use crate::common::consts::{MAX_KEY_SIZE, MAX_VALUE_SIZE, PGID_NO_FREELIST};
 use crate::common::data_pool::{DataPool, SharedData};
-use crate::common::errors::{IOError, PageError, TxError};
-use crate::common::id::{FreelistPageId, MetaPageId, NodePageId, TxId};
-use crate::common::layout::meta::Meta;
+use crate::common::epoch::EpochGuard;
+use crate::common::errors::{BucketError, IOError, PageError, TxError};
+use crate::common::id::{
+  DbPageId, DiskPageId, DiskPageTranslator, EOFPageId, FreelistPageId, MetaPageId, NodePageId,
+  PageMapTranslator, StableFreeSpaceTranslator, TxId,
+};
+use crate::common::layout::bucket::{BucketHeader, inline_page};
+use crate::common::layout::checksum::verify_page_checksums;
+use crate::common::layout::compression::{
+  compress_value, decompress_leaf, decompress_value, has_compressed_values,
+};
+use crate::common::layout::meta::{HeaderMetaPage, Meta};
+use crate::common::layout::node::LeafFlag;
+use crate::common::layout::page::PageHeader;
 use crate::common::vec_pool::VecPool;
-use crate::components::bucket::{BucketDelta, OnDiskBucket, ValueDelta};
+use crate::components::bucket::{BucketDelta, DeltaKv, OnDiskBucket, ValueDelta};
 use crate::components::bucket_path::BucketPathBuf;
 use crate::io::TxSlot;
 use crate::io::backends::{IOOverflowPageReader, IOPageReader, IOReader};
@@ -13,19 +26,29 @@ use crate::io::bytes::shared_bytes::{SharedBytes, SharedTxBytes};
 use crate::io::bytes::{FromIOBytes, IOBytes, IntoTxBytes, TxBytes};
 use crate::io::pages::direct::DirectPage;
 use crate::io::pages::lazy::LazyPage;
-use crate::io::pages::lazy::ops::RefIntoTryBuf;
-use crate::io::pages::types::freelist::FreelistPage;
+use crate::io::pages::lazy::ops::{RefIntoTryBuf, TryBuf};
+use crate::io::pages::types::free_space::FreeSpacePage;
+use crate::io::pages::types::freelist::{FreelistPage, HasFreelist};
 use crate::io::pages::types::meta::MetaPage;
 use crate::io::pages::types::node::NodePage;
+use crate::io::pages::types::node::branch::HasNodes;
 use crate::io::pages::types::node::branch::bbolt::BBoltBranch;
+use crate::io::pages::types::node::leaf::HasValues;
 use crate::io::pages::types::node::leaf::bbolt::BBoltLeaf;
-use crate::io::pages::{TxPage, TxPageType, TxReadLazyPageIO, TxReadPageIO};
+use crate::io::pages::types::node::{HasElements, HasKeys};
+use crate::io::pages::types::page_map::PageMapPage;
+use crate::io::pages::{GatKvRef, Page, TxPage, TxPageType, TxReadLazyPageIO, TxReadPageIO};
 use delegate::delegate;
 use error_stack::{FutureExt, ResultExt};
-use hashbrown::HashSet;
+use hashbrown::{HashMap, HashSet};
 use parking_lot::{Mutex, RwLockReadGuard, RwLockUpgradableReadGuard};
+use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::sync;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::ops::Deref;
+use std::path::Path;
+use std::{mem, slice, sync};
 
 pub trait TheTx<'tx>: TxReadPageIO<'tx> {
   fn stats(&self) -> &TxStats;
@@ -46,6 +69,17 @@ pub enum IOLockGuard<'tx, IO> {
   U(RwLockUpgradableReadGuard<'tx, IO>),
 }
 
+impl<'tx, IO> Deref for IOLockGuard<'tx, IO> {
+  type Target = IO;
+
+  fn deref(&self) -> &Self::Target {
+    match self {
+      IOLockGuard::R(io) => io,
+      IOLockGuard::U(io) => io,
+    }
+  }
+}
+
 impl<'tx, IO> From<RwLockReadGuard<'tx, IO>> for IOLockGuard<'tx, IO>
 where
   IO: IOPageReader,
@@ -92,6 +126,36 @@ where
       IOLockGuard::U(io) => io.read_node_page(node_page_id),
     }
   }
+
+  fn read_node_pages(
+    &self, node_page_ids: &[NodePageId],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    match self {
+      IOLockGuard::R(io) => io.read_node_pages(node_page_ids),
+      IOLockGuard::U(io) => io.read_node_pages(node_page_ids),
+    }
+  }
+
+  fn read_raw_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError> {
+    match self {
+      IOLockGuard::R(io) => io.read_raw_page(disk_page_id),
+      IOLockGuard::U(io) => io.read_raw_page(disk_page_id),
+    }
+  }
+
+  fn read_contig_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError> {
+    match self {
+      IOLockGuard::R(io) => io.read_contig_page(disk_page_id),
+      IOLockGuard::U(io) => io.read_contig_page(disk_page_id),
+    }
+  }
+
+  fn page_checksums(&self) -> bool {
+    match self {
+      IOLockGuard::R(io) => io.page_checksums(),
+      IOLockGuard::U(io) => io.page_checksums(),
+    }
+  }
 }
 
 impl<'tx, IO> IOOverflowPageReader for IOLockGuard<'tx, IO>
@@ -121,6 +185,32 @@ pub struct CoreTxHandle<'tx, IO> {
   pub(crate) io: IOLockGuard<'tx, IO>,
   pub(crate) stats: sync::Arc<TxStats>,
   pub(crate) tx_id: TxId,
+  /// Keeps the IO's retired regions alive while any `TxSlot<'tx>` borrowed from this
+  /// transaction may still point into them
+  pub(crate) epoch: Option<EpochGuard>,
+  /// Registers a read transaction by `tx_id` so the pages it can see aren't reused
+  pub(crate) reader: Option<EpochGuard>,
+  /// Where the node pages are stored in the version 3 and clustered formats, as of `tx_id`.
+  /// Read by [`RefTxHandle`].
+  pub(crate) page_map: Option<TxPageMap>,
+}
+
+/// Where a transaction finds the node pages of formats whose node page ids aren't disk page ids
+pub enum TxPageMap {
+  /// A snapshot of the version 3 page map
+  PageMap(PageMapTranslator),
+  /// The clustered layout, with the free space region of the transaction's meta
+  Clustered(StableFreeSpaceTranslator),
+}
+
+impl TxPageMap {
+  /// The disk page `page_id` is stored at, if it's stored at all
+  pub fn get(&self, page_id: NodePageId) -> Option<DiskPageId> {
+    match self {
+      TxPageMap::PageMap(translator) => translator.get(page_id),
+      TxPageMap::Clustered(translator) => Some(translator.node_to_disk(page_id)),
+    }
+  }
 }
 
 pub struct SharedTxHandle<'tx, IO> {
@@ -164,14 +254,46 @@ where
   fn read_node_page(
     self: &sync::Arc<Self>, node_page_id: NodePageId,
   ) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError> {
-    let page = self
+    let bytes = self
       .handle
       .io
       .read_node_page(node_page_id)
-      .map(|bytes| DirectPage::new(bytes.into_tx()))
+      .change_context(PageError::InvalidNode(node_page_id))?;
+    let page = share_decompressed(bytes.into_tx())
+      .map(DirectPage::new)
       .change_context(PageError::InvalidNode(node_page_id))?;
     NodePage::try_from(TxPage::new(page)).change_context(PageError::InvalidNode(node_page_id))
   }
+
+  fn read_inline_node(
+    self: &sync::Arc<Self>, value: <Self::LeafType as HasKeys<'tx>>::TxKv,
+  ) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError> {
+    let page = DirectPage::new(SharedTxBytes::new(share_inline_page(&value)?));
+    NodePage::try_from(TxPage::new(page)).change_context(PageError::InvalidInlineNode)
+  }
+
+  fn read_node_pages(
+    self: &sync::Arc<Self>, node_page_ids: &[NodePageId],
+  ) -> crate::Result<Vec<NodePage<Self::BranchType, Self::LeafType>>, PageError> {
+    let Some(first_page_id) = node_page_ids.first().copied() else {
+      return Ok(Vec::new());
+    };
+    let pages = self
+      .handle
+      .io
+      .read_node_pages(node_page_ids)
+      .change_context(PageError::InvalidNode(first_page_id))?;
+    node_page_ids
+      .iter()
+      .zip(pages)
+      .map(|(node_page_id, bytes)| {
+        let page = share_decompressed(bytes.into_tx())
+          .map(DirectPage::new)
+          .change_context(PageError::InvalidNode(*node_page_id))?;
+        NodePage::try_from(TxPage::new(page)).change_context(PageError::InvalidNode(*node_page_id))
+      })
+      .collect()
+  }
 }
 
 impl<'tx, IO> TheTx<'tx> for SharedTxHandle<'tx, IO>
@@ -185,8 +307,51 @@ where
   }
 }
 
+/// Copies `page` into a buffer of its own, aligned for the page header
+fn share_page(page: &[u8]) -> SharedBytes {
+  BufferPool::new_unbound(page.len()).fill_and_share(|buffer| buffer.copy_from_slice(page))
+}
+
+/// Reads a leaf with compressed values as though they were stored decompressed, like
+/// [`RefTxHandle`]. The decompressed leaf is dropped with the last page that shares it.
+fn share_decompressed(bytes: SharedTxBytes<'_>) -> crate::Result<SharedTxBytes<'_>, BucketError> {
+  Ok(match decompress_leaf(&bytes)? {
+    Some(leaf) => SharedTxBytes::new(share_page(&leaf)),
+    None => bytes,
+  })
+}
+
+/// Copies the leaf page of an inline bucket out of its bucket `value` into a buffer of its own,
+/// with its values decompressed
+fn share_inline_page(value: &[u8]) -> crate::Result<SharedBytes, PageError> {
+  let page = inline_page(value).ok_or(PageError::InvalidInlineNode)?;
+  let leaf = decompress_leaf(page).change_context(PageError::InvalidInlineNode)?;
+  Ok(share_page(leaf.as_deref().unwrap_or(page)))
+}
+
+/// Where a page kept by [`RefTxHandle`] was read from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum KeptPage {
+  Node(NodePageId),
+  /// The page of an inline bucket, by the address of the bucket's value. Values live as long as
+  /// the transaction, so no other value has the same address.
+  Inline(usize),
+}
+
 pub struct RefTxHandle<'tx, IO> {
   pub(crate) handle: CoreTxHandle<'tx, IO>,
+  /// Nodes gathered from scattered pages, leaves with their values decompressed and the pages of
+  /// inline buckets. Each is kept once however often it's read.
+  kept: Mutex<HashMap<KeptPage, Box<[u8]>>>,
+}
+
+impl<'tx, IO> RefTxHandle<'tx, IO> {
+  pub fn new(handle: CoreTxHandle<'tx, IO>) -> Self {
+    RefTxHandle {
+      handle,
+      kept: Mutex::new(HashMap::new()),
+    }
+  }
 }
 
 impl<'tx, IO> TxReadPageIO<'tx> for RefTxHandle<'tx, IO>
@@ -226,13 +391,72 @@ where
   fn read_node_page(
     self: &sync::Arc<Self>, node_page_id: NodePageId,
   ) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError> {
-    let page = self
+    let key = KeptPage::Node(node_page_id);
+    let bytes = match self.kept(key) {
+      Some(bytes) => bytes,
+      None => {
+        let bytes = self
+          .read_node_bytes(node_page_id)
+          .change_context(PageError::InvalidNode(node_page_id))?;
+        self
+          .decompress_leaf(key, bytes)
+          .change_context(PageError::InvalidNode(node_page_id))?
+      }
+    };
+    NodePage::try_from(TxPage::new(DirectPage::new(bytes)))
+      .change_context(PageError::InvalidNode(node_page_id))
+  }
+
+  fn read_inline_node(
+    self: &sync::Arc<Self>, value: <Self::LeafType as HasKeys<'tx>>::TxKv,
+  ) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError> {
+    let key = KeptPage::Inline(value.as_ptr() as usize);
+    let bytes = match self.kept(key) {
+      Some(bytes) => bytes,
+      None => {
+        let page = inline_page(&value).ok_or(PageError::InvalidInlineNode)?;
+        // The page is rarely aligned within the value
+        self
+          .decompress_leaf(key, Cow::Owned(page.to_vec()))
+          .change_context(PageError::InvalidInlineNode)?
+      }
+    };
+    NodePage::try_from(TxPage::new(DirectPage::new(bytes)))
+      .change_context(PageError::InvalidInlineNode)
+  }
+
+  fn read_node_pages(
+    self: &sync::Arc<Self>, node_page_ids: &[NodePageId],
+  ) -> crate::Result<Vec<NodePage<Self::BranchType, Self::LeafType>>, PageError> {
+    if self.handle.page_map.is_some() {
+      return node_page_ids
+        .iter()
+        .map(|node_page_id| self.read_node_page(*node_page_id))
+        .collect();
+    }
+    let Some(first_page_id) = node_page_ids.first().copied() else {
+      return Ok(Vec::new());
+    };
+    let pages = self
       .handle
       .io
-      .read_node_page(node_page_id)
-      .map(|bytes| DirectPage::new(bytes.into_tx()))
-      .change_context(PageError::InvalidNode(node_page_id))?;
-    NodePage::try_from(TxPage::new(page)).change_context(PageError::InvalidNode(node_page_id))
+      .read_node_pages(node_page_ids)
+      .change_context(PageError::InvalidNode(first_page_id))?;
+    node_page_ids
+      .iter()
+      .zip(pages)
+      .map(|(node_page_id, bytes)| {
+        let key = KeptPage::Node(*node_page_id);
+        let bytes = match self.kept(key) {
+          Some(bytes) => bytes,
+          None => self
+            .decompress_leaf(key, Cow::Borrowed(bytes.into_tx().as_tx_bytes()))
+            .change_context(PageError::InvalidNode(*node_page_id))?,
+        };
+        NodePage::try_from(TxPage::new(DirectPage::new(bytes)))
+          .change_context(PageError::InvalidNode(*node_page_id))
+      })
+      .collect()
   }
 }
 
@@ -247,6 +471,239 @@ where
   }
 }
 
+impl<'tx, IO> RefTxHandle<'tx, IO>
+where
+  IO: IOPageReader,
+  IO::Bytes: IntoTxBytes<'tx, RefTxBytes<'tx>>,
+{
+  /// Reads a node page and its overflow, through the page map when there is one. A node whose
+  /// pages are scattered is gathered into a buffer of its own.
+  fn read_node_bytes(&self, node_page_id: NodePageId) -> crate::Result<Cow<'tx, [u8]>, IOError> {
+    let Some(translator) = &self.handle.page_map else {
+      return self
+        .handle
+        .io
+        .read_node_page(node_page_id)
+        .map(|bytes| Cow::Borrowed(bytes.into_tx().as_tx_bytes()));
+    };
+    let disk_page_id = translator
+      .get(node_page_id)
+      .ok_or(IOError::Unmapped(node_page_id))?;
+    let first = self.handle.io.read_raw_page(disk_page_id)?;
+    let header: PageHeader = bytemuck::pod_read_unaligned(&first[0..size_of::<PageHeader>()]);
+    let overflow_ids = (1..=header.get_overflow())
+      .map(|overflow| {
+        translator
+          .get(node_page_id + overflow)
+          .ok_or(IOError::Unmapped(node_page_id + overflow))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    if (1..)
+      .zip(&overflow_ids)
+      .all(|(overflow, page_id)| *page_id == disk_page_id + overflow)
+    {
+      return self
+        .handle
+        .io
+        .read_contig_page(disk_page_id)
+        .map(|bytes| Cow::Borrowed(bytes.into_tx().as_tx_bytes()));
+    }
+    let mut gathered = Vec::with_capacity(first.len() * (overflow_ids.len() + 1));
+    gathered.extend_from_slice(&first);
+    for page_id in overflow_ids {
+      gathered.extend_from_slice(&self.handle.io.read_raw_page(page_id)?);
+    }
+    // Only contiguous reads verify the footer themselves
+    if self.handle.io.page_checksums() {
+      verify_page_checksums(&gathered, first.len())
+        .change_context(IOError::ReadError(disk_page_id))?;
+    }
+    Ok(Cow::Owned(gathered))
+  }
+
+  /// Reads a leaf with compressed values as though they were stored decompressed, so its values
+  /// are what was put. The elements keep [`LeafFlag::COMPRESSED`]. Decompressed leaves and
+  /// buffers of their own are kept as `key`.
+  fn decompress_leaf(
+    &self, key: KeptPage, bytes: Cow<'tx, [u8]>,
+  ) -> crate::Result<RefTxBytes<'tx>, BucketError> {
+    Ok(match (decompress_leaf(&bytes)?, bytes) {
+      (Some(leaf), _) => self.keep(key, leaf),
+      (None, Cow::Borrowed(bytes)) => RefTxBytes::new(bytes),
+      (None, Cow::Owned(buffer)) => self.keep(key, buffer),
+    })
+  }
+
+  /// The page kept as `key`, if it was read before
+  fn kept(&self, key: KeptPage) -> Option<RefTxBytes<'tx>> {
+    let kept = self.kept.lock();
+    let buffer = kept.get(&key)?;
+    // See `keep`
+    Some(RefTxBytes::new(unsafe {
+      slice::from_raw_parts(buffer.as_ptr(), buffer.len())
+    }))
+  }
+
+  /// Keeps `buffer` as `key` until the transaction is dropped. A page kept before is returned in
+  /// its place, so each page is kept once.
+  fn keep(&self, key: KeptPage, buffer: Vec<u8>) -> RefTxBytes<'tx> {
+    let mut kept = self.kept.lock();
+    let buffer = kept.entry(key).or_insert_with(|| buffer.into_boxed_slice());
+    // Like the memory map regions kept alive by the epoch, the buffer isn't dropped until the
+    // transaction is. Its contents stay put when the map moves the box.
+    let bytes = unsafe { slice::from_raw_parts(buffer.as_ptr(), buffer.len()) };
+    RefTxBytes::new(bytes)
+  }
+
+  /// Reads the version 3 page map page at `page_id`
+  pub fn read_page_map_page(
+    self: &sync::Arc<Self>, page_id: FreelistPageId,
+  ) -> crate::Result<PageMapPage<'tx, DirectPage<'tx, RefTxBytes<'tx>>>, PageError> {
+    // The page map page itself isn't mapped
+    let page = self
+      .handle
+      .io
+      .read_contig_page(DiskPageId(page_id.0.0))
+      .map(|bytes| DirectPage::new(bytes.into_tx()))
+      .change_context(PageError::InvalidPageMap(page_id))?;
+    PageMapPage::try_from(TxPage::new(page)).change_context(PageError::InvalidPageMap(page_id))
+  }
+
+  /// Reads the free space page at `page_id` from the region of the transaction's meta in the
+  /// clustered format
+  pub fn read_free_space_page(
+    self: &sync::Arc<Self>, page_id: FreelistPageId,
+  ) -> crate::Result<FreeSpacePage<'tx, DirectPage<'tx, RefTxBytes<'tx>>>, PageError> {
+    let Some(TxPageMap::Clustered(translator)) = &self.handle.page_map else {
+      return Err(PageError::InvalidFreeSpace(page_id).into());
+    };
+    let page = self
+      .handle
+      .io
+      .read_contig_page(translator.freelist_to_disk(page_id))
+      .map(|bytes| DirectPage::new(bytes.into_tx()))
+      .change_context(PageError::InvalidFreeSpace(page_id))?;
+    let id = page.page_header().id();
+    if id != page_id.0 {
+      return Err(PageError::UnexpectedDbPageId(page_id.0, id))
+        .change_context(PageError::InvalidFreeSpace(page_id));
+    }
+    FreeSpacePage::try_from(TxPage::new(page)).change_context(PageError::InvalidFreeSpace(page_id))
+  }
+
+  /// The pages that are free as of `meta`. See [`free_page_ids`]. In the version 3 format they
+  /// are every page past the meta pages that neither a node nor the page map is stored at, and
+  /// in the clustered format they're read from the free space pages of `meta`'s region.
+  pub fn free_page_ids(
+    self: &sync::Arc<Self>, meta: &Meta,
+  ) -> crate::Result<Vec<DiskPageId>, TxError> {
+    let page_map = match &self.handle.page_map {
+      None => return free_page_ids(self, meta),
+      Some(TxPageMap::Clustered(translator)) => {
+        return self.clustered_free_page_ids(translator, meta.eof_id);
+      }
+      Some(TxPageMap::PageMap(page_map)) => page_map,
+    };
+    let page_map_page = self
+      .read_page_map_page(meta.free_list)
+      .change_context(TxError::FreePagesError)?;
+    let start = DiskPageId(meta.free_list.0.0);
+    let page_map_pages = start..start + page_map_page.page_header().get_overflow() as u64 + 1;
+    Ok(
+      (2..meta.eof_id.0.0)
+        .map(DiskPageId)
+        .filter(|page_id| !page_map_pages.contains(page_id) && page_map.node_at(*page_id).is_none())
+        .collect(),
+    )
+  }
+
+  fn clustered_free_page_ids(
+    self: &sync::Arc<Self>, translator: &StableFreeSpaceTranslator, eof: EOFPageId,
+  ) -> crate::Result<Vec<DiskPageId>, TxError> {
+    let tracked_len = translator.tracked_len();
+    let mut free = Vec::new();
+    for index in 0..translator.free_space_len(eof) {
+      let free_space = self
+        .read_free_space_page(FreelistPageId(DbPageId(index)))
+        .change_context(TxError::FreePagesError)?;
+      let first = index * tracked_len;
+      free.extend(
+        free_space
+          .free_offsets()
+          .map(|offset| translator.node_to_disk(NodePageId(DbPageId(first + offset))))
+          .filter(|page_id| *page_id < eof.0),
+      );
+    }
+    Ok(free)
+  }
+
+  /// Writes the database as the transaction sees it through `meta` to `w`, returning the number
+  /// of bytes written. `Tx.WriteTo` in Go BBolt.
+  ///
+  /// Both meta pages are rewritten from `meta`, followed by every page below `meta.eof_id`. The
+  /// pages the transaction can see aren't reused until it ends, so the copy stays consistent
+  /// while writers commit. Writers may reuse free pages at any time so what they hold is
+  /// meaningless, and with `skip_free` they're written as zeroes without being read.
+  pub fn write_to<W: Write>(
+    self: &sync::Arc<Self>, meta: &Meta, mut w: W, skip_free: bool,
+  ) -> crate::Result<u64, TxError> {
+    let page_size = meta.page_size as usize;
+    let free: HashSet<DiskPageId> = if skip_free {
+      self.free_page_ids(meta)?.into_iter().collect()
+    } else {
+      HashSet::new()
+    };
+    let mut page = vec![0u8; page_size];
+    for page_id in 0..2 {
+      let meta_page = HeaderMetaPage {
+        header: PageHeader::init_meta(DbPageId(page_id)),
+        meta: *meta,
+      };
+      let meta_bytes = bytemuck::bytes_of(&meta_page);
+      page[0..meta_bytes.len()].copy_from_slice(meta_bytes);
+      w.write_all(&page).change_context(TxError::WriteError)?;
+    }
+    page.fill(0);
+    for page_id in (2..meta.eof_id.0.0).map(DiskPageId) {
+      if free.contains(&page_id) {
+        w.write_all(&page).change_context(TxError::WriteError)?;
+        continue;
+      }
+      let bytes = self
+        .handle
+        .io
+        .read_raw_page(page_id)
+        .change_context(TxError::PageReadError(page_id))?;
+      w.write_all(&bytes).change_context(TxError::WriteError)?;
+    }
+    w.flush().change_context(TxError::WriteError)?;
+    Ok(meta.eof_id.0.0 * page_size as u64)
+  }
+
+  /// Writes the database as the transaction sees it through `meta` to a new file at `path` and
+  /// syncs it. See [`RefTxHandle::write_to`]. `Tx.CopyFile` in Go BBolt
+  pub fn copy_file<P: AsRef<Path>>(
+    self: &sync::Arc<Self>, meta: &Meta, path: P, skip_free: bool,
+  ) -> crate::Result<(), TxError> {
+    let path = path.as_ref();
+    let error = || TxError::CopyFileError(path.to_path_buf());
+    let file = OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(path)
+      .change_context_lazy(error)?;
+    let mut w = BufWriter::new(file);
+    self
+      .write_to(meta, &mut w, skip_free)
+      .change_context_lazy(error)?;
+    let file = w
+      .into_inner()
+      .map_err(|err| err.into_error())
+      .change_context_lazy(error)?;
+    file.sync_all().change_context_lazy(error)
+  }
+}
+
 pub struct LazyTxHandle<'tx, IO> {
   pub(crate) handle: CoreTxHandle<'tx, IO>,
 }
@@ -281,6 +738,11 @@ where
       .read_freelist_page(freelist_page_id)
       .change_context(PageError::InvalidFreelist(freelist_page_id))?;
     let page = LazyPage::new(IntoTxBytes::<'tx>::into_tx(bytes), self);
+    if self.page_checksums() {
+      page
+        .verify_root()
+        .change_context(PageError::InvalidFreelist(freelist_page_id))?;
+    }
     FreelistPage::try_from(TxPage::new(page))
       .change_context(PageError::InvalidFreelist(freelist_page_id))
   }
@@ -294,8 +756,57 @@ where
       .read_node_page(node_page_id)
       .change_context(PageError::InvalidNode(node_page_id))?;
     let page = LazyPage::new(IntoTxBytes::<'tx>::into_tx(bytes), self);
+    if self.page_checksums() {
+      page
+        .verify_root()
+        .change_context(PageError::InvalidNode(node_page_id))?;
+    }
+    let page = decompress_lazy_leaf(page).change_context(PageError::InvalidNode(node_page_id))?;
     NodePage::try_from(TxPage::new(page)).change_context(PageError::InvalidNode(node_page_id))
   }
+
+  fn read_inline_node(
+    self: &sync::Arc<Self>, value: <Self::LeafType as HasKeys<'tx>>::TxKv,
+  ) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError> {
+    let mut try_buf = value
+      .ref_into_try_buf()
+      .change_context(PageError::InvalidInlineNode)?;
+    let mut bytes = Vec::with_capacity(try_buf.remaining());
+    while try_buf.remaining() != 0 {
+      let chunk_len = try_buf.chunk().len();
+      bytes.extend_from_slice(try_buf.chunk());
+      try_buf
+        .try_advance(chunk_len)
+        .change_context(PageError::InvalidInlineNode)?;
+    }
+    let page = LazyPage::new(SharedTxBytes::new(share_inline_page(&bytes)?), self);
+    NodePage::try_from(TxPage::new(page)).change_context(PageError::InvalidInlineNode)
+  }
+}
+
+/// Reads a leaf with compressed values as though they were stored decompressed, like
+/// [`RefTxHandle`]. The overflow is only read when the first page holds compressed values or
+/// doesn't hold every element.
+fn decompress_lazy_leaf<'tx, L>(
+  page: LazyPage<'tx, L>,
+) -> crate::Result<LazyPage<'tx, L>, BucketError>
+where
+  L: TxReadLazyPageIO<'tx, TxPageType = LazyPage<'tx, L>>,
+{
+  if has_compressed_values(page.root_page()) == Some(false) {
+    return Ok(page);
+  }
+  let mut bytes = Vec::with_capacity(page.len());
+  for overflow_index in 0..=page.page_header().get_overflow() {
+    let overflow = page
+      .read_overflow_page(overflow_index)
+      .change_context(BucketError::Decompress)?;
+    bytes.extend_from_slice(&overflow);
+  }
+  Ok(match decompress_leaf(&bytes)? {
+    Some(leaf) => LazyPage::new_whole(SharedTxBytes::new(share_page(&leaf))),
+    None => page,
+  })
 }
 
 impl<'tx, IO> TxReadLazyPageIO<'tx> for LazyTxHandle<'tx, IO>
@@ -322,6 +833,11 @@ where
       .read_node_overflow(node_page_id, overflow)
       .map(|bytes| bytes.into_tx())
   }
+
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self.handle.io.page_checksums()
+  }
 }
 
 impl<'tx, IO> TheTx<'tx> for LazyTxHandle<'tx, IO>
@@ -344,9 +860,56 @@ where
 
 pub struct MutTxHandle<TX> {
   tx: sync::Arc<TX>,
+  /// The root bucket as of the start of the transaction
+  root: BucketHeader,
   data_pool: DataPool,
   key_set: Mutex<HashSet<SharedData>>,
   delta_map: Mutex<BTreeMap<BucketPathBuf, BucketDelta>>,
+  freed: Mutex<Vec<(NodePageId, u32)>>,
+}
+
+impl<TX> MutTxHandle<TX> {
+  /// A transaction against the root bucket `root`, which compresses values at least
+  /// `compression` bytes long
+  pub fn new(
+    tx: sync::Arc<TX>, data_pool: DataPool, root: BucketHeader, compression: Option<usize>,
+  ) -> Self {
+    let mut delta_map = BTreeMap::new();
+    delta_map.insert(BucketPathBuf::new(), BucketDelta::new(root, compression));
+    MutTxHandle {
+      tx,
+      root,
+      data_pool,
+      key_set: Mutex::new(HashSet::new()),
+      delta_map: Mutex::new(delta_map),
+      freed: Mutex::new(Vec::new()),
+    }
+  }
+
+  #[inline]
+  pub fn tx(&self) -> &sync::Arc<TX> {
+    &self.tx
+  }
+
+  /// The root bucket's compression threshold as of this transaction
+  pub(crate) fn root_compression(&self) -> Option<usize> {
+    self
+      .delta_map
+      .lock()
+      .get(&BucketPathBuf::new())
+      .and_then(|delta| delta.compression())
+  }
+
+  /// Removes every bucket delta accumulated by the transaction, keyed by bucket path.
+  /// The root bucket's path is empty.
+  pub(crate) fn take_deltas(&self) -> BTreeMap<BucketPathBuf, BucketDelta> {
+    mem::take(&mut *self.delta_map.lock())
+  }
+
+  /// Removes the on-disk pages released by deleted buckets
+  pub(crate) fn take_freed(&self) -> Vec<(NodePageId, u32)> {
+    mem::take(&mut *self.freed.lock())
+  }
 }
 
 impl<'tx, TX> TxReadPageIO<'tx> for MutTxHandle<TX>
@@ -362,6 +925,8 @@ where
       fn read_meta_page(self: &sync::Arc<Self>, meta_page_id: MetaPageId) -> crate::Result<MetaPage<'tx, Self::TxPageType>, PageError>;
       fn read_freelist_page(self: &sync::Arc<Self>, freelist_page_id: FreelistPageId) -> crate::Result<FreelistPage<'tx, Self::TxPageType>, PageError>;
       fn read_node_page(self: &sync::Arc<Self>, node_page_id: NodePageId) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError>;
+      fn read_inline_node(self: &sync::Arc<Self>, value: <Self::LeafType as HasKeys<'tx>>::TxKv) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError>;
+      fn read_node_pages(self: &sync::Arc<Self>, node_page_ids: &[NodePageId]) -> crate::Result<Vec<NodePage<Self::BranchType, Self::LeafType>>, PageError>;
       }
   }
 }
@@ -427,4 +992,337 @@ where
   }
 }
 
-impl<'tx, TX> MutTxHandle<TX> where TX: TheTx<'tx> {}
+impl<'tx, TX> MutTxHandle<TX>
+where
+  TX: TheTx<'tx>,
+  for<'b> <TX::BranchType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
+  for<'b> <TX::LeafType as GatKvRef<'b>>::KvRef: PartialOrd<[u8]>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]> + AsRef<[u8]>,
+{
+  /// The bucket at `path` as of the start of the transaction, whose header is `header`. A
+  /// bucket stored inline owns no pages, so it's found again from the root.
+  pub(crate) fn on_disk_bucket(
+    self: &sync::Arc<Self>, path: &BucketPathBuf, header: BucketHeader,
+  ) -> crate::Result<OnDiskBucket<TX::BranchType, TX::LeafType, Self>, BucketError> {
+    if header.root().0.0 != 0 {
+      return OnDiskBucket::new(self.clone(), VecPool::new(0, 0, 16), header);
+    }
+    let mut bucket = OnDiskBucket::new(self.clone(), VecPool::new(0, 0, 16), self.root)?;
+    for key in path {
+      bucket = bucket.bucket(key)?.ok_or(BucketError::BucketNotFound)?;
+    }
+    Ok(bucket)
+  }
+
+  /// Resolves the delta for the bucket at `path`, loading its header from disk on first use.
+  /// Returns `None` if the bucket does not exist.
+  fn bucket_delta(
+    self: &sync::Arc<Self>, path: &BucketPathBuf,
+  ) -> crate::Result<Option<BucketDelta>, BucketError> {
+    if let Some(delta) = self.delta_map.lock().get(path) {
+      return Ok(Some(delta.clone()));
+    }
+    let key = path.last().expect("the root bucket always has a delta");
+    let mut parent_path = path.clone();
+    parent_path.pop();
+    let parent = match self.bucket_delta(&parent_path)? {
+      Some(parent) => parent,
+      None => return Ok(None),
+    };
+    if let Some(value_delta) = parent.lock().get(key) {
+      return match value_delta {
+        ValueDelta::UValue(_) | ValueDelta::UCompressed(_) => Err(BucketError::ValueIsBytes.into()),
+        ValueDelta::UBucket(..) => unreachable!("created buckets always have a delta"),
+        ValueDelta::Delete => Ok(None),
+      };
+    }
+    let header = match parent.on_disk() {
+      Some(header) => header,
+      None => return Ok(None),
+    };
+    match self
+      .on_disk_bucket(&parent_path, header)?
+      .bucket_element(key)?
+    {
+      Some((flag, header)) => {
+        let delta = BucketDelta::new(header, flag.compression_threshold());
+        self.delta_map.lock().insert(path.clone(), delta.clone());
+        Ok(Some(delta))
+      }
+      None => Ok(None),
+    }
+  }
+
+  fn check_key(key: &[u8]) -> crate::Result<(), BucketError> {
+    if key.is_empty() {
+      Err(BucketError::KeyRequired.into())
+    } else if key.len() > MAX_KEY_SIZE {
+      Err(BucketError::KeyTooLarge.into())
+    } else {
+      Ok(())
+    }
+  }
+
+  pub fn bucket_exists(
+    self: &sync::Arc<Self>, path: &BucketPathBuf,
+  ) -> crate::Result<bool, BucketError> {
+    Ok(self.bucket_delta(path)?.is_some())
+  }
+
+  pub fn get(
+    self: &sync::Arc<Self>, path: &BucketPathBuf, key: &[u8],
+  ) -> crate::Result<Option<DeltaKv<<TX::LeafType as HasKeys<'tx>>::TxKv>>, BucketError> {
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    if let Some(value_delta) = delta.lock().get(key) {
+      return match value_delta {
+        ValueDelta::UValue(value) => Ok(Some(DeltaKv::Delta(value.clone()))),
+        ValueDelta::UCompressed(value) => {
+          Ok(Some(DeltaKv::Decompressed(decompress_value(value)?.into())))
+        }
+        ValueDelta::UBucket(..) => Err(BucketError::ValueIsABucket.into()),
+        ValueDelta::Delete => Ok(None),
+      };
+    }
+    match delta.on_disk() {
+      Some(header) => self.on_disk_bucket(path, header)?.get(key),
+      None => Ok(None),
+    }
+  }
+
+  pub fn put(
+    self: &sync::Arc<Self>, path: &BucketPathBuf, key: &[u8], value: &[u8],
+  ) -> crate::Result<(), BucketError> {
+    if value.len() > MAX_VALUE_SIZE {
+      return Err(BucketError::ValueTooLarge.into());
+    }
+    Self::check_key(key)?;
+    // Fails if the key currently holds a bucket
+    self.get(path, key)?;
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    let compressed = delta
+      .compression()
+      .and_then(|threshold| compress_value(value, threshold));
+    let value = match compressed {
+      Some(compressed) => ValueDelta::UCompressed(self.clone_value(&compressed)),
+      None => ValueDelta::UValue(self.clone_value(value)),
+    };
+    let key = self.clone_key(key);
+    delta.lock().insert(key, value);
+    Ok(())
+  }
+
+  /// The size values put into the bucket at `path` are compressed from, if they're compressed
+  pub fn compression(
+    self: &sync::Arc<Self>, path: &BucketPathBuf,
+  ) -> crate::Result<Option<usize>, BucketError> {
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    Ok(delta.compression())
+  }
+
+  /// Compresses values put into the bucket at `path` that are at least `threshold` bytes long.
+  /// `None` stops compressing. The threshold is stored with the bucket when the transaction
+  /// commits. Values already stored are read back the same either way.
+  pub fn set_compression(
+    self: &sync::Arc<Self>, path: &BucketPathBuf, threshold: Option<usize>,
+  ) -> crate::Result<(), BucketError> {
+    if let Some(threshold) = threshold.filter(|t| *t > LeafFlag::MAX_COMPRESSION_THRESHOLD) {
+      return Err(BucketError::CompressionThreshold(threshold).into());
+    }
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    delta.set_compression(threshold);
+    Ok(())
+  }
+
+  pub fn delete(
+    self: &sync::Arc<Self>, path: &BucketPathBuf, key: &[u8],
+  ) -> crate::Result<(), BucketError> {
+    // Fails if the key currently holds a bucket
+    self.get(path, key)?;
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    if delta.is_new() {
+      delta.lock().remove(key);
+    } else {
+      let key = self.clone_key(key);
+      delta.lock().insert(key, ValueDelta::Delete);
+    }
+    Ok(())
+  }
+
+  pub fn create_bucket(
+    self: &sync::Arc<Self>, path: &BucketPathBuf,
+  ) -> crate::Result<(), BucketError> {
+    let key = path.last().ok_or(BucketError::KeyRequired)?;
+    Self::check_key(key)?;
+    if self.bucket_delta(path)?.is_some() {
+      return Err(BucketError::BucketExists.into());
+    }
+    let mut parent_path = path.clone();
+    parent_path.pop();
+    if self.get(&parent_path, key)?.is_some() {
+      return Err(BucketError::ValueIsBytes.into());
+    }
+    let parent = self
+      .bucket_delta(&parent_path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    // The header is rewritten with the bucket's real root when the transaction commits
+    let header = self.clone_value(bytemuck::bytes_of(&BucketHeader::default()));
+    parent.lock().insert(
+      self.clone_key(key),
+      ValueDelta::UBucket(header, LeafFlag::BUCKET),
+    );
+    self
+      .delta_map
+      .lock()
+      .insert(path.clone(), BucketDelta::new_bucket());
+    Ok(())
+  }
+
+  /// The sequence of the bucket at `path`. `Bucket.Sequence()` in Go BBolt
+  pub fn sequence(self: &sync::Arc<Self>, path: &BucketPathBuf) -> crate::Result<u64, BucketError> {
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    Ok(delta.sequence())
+  }
+
+  /// `Bucket.SetSequence()` in Go BBolt
+  pub fn set_sequence(
+    self: &sync::Arc<Self>, path: &BucketPathBuf, sequence: u64,
+  ) -> crate::Result<(), BucketError> {
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    delta.set_sequence(sequence);
+    Ok(())
+  }
+
+  /// Increments the sequence of the bucket at `path` and returns it.
+  /// `Bucket.NextSequence()` in Go BBolt
+  pub fn next_sequence(
+    self: &sync::Arc<Self>, path: &BucketPathBuf,
+  ) -> crate::Result<u64, BucketError> {
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    Ok(delta.next_sequence())
+  }
+
+  pub fn delete_bucket(
+    self: &sync::Arc<Self>, path: &BucketPathBuf,
+  ) -> crate::Result<(), BucketError> {
+    let key = path.last().ok_or(BucketError::KeyRequired)?;
+    let delta = self
+      .bucket_delta(path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    self
+      .delta_map
+      .lock()
+      .retain(|delta_path, _| !delta_path.starts_with(path));
+    // Inline buckets own no pages
+    if let Some(header) = delta.on_disk().filter(|header| header.root().0.0 != 0) {
+      let mut freed = Vec::new();
+      walk_node_tree(self, header.root().into(), &mut |page_id, overflow| {
+        freed.push((page_id, overflow))
+      })?;
+      self.freed.lock().extend(freed);
+    }
+    let mut parent_path = path.clone();
+    parent_path.pop();
+    let parent = self
+      .bucket_delta(&parent_path)?
+      .ok_or(BucketError::BucketNotFound)?;
+    if parent.is_new() {
+      parent.lock().remove(key);
+    } else {
+      parent
+        .lock()
+        .insert(self.clone_key(key), ValueDelta::Delete);
+    }
+    Ok(())
+  }
+}
+
+/// Calls `f` with every page reachable from `page_id` and its overflow, including the pages of
+/// nested buckets. Children are visited before their parents. Buckets stored inline are skipped.
+pub fn walk_node_tree<'tx, TX, F>(
+  tx: &sync::Arc<TX>, page_id: NodePageId, f: &mut F,
+) -> crate::Result<(), BucketError>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+  F: FnMut(NodePageId, u32),
+{
+  let node = tx
+    .read_node_page(page_id)
+    .change_context(BucketError::PageReadError(page_id))?;
+  match &node {
+    NodePage::Branch(branch) => {
+      for index in 0..branch.element_count() {
+        let child = branch.node(index).expect("index in bounds");
+        walk_node_tree(tx, child, f)?;
+      }
+    }
+    NodePage::Leaf(leaf) => {
+      for index in 0..leaf.element_count() {
+        if leaf
+          .leaf_flag(index)
+          .is_some_and(|flag| flag.contains(LeafFlag::BUCKET))
+        {
+          let value = leaf.value(index).expect("index in bounds");
+          let header: BucketHeader =
+            bytemuck::pod_read_unaligned(&value[0..size_of::<BucketHeader>()]);
+          // Inline buckets own no pages
+          if header.root().0.0 != 0 {
+            walk_node_tree(tx, header.root().into(), f)?;
+          }
+        }
+      }
+    }
+  }
+  f(page_id, node.page_header().get_overflow());
+  Ok(())
+}
+
+/// The free pages as of `meta`, read from the freelist page. When the freelist wasn't written
+/// every page not reachable from the root bucket is free.
+pub fn free_page_ids<'tx, TX>(
+  tx: &sync::Arc<TX>, meta: &Meta,
+) -> crate::Result<Vec<DiskPageId>, TxError>
+where
+  TX: TxReadPageIO<'tx>,
+  <TX::LeafType as HasKeys<'tx>>::TxKv: Deref<Target = [u8]>,
+{
+  if meta.free_list.0.0 != PGID_NO_FREELIST {
+    let freelist = tx
+      .read_freelist_page(meta.free_list)
+      .change_context(TxError::FreePagesError)?;
+    return Ok(freelist.freelist_iter().collect());
+  }
+  let mut reachable = vec![false; meta.eof_id.0.0 as usize];
+  // Both meta pages
+  reachable[0..2].fill(true);
+  walk_node_tree(tx, meta.root.root().into(), &mut |page_id, overflow| {
+    let start = (page_id.0.0 as usize).min(reachable.len());
+    let end = (start + overflow as usize + 1).min(reachable.len());
+    reachable[start..end].fill(true);
+  })
+  .change_context(TxError::FreePagesError)?;
+  Ok(
+    reachable
+      .iter()
+      .enumerate()
+      .filter(|(_, reachable)| !**reachable)
+      .map(|(page_id, _)| DiskPageId(page_id as u64))
+      .collect(),
+  )
+}
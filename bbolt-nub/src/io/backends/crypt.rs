@@ -0,0 +1,776 @@
+use crate::common::buffer_pool::BufferPool;
+use crate::common::errors::IOError;
+use crate::common::id::{DiskPageId, EOFPageId};
+use crate::common::layout::page::PageHeader;
+use crate::io::backends::memmap::MemMapIO;
+use crate::io::backends::{ContigIOReader, IOBackend, IOReader, IOType, IOWriter};
+use crate::io::bytes::ref_bytes::RefBytes;
+use crate::io::bytes::shared_bytes::SharedBytes;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use error_stack::ResultExt;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// The length of a page's authentication tag
+pub const CRYPT_TAG_LEN: usize = 16;
+
+/// The length of a page's nonce
+pub const CRYPT_NONCE_LEN: usize = 12;
+
+/// The length of the footer at the end of each encrypted disk page. It holds the random nonce
+/// the page was sealed with, then the page's authentication tag.
+pub const CRYPT_FOOTER_LEN: usize = CRYPT_NONCE_LEN + CRYPT_TAG_LEN;
+
+/// The nonce of the bootstrap header's key check
+const KEY_CHECK_NONCE: [u8; CRYPT_NONCE_LEN] = [u8::MAX; CRYPT_NONCE_LEN];
+
+const CRYPT_MAGIC: [u8; 8] = *b"BBOLTENC";
+const CRYPT_VERSION: u32 = 2;
+
+/// A 256-bit key. It's zeroed when dropped.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct CryptKey([u8; 32]);
+
+impl CryptKey {
+  pub fn new(key: [u8; 32]) -> Self {
+    CryptKey(key)
+  }
+}
+
+impl Debug for CryptKey {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.write_str("CryptKey(..)")
+  }
+}
+
+/// An AEAD cipher with 96-bit nonces and 128-bit tags
+pub trait PageCipher: Sized {
+  /// Stored in the bootstrap header so a file isn't opened with the wrong cipher
+  const CIPHER_ID: u32;
+
+  fn new_cipher(key: &CryptKey) -> Self;
+
+  /// Encrypts `data` in place, returning its tag
+  fn seal(&self, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8])
+  -> [u8; CRYPT_TAG_LEN];
+
+  /// Decrypts `data` in place. Returns false if it fails authentication.
+  fn open(
+    &self, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8], tag: &[u8; CRYPT_TAG_LEN],
+  ) -> bool;
+}
+
+impl PageCipher for ChaCha20Poly1305 {
+  const CIPHER_ID: u32 = 1;
+
+  fn new_cipher(key: &CryptKey) -> Self {
+    ChaCha20Poly1305::new(&key.0.into())
+  }
+
+  fn seal(
+    &self, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8],
+  ) -> [u8; CRYPT_TAG_LEN] {
+    seal_in_place(self, nonce, aad, data)
+  }
+
+  fn open(
+    &self, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8], tag: &[u8; CRYPT_TAG_LEN],
+  ) -> bool {
+    open_in_place(self, nonce, aad, data, tag)
+  }
+}
+
+impl PageCipher for Aes256Gcm {
+  const CIPHER_ID: u32 = 2;
+
+  fn new_cipher(key: &CryptKey) -> Self {
+    Aes256Gcm::new(&key.0.into())
+  }
+
+  fn seal(
+    &self, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8],
+  ) -> [u8; CRYPT_TAG_LEN] {
+    seal_in_place(self, nonce, aad, data)
+  }
+
+  fn open(
+    &self, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8], tag: &[u8; CRYPT_TAG_LEN],
+  ) -> bool {
+    open_in_place(self, nonce, aad, data, tag)
+  }
+}
+
+fn seal_in_place<A: AeadInPlace>(
+  cipher: &A, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8],
+) -> [u8; CRYPT_TAG_LEN] {
+  let tag = cipher
+    .encrypt_in_place_detached(nonce.as_slice().into(), aad, data)
+    .expect("pages are well under the cipher's length limit");
+  tag.as_slice().try_into().expect("128-bit tag")
+}
+
+fn open_in_place<A: AeadInPlace>(
+  cipher: &A, nonce: &[u8; CRYPT_NONCE_LEN], aad: &[u8], data: &mut [u8], tag: &[u8; CRYPT_TAG_LEN],
+) -> bool {
+  cipher
+    .decrypt_in_place_detached(nonce.as_slice().into(), aad, data, tag.as_slice().into())
+    .is_ok()
+}
+
+/// The plaintext header stored in the first disk page of an encrypted file
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CryptHeader {
+  magic: [u8; 8],
+  version: u32,
+  cipher_id: u32,
+  /// The size of the pages on disk, footers included
+  page_size: u32,
+  unused: u32,
+  /// The tag of an empty message, used to reject the wrong key before reading any pages
+  key_check: [u8; CRYPT_TAG_LEN],
+}
+
+impl CryptHeader {
+  fn new<C: PageCipher>(cipher: &C, page_size: usize) -> Self {
+    let mut header = CryptHeader {
+      magic: CRYPT_MAGIC,
+      version: CRYPT_VERSION,
+      cipher_id: C::CIPHER_ID,
+      page_size: page_size as u32,
+      unused: 0,
+      key_check: [0; CRYPT_TAG_LEN],
+    };
+    header.key_check = cipher.seal(&KEY_CHECK_NONCE, header.key_check_aad(), &mut []);
+    header
+  }
+
+  fn key_check_aad(&self) -> &[u8] {
+    &bytemuck::bytes_of(self)[0..size_of::<CryptHeader>() - CRYPT_TAG_LEN]
+  }
+
+  fn verify<C: PageCipher>(&self, cipher: &C, page_size: usize) -> crate::Result<(), IOError> {
+    if self.magic != CRYPT_MAGIC
+      || self.version != CRYPT_VERSION
+      || self.cipher_id != C::CIPHER_ID
+      || self.page_size as usize != page_size
+    {
+      return Err(IOError::CryptHeaderError.into());
+    }
+    if cipher.open(
+      &KEY_CHECK_NONCE,
+      self.key_check_aad(),
+      &mut [],
+      &self.key_check,
+    ) {
+      Ok(())
+    } else {
+      Err(IOError::CryptKeyError.into())
+    }
+  }
+}
+
+/// The disk page size recorded in the bootstrap header of an encrypted file
+pub fn crypt_page_size(file: &File) -> crate::Result<usize, IOError> {
+  let mut header: CryptHeader = bytemuck::Zeroable::zeroed();
+  file
+    .read_exact_at(bytemuck::bytes_of_mut(&mut header), 0)
+    .change_context(IOError::CryptHeaderError)?;
+  if header.magic != CRYPT_MAGIC || header.version != CRYPT_VERSION {
+    return Err(IOError::CryptHeaderError.into());
+  }
+  Ok(header.page_size as usize)
+}
+
+/// Encrypts page `disk_page_id` in place under a fresh random nonce and fills in its footer.
+///
+/// The page id is the associated data, so a page can't be moved to another page's place.
+/// Random nonces stay unique no matter how often a page is rewritten, but a key should seal
+/// fewer than 2^32 pages before it's rotated with [`CryptShell::rotate_key`].
+fn seal_disk_page<C: PageCipher>(
+  cipher: &C, disk_page_id: DiskPageId, disk_page: &mut [u8],
+) -> crate::Result<(), IOError> {
+  let mut nonce = [0u8; CRYPT_NONCE_LEN];
+  OsRng
+    .try_fill_bytes(&mut nonce)
+    .map_err(|_| IOError::EncryptError(disk_page_id))?;
+  let (data, footer) = disk_page.split_at_mut(disk_page.len() - CRYPT_FOOTER_LEN);
+  let tag = cipher.seal(&nonce, &disk_page_id.0.to_le_bytes(), data);
+  footer[0..CRYPT_NONCE_LEN].copy_from_slice(&nonce);
+  footer[CRYPT_NONCE_LEN..].copy_from_slice(&tag);
+  Ok(())
+}
+
+/// Decrypts page `disk_page_id` into `page`. Returns false if it fails authentication.
+fn open_disk_page<C: PageCipher>(
+  cipher: &C, disk_page_id: DiskPageId, disk_page: &[u8], page: &mut [u8],
+) -> bool {
+  let (data, footer) = disk_page.split_at(disk_page.len() - CRYPT_FOOTER_LEN);
+  let nonce = footer[0..CRYPT_NONCE_LEN].try_into().expect("nonce length");
+  let tag = footer[CRYPT_NONCE_LEN..].try_into().expect("tag length");
+  page.copy_from_slice(data);
+  cipher.open(nonce, &disk_page_id.0.to_le_bytes(), page, tag)
+}
+
+/// Encrypts every page of the backend it wraps with a [`PageCipher`].
+///
+/// The first disk page holds a plaintext bootstrap header naming the cipher and page size, so
+/// page `n` is stored in disk page `n + 1`. Each disk page ends in a footer holding its nonce
+/// and tag, so pages are `CRYPT_FOOTER_LEN` bytes smaller than the backend's.
+pub struct CryptShell<I, C> {
+  inner: I,
+  cipher: C,
+}
+
+impl<I, C> CryptShell<I, C>
+where
+  I: IOBackend,
+  C: PageCipher,
+{
+  /// Writes a new bootstrap header for `key`, replacing anything already in the file
+  pub fn create(inner: I, key: &CryptKey) -> crate::Result<Self, IOError>
+  where
+    I: IOWriter,
+  {
+    let shell = CryptShell {
+      inner,
+      cipher: C::new_cipher(key),
+    };
+    shell.write_header()?;
+    Ok(shell)
+  }
+
+  /// Opens a file written by [`CryptShell::create`], failing if `key` isn't the file's key
+  pub fn open(inner: I, key: &CryptKey) -> crate::Result<Self, IOError>
+  where
+    I: IOReader,
+  {
+    let shell = CryptShell {
+      inner,
+      cipher: C::new_cipher(key),
+    };
+    let page = shell.inner.read_single_page(DiskPageId(0))?;
+    let header: CryptHeader = bytemuck::pod_read_unaligned(&page[0..size_of::<CryptHeader>()]);
+    header.verify(&shell.cipher, shell.inner.page_size())?;
+    Ok(shell)
+  }
+
+  #[inline]
+  pub fn inner(&self) -> &I {
+    &self.inner
+  }
+
+  pub fn into_inner(self) -> I {
+    self.inner
+  }
+
+  fn write_header(&self) -> crate::Result<(), IOError>
+  where
+    I: IOWriter,
+  {
+    let disk_page_size = self.inner.page_size();
+    let header = CryptHeader::new(&self.cipher, disk_page_size);
+    let page = BufferPool::new_unbound(disk_page_size).fill_and_share(|page| {
+      page[0..size_of::<CryptHeader>()].copy_from_slice(bytemuck::bytes_of(&header))
+    });
+    self.inner.write_single_page(DiskPageId(0), page)?;
+    self.inner.sync()
+  }
+
+  /// The disk page and length that hold `page_len` bytes of pages starting at `disk_page_id`
+  #[inline]
+  fn disk_run(&self, disk_page_id: DiskPageId, page_len: usize) -> (DiskPageId, usize) {
+    assert_eq!(0, page_len % self.page_size());
+    let disk_len = page_len / self.page_size() * self.inner.page_size();
+    (DiskPageId(disk_page_id.0 + 1), disk_len)
+  }
+
+  fn open_pages(
+    &self, disk_page_id: DiskPageId, disk_pages: &[u8],
+  ) -> crate::Result<SharedBytes, IOError> {
+    let disk_page_size = self.inner.page_size();
+    let page_size = self.page_size();
+    let count = disk_pages.len() / disk_page_size;
+    BufferPool::new_unbound(page_size * count)
+      .read_with_and_share(|pages| {
+        let chunks = disk_pages
+          .chunks_exact(disk_page_size)
+          .zip(pages.chunks_exact_mut(page_size));
+        for (index, (disk_page, page)) in chunks.enumerate() {
+          let page_id = DiskPageId(disk_page_id.0 + index as u64);
+          if !open_disk_page(&self.cipher, page_id, disk_page, page) {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+          }
+        }
+        Ok(())
+      })
+      .change_context(IOError::DecryptError(disk_page_id))
+  }
+
+  fn seal_pages(
+    &self, disk_page_id: DiskPageId, pages: &[u8],
+  ) -> crate::Result<SharedBytes, IOError> {
+    let disk_page_size = self.inner.page_size();
+    let page_size = self.page_size();
+    let count = pages.len() / page_size;
+    let mut sealed = Ok(());
+    let disk_pages = BufferPool::new_unbound(disk_page_size * count).fill_and_share(|disk_pages| {
+      let chunks = disk_pages
+        .chunks_exact_mut(disk_page_size)
+        .zip(pages.chunks_exact(page_size));
+      for (index, (disk_page, page)) in chunks.enumerate() {
+        disk_page[0..page_size].copy_from_slice(page);
+        let page_id = DiskPageId(disk_page_id.0 + index as u64);
+        sealed = seal_disk_page(&self.cipher, page_id, disk_page);
+        if sealed.is_err() {
+          return;
+        }
+      }
+    });
+    sealed.map(|_| disk_pages)
+  }
+}
+
+impl<I, C> CryptShell<I, C>
+where
+  I: IOReader + IOWriter,
+  C: PageCipher,
+{
+  /// Re-encrypts pages `0..eof` and then the bootstrap header with `key`.
+  ///
+  /// Each page is sealed under a fresh nonce. Disk pages that were never written
+  /// are skipped. The database must not be in use, and a crash part way through leaves pages
+  /// under both keys, so rotate a copy or keep a backup.
+  pub fn rotate_key(&mut self, key: &CryptKey, eof: EOFPageId) -> crate::Result<(), IOError> {
+    let cipher = C::new_cipher(key);
+    let disk_page_size = self.inner.page_size();
+    let page_size = self.page_size();
+    for page_id in 0..eof.0.0 {
+      let disk_page_id = DiskPageId(page_id);
+      let disk_page = self.inner.read_single_page(DiskPageId(page_id + 1))?;
+      if disk_page.iter().all(|b| *b == 0) {
+        continue;
+      }
+      let mut resealed = Ok(());
+      let sealed = BufferPool::new_unbound(disk_page_size)
+        .read_with_and_share(|sealed| {
+          if !open_disk_page(
+            &self.cipher,
+            disk_page_id,
+            &disk_page,
+            &mut sealed[0..page_size],
+          ) {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+          }
+          resealed = seal_disk_page(&cipher, disk_page_id, sealed);
+          Ok(())
+        })
+        .change_context(IOError::DecryptError(disk_page_id))?;
+      resealed?;
+      self
+        .inner
+        .write_single_page(DiskPageId(page_id + 1), sealed)?;
+    }
+    // Every page must be under the new key before the header says so
+    self.inner.sync()?;
+    self.cipher = cipher;
+    self.write_header()
+  }
+}
+
+impl<I, C> IOBackend for CryptShell<I, C>
+where
+  I: IOBackend,
+{
+  #[inline]
+  fn io_type(&self) -> IOType {
+    self.inner.io_type()
+  }
+
+  #[inline]
+  fn page_size(&self) -> usize {
+    self.inner.page_size() - CRYPT_FOOTER_LEN
+  }
+
+  /// Checksum footers are inside the encrypted page
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self.inner.page_checksums()
+  }
+
+  fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError> {
+    self.inner.apply_length_update(new_len)
+  }
+}
+
+impl<I, C> IOReader for CryptShell<I, C>
+where
+  I: IOReader,
+  C: PageCipher,
+{
+  type Bytes = SharedBytes;
+
+  fn read_disk_page(
+    &self, disk_page_id: DiskPageId, page_len: usize,
+  ) -> crate::Result<Self::Bytes, IOError> {
+    let (disk_run_id, disk_len) = self.disk_run(disk_page_id, page_len);
+    let disk_pages = self.inner.read_disk_page(disk_run_id, disk_len)?;
+    self.open_pages(disk_page_id, &disk_pages)
+  }
+
+  fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    let disk_runs: Vec<_> = pages
+      .iter()
+      .map(|(disk_page_id, page_len)| self.disk_run(*disk_page_id, *page_len))
+      .collect();
+    let disk_pages = self.inner.read_disk_pages(&disk_runs)?;
+    pages
+      .iter()
+      .zip(disk_pages)
+      .map(|((disk_page_id, _), disk_pages)| self.open_pages(*disk_page_id, &disk_pages))
+      .collect()
+  }
+}
+
+impl<I, C> ContigIOReader for CryptShell<I, C>
+where
+  I: IOReader,
+  C: PageCipher,
+{
+  fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError> {
+    let page = self.read_single_page(disk_page_id)?;
+    Ok(bytemuck::pod_read_unaligned(
+      &page[0..size_of::<PageHeader>()],
+    ))
+  }
+}
+
+impl<I, C> IOWriter for CryptShell<I, C>
+where
+  I: IOWriter,
+  C: PageCipher,
+{
+  fn write_disk_page(
+    &self, disk_page_id: DiskPageId, page: SharedBytes,
+  ) -> crate::Result<(), IOError> {
+    let disk_pages = self.seal_pages(disk_page_id, &page)?;
+    self
+      .inner
+      .write_disk_page(DiskPageId(disk_page_id.0 + 1), disk_pages)
+  }
+
+  fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError> {
+    let disk_pages = pages
+      .into_iter()
+      .map(|(disk_page_id, page)| {
+        let disk_pages = self.seal_pages(disk_page_id, &page)?;
+        Ok((DiskPageId(disk_page_id.0 + 1), disk_pages))
+      })
+      .collect::<crate::Result<_, IOError>>()?;
+    self.inner.write_disk_pages(disk_pages)
+  }
+
+  fn sync(&self) -> crate::Result<(), IOError> {
+    self.inner.sync()
+  }
+}
+
+/// The cipher and key a database's pages are encrypted with
+#[derive(Debug, Clone)]
+pub enum PageEncryption {
+  ChaCha20Poly1305(CryptKey),
+  Aes256Gcm(CryptKey),
+}
+
+/// A memory map read and written either directly or through a [`CryptShell`], as chosen when
+/// the database is opened.
+///
+/// Decrypted pages are kept by the map until they're rewritten, then kept alive by its epochs
+/// like a retired mapping, so they can be handed out as [`RefBytes`]. Pages must only be read
+/// while the map is pinned.
+pub enum CryptMemMapIO {
+  Plain(MemMapIO),
+  ChaCha20Poly1305(CryptShell<MemMapIO, ChaCha20Poly1305>),
+  /// Boxed for its key schedule
+  Aes256Gcm(Box<CryptShell<MemMapIO, Aes256Gcm>>),
+}
+
+macro_rules! each_io {
+  ($io:expr, $inner:ident => $body:expr) => {
+    match $io {
+      CryptMemMapIO::Plain($inner) => $body,
+      CryptMemMapIO::ChaCha20Poly1305($inner) => $body,
+      CryptMemMapIO::Aes256Gcm($inner) => $body,
+    }
+  };
+}
+
+impl CryptMemMapIO {
+  /// Writes a new bootstrap header to `io` for `encryption`. See [`CryptShell::create`]
+  pub fn create(io: MemMapIO, encryption: &PageEncryption) -> crate::Result<Self, IOError> {
+    match encryption {
+      PageEncryption::ChaCha20Poly1305(key) => {
+        CryptShell::create(io, key).map(CryptMemMapIO::ChaCha20Poly1305)
+      }
+      PageEncryption::Aes256Gcm(key) => {
+        CryptShell::create(io, key).map(|shell| CryptMemMapIO::Aes256Gcm(Box::new(shell)))
+      }
+    }
+  }
+
+  /// Opens `io` encrypted with `encryption`. See [`CryptShell::open`]
+  pub fn open(io: MemMapIO, encryption: &PageEncryption) -> crate::Result<Self, IOError> {
+    match encryption {
+      PageEncryption::ChaCha20Poly1305(key) => {
+        CryptShell::open(io, key).map(CryptMemMapIO::ChaCha20Poly1305)
+      }
+      PageEncryption::Aes256Gcm(key) => {
+        CryptShell::open(io, key).map(|shell| CryptMemMapIO::Aes256Gcm(Box::new(shell)))
+      }
+    }
+  }
+
+  /// The map the pages are stored in
+  #[inline]
+  pub fn memmap(&self) -> &MemMapIO {
+    match self {
+      CryptMemMapIO::Plain(io) => io,
+      CryptMemMapIO::ChaCha20Poly1305(shell) => shell.inner(),
+      CryptMemMapIO::Aes256Gcm(shell) => shell.inner(),
+    }
+  }
+
+  #[inline]
+  pub fn is_encrypted(&self) -> bool {
+    !matches!(self, CryptMemMapIO::Plain(_))
+  }
+}
+
+impl IOBackend for CryptMemMapIO {
+  #[inline]
+  fn io_type(&self) -> IOType {
+    each_io!(self, io => io.io_type())
+  }
+
+  #[inline]
+  fn page_size(&self) -> usize {
+    each_io!(self, io => io.page_size())
+  }
+
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    each_io!(self, io => io.page_checksums())
+  }
+
+  fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError> {
+    each_io!(self, io => io.apply_length_update(new_len))
+  }
+}
+
+impl IOReader for CryptMemMapIO {
+  type Bytes = RefBytes;
+
+  fn read_disk_page(
+    &self, disk_page_id: DiskPageId, page_len: usize,
+  ) -> crate::Result<Self::Bytes, IOError> {
+    match self {
+      CryptMemMapIO::Plain(io) => io.read_disk_page(disk_page_id, page_len),
+      CryptMemMapIO::ChaCha20Poly1305(shell) => shell.inner().keep(disk_page_id, page_len, || {
+        shell.read_disk_page(disk_page_id, page_len)
+      }),
+      CryptMemMapIO::Aes256Gcm(shell) => shell.inner().keep(disk_page_id, page_len, || {
+        shell.read_disk_page(disk_page_id, page_len)
+      }),
+    }
+  }
+
+  fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    match self {
+      CryptMemMapIO::Plain(io) => io.read_disk_pages(pages),
+      _ => pages
+        .iter()
+        .map(|(disk_page_id, page_len)| self.read_disk_page(*disk_page_id, *page_len))
+        .collect(),
+    }
+  }
+}
+
+impl ContigIOReader for CryptMemMapIO {
+  fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError> {
+    each_io!(self, io => io.read_header(disk_page_id))
+  }
+}
+
+impl CryptMemMapIO {
+  /// Forgets the decrypted pages at the `page_count` ids written from `disk_page_id`. Pages
+  /// decrypted while they were written are forgotten too.
+  fn forget(&self, disk_page_id: DiskPageId, page_count: usize) {
+    if self.is_encrypted() {
+      self.memmap().forget(disk_page_id, page_count as u64);
+    }
+  }
+}
+
+impl IOWriter for CryptMemMapIO {
+  fn write_disk_page(
+    &self, disk_page_id: DiskPageId, page: SharedBytes,
+  ) -> crate::Result<(), IOError> {
+    let page_count = page.len() / self.page_size();
+    each_io!(self, io => io.write_disk_page(disk_page_id, page.clone()))?;
+    self.forget(disk_page_id, page_count);
+    Ok(())
+  }
+
+  fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError> {
+    let written = pages
+      .iter()
+      .map(|(disk_page_id, page)| (*disk_page_id, page.len() / self.page_size()))
+      .collect::<Vec<_>>();
+    each_io!(self, io => io.write_disk_pages(pages))?;
+    for (disk_page_id, page_count) in written {
+      self.forget(disk_page_id, page_count);
+    }
+    Ok(())
+  }
+
+  fn sync(&self) -> crate::Result<(), IOError> {
+    each_io!(self, io => io.sync())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::id::DbPageId;
+  use crate::io::backends::NewIOReadWriter;
+  use crate::io::backends::p_file::{PFileIO, PFileReadOptions, PFileWriteOptions};
+  use size::Size;
+  use std::fs;
+  use std::os::unix::fs::FileExt;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+
+  fn open_p_file(path: &Arc<PathBuf>, page_size: usize) -> PFileIO {
+    let buffer_pool = BufferPool::new(
+      page_size,
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(64),
+    );
+    PFileIO::new_rw(
+      path.clone(),
+      page_size,
+      PFileReadOptions::new(buffer_pool),
+      PFileWriteOptions::default(),
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn test_crypt_shell() {
+    let disk_page_size = 4096;
+    let path = std::env::temp_dir().join(format!("bbolt-nub-crypt-{}.db", std::process::id()));
+    fs::write(&path, []).unwrap();
+    let path = Arc::new(path);
+    let key = CryptKey::new([7; 32]);
+    let shell: CryptShell<_, ChaCha20Poly1305> =
+      CryptShell::create(open_p_file(&path, disk_page_size), &key).unwrap();
+    let page_size = shell.page_size();
+    assert_eq!(disk_page_size - CRYPT_FOOTER_LEN, page_size);
+
+    let page = BufferPool::new_unbound(page_size * 2).fill_and_share(|b| {
+      b.fill(b'x');
+      let mut header = PageHeader::init_leaf(DbPageId(1));
+      unsafe { header.set_overflow(1) };
+      b[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+    });
+    let single = BufferPool::new_unbound(page_size).fill_and_share(|b| b.fill(b'y'));
+    shell
+      .write_disk_pages(vec![
+        (DiskPageId(1), page.clone()),
+        (DiskPageId(3), single.clone()),
+      ])
+      .unwrap();
+    shell.sync().unwrap();
+    // Rewriting a page with the same contents seals it under a new nonce
+    let disk_page = |on_disk: &[u8]| on_disk[disk_page_size * 4..disk_page_size * 5].to_vec();
+    let sealed = disk_page(&fs::read(path.as_ref()).unwrap());
+    shell.write_single_page(DiskPageId(3), single).unwrap();
+    shell.sync().unwrap();
+    let resealed = disk_page(&fs::read(path.as_ref()).unwrap());
+    assert_ne!(sealed[page_size..], resealed[page_size..]);
+    assert_ne!(sealed[0..page_size], resealed[0..page_size]);
+    assert_eq!(
+      &page[..],
+      &shell.read_contig_page(DiskPageId(1)).unwrap()[..]
+    );
+    let on_disk = fs::read(path.as_ref()).unwrap();
+    assert_eq!(disk_page_size * 5, on_disk.len());
+    assert!(!on_disk.windows(64).any(|w| w.iter().all(|b| *b == b'x')));
+
+    // The wrong key or cipher is rejected before any page is read
+    let shell = shell.into_inner();
+    let other_key = CryptKey::new([8; 32]);
+    let report = CryptShell::<_, ChaCha20Poly1305>::open(shell, &other_key)
+      .err()
+      .unwrap();
+    assert!(matches!(report.current_context(), IOError::CryptKeyError));
+    let report = CryptShell::<_, Aes256Gcm>::open(open_p_file(&path, disk_page_size), &key)
+      .err()
+      .unwrap();
+    assert!(matches!(
+      report.current_context(),
+      IOError::CryptHeaderError
+    ));
+
+    let mut shell: CryptShell<_, ChaCha20Poly1305> =
+      CryptShell::open(open_p_file(&path, disk_page_size), &key).unwrap();
+    shell
+      .rotate_key(&other_key, EOFPageId(DiskPageId(4)))
+      .unwrap();
+    assert_eq!(
+      &page[..],
+      &shell.read_contig_page(DiskPageId(1)).unwrap()[..]
+    );
+    let mut shell: CryptShell<_, ChaCha20Poly1305> =
+      CryptShell::open(open_p_file(&path, disk_page_size), &other_key).unwrap();
+    assert!(
+      shell
+        .read_single_page(DiskPageId(3))
+        .unwrap()
+        .iter()
+        .all(|b| *b == b'y')
+    );
+    assert!(
+      CryptShell::<_, ChaCha20Poly1305>::open(open_p_file(&path, disk_page_size), &key).is_err()
+    );
+
+    // Flip a byte of the overflow page behind the shell's back
+    let file = fs::OpenOptions::new()
+      .write(true)
+      .open(path.as_ref())
+      .unwrap();
+    file
+      .write_at(&[0xFF], disk_page_size as u64 * 3 + 10)
+      .unwrap();
+    let report = shell.read_contig_page(DiskPageId(1)).err().unwrap();
+    assert!(matches!(
+      report.current_context(),
+      IOError::DecryptError(DiskPageId(1))
+    ));
+    // Rotation refuses to re-encrypt a page it can't authenticate
+    assert!(shell.rotate_key(&key, EOFPageId(DiskPageId(4))).is_err());
+    fs::remove_file(path.as_ref()).unwrap();
+  }
+}
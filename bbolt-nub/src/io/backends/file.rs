@@ -1,8 +1,11 @@
-use crate::common::buffer_pool::BufferPool;
+use crate::common::buffer_pool::{BufferPool, UniqueBuffer};
 use crate::common::errors::IOError;
 use crate::common::id::DiskPageId;
+use crate::common::layout::page::PageHeader;
 use crate::io::backends::channel_store::ChannelStore;
-use crate::io::backends::{IOBackend, IOCore, IOReader, IOType, NewIOReader, ROShell};
+use crate::io::backends::{
+  ContigIOReader, IOBackend, IOCore, IOReader, IOType, NewIOReader, ROShell,
+};
 use crate::io::bytes::shared_bytes::SharedBytes;
 use crossbeam_channel::{Receiver, Sender};
 use error_stack::ResultExt;
@@ -16,11 +19,21 @@ use std::sync::Arc;
 #[derive(Debug, Clone)]
 pub struct FileReadOptions {
   buffer_pool: BufferPool,
+  page_checksums: bool,
 }
 
 impl FileReadOptions {
   pub fn new(buffer_pool: BufferPool) -> FileReadOptions {
-    FileReadOptions { buffer_pool }
+    FileReadOptions {
+      buffer_pool,
+      page_checksums: false,
+    }
+  }
+
+  /// Verifies the checksum footer of each page as it's read
+  pub fn with_page_checksums(mut self, page_checksums: bool) -> Self {
+    self.page_checksums = page_checksums;
+    self
   }
 }
 
@@ -31,6 +44,7 @@ pub struct SingleFileIO {
   core: IOCore,
   file: Mutex<BufReader<File>>,
   buffer_pool: Option<BufferPool>,
+  page_checksums: bool,
 }
 
 impl SingleFileIO {
@@ -49,6 +63,11 @@ impl IOBackend for SingleFileIO {
   fn page_size(&self) -> usize {
     self.core.page_size
   }
+
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self.page_checksums
+  }
 }
 
 impl IOReader for SingleFileIO {
@@ -68,6 +87,50 @@ impl IOReader for SingleFileIO {
       })
       .change_context(IOError::ReadError(disk_page_id))
   }
+
+  /// Reads every page in one ascending sweep under a single lock, seeking only between runs
+  fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    let Some((first_page_id, _)) = pages.first() else {
+      return Ok(Vec::new());
+    };
+    let buffer_pool = self.expect_read_resources();
+    let page_size = self.core.page_size as u64;
+    let mut order: Vec<_> = (0..pages.len()).collect();
+    order.sort_by_key(|index| pages[*index].0);
+    let buffers = pages
+      .iter()
+      .map(|(_, page_len)| buffer_pool.pop_with_len(*page_len))
+      .collect();
+    let mut lock = self.file.lock();
+    UniqueBuffer::read_all_with_and_share(buffers, |buffers| {
+      let mut position = None;
+      for index in order {
+        let page_offset = pages[index].0.0 * page_size;
+        if position != Some(page_offset) {
+          lock.seek(SeekFrom::Start(page_offset))?;
+        }
+        lock.read_exact(buffers[index])?;
+        position = Some(page_offset + buffers[index].len() as u64);
+      }
+      Ok(())
+    })
+    .change_context(IOError::ReadError(*first_page_id))
+  }
+}
+
+impl ContigIOReader for SingleFileIO {
+  fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError> {
+    let mut header = [0u8; size_of::<PageHeader>()];
+    let page_offset = disk_page_id.0 * self.core.page_size as u64;
+    let mut lock = self.file.lock();
+    lock
+      .seek(SeekFrom::Start(page_offset))
+      .and_then(|_| lock.read_exact(&mut header))
+      .change_context(IOError::ReadError(disk_page_id))?;
+    Ok(bytemuck::pod_read_unaligned(&header))
+  }
 }
 
 impl NewIOReader for SingleFileIO {
@@ -82,6 +145,7 @@ impl NewIOReader for SingleFileIO {
       core,
       file: Mutex::new(BufReader::new(file)),
       buffer_pool: Some(options.buffer_pool),
+      page_checksums: options.page_checksums,
     }))
   }
 }
@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FileLockType {
+  /// Many processes may hold a shared lock. Used by read-only databases
+  Shared,
+  /// Only one process may hold an exclusive lock. Used by read-write databases
+  Exclusive,
+}
+
+/// Attempts to take an advisory `flock` on the file without blocking.
+///
+/// Returns `Ok(false)` if another process holds a conflicting lock. The lock is released when
+/// the last descriptor referencing the file is closed.
+#[cfg(target_family = "unix")]
+pub fn try_lock_file(file: &File, lock_type: FileLockType) -> io::Result<bool> {
+  use std::os::fd::AsRawFd;
+  let operation = match lock_type {
+    FileLockType::Shared => libc::LOCK_SH,
+    FileLockType::Exclusive => libc::LOCK_EX,
+  } | libc::LOCK_NB;
+  let r = unsafe { libc::flock(file.as_raw_fd(), operation) };
+  if r == 0 {
+    Ok(true)
+  } else {
+    let err = io::Error::last_os_error();
+    match err.kind() {
+      io::ErrorKind::WouldBlock => Ok(false),
+      _ => Err(err),
+    }
+  }
+}
+
+/// Advisory locks aren't implemented on this platform, so opening fails rather than letting
+/// two processes write the same file
+#[cfg(not(target_family = "unix"))]
+pub fn try_lock_file(_file: &File, _lock_type: FileLockType) -> io::Result<bool> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "file locking is not supported on this platform",
+  ))
+}
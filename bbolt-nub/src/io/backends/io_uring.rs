@@ -1 +1,609 @@
+use crate::common::buffer_pool::{BufferPool, PoolBuffer};
+use crate::common::errors::IOError;
+use crate::common::id::DiskPageId;
+use crate::common::layout::page::PageHeader;
+use crate::io::backends::{
+  ContigIOReader, IOBackend, IOCore, IOReader, IOType, IOWriter, NewIOReadWriter, NewIOReader,
+  NewIOWriter, ROShell, WOShell,
+};
+use crate::io::bytes::shared_bytes::SharedBytes;
+use error_stack::ResultExt;
+use io_uring::{IoUring, opcode, squeue, types};
+use parking_lot::Mutex;
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+use triomphe::UniqueArc;
 
+/// The smallest read ring. Reads are submitted one page, or one batch, at a time
+const MIN_READ_ENTRIES: u32 = 4;
+
+#[derive(Debug, Error)]
+pub enum IOUringError {
+  #[error("IOUring Setup Failure")]
+  SetupFailure,
+  #[error("IOUring Register Buffers Failure")]
+  RegisterFailure,
+  #[error("IOUring Submit Failure")]
+  SubmitFailure,
+}
+
+#[derive(Debug, Clone)]
+pub struct IOUringReadOptions {
+  buffer_pool: BufferPool,
+  /// Page sized buffers drawn from `buffer_pool` and registered with the read ring. Pages are
+  /// shared straight from the buffer they're read into, so while every registered buffer holds
+  /// a page still in use reads fall back to buffers from the pool
+  registered_buffers: u16,
+  /// Poll the read ring's submission queue from a kernel thread which sleeps after this many
+  /// idle milliseconds. `None` disables SQ polling
+  sq_poll_idle: Option<u32>,
+}
+
+impl IOUringReadOptions {
+  pub fn new(buffer_pool: BufferPool, registered_buffers: u16, sq_poll_idle: Option<u32>) -> Self {
+    Self {
+      buffer_pool,
+      registered_buffers,
+      sq_poll_idle,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct IOUringWriteOptions {
+  /// Submission queue entries, and the size of the table the pages of each chunk are registered
+  /// in. Commits with more pages than this are submitted in chunks
+  entries: u32,
+  /// Sync with `fdatasync` semantics instead of `fsync`
+  use_fdatasync: bool,
+}
+
+impl IOUringWriteOptions {
+  pub fn new(entries: u32, use_fdatasync: bool) -> Self {
+    Self {
+      entries,
+      use_fdatasync,
+    }
+  }
+}
+
+impl Default for IOUringWriteOptions {
+  fn default() -> Self {
+    Self::new(256, true)
+  }
+}
+
+/// Where a page is read into
+enum ReadBuffer {
+  /// The index of a registered buffer
+  Registered(usize),
+  Pooled(UniqueArc<PoolBuffer>),
+}
+
+struct IOUringReader {
+  // The ring must drop first so the kernel releases the registered buffers before they're freed
+  ring: IoUring,
+  /// A registered buffer is free to read into while the reader holds its only reference
+  registered: Vec<triomphe::Arc<PoolBuffer>>,
+  buffer_pool: BufferPool,
+}
+
+impl IOUringReader {
+  /// Picks a buffer for each page, preferring the free registered buffers for pages that fit
+  fn read_buffers(&self, pages: &[(DiskPageId, usize)]) -> Vec<ReadBuffer> {
+    let mut free = self
+      .registered
+      .iter()
+      .enumerate()
+      .filter(|(_, buffer)| buffer.is_unique())
+      .map(|(index, _)| index)
+      .peekable();
+    pages
+      .iter()
+      .map(|(_, page_len)| match free.peek() {
+        Some(index) if self.registered[*index].slice.len() == *page_len => {
+          ReadBuffer::Registered(free.next().unwrap())
+        }
+        _ => ReadBuffer::Pooled(self.buffer_pool.pop_with_len(*page_len).into_init()),
+      })
+      .collect()
+  }
+
+  fn buffer_mut<'a>(&'a mut self, buffer: &'a mut ReadBuffer) -> &'a mut [u8] {
+    match buffer {
+      ReadBuffer::Registered(index) => {
+        let registered = triomphe::Arc::get_mut(&mut self.registered[*index])
+          .expect("registered buffer must be free");
+        &mut registered.slice
+      }
+      ReadBuffer::Pooled(unique) => &mut unique.slice,
+    }
+  }
+
+  /// Reads each page into its buffer in one batch
+  fn read_into(
+    &mut self, fd: types::Fd, file: &File, page_size: u64, pages: &[(DiskPageId, usize)],
+    buffers: &mut [ReadBuffer],
+  ) -> io::Result<()> {
+    let entries: Vec<_> = pages
+      .iter()
+      .zip(buffers.iter_mut())
+      .map(|((disk_page_id, _), buffer)| {
+        let offset = disk_page_id.0 * page_size;
+        match buffer {
+          ReadBuffer::Registered(index) => {
+            let buf_index = *index as u16;
+            let buf = self.buffer_mut(buffer);
+            opcode::ReadFixed::new(fd, buf.as_mut_ptr(), buf.len() as u32, buf_index)
+              .offset(offset)
+              .build()
+          }
+          ReadBuffer::Pooled(unique) => {
+            opcode::Read::new(fd, unique.slice.as_mut_ptr(), unique.slice.len() as u32)
+              .offset(offset)
+              .build()
+          }
+        }
+      })
+      .collect();
+    let results = submit_all(&mut self.ring, &entries)?;
+    for (((disk_page_id, _), buffer), result) in pages.iter().zip(buffers.iter_mut()).zip(results) {
+      let read = completion_len(result)?;
+      let buf = self.buffer_mut(buffer);
+      if read < buf.len() {
+        file.read_exact_at(&mut buf[read..], disk_page_id.0 * page_size + read as u64)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn share(&self, buffer: ReadBuffer) -> SharedBytes {
+    let inner = match buffer {
+      ReadBuffer::Registered(index) => self.registered[index].clone(),
+      ReadBuffer::Pooled(unique) => unique.shareable(),
+    };
+    SharedBytes {
+      inner: ManuallyDrop::new(inner),
+    }
+  }
+}
+
+struct IOUringWriter {
+  ring: IoUring,
+  options: IOUringWriteOptions,
+  /// Whether the ring has a sparse table of `options.entries` buffers, which needs Linux 5.13.
+  /// The pages of each chunk are registered in it and written with `WriteFixed`
+  fixed: bool,
+}
+
+pub struct IOUringIO {
+  core: IOCore,
+  file: File,
+  reader: Option<Mutex<IOUringReader>>,
+  writer: Option<Mutex<IOUringWriter>>,
+}
+
+/// Converts a completion result into the number of bytes transferred
+fn completion_len(result: i32) -> io::Result<usize> {
+  if result < 0 {
+    Err(io::Error::from_raw_os_error(-result))
+  } else {
+    Ok(result as usize)
+  }
+}
+
+/// Whether `io_uring_enter` failed without consuming the ring, so it can be called again
+fn is_retryable(error: &io::Error) -> bool {
+  matches!(
+    error.kind(),
+    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy
+  )
+}
+
+/// Submits `entries`, in chunks no larger than the submission queue, and waits for each to
+/// complete. Returns the completion results in submission order.
+///
+/// Every buffer referenced by `entries` must remain valid until this returns, so every submitted
+/// entry is waited for before an error is returned.
+fn submit_all(ring: &mut IoUring, entries: &[squeue::Entry]) -> io::Result<Vec<i32>> {
+  let capacity = ring.params().sq_entries() as usize;
+  let mut results = vec![0; entries.len()];
+  for (chunk_index, chunk) in entries.chunks(capacity).enumerate() {
+    let base = chunk_index * capacity;
+    let chunk: Vec<_> = chunk
+      .iter()
+      .enumerate()
+      .map(|(i, entry)| entry.clone().user_data((base + i) as u64))
+      .collect();
+    // Either the whole chunk is queued or none of it is, so nothing is left behind for the next
+    // submission
+    unsafe { ring.submission().push_multiple(&chunk) }
+      .map_err(|_| io::Error::other("submission queue is full"))?;
+    let mut submitted = 0;
+    let mut completed = 0;
+    let mut error = None;
+    while completed < chunk.len() && (error.is_none() || completed < submitted) {
+      match ring.submit_and_wait(chunk.len() - completed) {
+        Ok(count) => submitted += count,
+        Err(err) if is_retryable(&err) => {}
+        // Any other failure means the ring itself is unusable, so the entries it never took can't
+        // be submitted later. Only those already in flight are waited for.
+        Err(err) => {
+          if submitted == completed {
+            return Err(err);
+          }
+          error.get_or_insert(err);
+        }
+      }
+      for entry in ring.completion() {
+        results[entry.user_data() as usize] = entry.result();
+        completed += 1;
+      }
+    }
+    if let Some(err) = error {
+      return Err(err);
+    }
+  }
+  Ok(results)
+}
+
+impl IOUringIO {
+  fn open(
+    core: IOCore, read_options: Option<IOUringReadOptions>,
+    write_options: Option<IOUringWriteOptions>,
+  ) -> crate::Result<Self, IOError> {
+    let file = core.open_file()?;
+    let reader = match read_options {
+      Some(options) => {
+        let entries = (options.registered_buffers as u32)
+          .next_power_of_two()
+          .max(MIN_READ_ENTRIES);
+        let mut builder = IoUring::builder();
+        if let Some(idle) = options.sq_poll_idle {
+          builder.setup_sqpoll(idle);
+        }
+        let ring = builder
+          .build(entries)
+          .change_context(IOUringError::SetupFailure)
+          .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
+        let mut buffers: Vec<_> = (0..options.registered_buffers)
+          .map(|_| options.buffer_pool.pop().into_init())
+          .collect();
+        if !buffers.is_empty() {
+          let iovecs: Vec<_> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+              iov_base: buffer.slice.as_mut_ptr().cast(),
+              iov_len: buffer.slice.len(),
+            })
+            .collect();
+          // Safety: The buffers are heap allocated and the reader holds them until the ring is
+          // dropped
+          unsafe { ring.submitter().register_buffers(&iovecs) }
+            .change_context(IOUringError::RegisterFailure)
+            .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
+        }
+        Some(Mutex::new(IOUringReader {
+          ring,
+          registered: buffers.into_iter().map(UniqueArc::shareable).collect(),
+          buffer_pool: options.buffer_pool,
+        }))
+      }
+      None => None,
+    };
+    let writer = match write_options {
+      Some(options) => {
+        let ring = IoUring::new(options.entries)
+          .change_context(IOUringError::SetupFailure)
+          .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
+        let fixed = ring
+          .submitter()
+          .register_buffers_sparse(options.entries)
+          .is_ok();
+        Some(Mutex::new(IOUringWriter {
+          ring,
+          options,
+          fixed,
+        }))
+      }
+      None => None,
+    };
+    Ok(IOUringIO {
+      core,
+      file,
+      reader,
+      writer,
+    })
+  }
+
+  #[inline]
+  fn fd(&self) -> types::Fd {
+    types::Fd(self.file.as_raw_fd())
+  }
+}
+
+impl IOBackend for IOUringIO {
+  #[inline]
+  fn io_type(&self) -> IOType {
+    self.core.io_type
+  }
+
+  #[inline]
+  fn page_size(&self) -> usize {
+    self.core.page_size
+  }
+}
+
+impl IOReader for IOUringIO {
+  type Bytes = SharedBytes;
+
+  /// Reads a page into a free registered buffer when it fits, otherwise into a buffer from the
+  /// pool
+  fn read_disk_page(
+    &self, disk_page_id: DiskPageId, page_len: usize,
+  ) -> crate::Result<Self::Bytes, IOError> {
+    let mut pages = self.read_disk_pages(&[(disk_page_id, page_len)])?;
+    Ok(pages.pop().expect("one page was read"))
+  }
+
+  /// Submits a read for every page in one batch, into the free registered buffers first
+  fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    let Some((first_page_id, _)) = pages.first() else {
+      return Ok(Vec::new());
+    };
+    let page_size = self.core.page_size as u64;
+    let fd = self.fd();
+    let mut reader = self.reader.as_ref().expect("must be set to read").lock();
+    let mut buffers = reader.read_buffers(pages);
+    reader
+      .read_into(fd, &self.file, page_size, pages, &mut buffers)
+      .change_context(IOError::ReadError(*first_page_id))?;
+    Ok(
+      buffers
+        .into_iter()
+        .map(|buffer| reader.share(buffer))
+        .collect(),
+    )
+  }
+}
+
+impl ContigIOReader for IOUringIO {
+  fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError> {
+    let page = self.read_single_page(disk_page_id)?;
+    Ok(bytemuck::pod_read_unaligned(
+      &page[0..size_of::<PageHeader>()],
+    ))
+  }
+
+  fn read_contig_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError> {
+    let page = self.read_single_page(disk_page_id)?;
+    let header: PageHeader = bytemuck::pod_read_unaligned(&page[0..size_of::<PageHeader>()]);
+    let overflow = header.get_overflow();
+    let page = if overflow == 0 {
+      page
+    } else {
+      let page_len = self.core.page_size * (overflow + 1) as usize;
+      self.read_disk_page(disk_page_id, page_len)?
+    };
+    self.verify_contig_page(disk_page_id, &page)?;
+    Ok(page)
+  }
+}
+
+impl IOWriter for IOUringIO {
+  fn write_disk_page(
+    &self, disk_page_id: DiskPageId, page: SharedBytes,
+  ) -> crate::Result<(), IOError> {
+    self.write_disk_pages(vec![(disk_page_id, page)])
+  }
+
+  /// Submits every page in one batch, splitting only when the batch is larger than the ring.
+  /// The pages of each chunk are registered with the ring and written with `WriteFixed`, or
+  /// with `Write` when they can't be registered
+  fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError> {
+    let page_size = self.core.page_size;
+    let fd = self.fd();
+    let mut writer = self.writer.as_ref().expect("must be set to write").lock();
+    let writer = &mut *writer;
+    for chunk in pages.chunks(writer.options.entries as usize) {
+      let first_page_id = chunk[0].0;
+      let iovecs: Vec<_> = chunk
+        .iter()
+        .map(|(_, page)| libc::iovec {
+          iov_base: page.as_ptr().cast_mut().cast(),
+          iov_len: page.len(),
+        })
+        .collect();
+      // Safety: The pages outlive the submission. The kernel pins what's registered, and a slot
+      // is only written from once it's registered again
+      let fixed = writer.fixed
+        && unsafe {
+          writer
+            .ring
+            .submitter()
+            .register_buffers_update(0, &iovecs, None)
+        }
+        .is_ok();
+      let entries: Vec<_> = chunk
+        .iter()
+        .enumerate()
+        .map(|(index, (disk_page_id, page))| {
+          assert_eq!(0, page.len() % page_size);
+          let offset = disk_page_id.0 * page_size as u64;
+          if fixed {
+            opcode::WriteFixed::new(fd, page.as_ptr(), page.len() as u32, index as u16)
+              .offset(offset)
+              .build()
+          } else {
+            opcode::Write::new(fd, page.as_ptr(), page.len() as u32)
+              .offset(offset)
+              .build()
+          }
+        })
+        .collect();
+      let results = submit_all(&mut writer.ring, &entries)
+        .change_context(IOUringError::SubmitFailure)
+        .change_context(IOError::WriteError(first_page_id))?;
+      for ((disk_page_id, page), result) in chunk.iter().zip(results) {
+        let page_offset = disk_page_id.0 * page_size as u64;
+        let written = completion_len(result).change_context(IOError::WriteError(*disk_page_id))?;
+        if written < page.len() {
+          self
+            .file
+            .write_all_at(&page[written..], page_offset + written as u64)
+            .change_context(IOError::WriteError(*disk_page_id))?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn sync(&self) -> crate::Result<(), IOError> {
+    let mut writer = self.writer.as_ref().expect("must be set to write").lock();
+    let flags = if writer.options.use_fdatasync {
+      types::FsyncFlags::DATASYNC
+    } else {
+      types::FsyncFlags::empty()
+    };
+    let entry = opcode::Fsync::new(self.fd()).flags(flags).build();
+    let result = submit_all(&mut writer.ring, &[entry])
+      .change_context(IOUringError::SubmitFailure)
+      .change_context(IOError::SyncError)?;
+    completion_len(result[0]).change_context(IOError::SyncError)?;
+    Ok(())
+  }
+}
+
+impl NewIOReader for IOUringIO {
+  type ReadOptions = IOUringReadOptions;
+
+  fn new_ro(
+    path: Arc<PathBuf>, page_size: usize, options: Self::ReadOptions,
+  ) -> crate::Result<ROShell<Self>, IOError> {
+    let core = IOCore {
+      path,
+      page_size,
+      io_type: IOType::RO,
+    };
+    let io = IOUringIO::open(core, Some(options), None)?;
+    Ok(ROShell::new(io))
+  }
+}
+
+impl NewIOWriter for IOUringIO {
+  type WriteOptions = IOUringWriteOptions;
+
+  fn new_wo(
+    path: Arc<PathBuf>, page_size: usize, options: Self::WriteOptions,
+  ) -> crate::Result<WOShell<Self>, IOError> {
+    let core = IOCore {
+      path,
+      page_size,
+      io_type: IOType::WO,
+    };
+    let io = IOUringIO::open(core, None, Some(options))?;
+    Ok(WOShell::new(io))
+  }
+}
+
+impl NewIOReadWriter for IOUringIO {
+  fn new_rw(
+    path: Arc<PathBuf>, page_size: usize, read_options: Self::ReadOptions,
+    write_options: Self::WriteOptions,
+  ) -> crate::Result<Self, IOError> {
+    let core = IOCore {
+      path,
+      page_size,
+      io_type: IOType::RW,
+    };
+    IOUringIO::open(core, Some(read_options), Some(write_options))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::id::DbPageId;
+  use size::Size;
+  use std::fs;
+
+  #[test]
+  fn test_write_and_read() {
+    let page_size = 4096;
+    let path = std::env::temp_dir().join(format!("bbolt-nub-io-uring-{}.db", std::process::id()));
+    fs::write(&path, []).unwrap();
+    let path = Arc::new(path);
+    let buffer_pool = BufferPool::new(
+      page_size,
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(64),
+    );
+    let read_options = IOUringReadOptions::new(buffer_pool.clone(), 2, None);
+    let write_options = IOUringWriteOptions::new(4, true);
+    let io = IOUringIO::new_rw(path.clone(), page_size, read_options, write_options).unwrap();
+
+    let mut pages = Vec::new();
+    for i in 0..6u8 {
+      pages.push((
+        DiskPageId(i as u64),
+        buffer_pool.pop().fill_and_share(|b| b.fill(i)),
+      ));
+    }
+    let mut header = PageHeader::init_leaf(DbPageId(6));
+    unsafe { header.set_overflow(1) };
+    let overflow = BufferPool::new_unbound(page_size * 2).fill_and_share(|b| {
+      b.fill(6);
+      b[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+    });
+    pages.push((DiskPageId(6), overflow));
+    io.write_disk_pages(pages).unwrap();
+    io.sync().unwrap();
+    assert_eq!(
+      page_size as u64 * 8,
+      fs::metadata(path.as_ref()).unwrap().len()
+    );
+
+    for i in 0..6u8 {
+      let page = io.read_single_page(DiskPageId(i as u64)).unwrap();
+      assert!(page.iter().all(|b| *b == i));
+    }
+    // Held pages keep their registered buffers, so the third read falls back to the pool
+    let held: Vec<_> = (0..3)
+      .map(|i| io.read_single_page(DiskPageId(i)).unwrap())
+      .collect();
+    for (i, page) in held.iter().enumerate() {
+      assert!(page.iter().all(|b| *b == i as u8));
+    }
+    let is_free = |io: &IOUringIO| {
+      let reader = io.reader.as_ref().unwrap().lock();
+      reader
+        .registered
+        .iter()
+        .map(|buffer| buffer.is_unique())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(vec![false, false], is_free(&io));
+    drop(held);
+    assert_eq!(vec![true, true], is_free(&io));
+    let page = io.read_contig_page(DiskPageId(6)).unwrap();
+    assert_eq!(page_size * 2, page.len());
+    assert!(page[size_of::<PageHeader>()..].iter().all(|b| *b == 6));
+
+    let batch: Vec<_> = (0..6).rev().map(|i| (DiskPageId(i), page_size)).collect();
+    for (i, page) in io.read_disk_pages(&batch).unwrap().iter().rev().enumerate() {
+      assert!(page.iter().all(|b| *b == i as u8));
+    }
+    let batch = io.read_contig_pages(&[DiskPageId(6)]).unwrap();
+    assert_eq!(page_size * 2, batch[0].len());
+    fs::remove_file(path.as_ref()).unwrap();
+  }
+}
@@ -1,19 +1,26 @@
+use crate::common::consts::DEFAULT_ALLOC_SIZE;
+use crate::common::epoch::{EpochGuard, Epochs};
 use crate::common::errors::IOError;
 use crate::common::id::{DiskPageId, EOFPageId, FreelistPageId, MetaPageId, NodePageId};
 use crate::common::layout::page::PageHeader;
 use crate::io::backends::{
   ContigIOReader, IOBackend, IOCore, IOOverflowPageReader, IOPageReader, IOReader, IOType,
-  NewIOReader, ROShell, ReadLoadedPageIO, WOShell,
+  IOWriter, NewIOReadWriter, NewIOReader, NewIOWriter, ROShell, ReadLoadedPageIO, WOShell,
 };
 use crate::io::bytes::ref_bytes::RefBytes;
+use crate::io::bytes::shared_bytes::SharedBytes;
 use crate::io::pages::{TxReadLazyPageIO, TxReadPageIO};
 use crate::io::transmogrify::{TxContext, TxDirectContext, TxIndirectContext};
 use error_stack::ResultExt;
 use memmap2::{Advice, Mmap, MmapOptions, MmapRaw};
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::mem;
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{mem, ptr};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -24,6 +31,10 @@ pub enum MemMapError {
   MLockFailure,
   #[error("MemMap Advice Failure")]
   AdviceFailure,
+  #[error("MemMap Flush Failure")]
+  FlushFailure,
+  #[error("MemMap Grow Failure")]
+  GrowFailure,
 }
 
 #[derive(Debug, Clone)]
@@ -31,6 +42,7 @@ pub struct MemMapReadOptions {
   pre_populate_pages: bool,
   use_mlock: bool,
   advise_random: bool,
+  page_checksums: bool,
 }
 
 impl MemMapReadOptions {
@@ -39,68 +51,292 @@ impl MemMapReadOptions {
       pre_populate_pages,
       use_mlock,
       advise_random,
+      page_checksums: false,
     }
   }
+
+  /// Verifies the checksum footer of each page as it's read
+  pub fn with_page_checksums(mut self, page_checksums: bool) -> Self {
+    self.page_checksums = page_checksums;
+    self
+  }
 }
 
+/// The smallest size a file is grown to, matching Go BBolt's minimum mmap size
+const MIN_GROW_SIZE: u64 = 1 << 15;
+
 #[derive(Debug, Clone)]
-pub struct MemMapWriteOptions {}
+pub struct MemMapWriteOptions {
+  /// Once the file is this large it grows in steps of this size
+  alloc_size: u64,
+  /// Skip syncing the file's new length after growing it. Matches Go BBolt's `NoGrowSync`
+  no_grow_sync: bool,
+}
+
+impl MemMapWriteOptions {
+  pub fn new(alloc_size: u64, no_grow_sync: bool) -> Self {
+    Self {
+      alloc_size,
+      no_grow_sync,
+    }
+  }
+}
+
+impl Default for MemMapWriteOptions {
+  fn default() -> Self {
+    Self::new(DEFAULT_ALLOC_SIZE.bytes() as u64, false)
+  }
+}
+
+/// What the epochs keep alive for pinned readers. Only ever dropped.
+#[allow(dead_code)]
+enum Retired {
+  Mapping(MmapRaw),
+  Bytes(SharedBytes),
+}
+
+/// Pages decoded out of the mapping, by the id they were read at
+#[derive(Default)]
+struct KeptPages {
+  pages: HashMap<DiskPageId, SharedBytes>,
+  /// Bumped whenever kept pages are forgotten, so a page decoded across a write isn't kept
+  writes: u64,
+}
 
 pub struct MemMapIO {
   core: IOCore,
   file: File,
-  mmap: MmapRaw,
+  mmap: RwLock<MmapRaw>,
+  /// Mappings replaced by a remap. Readers may still hold `RefBytes` into them so each lives
+  /// until every transaction pinned before it was replaced is dropped
+  epochs: Epochs<Retired>,
+  kept: Mutex<KeptPages>,
   read_options: Option<MemMapReadOptions>,
+  write_options: Option<MemMapWriteOptions>,
+  file_len: AtomicU64,
+  mmap_dirty: AtomicBool,
+  file_dirty: AtomicBool,
 }
 
-impl IOBackend for MemMapIO {
+impl MemMapIO {
+  /// The length of the file, including space allocated by writes that is not mapped yet
   #[inline]
-  fn io_type(&self) -> IOType {
-    self.core.io_type
+  pub fn file_len(&self) -> u64 {
+    self.file_len.load(Ordering::Acquire)
   }
 
+  /// The length of the current mapping
   #[inline]
-  fn page_size(&self) -> usize {
-    self.core.page_size
+  pub fn mmap_len(&self) -> usize {
+    self.mmap.read().len()
   }
 
-  fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError> {
+  /// Pins the current mapping, and any mapping that replaces it, until the guard is dropped.
+  ///
+  /// `RefBytes` read while the guard is held remain valid across remaps.
+  pub fn pin(&self) -> EpochGuard {
+    self.epochs.pin()
+  }
+
+  /// Maps the file again if it's grown past `new_len`. The old mapping is retired rather than
+  /// unmapped so readers pinned to it aren't blocked or invalidated.
+  pub fn remap(&self, new_len: usize) -> crate::Result<(), IOError> {
+    let mut mmap = self.mmap.write();
+    if new_len <= mmap.len() {
+      return Ok(());
+    }
+    // Pending writes made through the old mapping must reach the file before it's retired
+    MemMapIO::flush(&mmap, &self.mmap_dirty)?;
     let mut options = MmapOptions::new();
     if let Some(read_options) = self.read_options.as_ref() {
       if read_options.pre_populate_pages {
         options.populate();
       }
     }
-    let mut new_mmap = match self.core.io_type {
+    let new_mmap = match self.core.io_type {
       IOType::RO => options.map_raw_read_only(&self.file),
       _ => options.map_raw(&self.file),
     }
     .change_context_lazy(|| IOError::OpenError((*self.core.path).clone()))?;
 
-    mem::swap(&mut self.mmap, &mut new_mmap);
-
-    if let Some(read_options) = self.read_options.as_mut() {
+    if let Some(read_options) = self.read_options.as_ref() {
       if read_options.use_mlock {
-        new_mmap
+        mmap
           .unlock()
           .change_context(MemMapError::MUnlockFailure)
           .change_context(IOError::UpdateLengthError)?;
-        self
-          .mmap
+        new_mmap
           .lock()
           .change_context(MemMapError::MLockFailure)
           .change_context(IOError::UpdateLengthError)?;
       }
       if read_options.advise_random {
-        self
-          .mmap
+        new_mmap
           .advise(Advice::Random)
           .change_context(MemMapError::AdviceFailure)
           .change_context(IOError::UpdateLengthError)?
       }
     }
+    let old_mmap = mem::replace(&mut *mmap, new_mmap);
+    drop(mmap);
+    self.epochs.retire(Retired::Mapping(old_mmap));
     Ok(())
   }
+
+  /// Hands out the page decoded at `disk_page_id` by `decode` as `RefBytes`, decoding it only
+  /// if it isn't kept already.
+  ///
+  /// Kept pages live until [`MemMapIO::forget`] retires them, so the bytes stay valid as long as
+  /// a mapping retired then would. It must only be called while pinned.
+  pub fn keep(
+    &self, disk_page_id: DiskPageId, page_len: usize,
+    decode: impl FnOnce() -> crate::Result<SharedBytes, IOError>,
+  ) -> crate::Result<RefBytes, IOError> {
+    let writes = {
+      let kept = self.kept.lock();
+      if let Some(page) = kept.pages.get(&disk_page_id) {
+        if page.len() == page_len {
+          return Ok(RefBytes::from_ref(page));
+        }
+      }
+      kept.writes
+    };
+    let page = decode()?;
+    let bytes = RefBytes::from_ref(&page);
+    let mut kept = self.kept.lock();
+    if kept.writes != writes {
+      // The page may have been rewritten while it was decoded
+      self.epochs.retire(Retired::Bytes(page));
+    } else if let Some(replaced) = kept.pages.insert(disk_page_id, page) {
+      self.epochs.retire(Retired::Bytes(replaced));
+    }
+    Ok(bytes)
+  }
+
+  /// Retires the pages kept for the `page_count` ids starting at `disk_page_id`. Called once
+  /// they're rewritten.
+  pub fn forget(&self, disk_page_id: DiskPageId, page_count: u64) {
+    let mut kept = self.kept.lock();
+    kept.writes += 1;
+    for id in disk_page_id.0..disk_page_id.0 + page_count {
+      if let Some(page) = kept.pages.remove(&DiskPageId(id)) {
+        self.epochs.retire(Retired::Bytes(page));
+      }
+    }
+  }
+
+  /// The number of decoded pages kept
+  pub fn kept_len(&self) -> usize {
+    self.kept.lock().pages.len()
+  }
+
+  /// Grows the file so it is at least `min_len` long.
+  ///
+  /// Small files double in size starting from 32KiB. Once the file reaches `alloc_size` it grows
+  /// in `alloc_size` steps.
+  fn grow(&self, min_len: u64) -> crate::Result<(), IOError> {
+    let file_len = self.file_len();
+    if min_len <= file_len {
+      return Ok(());
+    }
+    let options = self.write_options.as_ref().expect("must be set to write");
+    let new_len = if min_len < options.alloc_size {
+      min_len
+        .next_power_of_two()
+        .clamp(MIN_GROW_SIZE, options.alloc_size)
+    } else {
+      min_len.div_ceil(options.alloc_size) * options.alloc_size
+    };
+    self
+      .file
+      .set_len(new_len)
+      .change_context(MemMapError::GrowFailure)
+      .change_context(IOError::UpdateLengthError)?;
+    if !options.no_grow_sync {
+      self
+        .file
+        .sync_all()
+        .change_context(MemMapError::GrowFailure)
+        .change_context(IOError::UpdateLengthError)?;
+    }
+    self.file_len.store(new_len, Ordering::Release);
+    Ok(())
+  }
+
+  fn open(
+    core: IOCore, read_options: Option<MemMapReadOptions>,
+    write_options: Option<MemMapWriteOptions>,
+  ) -> crate::Result<Self, IOError> {
+    // A writable mapping needs a readable file, even when it's write-only
+    let file = OpenOptions::new()
+      .read(true)
+      .write(core.io_type != IOType::RO)
+      .open(core.path())
+      .change_context_lazy(|| IOError::OpenError(core.path().into()))?;
+    let file_len = file.metadata().change_context(IOError::MetaError)?.len();
+    let mut mmap_options = MmapOptions::new();
+    let pre_populate_pages = read_options
+      .as_ref()
+      .map(|options| options.pre_populate_pages)
+      .unwrap_or_default();
+    if pre_populate_pages {
+      mmap_options.populate();
+    }
+    let mmap = match core.io_type {
+      IOType::RO => mmap_options.map_raw_read_only(&file),
+      _ => mmap_options.map_raw(&file),
+    }
+    .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
+    if let Some(options) = read_options.as_ref() {
+      if options.use_mlock {
+        mmap
+          .lock()
+          .change_context(MemMapError::MLockFailure)
+          .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
+      }
+      if options.advise_random {
+        mmap
+          .advise(Advice::Random)
+          .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
+      }
+    }
+    Ok(MemMapIO {
+      core,
+      file,
+      mmap: RwLock::new(mmap),
+      epochs: Epochs::new(),
+      kept: Mutex::default(),
+      read_options,
+      write_options,
+      file_len: AtomicU64::new(file_len),
+      mmap_dirty: AtomicBool::new(false),
+      file_dirty: AtomicBool::new(false),
+    })
+  }
+}
+
+impl IOBackend for MemMapIO {
+  #[inline]
+  fn io_type(&self) -> IOType {
+    self.core.io_type
+  }
+
+  #[inline]
+  fn page_size(&self) -> usize {
+    self.core.page_size
+  }
+
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self
+      .read_options
+      .as_ref()
+      .is_some_and(|read_options| read_options.page_checksums)
+  }
+
+  fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError> {
+    self.remap(new_len)
+  }
 }
 
 impl IOReader for MemMapIO {
@@ -110,25 +346,47 @@ impl IOReader for MemMapIO {
     &self, disk_page_id: DiskPageId, page_len: usize,
   ) -> error_stack::Result<Self::Bytes, IOError> {
     let page_offset = disk_page_id.0 as usize * self.core.page_size;
-    if page_offset + page_len > self.mmap.len() {
-      let eof = EOFPageId(DiskPageId((self.mmap.len() / self.core.page_size) as u64));
+    let mmap = self.mmap.read();
+    if page_offset + page_len > mmap.len() {
+      let eof = EOFPageId(DiskPageId((mmap.len() / self.core.page_size) as u64));
       Err(IOError::UnexpectedEOF(disk_page_id, eof).into())
     } else {
-      let ptr = unsafe { self.mmap.as_ptr().add(page_offset) };
+      let ptr = unsafe { mmap.as_ptr().add(page_offset) };
       Ok(RefBytes::from_ptr_len(ptr, page_len))
     }
   }
+
+  /// Hints the kernel with `madvise(WILLNEED)` before handing out the mapped pages.
+  /// The hint is best-effort and failures are ignored.
+  fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    {
+      let mmap = self.mmap.read();
+      for (disk_page_id, page_len) in pages {
+        let page_offset = disk_page_id.0 as usize * self.core.page_size;
+        if page_offset + page_len <= mmap.len() {
+          let _ = mmap.advise_range(Advice::WillNeed, page_offset, *page_len);
+        }
+      }
+    }
+    pages
+      .iter()
+      .map(|(disk_page_id, page_len)| self.read_disk_page(*disk_page_id, *page_len))
+      .collect()
+  }
 }
 
 impl ContigIOReader for MemMapIO {
   fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError> {
     let page_offset = disk_page_id.0 as usize * self.core.page_size;
     let header_end = page_offset + size_of::<PageHeader>();
-    if header_end > self.mmap.len() {
-      let eof = EOFPageId(DiskPageId((self.mmap.len() / self.core.page_size) as u64));
+    let mmap = self.mmap.read();
+    if header_end > mmap.len() {
+      let eof = EOFPageId(DiskPageId((mmap.len() / self.core.page_size) as u64));
       Err(IOError::UnexpectedEOF(disk_page_id, eof.into()).into())
     } else {
-      let ptr = unsafe { self.mmap.as_ptr().add(page_offset) };
+      let ptr = unsafe { mmap.as_ptr().add(page_offset) };
       let bytes = RefBytes::from_ptr_len(ptr, size_of::<PageHeader>());
       Ok(*bytemuck::from_bytes(bytes.as_ref()))
     }
@@ -146,134 +404,143 @@ impl NewIOReader for MemMapIO {
       page_size,
       io_type: IOType::RO,
     };
-    let file = core.open_file()?;
-    let mut mmap_options = MmapOptions::new();
-    if options.pre_populate_pages {
-      mmap_options.populate();
-    }
-    let mmap = mmap_options
-      .map_raw_read_only(&file)
-      .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
-    if options.advise_random {
-      mmap
-        .advise(Advice::Random)
-        .change_context_lazy(|| IOError::OpenError((*core.path).clone()))?;
-    }
-    Ok(ROShell::new(MemMapIO {
-      core,
-      file,
-      mmap,
-      read_options: Some(options),
-    }))
+    let io = MemMapIO::open(core, Some(options), None)?;
+    Ok(ROShell::new(io))
   }
 }
 
-/*
 impl MemMapIO {
-  pub fn new_rw<P: AsRef<Path>>(path: P, page_size: usize) -> crate::Result<Self, DiskError> {
-    let path = path.as_ref();
-    let file = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .open(path)
-      .change_context_lazy(|| DiskError::OpenError(path.into()))?;
-    let options = MmapOptions::new();
-    let mmap = options
-      .clone()
-      .map_raw(&file)
-      .change_context_lazy(|| DiskError::OpenError(path.into()))?;
-    Ok(Self {
-      io_type: IOType::RW,
-      path: path.into(),
-      file,
-      mmap,
-      options,
-      page_size,
-    })
+  fn flush(mmap: &MmapRaw, mmap_dirty: &AtomicBool) -> crate::Result<(), IOError> {
+    if mmap_dirty.swap(false, Ordering::AcqRel) {
+      mmap
+        .flush()
+        .change_context(MemMapError::FlushFailure)
+        .change_context(IOError::SyncError)?;
+    }
+    Ok(())
   }
+}
 
-  pub fn new_ro<P: AsRef<Path>>(
-    path: P, page_size: usize,
-  ) -> crate::Result<ROShell<Self>, DiskError> {
-    let path = path.as_ref();
-    let file = OpenOptions::new()
-      .read(true)
-      .open(path)
-      .change_context_lazy(|| DiskError::OpenError(path.into()))?;
-    let options = MmapOptions::new();
-    let mmap = options
-      .clone()
-      .map_raw_read_only(&file)
-      .change_context_lazy(|| DiskError::OpenError(path.into()))?;
-    Ok(ROShell::new(Self {
-      io_type: IOType::RO,
-      path: path.into(),
-      file,
-      mmap,
-      options,
-      page_size,
-    }))
+impl IOWriter for MemMapIO {
+  /// Writes through the mapping when the page is mapped, otherwise falls back to `pwrite`.
+  /// Pages past the end of the file grow it first.
+  fn write_disk_page(
+    &self, disk_page_id: DiskPageId, page: SharedBytes,
+  ) -> crate::Result<(), IOError> {
+    let page_size = self.core.page_size;
+    assert_eq!(0, page.len() % page_size);
+    let page_offset = disk_page_id.0 as usize * page_size;
+    let page_end = page_offset + page.len();
+    self.grow(page_end as u64)?;
+    let mmap = self.mmap.read();
+    if page_end <= mmap.len() {
+      // Safety: The range is within the writable mapping, and pages being written are never
+      // visible to readers until the transaction's meta page is written.
+      unsafe {
+        let dst = mmap.as_mut_ptr().add(page_offset);
+        ptr::copy_nonoverlapping(page.as_ptr(), dst, page.len());
+      }
+      self.mmap_dirty.store(true, Ordering::Release);
+      Ok(())
+    } else {
+      self
+        .file
+        .write_all_at(&page, page_offset as u64)
+        .change_context(IOError::WriteError(disk_page_id))?;
+      self.file_dirty.store(true, Ordering::Release);
+      Ok(())
+    }
   }
 
-  pub fn new_wo<P: AsRef<Path>>(
-    path: P, page_size: usize,
-  ) -> crate::Result<WOShell<Self>, DiskError> {
-    let path = path.as_ref();
-    let file = OpenOptions::new()
-      .write(true)
-      .open(path)
-      .change_context_lazy(|| DiskError::OpenError(path.into()))?;
-    let options = MmapOptions::new();
-    let mmap = options
-      .clone()
-      .map_raw(&file)
-      .change_context_lazy(|| DiskError::OpenError(path.into()))?;
-    Ok(WOShell::new(Self {
-      io_type: IOType::WO,
-      path: path.into(),
-      file,
-      mmap,
-      options,
-      page_size,
-    }))
+  /// Flushes the mapping with `msync` and any `pwrite`s with `fdatasync`
+  fn sync(&self) -> crate::Result<(), IOError> {
+    MemMapIO::flush(&self.mmap.read(), &self.mmap_dirty)?;
+    if self.file_dirty.swap(false, Ordering::AcqRel) {
+      self.file.sync_data().change_context(IOError::SyncError)?;
+    }
+    Ok(())
   }
 }
 
-pub struct MemMapReader {
-  mmap: Mmap,
-  page_size: usize,
+impl NewIOWriter for MemMapIO {
+  type WriteOptions = MemMapWriteOptions;
+
+  fn new_wo(
+    path: Arc<PathBuf>, page_size: usize, options: Self::WriteOptions,
+  ) -> crate::Result<WOShell<Self>, IOError> {
+    let core = IOCore {
+      path,
+      page_size,
+      io_type: IOType::WO,
+    };
+    let io = MemMapIO::open(core, None, Some(options))?;
+    Ok(WOShell::new(io))
+  }
 }
 
-impl MemMapReader {
-  pub fn new<P: AsRef<Path>>(path: P, page_size: usize) -> Self {
-    let file = File::open(path.as_ref()).unwrap();
-    let mmap = unsafe { MmapOptions::new().map(&file) }.unwrap();
-    mmap.advise(Advice::Random).unwrap();
-    Self { mmap, page_size }
+impl NewIOReadWriter for MemMapIO {
+  fn new_rw(
+    path: Arc<PathBuf>, page_size: usize, read_options: Self::ReadOptions,
+    write_options: Self::WriteOptions,
+  ) -> crate::Result<Self, IOError> {
+    let core = IOCore {
+      path,
+      page_size,
+      io_type: IOType::RW,
+    };
+    MemMapIO::open(core, Some(read_options), Some(write_options))
   }
 }
 
-impl IOReader for MemMapReader {
-  type Bytes = RefBytes;
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::buffer_pool::BufferPool;
+  use std::fs;
 
-  #[inline]
-  fn page_size(&self) -> usize {
-    self.page_size
-  }
+  #[test]
+  fn test_write_grow_and_remap() {
+    let page_size = 4096;
+    let path = std::env::temp_dir().join(format!("bbolt-nub-memmap-{}.db", std::process::id()));
+    fs::write(&path, vec![1u8; page_size]).unwrap();
+    let path = Arc::new(path);
+    let read_options = MemMapReadOptions::new(false, false, true);
+    let write_options = MemMapWriteOptions::new(1 << 20, false);
+    let mut io = MemMapIO::new_rw(path.clone(), page_size, read_options, write_options).unwrap();
+    let epoch = io.pin();
+    let old_page = io.read_single_page(DiskPageId(0)).unwrap();
 
-  fn read_disk_page(
-    &self, disk_page_id: DiskPageId, page_len: usize,
-  ) -> crate::Result<Self::Bytes, DiskError> {
-    let page_offset = disk_page_id.0 as usize * self.page_size;
-    if page_offset + page_len > self.mmap.len() {
-      let eof = EOFPageId(DiskPageId((self.mmap.len() / self.page_size) as u64));
-      Err(DiskError::UnexpectedEOF(disk_page_id, eof).into())
-    } else {
-      let bytes = &self.mmap[page_offset..page_offset + page_len];
-      Ok(RefBytes::from_ref(bytes))
-    }
-  }
-}
+    let page = BufferPool::new_unbound(page_size * 2).fill_and_share(|b| b.fill(2));
+    io.write_disk_page(DiskPageId(1), page).unwrap();
+    io.sync().unwrap();
+    assert_eq!(MIN_GROW_SIZE, io.file_len());
+    assert_eq!(page_size, io.mmap_len());
+    assert!(io.read_single_page(DiskPageId(1)).is_err());
+
+    io.apply_length_update(io.file_len() as usize).unwrap();
+    assert_eq!(MIN_GROW_SIZE as usize, io.mmap_len());
+    // Pages read from the old mapping remain valid while pinned
+    assert_eq!(1, io.epochs.retired_len());
+    assert!(old_page.iter().all(|b| *b == 1));
+    drop(epoch);
+    assert_eq!(0, io.epochs.retired_len());
+    let new_page = io.read_disk_page(DiskPageId(1), page_size * 2).unwrap();
+    assert!(new_page.iter().all(|b| *b == 2));
 
+    let page = BufferPool::new_unbound(page_size).fill_and_share(|b| b.fill(3));
+    io.write_single_page(DiskPageId(3), page).unwrap();
+    io.sync().unwrap();
+    assert!(
+      io.read_single_page(DiskPageId(3))
+        .unwrap()
+        .iter()
+        .all(|b| *b == 3)
+    );
 
-*/
+    let page = BufferPool::new_unbound(page_size).fill_and_share(|b| b.fill(4));
+    io.write_single_page(DiskPageId(300), page).unwrap();
+    assert_eq!(1 << 21, io.file_len());
+    drop(io);
+    fs::remove_file(path.as_ref()).unwrap();
+  }
+}
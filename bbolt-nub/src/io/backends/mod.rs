@@ -1,10 +1,11 @@
 use crate::common::errors::IOError;
 use crate::common::id::{DiskPageId, FreelistPageId, MetaPageId, NodePageId};
+use crate::common::layout::checksum::verify_page_checksums;
 use crate::common::layout::page::PageHeader;
 use crate::io::bytes::IOBytes;
 use crate::io::bytes::ref_bytes::RefBytes;
 use crate::io::bytes::shared_bytes::SharedBytes;
-use crate::io::transmogrify::{TxContext, TxDirectContext};
+use crate::io::transmogrify::{TxContext, TxDirectContext, TxIndirectContext};
 use bytes::BufMut;
 use delegate::delegate;
 use error_stack::{Report, ResultExt};
@@ -18,8 +19,10 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 pub mod channel_store;
+pub mod crypt;
 
 pub mod file;
+pub mod file_lock;
 
 #[cfg(target_family = "unix")]
 pub mod p_file;
@@ -76,6 +79,12 @@ pub trait IOBackend: Sized {
 
   fn page_size(&self) -> usize;
 
+  /// Whether pages end in a checksum footer that's verified when they're read
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    false
+  }
+
   #[inline]
   fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError> {
     Ok(())
@@ -103,6 +112,17 @@ pub trait IOReader: IOBackend {
     let page_len = page_size;
     self.read_disk_page(disk_page_id, page_len)
   }
+
+  /// Reads each `(disk_page_id, page_len)` run, returning the pages in the same order.
+  /// Backends that can read several pages at once should override this.
+  fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    pages
+      .iter()
+      .map(|(disk_page_id, page_len)| self.read_disk_page(*disk_page_id, *page_len))
+      .collect()
+  }
 }
 
 pub trait NewIOReader: IOReader {
@@ -119,15 +139,70 @@ pub trait ContigIOReader: IOReader {
     let page_size = self.page_size();
     let header = self.read_header(disk_page_id)?;
     let overflow = header.get_overflow();
-    let page_len = page_size + (overflow + 1) as usize;
-    self.read_disk_page(disk_page_id, page_len)
+    let page_len = page_size * (overflow + 1) as usize;
+    let page = self.read_disk_page(disk_page_id, page_len)?;
+    self.verify_contig_page(disk_page_id, &page)?;
+    Ok(page)
+  }
+
+  /// Reads each contiguous page with a batch of single page reads. Pages with overflow are read
+  /// again in full.
+  fn read_contig_pages(
+    &self, disk_page_ids: &[DiskPageId],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    let page_size = self.page_size();
+    let single_pages: Vec<_> = disk_page_ids.iter().map(|id| (*id, page_size)).collect();
+    let mut pages = self.read_disk_pages(&single_pages)?;
+    for (disk_page_id, page) in disk_page_ids.iter().zip(pages.iter_mut()) {
+      let header: PageHeader = bytemuck::pod_read_unaligned(&page[0..size_of::<PageHeader>()]);
+      let overflow = header.get_overflow();
+      if overflow > 0 {
+        *page = self.read_disk_page(*disk_page_id, page_size * (overflow + 1) as usize)?;
+      }
+      self.verify_contig_page(*disk_page_id, page)?;
+    }
+    Ok(pages)
+  }
+
+  /// Verifies the checksum footer of a page read in full when the backend has them. Meta pages
+  /// carry their own checksum instead.
+  fn verify_contig_page(
+    &self, disk_page_id: DiskPageId, page: &[u8],
+  ) -> crate::Result<(), IOError> {
+    if self.page_checksums() {
+      let header: PageHeader = bytemuck::pod_read_unaligned(&page[0..size_of::<PageHeader>()]);
+      if !header.is_meta() {
+        verify_page_checksums(page, self.page_size())
+          .change_context(IOError::ReadError(disk_page_id))?;
+      }
+    }
+    Ok(())
   }
 }
 
 pub trait IOWriter: IOBackend {
-  fn write_single_page(
+  /// Writes `page` starting at `disk_page_id`. `page` may span several contiguous pages.
+  fn write_disk_page(
     &self, disk_page_id: DiskPageId, page: SharedBytes,
   ) -> crate::Result<(), IOError>;
+
+  fn write_single_page(
+    &self, disk_page_id: DiskPageId, page: SharedBytes,
+  ) -> crate::Result<(), IOError> {
+    assert_eq!(self.page_size(), page.len());
+    self.write_disk_page(disk_page_id, page)
+  }
+
+  /// Writes every page in `pages`. Backends that can submit writes together should override this.
+  fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError> {
+    for (disk_page_id, page) in pages {
+      self.write_disk_page(disk_page_id, page)?;
+    }
+    Ok(())
+  }
+
+  /// Flushes all written pages to disk
+  fn sync(&self) -> crate::Result<(), IOError>;
 }
 
 pub trait NewIOWriter: IOWriter {
@@ -152,6 +227,10 @@ impl<R> ROShell<R> {
   pub fn new(read: R) -> Self {
     Self { read }
   }
+
+  pub fn into_inner(self) -> R {
+    self.read
+  }
 }
 
 impl<R> IOBackend for ROShell<R>
@@ -162,6 +241,7 @@ where
       to self.read {
           fn io_type(&self) -> IOType;
           fn page_size(&self) -> usize;
+          fn page_checksums(&self) -> bool;
           fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError>;
       }
   }
@@ -178,6 +258,9 @@ where
     &self, disk_page_id: DiskPageId, page_len: usize,
   ) -> crate::Result<Self::Bytes, IOError>;
       fn read_single_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError>;
+      fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError>;
     }
   }
 }
@@ -202,6 +285,9 @@ where
     to self.read {
       fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError>;
       fn read_contig_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError>;
+      fn read_contig_pages(
+    &self, disk_page_ids: &[DiskPageId],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError>;
     }
   }
 }
@@ -224,6 +310,7 @@ where
       to self.write {
           fn io_type(&self) -> IOType;
           fn page_size(&self) -> usize;
+          fn page_checksums(&self) -> bool;
           fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError>;
       }
   }
@@ -235,9 +322,14 @@ where
 {
   delegate! {
     to self.write {
+      fn write_disk_page(
+          &self, disk_page_id: DiskPageId, page: SharedBytes,
+        ) -> crate::Result<(), IOError>;
       fn write_single_page(
           &self, disk_page_id: DiskPageId, page: SharedBytes,
         ) -> crate::Result<(), IOError>;
+      fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError>;
+      fn sync(&self) -> crate::Result<(), IOError>;
     }
   }
 }
@@ -300,6 +392,11 @@ where
     self.read.page_size()
   }
 
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self.read.page_checksums()
+  }
+
   fn apply_length_update(&mut self, new_len: usize) -> error_stack::Result<(), IOError> {
     self.read.apply_length_update(new_len)?;
     self.write.apply_length_update(new_len)
@@ -319,6 +416,9 @@ where
     &self, disk_page_id: DiskPageId, page_len: usize,
   ) -> crate::Result<Self::Bytes, IOError>;
       fn read_single_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError>;
+      fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError>;
     }
   }
 }
@@ -332,6 +432,9 @@ where
     to self.read {
       fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError>;
       fn read_contig_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError>;
+      fn read_contig_pages(
+    &self, disk_page_ids: &[DiskPageId],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError>;
     }
   }
 }
@@ -343,9 +446,14 @@ where
 {
   delegate! {
     to self.write {
+      fn write_disk_page(
+          &self, disk_page_id: DiskPageId, page: SharedBytes,
+        ) -> crate::Result<(), IOError>;
       fn write_single_page(
           &self, disk_page_id: DiskPageId, page: SharedBytes,
         ) -> crate::Result<(), IOError>;
+      fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError>;
+      fn sync(&self) -> crate::Result<(), IOError>;
     }
   }
 }
@@ -355,6 +463,38 @@ pub struct DirectReadHandler<T, I> {
   pub(crate) io: I,
 }
 
+impl<T, I> IOBackend for DirectReadHandler<T, I>
+where
+  I: IOBackend,
+{
+  delegate! {
+      to self.io {
+          fn io_type(&self) -> IOType;
+          fn page_size(&self) -> usize;
+          fn page_checksums(&self) -> bool;
+          fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError>;
+      }
+  }
+}
+
+impl<T, I> IOWriter for DirectReadHandler<T, I>
+where
+  I: IOWriter,
+{
+  delegate! {
+    to self.io {
+      fn write_disk_page(
+          &self, disk_page_id: DiskPageId, page: SharedBytes,
+        ) -> crate::Result<(), IOError>;
+      fn write_single_page(
+          &self, disk_page_id: DiskPageId, page: SharedBytes,
+        ) -> crate::Result<(), IOError>;
+      fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError>;
+      fn sync(&self) -> crate::Result<(), IOError>;
+    }
+  }
+}
+
 pub trait IOPageReader {
   type Bytes: IOBytes;
 
@@ -365,6 +505,28 @@ pub trait IOPageReader {
   ) -> crate::Result<Self::Bytes, IOError>;
 
   fn read_node_page(&self, node_page_id: NodePageId) -> crate::Result<Self::Bytes, IOError>;
+
+  /// Reads several node pages, returning them in the same order
+  fn read_node_pages(
+    &self, node_page_ids: &[NodePageId],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    node_page_ids
+      .iter()
+      .map(|node_page_id| self.read_node_page(*node_page_id))
+      .collect()
+  }
+
+  /// Reads the single page at `disk_page_id` as it is, whatever it holds
+  fn read_raw_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError>;
+
+  /// Reads the page at `disk_page_id` along with the overflow pages stored after it
+  fn read_contig_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError>;
+
+  /// Whether pages end in a checksum footer that's verified when they're read
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    false
+  }
 }
 
 impl<T, I> IOPageReader for DirectReadHandler<T, I>
@@ -390,6 +552,29 @@ where
     let disk_page_id = self.tx_context.trans_node_id(node_page_id);
     self.io.read_contig_page(disk_page_id)
   }
+
+  fn read_node_pages(
+    &self, node_page_ids: &[NodePageId],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    let disk_page_ids: Vec<_> = node_page_ids
+      .iter()
+      .map(|node_page_id| self.tx_context.trans_node_id(*node_page_id))
+      .collect();
+    self.io.read_contig_pages(&disk_page_ids)
+  }
+
+  fn read_raw_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError> {
+    self.io.read_single_page(disk_page_id)
+  }
+
+  fn read_contig_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError> {
+    self.io.read_contig_page(disk_page_id)
+  }
+
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self.io.page_checksums()
+  }
 }
 
 pub trait ReadLoadedPageIO: IOPageReader {}
@@ -480,11 +665,26 @@ where
     let disk_page_id = self.handler.tx_context.trans_node_id(node_page_id);
     self.read_cache_or_disk(disk_page_id)
   }
+
+  /// Bypasses the cache, as the page may not be one the cache should hold
+  fn read_raw_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError> {
+    self.handler.io.read_single_page(disk_page_id)
+  }
+
+  /// Only the first page is read, as overflow is read page by page
+  fn read_contig_page(&self, disk_page_id: DiskPageId) -> crate::Result<Self::Bytes, IOError> {
+    self.read_cache_or_disk(disk_page_id)
+  }
+
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self.handler.io.page_checksums()
+  }
 }
 
 impl<T, I> IOOverflowPageReader for CachedReadHandler<T, I>
 where
-  T: TxContext,
+  T: TxIndirectContext,
   I: IOReader<Bytes = SharedBytes>,
 {
   fn read_freelist_overflow(
@@ -493,7 +693,7 @@ where
     let disk_page_id = self
       .handler
       .tx_context
-      .trans_freelist_id(freelist_page_id + overflow);
+      .trans_freelist_overflow(freelist_page_id, overflow);
     self.read_cache_or_disk(disk_page_id)
   }
 
@@ -503,7 +703,7 @@ where
     let disk_page_id = self
       .handler
       .tx_context
-      .trans_node_id(node_page_id + overflow);
+      .trans_node_overflow(node_page_id, overflow);
     self.read_cache_or_disk(disk_page_id)
   }
 }
@@ -528,6 +728,13 @@ where
       -> crate::Result<Self::Bytes, IOError>;
         fn read_node_page(&self, node_page_id: NodePageId)
       -> crate::Result<Self::Bytes, IOError>;
+        fn read_node_pages(&self, node_page_ids: &[NodePageId])
+      -> crate::Result<Vec<Self::Bytes>, IOError>;
+        fn read_raw_page(&self, disk_page_id: DiskPageId)
+      -> crate::Result<Self::Bytes, IOError>;
+        fn read_contig_page(&self, disk_page_id: DiskPageId)
+      -> crate::Result<Self::Bytes, IOError>;
+        fn page_checksums(&self) -> bool;
     }
   }
 }
@@ -546,7 +753,53 @@ where
   }
 }
 
-impl<R> RHandler<R> {}
+impl<R> IOBackend for RHandler<R>
+where
+  R: IOBackend,
+{
+  delegate! {
+      to self.reader {
+          fn io_type(&self) -> IOType;
+          fn page_size(&self) -> usize;
+          fn page_checksums(&self) -> bool;
+          fn apply_length_update(&mut self, new_len: usize) -> crate::Result<(), IOError>;
+      }
+  }
+}
+
+impl<R> IOWriter for RHandler<R>
+where
+  R: IOWriter,
+{
+  delegate! {
+    to self.reader {
+      fn write_disk_page(
+          &self, disk_page_id: DiskPageId, page: SharedBytes,
+        ) -> crate::Result<(), IOError>;
+      fn write_single_page(
+          &self, disk_page_id: DiskPageId, page: SharedBytes,
+        ) -> crate::Result<(), IOError>;
+      fn write_disk_pages(&self, pages: Vec<(DiskPageId, SharedBytes)>) -> crate::Result<(), IOError>;
+      fn sync(&self) -> crate::Result<(), IOError>;
+    }
+  }
+}
+
+impl<R> RHandler<R> {
+  pub fn new(path: PathBuf, lock: File, reader: R) -> Self {
+    RHandler { path, lock, reader }
+  }
+
+  #[inline]
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  #[inline]
+  pub fn reader(&self) -> &R {
+    &self.reader
+  }
+}
 
 pub struct RWHandler<R, W> {
   reader: RHandler<R>,
@@ -574,6 +827,13 @@ where
       -> crate::Result<Self::Bytes, IOError>;
         fn read_node_page(&self, node_page_id: NodePageId)
       -> crate::Result<Self::Bytes, IOError>;
+        fn read_node_pages(&self, node_page_ids: &[NodePageId])
+      -> crate::Result<Vec<Self::Bytes>, IOError>;
+        fn read_raw_page(&self, disk_page_id: DiskPageId)
+      -> crate::Result<Self::Bytes, IOError>;
+        fn read_contig_page(&self, disk_page_id: DiskPageId)
+      -> crate::Result<Self::Bytes, IOError>;
+        fn page_checksums(&self) -> bool;
     }
   }
 }
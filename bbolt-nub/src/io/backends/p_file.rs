@@ -1,36 +1,128 @@
-use crate::common::buffer_pool::BufferPool;
+use crate::common::buffer_pool::{BufferPool, UniqueBuffer};
 use crate::common::errors::IOError;
 use crate::common::id::DiskPageId;
+use crate::common::layout::page::PageHeader;
 use crate::io::backends::{
-  IOBackend, IOCore, IOReader, IOType, IOWriter, NewIOReader, ROShell, WOShell,
+  ContigIOReader, IOBackend, IOCore, IOReader, IOType, IOWriter, NewIOReadWriter, NewIOReader,
+  NewIOWriter, ROShell, WOShell,
 };
 use crate::io::bytes::shared_bytes::SharedBytes;
 use error_stack::ResultExt;
 use std::fs::{File, OpenOptions};
-use std::mem::offset_of;
-use std::os::unix::fs::FileExt;
-use std::os::unix::raw::off_t;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::PathBuf;
 use std::sync::Arc;
 
+/// The most buffers handed to a single `preadv` call
+const MAX_IOVECS: usize = 1024;
+
+/// Reads `buffers` back to back starting at `offset` with `preadv`.
+/// Short reads are finished with `read_exact_at`.
+fn preadv_exact(file: &File, buffers: &mut [&mut [u8]], offset: u64) -> io::Result<()> {
+  let iovecs: Vec<_> = buffers
+    .iter_mut()
+    .map(|buffer| libc::iovec {
+      iov_base: buffer.as_mut_ptr().cast(),
+      iov_len: buffer.len(),
+    })
+    .collect();
+  // Safety: every iovec points into a live, exclusively borrowed buffer
+  let result = unsafe {
+    libc::preadv(
+      file.as_raw_fd(),
+      iovecs.as_ptr(),
+      iovecs.len() as libc::c_int,
+      offset as libc::off_t,
+    )
+  };
+  if result < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  let mut read = result as usize;
+  let mut buffer_offset = offset;
+  for buffer in buffers.iter_mut() {
+    let buffer_read = read.min(buffer.len());
+    if buffer_read < buffer.len() {
+      file.read_exact_at(
+        &mut buffer[buffer_read..],
+        buffer_offset + buffer_read as u64,
+      )?;
+    }
+    read -= buffer_read;
+    buffer_offset += buffer.len() as u64;
+  }
+  Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct PFileReadOptions {
   buffer_pool: BufferPool,
+  page_checksums: bool,
 }
 
 impl PFileReadOptions {
   pub fn new(buffer_pool: BufferPool) -> Self {
-    Self { buffer_pool }
+    Self {
+      buffer_pool,
+      page_checksums: false,
+    }
+  }
+
+  /// Verifies the checksum footer of each page as it's read
+  pub fn with_page_checksums(mut self, page_checksums: bool) -> Self {
+    self.page_checksums = page_checksums;
+    self
   }
 }
 
 #[derive(Debug, Clone)]
-pub struct PFileWriteOptions {}
+pub struct PFileWriteOptions {
+  /// Sync with `fdatasync` instead of `fsync`, skipping metadata not needed to read the data back
+  use_fdatasync: bool,
+  /// Open the file with `O_DSYNC` so every write is synced before it returns
+  use_o_dsync: bool,
+}
+
+impl PFileWriteOptions {
+  pub fn new(use_fdatasync: bool, use_o_dsync: bool) -> Self {
+    Self {
+      use_fdatasync,
+      use_o_dsync,
+    }
+  }
+}
+
+impl Default for PFileWriteOptions {
+  fn default() -> Self {
+    Self::new(true, false)
+  }
+}
 
 pub struct PFileIO {
   core: IOCore,
   file: File,
   buffer_pool: Option<BufferPool>,
+  page_checksums: bool,
+  write_options: Option<PFileWriteOptions>,
+}
+
+impl PFileIO {
+  fn open_file(core: &IOCore, write_options: &PFileWriteOptions) -> crate::Result<File, IOError> {
+    let mut options = OpenOptions::new();
+    match core.io_type {
+      IOType::RO => options.read(true),
+      IOType::WO => options.write(true),
+      IOType::RW => options.read(true).write(true),
+    };
+    if write_options.use_o_dsync {
+      options.custom_flags(libc::O_DSYNC);
+    }
+    options
+      .open(core.path())
+      .change_context_lazy(|| IOError::OpenError(core.path().into()))
+  }
 }
 
 impl IOBackend for PFileIO {
@@ -43,6 +135,11 @@ impl IOBackend for PFileIO {
   fn page_size(&self) -> usize {
     self.core.page_size
   }
+
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    self.page_checksums
+  }
 }
 
 impl IOReader for PFileIO {
@@ -61,6 +158,51 @@ impl IOReader for PFileIO {
       .read_exact_at_and_share(&self.file, page_offset)
       .change_context(IOError::ReadError(disk_page_id))
   }
+
+  /// Reads each run of adjacent pages with a single `preadv`
+  fn read_disk_pages(
+    &self, pages: &[(DiskPageId, usize)],
+  ) -> crate::Result<Vec<Self::Bytes>, IOError> {
+    let Some((first_page_id, _)) = pages.first() else {
+      return Ok(Vec::new());
+    };
+    let page_size = self.core.page_size as u64;
+    let buffer_pool = self.buffer_pool.as_ref().expect("must be set to read");
+    let buffers = pages
+      .iter()
+      .map(|(_, page_len)| buffer_pool.pop_with_len(*page_len))
+      .collect();
+    UniqueBuffer::read_all_with_and_share(buffers, |buffers| {
+      let mut start = 0;
+      while start < pages.len() {
+        let offset = pages[start].0.0 * page_size;
+        let mut end = start + 1;
+        let mut next_offset = offset + pages[start].1 as u64;
+        while end < pages.len()
+          && end - start < MAX_IOVECS
+          && pages[end].0.0 * page_size == next_offset
+        {
+          next_offset += pages[end].1 as u64;
+          end += 1;
+        }
+        preadv_exact(&self.file, &mut buffers[start..end], offset)?;
+        start = end;
+      }
+      Ok(())
+    })
+    .change_context(IOError::ReadError(*first_page_id))
+  }
+}
+
+impl ContigIOReader for PFileIO {
+  fn read_header(&self, disk_page_id: DiskPageId) -> crate::Result<PageHeader, IOError> {
+    let mut header = [0u8; size_of::<PageHeader>()];
+    self
+      .file
+      .read_exact_at(&mut header, disk_page_id.0 * self.core.page_size as u64)
+      .change_context(IOError::ReadError(disk_page_id))?;
+    Ok(bytemuck::pod_read_unaligned(&header))
+  }
 }
 
 impl NewIOReader for PFileIO {
@@ -79,59 +221,198 @@ impl NewIOReader for PFileIO {
       core,
       file,
       buffer_pool: Some(options.buffer_pool),
+      page_checksums: options.page_checksums,
+      write_options: None,
     };
     Ok(ROShell::new(p_file))
   }
 }
 
-/*
-impl PFileIO {
-  pub fn new_rw<P: AsRef<Path>>(
-    path: P, buffer_pool: BufferPool, page_size: usize,
-  ) -> crate::Result<Self, DiskError> {
-    let path = path.as_ref();
-    let file = OpenOptions::new()
-      .read(true)
-      .write(true)
-      .open(path)
-      .change_context(DiskError::OpenError(path.into()))?;
-    Ok(PFileIO {
-      io_type: IOType::RW,
-      file,
-      buffer_pool: Some(buffer_pool),
-      page_size,
-    })
+impl IOWriter for PFileIO {
+  fn write_disk_page(
+    &self, disk_page_id: DiskPageId, page: SharedBytes,
+  ) -> crate::Result<(), IOError> {
+    let page_size = self.core.page_size;
+    assert_eq!(0, page.len() % page_size);
+    let page_offset = disk_page_id.0 * page_size as u64;
+    self
+      .file
+      .write_all_at(&page, page_offset)
+      .change_context(IOError::WriteError(disk_page_id))
   }
 
-  pub fn new_wo<P: AsRef<Path>>(
-    path: P, page_size: usize,
-  ) -> crate::Result<WOShell<Self>, DiskError> {
-    let path = path.as_ref();
-    let file = OpenOptions::new()
-      .write(true)
-      .open(path)
-      .change_context_lazy(|| DiskError::OpenError(path.into()))?;
-    let p_file = PFileIO {
+  fn sync(&self) -> crate::Result<(), IOError> {
+    let options = self.write_options.as_ref().expect("must be set to write");
+    if options.use_o_dsync {
+      return Ok(());
+    }
+    let r = if options.use_fdatasync {
+      self.file.sync_data()
+    } else {
+      self.file.sync_all()
+    };
+    r.change_context(IOError::SyncError)
+  }
+}
+
+impl NewIOWriter for PFileIO {
+  type WriteOptions = PFileWriteOptions;
+
+  fn new_wo(
+    path: Arc<PathBuf>, page_size: usize, options: Self::WriteOptions,
+  ) -> crate::Result<WOShell<Self>, IOError> {
+    let core = IOCore {
+      path,
+      page_size,
       io_type: IOType::WO,
+    };
+    let file = PFileIO::open_file(&core, &options)?;
+    let p_file = PFileIO {
+      core,
       file,
       buffer_pool: None,
-      page_size,
+      page_checksums: false,
+      write_options: Some(options),
     };
     Ok(WOShell::new(p_file))
   }
 }
-*/
-/*
-impl IOWriter for PFileIO {
-  fn write_single_page(
-    &self, disk_page_id: DiskPageId, page: SharedBytes,
-  ) -> crate::Result<(), DiskError> {
-    let page_offset = disk_page_id.0 * self.page_size as u64;
-    assert_eq!(self.page_size, page.len());
-    self
-      .file
-      .write_all_at(&page, page_offset)
-      .change_context(DiskError::WriteError(disk_page_id))
+
+impl NewIOReadWriter for PFileIO {
+  fn new_rw(
+    path: Arc<PathBuf>, page_size: usize, read_options: Self::ReadOptions,
+    write_options: Self::WriteOptions,
+  ) -> crate::Result<Self, IOError> {
+    let core = IOCore {
+      path,
+      page_size,
+      io_type: IOType::RW,
+    };
+    let file = PFileIO::open_file(&core, &write_options)?;
+    Ok(PFileIO {
+      core,
+      file,
+      buffer_pool: Some(read_options.buffer_pool),
+      page_checksums: read_options.page_checksums,
+      write_options: Some(write_options),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::errors::PageError;
+  use crate::common::id::DbPageId;
+  use crate::common::layout::checksum::write_page_checksums;
+  use size::Size;
+  use std::fs;
+
+  #[test]
+  fn test_write_and_read() {
+    let page_size = 4096;
+    let path = std::env::temp_dir().join(format!("bbolt-nub-pfile-{}.db", std::process::id()));
+    fs::write(&path, []).unwrap();
+    let path = Arc::new(path);
+    let buffer_pool = BufferPool::new(
+      page_size,
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(64),
+    );
+    let read_options = PFileReadOptions::new(buffer_pool.clone());
+    for write_options in [
+      PFileWriteOptions::default(),
+      PFileWriteOptions::new(false, false),
+      PFileWriteOptions::new(true, true),
+    ] {
+      let io =
+        PFileIO::new_rw(path.clone(), page_size, read_options.clone(), write_options).unwrap();
+      let single = buffer_pool.pop().fill_and_share(|b| b.fill(1));
+      let overflow = buffer_pool
+        .pop_with_len(page_size * 2)
+        .fill_and_share(|b| b.fill(2));
+      io.write_single_page(DiskPageId(0), single).unwrap();
+      io.write_disk_page(DiskPageId(1), overflow).unwrap();
+      io.sync().unwrap();
+      assert_eq!(
+        page_size as u64 * 3,
+        fs::metadata(path.as_ref()).unwrap().len()
+      );
+      assert!(
+        io.read_single_page(DiskPageId(0))
+          .unwrap()
+          .iter()
+          .all(|b| *b == 1)
+      );
+      let read = io.read_disk_page(DiskPageId(1), page_size * 2).unwrap();
+      assert!(read.iter().all(|b| *b == 2));
+      let batch = io
+        .read_disk_pages(&[
+          (DiskPageId(1), page_size * 2),
+          (DiskPageId(0), page_size),
+          (DiskPageId(1), page_size),
+          (DiskPageId(2), page_size),
+        ])
+        .unwrap();
+      assert_eq!(
+        vec![page_size * 2, page_size, page_size, page_size],
+        batch.iter().map(|page| page.len()).collect::<Vec<_>>()
+      );
+      assert!(batch[0].iter().all(|b| *b == 2));
+      assert!(batch[1].iter().all(|b| *b == 1));
+      assert!(batch[2].iter().chain(batch[3].iter()).all(|b| *b == 2));
+    }
+    fs::remove_file(path.as_ref()).unwrap();
+  }
+
+  #[test]
+  fn test_page_checksums() {
+    let page_size = 4096;
+    let path = std::env::temp_dir().join(format!(
+      "bbolt-nub-pfile-checksums-{}.db",
+      std::process::id()
+    ));
+    fs::write(&path, []).unwrap();
+    let path = Arc::new(path);
+    let buffer_pool = BufferPool::new(
+      page_size,
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(16),
+      Size::from_kibibytes(64),
+    );
+    let read_options = PFileReadOptions::new(buffer_pool.clone()).with_page_checksums(true);
+    let io = PFileIO::new_rw(
+      path.clone(),
+      page_size,
+      read_options,
+      PFileWriteOptions::default(),
+    )
+    .unwrap();
+    let page = buffer_pool.pop_with_len(page_size * 2).fill_and_share(|b| {
+      b.fill(3);
+      let mut header = PageHeader::init_leaf(DbPageId(1));
+      unsafe { header.set_overflow(1) };
+      b[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+      write_page_checksums(b, page_size);
+    });
+    io.write_disk_page(DiskPageId(1), page).unwrap();
+    assert_eq!(
+      page_size * 2,
+      io.read_contig_page(DiskPageId(1)).unwrap().len()
+    );
+
+    // Flip a byte in the overflow page behind the backend's back
+    let file = fs::OpenOptions::new()
+      .write(true)
+      .open(path.as_ref())
+      .unwrap();
+    file.write_at(&[0xFF], page_size as u64 * 2 + 10).unwrap();
+    let report = io.read_contig_page(DiskPageId(1)).err().unwrap();
+    assert!(matches!(
+      report.downcast_ref::<PageError>(),
+      Some(PageError::ChecksumMismatch(DbPageId(1), 1))
+    ));
+    fs::remove_file(path.as_ref()).unwrap();
   }
 }
-*/
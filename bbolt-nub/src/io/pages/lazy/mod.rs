@@ -1,5 +1,6 @@
 use crate::common::errors::{OpsError, PageError};
 use crate::common::id::OverflowPageId;
+use crate::common::layout::checksum::verify_page_checksum;
 use crate::io::TxSlot;
 use crate::io::bytes::shared_bytes::SharedTxBytes;
 use crate::io::ops::RefIntoBuf;
@@ -55,10 +56,27 @@ impl<'tx, L: TxReadLazyPageIO<'tx>> LazyPage<'tx, L> {
     page
   }
 
+  /// A page held whole by `root`, such as a leaf read with its values decompressed. Its overflow
+  /// is never read.
+  pub fn new_whole(
+    root: <<L as TxReadPageIO<'tx>>::TxPageType as TxPageType<'tx>>::TxPageBytes,
+  ) -> Self {
+    LazyPage {
+      tx: Default::default(),
+      root,
+      r: None,
+    }
+  }
+
   pub fn len(&self) -> usize {
-    self.root_page().len() * (self.page_header().get_overflow() + 1) as usize
+    match self.r {
+      Some(_) => self.root_page().len() * (self.page_header().get_overflow() + 1) as usize,
+      None => self.root_page().len(),
+    }
   }
 
+  /// Reads one page of the overflow. Each index is translated on its own through the
+  /// transaction context, so the overflow needn't be contiguous on disk.
   pub fn read_overflow_page(
     &self, overflow_index: u32,
   ) -> crate::Result<
@@ -67,19 +85,41 @@ impl<'tx, L: TxReadLazyPageIO<'tx>> LazyPage<'tx, L> {
   > {
     let header = self.page_header();
     let overflow_count = header.get_overflow();
+    // Pages without overflow to read are held whole by the root
+    if self.r.is_none() {
+      return Ok(self.root.clone());
+    }
     assert!(overflow_index <= overflow_count);
     if overflow_index == 0 {
       Ok(self.root.clone())
     } else {
       let page_id = header.overflow_page_id().expect("overflow page id");
       let r = self.r.as_ref().unwrap();
-      match page_id {
+      let page = match page_id {
         OverflowPageId::Freelist(page_id) => r.read_freelist_overflow(page_id, overflow_index),
         OverflowPageId::Node(page_id) => r.read_node_overflow(page_id, overflow_index),
       }
-      .change_context(PageError::OverflowReadError(page_id, overflow_index))
+      .change_context(PageError::OverflowReadError(page_id, overflow_index))?;
+      if r.page_checksums() {
+        let last_page = if overflow_index == overflow_count {
+          page.clone()
+        } else {
+          self.read_overflow_page(overflow_count)?
+        };
+        verify_page_checksum(header, overflow_index, page.as_ref(), last_page.as_ref())?;
+      }
+      Ok(page)
     }
   }
+
+  /// Verifies the root page against the checksum footer in the last page
+  pub fn verify_root(&self) -> crate::Result<(), PageError> {
+    let header = self.page_header();
+    let overflow_count = header.get_overflow();
+    let last_page = self.read_overflow_page(overflow_count)?;
+    verify_page_checksum(header, 0, self.root.as_ref(), last_page.as_ref())?;
+    Ok(())
+  }
 }
 
 impl<'tx, L: TxReadLazyPageIO<'tx>> Page for LazyPage<'tx, L> {
@@ -164,13 +164,13 @@ impl<'a, 'tx, L: TxReadLazyPageIO<'tx>> TryBuf for LazyRefTryBuf<'a, 'tx, L> {
   }
 
   fn try_advance(&mut self, cnt: usize) -> crate::Result<(), Self::Error> {
+    self.range = self.range.sub_range_bound(cnt..);
     let overflow_index = (self.range.start / self.slice.page.root.as_ref().len()) as u32;
-    if overflow_index != self.overflow_index {
+    if !self.range.is_empty() && overflow_index != self.overflow_index {
       let page = self.slice.page.read_overflow_page(overflow_index)?;
       self.overflow_index = overflow_index;
       self.page = page;
     }
-    self.range = self.range.sub_range_bound(cnt..);
     Ok(())
   }
 }
@@ -7,7 +7,7 @@ use crate::io::bytes::TxBytes;
 use crate::io::pages::direct::ops::KvDataType;
 use crate::io::pages::types::freelist::FreelistPage;
 use crate::io::pages::types::meta::MetaPage;
-use crate::io::pages::types::node::NodePage;
+use crate::io::pages::types::node::{HasKeys, NodePage};
 use crate::io::pages::types::node::branch::HasBranches;
 use crate::io::pages::types::node::branch::bbolt::BBoltBranch;
 use crate::io::pages::types::node::leaf::HasLeaves;
@@ -153,6 +153,23 @@ pub trait TxReadPageIO<'tx> {
   fn read_node_page(
     self: &sync::Arc<Self>, node_page_id: NodePageId,
   ) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError>;
+
+  /// Reads the root leaf of an inline bucket from its bucket `value`, the bucket header followed
+  /// by the leaf page
+  fn read_inline_node(
+    self: &sync::Arc<Self>, value: <Self::LeafType as HasKeys<'tx>>::TxKv,
+  ) -> crate::Result<NodePage<Self::BranchType, Self::LeafType>, PageError>;
+
+  /// Reads several node pages, returning them in the same order
+  #[allow(clippy::type_complexity)]
+  fn read_node_pages(
+    self: &sync::Arc<Self>, node_page_ids: &[NodePageId],
+  ) -> crate::Result<Vec<NodePage<Self::BranchType, Self::LeafType>>, PageError> {
+    node_page_ids
+      .iter()
+      .map(|node_page_id| self.read_node_page(*node_page_id))
+      .collect()
+  }
 }
 
 pub trait TxReadLoadedPageIO<'tx>: TxReadPageIO<'tx> {}
@@ -165,4 +182,10 @@ pub trait TxReadLazyPageIO<'tx>: TxReadPageIO<'tx> {
   fn read_node_overflow(
     &self, node_page_id: NodePageId, overflow: u32,
   ) -> crate::Result<<Self::TxPageType as TxPageType<'tx>>::TxPageBytes, IOError>;
+
+  /// Whether freelist and node pages end in a checksum footer to verify as they're loaded
+  #[inline]
+  fn page_checksums(&self) -> bool {
+    false
+  }
 }
@@ -0,0 +1,123 @@
+use crate::common::errors::PageError;
+use crate::common::id::{DbPageId, FreelistPageId};
+use crate::common::layout::checksum::PAGE_CHECKSUM_LEN;
+use crate::common::layout::page::PageHeader;
+use crate::io::pages::{Page, TxPage, TxPageType};
+use delegate::delegate;
+
+/// Tracks which data pages of a cluster are free in the clustered format, one bit per page. Each
+/// meta page has its own free space pages in every cluster. See
+/// [`StableFreeSpaceTranslator`](crate::common::id::StableFreeSpaceTranslator)
+pub struct FreeSpacePage<'tx, T> {
+  page: TxPage<'tx, T>,
+}
+
+impl<'tx, T> TryFrom<TxPage<'tx, T>> for FreeSpacePage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  type Error = PageError;
+
+  fn try_from(value: TxPage<'tx, T>) -> Result<Self, Self::Error> {
+    if value.page.page_header().is_free_space() {
+      Ok(FreeSpacePage { page: value })
+    } else {
+      Err(PageError::InvalidFreeSpaceFlag(
+        value.page.page_header().flags(),
+      ))
+    }
+  }
+}
+
+impl<'tx, T> Page for FreeSpacePage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  delegate! {
+      to &self.page {
+      fn root_page(&self) -> &[u8];
+      }
+  }
+}
+
+impl<'tx, T> FreeSpacePage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  /// The bitmap, whose length is stored as the header's count
+  fn bitmap(&self) -> &[u8] {
+    let start = size_of::<PageHeader>();
+    let end = (start + self.page_header().count() as usize).min(self.root_page().len());
+    &self.root_page()[start.min(end)..end]
+  }
+
+  /// The offsets of the free pages among the data pages the page tracks, in order
+  pub fn free_offsets(&self) -> impl Iterator<Item = u64> + '_ {
+    self.bitmap().iter().enumerate().flat_map(|(index, byte)| {
+      (0..8)
+        .filter(move |bit| byte & (1 << bit) != 0)
+        .map(move |bit| index as u64 * 8 + bit)
+    })
+  }
+}
+
+/// The length of the bitmap of a free space page, which fills the page past its header and any
+/// checksum footer
+pub fn free_space_bitmap_len(page_size: usize, page_checksums: bool) -> usize {
+  let footer_len = if page_checksums { PAGE_CHECKSUM_LEN } else { 0 };
+  page_size - size_of::<PageHeader>() - footer_len
+}
+
+/// Writes a free space page with a `bitmap_len` byte bitmap marking the data pages at
+/// `free_offsets` free into `buffer`, which spans one page
+pub fn write_free_space_page<I>(
+  buffer: &mut [u8], page_id: FreelistPageId, bitmap_len: usize, free_offsets: I,
+) where
+  I: Iterator<Item = u64>,
+{
+  let start = size_of::<PageHeader>();
+  assert!(buffer.len() >= start + bitmap_len);
+  let mut header = PageHeader::init_free_space(DbPageId(page_id.0.0));
+  header.set_count(u16::try_from(bitmap_len).expect("bitmap length must fit the count"));
+  buffer[0..start].copy_from_slice(bytemuck::bytes_of(&header));
+  let bitmap = &mut buffer[start..start + bitmap_len];
+  bitmap.fill(0);
+  for offset in free_offsets {
+    bitmap[offset as usize / 8] |= 1 << (offset % 8);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::io::bytes::IntoTxBytes;
+  use crate::io::bytes::ref_bytes::{RefBytes, RefTxBytes};
+  use crate::io::pages::direct::DirectPage;
+  use crate::io::pages::types::freelist::FreelistPage;
+
+  #[test]
+  fn test_encode_decode() {
+    for (page_size, page_checksums) in [(512, false), (4096, true)] {
+      let bitmap_len = free_space_bitmap_len(page_size, page_checksums);
+      let free: Vec<u64> = [0, 1, 7, 8, 100, bitmap_len as u64 * 8 - 1].to_vec();
+      let mut buffer = vec![0xFFu8; page_size];
+      write_free_space_page(
+        &mut buffer,
+        FreelistPageId(DbPageId(3)),
+        bitmap_len,
+        free.iter().copied(),
+      );
+
+      let bytes = RefBytes::from_ptr_len(buffer.as_ptr(), buffer.len());
+      let page = TxPage::new(DirectPage::new(IntoTxBytes::<RefTxBytes>::into_tx(bytes)));
+      assert_eq!(DbPageId(3), page.page_header().id());
+      let page = FreeSpacePage::try_from(page).unwrap();
+      // Bytes past the bitmap are left alone for the checksum footer
+      assert_eq!(free, page.free_offsets().collect::<Vec<_>>());
+
+      let bytes = RefBytes::from_ptr_len(buffer.as_ptr(), buffer.len());
+      let page = TxPage::new(DirectPage::new(IntoTxBytes::<RefTxBytes>::into_tx(bytes)));
+      assert!(FreelistPage::try_from(page).is_err());
+    }
+  }
+}
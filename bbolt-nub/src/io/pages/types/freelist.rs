@@ -1,13 +1,24 @@
 use crate::common::errors::PageError;
-use crate::common::id::FreelistPageId;
-use crate::io::pages::types::meta::MetaPage;
+use crate::common::id::{DbPageId, DiskPageId, FreelistPageId};
+use crate::common::layout::page::PageHeader;
 use crate::io::pages::{Page, TxPage, TxPageType};
 use delegate::delegate;
+use std::iter::FusedIterator;
+use std::slice::ChunksExact;
+
+/// The header count marking that the real count is stored in the first element
+pub const FREELIST_COUNT_OVERFLOW: u16 = 0xFFFF;
 
 pub trait HasFreelist {
-  type FreelistIter: Iterator<Item = FreelistPageId>;
+  type FreelistIter<'a>: Iterator<Item = DiskPageId>
+  where
+    Self: 'a;
+
+  /// The number of free page ids stored in the page
+  fn free_count(&self) -> usize;
 
-  fn freelist_iter(&self) -> Self::FreelistIter;
+  /// The free page ids in the order they were written. Go BBolt writes them sorted.
+  fn freelist_iter(&self) -> Self::FreelistIter<'_>;
 }
 
 pub struct FreelistPage<'tx, T> {
@@ -21,7 +32,7 @@ where
   type Error = PageError;
 
   fn try_from(value: TxPage<'tx, T>) -> Result<Self, Self::Error> {
-    if value.page.page_header().is_meta() {
+    if value.page.page_header().is_freelist() {
       Ok(FreelistPage { page: value })
     } else {
       Err(PageError::InvalidFreelistFlag(
@@ -41,3 +52,145 @@ where
       }
   }
 }
+
+impl<'tx, T> FreelistPage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  /// The free page ids, skipping the first element when it holds the real count
+  fn ids(&self) -> &[u8] {
+    let count = self.free_count();
+    let start = if self.page_header().count() == FREELIST_COUNT_OVERFLOW {
+      size_of::<PageHeader>() + size_of::<u64>()
+    } else {
+      size_of::<PageHeader>()
+    };
+    let end = (start + count * size_of::<u64>()).min(self.root_page().len());
+    &self.root_page()[start.min(end)..end]
+  }
+}
+
+impl<'tx, T> HasFreelist for FreelistPage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  type FreelistIter<'a>
+    = FreelistIter<'a>
+  where
+    Self: 'a;
+
+  fn free_count(&self) -> usize {
+    let count = self.page_header().count();
+    if count == FREELIST_COUNT_OVERFLOW {
+      let start = size_of::<PageHeader>();
+      bytemuck::pod_read_unaligned::<u64>(&self.root_page()[start..start + size_of::<u64>()])
+        as usize
+    } else {
+      count as usize
+    }
+  }
+
+  fn freelist_iter(&self) -> Self::FreelistIter<'_> {
+    FreelistIter {
+      chunks: self.ids().chunks_exact(size_of::<u64>()),
+    }
+  }
+}
+
+pub struct FreelistIter<'a> {
+  chunks: ChunksExact<'a, u8>,
+}
+
+impl<'a> Iterator for FreelistIter<'a> {
+  type Item = DiskPageId;
+
+  #[inline]
+  fn next(&mut self) -> Option<Self::Item> {
+    self
+      .chunks
+      .next()
+      .map(|chunk| DiskPageId(bytemuck::pod_read_unaligned(chunk)))
+  }
+
+  #[inline]
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.chunks.size_hint()
+  }
+}
+
+impl<'a> ExactSizeIterator for FreelistIter<'a> {}
+
+impl<'a> FusedIterator for FreelistIter<'a> {}
+
+/// The number of bytes needed by a freelist page holding `count` ids
+pub fn freelist_page_len(count: usize) -> usize {
+  let elements = if count >= FREELIST_COUNT_OVERFLOW as usize {
+    count + 1
+  } else {
+    count
+  };
+  size_of::<PageHeader>() + elements * size_of::<u64>()
+}
+
+/// Writes a Go BBolt freelist page holding `ids` into `buffer`, which spans the whole page
+/// including its overflow.
+///
+/// Counts of `0xFFFF` or more are stored in the first element.
+pub fn write_freelist_page<I>(buffer: &mut [u8], page_id: FreelistPageId, page_size: usize, ids: I)
+where
+  I: ExactSizeIterator<Item = DiskPageId>,
+{
+  let count = ids.len();
+  assert!(buffer.len() >= freelist_page_len(count));
+  assert_eq!(0, buffer.len() % page_size);
+  let mut header = PageHeader::init_freelist(DbPageId(page_id.0.0));
+  unsafe { header.set_overflow((buffer.len() / page_size - 1) as u32) };
+  let mut offset = size_of::<PageHeader>();
+  if count >= FREELIST_COUNT_OVERFLOW as usize {
+    header.set_count(FREELIST_COUNT_OVERFLOW);
+    buffer[offset..offset + size_of::<u64>()].copy_from_slice(&(count as u64).to_ne_bytes());
+    offset += size_of::<u64>();
+  } else {
+    header.set_count(count as u16);
+  }
+  buffer[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+  for id in ids {
+    buffer[offset..offset + size_of::<u64>()].copy_from_slice(&id.0.to_ne_bytes());
+    offset += size_of::<u64>();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::io::bytes::IntoTxBytes;
+  use crate::io::bytes::ref_bytes::{RefBytes, RefTxBytes};
+  use crate::io::pages::direct::DirectPage;
+
+  #[test]
+  fn test_encode_decode() {
+    let page_size = 4096;
+    for count in [0usize, 3, FREELIST_COUNT_OVERFLOW as usize - 1, 70_000] {
+      let ids: Vec<_> = (0..count as u64).map(|id| DiskPageId(id * 2 + 5)).collect();
+      let pages = freelist_page_len(count).div_ceil(page_size);
+      let mut buffer = vec![0u8; pages * page_size];
+      write_freelist_page(
+        &mut buffer,
+        FreelistPageId(DbPageId(2)),
+        page_size,
+        ids.iter().copied(),
+      );
+      let header: PageHeader = bytemuck::pod_read_unaligned(&buffer[0..size_of::<PageHeader>()]);
+      assert_eq!(pages as u32 - 1, header.get_overflow());
+      assert_eq!(count >= 0xFFFF, header.count() == FREELIST_COUNT_OVERFLOW);
+
+      let bytes = RefBytes::from_ptr_len(buffer.as_ptr(), buffer.len());
+      let page = FreelistPage::try_from(TxPage::new(DirectPage::new(
+        IntoTxBytes::<RefTxBytes>::into_tx(bytes),
+      )))
+      .unwrap();
+      assert_eq!(count, page.free_count());
+      assert_eq!(ids, page.freelist_iter().collect::<Vec<_>>());
+    }
+  }
+}
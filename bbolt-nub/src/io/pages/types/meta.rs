@@ -45,6 +45,7 @@ where
   T: TxPageType<'tx>,
 {
   fn meta(&self) -> &Meta {
-    from_bytes(&self.page.root_page()[size_of::<PageHeader>()..size_of::<Meta>()])
+    let meta_start = size_of::<PageHeader>();
+    from_bytes(&self.page.root_page()[meta_start..meta_start + size_of::<Meta>()])
   }
 }
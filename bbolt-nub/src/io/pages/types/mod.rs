@@ -1,3 +1,5 @@
+pub mod free_space;
 pub mod freelist;
 pub mod meta;
 pub mod node;
+pub mod page_map;
@@ -68,7 +68,9 @@ pub trait HasElements<'tx>: Page + HasKeyRefs + Sync + Send {
     <Self as GatKvRef<'a>>::KvRef: PartialOrd<[u8]>,
   {
     let elements = self.elements();
-    assert!(!elements.is_empty());
+    if elements.is_empty() {
+      return Err(0);
+    }
     let elements_start = elements.as_ptr().addr();
     elements.binary_search_by(|element| {
       let element_index =
@@ -90,7 +92,9 @@ pub trait HasElements<'tx>: Page + HasKeyRefs + Sync + Send {
     <Self as GatKvRef<'a>>::KvRef: TryPartialOrd<[u8]>,
   {
     let elements = self.elements();
-    assert!(!elements.is_empty());
+    if elements.is_empty() {
+      return Ok(Err(0));
+    }
     let elements_start = elements.as_ptr().addr();
     elements.try_binary_search_by(|element| {
       let element_index =
@@ -112,15 +116,17 @@ pub trait HasElements<'tx>: Page + HasKeyRefs + Sync + Send {
     use rayon::iter::ParallelIterator;
     use rayon::slice::ParallelSlice;
     let elements = self.elements();
-    assert!(!elements.is_empty());
+    if elements.is_empty() {
+      return Err(0);
+    }
     let elements_start = elements.as_ptr().addr();
-    let chunk_size = (elements.len() / rayon::current_num_threads()).min(16);
+    let chunk_size = (elements.len() / rayon::current_num_threads()).clamp(1, 16);
     let p = elements
       .par_chunks(chunk_size)
       .enumerate()
       .filter_map(|(chunk_index, chunk)| {
         let first = &chunk[0];
-        let first_key_start = first.kv_data_start(0);
+        let first_key_start = first.kv_data_start(chunk_index * chunk_size);
         let first_key = self.get_ref_slice(first_key_start..first_key_start + first.elem_key_len());
         if PartialOrd::gt(&first_key, v) {
           None
@@ -137,9 +143,10 @@ pub trait HasElements<'tx>: Page + HasKeyRefs + Sync + Send {
           ))
         }
       });
-    let (chunk, result) = p
-      .max_by_key(|chunk| chunk.0)
-      .expect("iterator can't be empty");
+    // Every key is greater than `v`
+    let Some((chunk, result)) = p.max_by_key(|chunk| chunk.0) else {
+      return Err(0);
+    };
     result
       .map(|index| (chunk * chunk_size) + index)
       .map_err(|index| (chunk * chunk_size) + index)
@@ -160,15 +167,17 @@ pub trait HasElements<'tx>: Page + HasKeyRefs + Sync + Send {
     use rayon::iter::ParallelIterator;
     use rayon::slice::ParallelSlice;
     let elements = self.elements();
-    assert!(!elements.is_empty());
+    if elements.is_empty() {
+      return Ok(Err(0));
+    }
     let elements_start = elements.as_ptr().addr();
-    let chunk_size = (elements.len() / rayon::current_num_threads()).min(16);
+    let chunk_size = (elements.len() / rayon::current_num_threads()).clamp(1, 16);
     let p = elements
       .par_chunks(chunk_size)
       .enumerate()
       .filter_map(|(chunk_index, chunk)| {
         let first = &chunk[0];
-        let first_key_start = first.kv_data_start(0);
+        let first_key_start = first.kv_data_start(chunk_index * chunk_size);
         let first_key = self.get_ref_slice(first_key_start..first_key_start + first.elem_key_len());
         match TryPartialOrd::try_gt(&first_key, v) {
           Ok(true) => None,
@@ -185,15 +194,18 @@ pub trait HasElements<'tx>: Page + HasKeyRefs + Sync + Send {
           Err(report) => Some(Err(report)),
         }
       });
-    let (chunk, ord_result) = p
-      .try_reduce_with(|(x_chunk, x_result), (y_chunk, y_result)| {
-        if x_chunk > y_chunk {
-          Ok((x_chunk, x_result))
-        } else {
-          Ok((y_chunk, y_result))
-        }
-      })
-      .expect("iterator can't be empty")?;
+    let reduced = p.try_reduce_with(|(x_chunk, x_result), (y_chunk, y_result)| {
+      if x_chunk > y_chunk {
+        Ok((x_chunk, x_result))
+      } else {
+        Ok((y_chunk, y_result))
+      }
+    });
+    // Every key is greater than `v`
+    let Some(reduced) = reduced else {
+      return Ok(Err(0));
+    };
+    let (chunk, ord_result) = reduced?;
     let result = ord_result?;
     Ok(
       result
@@ -0,0 +1,198 @@
+use crate::common::errors::PageError;
+use crate::common::id::{DbPageId, FreelistPageId};
+use crate::common::layout::page::PageHeader;
+use crate::common::layout::page_map::PageMapEntry;
+use crate::io::pages::types::freelist::FREELIST_COUNT_OVERFLOW;
+use crate::io::pages::{Page, TxPage, TxPageType};
+use delegate::delegate;
+use std::iter::FusedIterator;
+use std::slice::ChunksExact;
+
+pub trait HasPageMap {
+  type PageMapIter<'a>: Iterator<Item = PageMapEntry>
+  where
+    Self: 'a;
+
+  /// The number of entries stored in the page
+  fn entry_count(&self) -> usize;
+
+  /// The entries in the order they were written, sorted by node page id
+  fn page_map_iter(&self) -> Self::PageMapIter<'_>;
+}
+
+/// Maps node page ids to disk pages in the version 3 format, stored in place of the freelist
+pub struct PageMapPage<'tx, T> {
+  page: TxPage<'tx, T>,
+}
+
+impl<'tx, T> TryFrom<TxPage<'tx, T>> for PageMapPage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  type Error = PageError;
+
+  fn try_from(value: TxPage<'tx, T>) -> Result<Self, Self::Error> {
+    if value.page.page_header().is_page_map() {
+      Ok(PageMapPage { page: value })
+    } else {
+      Err(PageError::InvalidPageMapFlag(
+        value.page.page_header().flags(),
+      ))
+    }
+  }
+}
+
+impl<'tx, T> Page for PageMapPage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  delegate! {
+      to &self.page {
+      fn root_page(&self) -> &[u8];
+      }
+  }
+}
+
+impl<'tx, T> PageMapPage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  /// The entries, skipping the first element when it holds the real count
+  fn entries(&self) -> &[u8] {
+    let count = self.entry_count();
+    let start = if self.page_header().count() == FREELIST_COUNT_OVERFLOW {
+      size_of::<PageHeader>() + size_of::<u64>()
+    } else {
+      size_of::<PageHeader>()
+    };
+    let end = (start + count * size_of::<PageMapEntry>()).min(self.root_page().len());
+    &self.root_page()[start.min(end)..end]
+  }
+}
+
+impl<'tx, T> HasPageMap for PageMapPage<'tx, T>
+where
+  T: TxPageType<'tx>,
+{
+  type PageMapIter<'a>
+    = PageMapIter<'a>
+  where
+    Self: 'a;
+
+  fn entry_count(&self) -> usize {
+    let count = self.page_header().count();
+    if count == FREELIST_COUNT_OVERFLOW {
+      let start = size_of::<PageHeader>();
+      bytemuck::pod_read_unaligned::<u64>(&self.root_page()[start..start + size_of::<u64>()])
+        as usize
+    } else {
+      count as usize
+    }
+  }
+
+  fn page_map_iter(&self) -> Self::PageMapIter<'_> {
+    PageMapIter {
+      chunks: self.entries().chunks_exact(size_of::<PageMapEntry>()),
+    }
+  }
+}
+
+pub struct PageMapIter<'a> {
+  chunks: ChunksExact<'a, u8>,
+}
+
+impl<'a> Iterator for PageMapIter<'a> {
+  type Item = PageMapEntry;
+
+  #[inline]
+  fn next(&mut self) -> Option<Self::Item> {
+    self.chunks.next().map(bytemuck::pod_read_unaligned)
+  }
+
+  #[inline]
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.chunks.size_hint()
+  }
+}
+
+impl<'a> ExactSizeIterator for PageMapIter<'a> {}
+
+impl<'a> FusedIterator for PageMapIter<'a> {}
+
+/// The number of bytes needed by a page map page holding `count` entries
+pub fn page_map_page_len(count: usize) -> usize {
+  let count_len = if count >= FREELIST_COUNT_OVERFLOW as usize {
+    size_of::<u64>()
+  } else {
+    0
+  };
+  size_of::<PageHeader>() + count_len + count * size_of::<PageMapEntry>()
+}
+
+/// Writes a page map page holding `entries` into `buffer`, which spans the whole page including
+/// its overflow.
+///
+/// As with the freelist, counts of `0xFFFF` or more are stored in the first element.
+pub fn write_page_map_page<I>(
+  buffer: &mut [u8], page_id: FreelistPageId, page_size: usize, entries: I,
+) where
+  I: ExactSizeIterator<Item = PageMapEntry>,
+{
+  let count = entries.len();
+  assert!(buffer.len() >= page_map_page_len(count));
+  assert_eq!(0, buffer.len() % page_size);
+  let mut header = PageHeader::init_page_map(DbPageId(page_id.0.0));
+  unsafe { header.set_overflow((buffer.len() / page_size - 1) as u32) };
+  let mut offset = size_of::<PageHeader>();
+  if count >= FREELIST_COUNT_OVERFLOW as usize {
+    header.set_count(FREELIST_COUNT_OVERFLOW);
+    buffer[offset..offset + size_of::<u64>()].copy_from_slice(&(count as u64).to_ne_bytes());
+    offset += size_of::<u64>();
+  } else {
+    header.set_count(count as u16);
+  }
+  buffer[0..size_of::<PageHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+  for entry in entries {
+    buffer[offset..offset + size_of::<PageMapEntry>()].copy_from_slice(bytemuck::bytes_of(&entry));
+    offset += size_of::<PageMapEntry>();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::common::id::{DiskPageId, NodePageId};
+  use crate::io::bytes::IntoTxBytes;
+  use crate::io::bytes::ref_bytes::{RefBytes, RefTxBytes};
+  use crate::io::pages::direct::DirectPage;
+  use crate::io::pages::types::freelist::FreelistPage;
+
+  #[test]
+  fn test_encode_decode() {
+    let page_size = 4096;
+    for count in [0usize, 3, FREELIST_COUNT_OVERFLOW as usize + 10] {
+      let entries: Vec<_> = (0..count as u64)
+        .map(|id| PageMapEntry::new(NodePageId(DbPageId(id)), DiskPageId(id * 3 + 7)))
+        .collect();
+      let pages = page_map_page_len(count).div_ceil(page_size);
+      let mut buffer = vec![0u8; pages * page_size];
+      write_page_map_page(
+        &mut buffer,
+        FreelistPageId(DbPageId(2)),
+        page_size,
+        entries.iter().copied(),
+      );
+
+      let bytes = RefBytes::from_ptr_len(buffer.as_ptr(), buffer.len());
+      let page = TxPage::new(DirectPage::new(IntoTxBytes::<RefTxBytes>::into_tx(bytes)));
+      assert_eq!(pages as u32 - 1, page.page_header().get_overflow());
+      let page = PageMapPage::try_from(page).unwrap();
+      assert_eq!(count, page.entry_count());
+      assert_eq!(entries, page.page_map_iter().collect::<Vec<_>>());
+
+      let bytes = RefBytes::from_ptr_len(buffer.as_ptr(), buffer.len());
+      let page = TxPage::new(DirectPage::new(IntoTxBytes::<RefTxBytes>::into_tx(bytes)));
+      assert!(FreelistPage::try_from(page).is_err());
+    }
+  }
+}
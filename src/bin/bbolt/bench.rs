@@ -0,0 +1,83 @@
+use crate::tree::TreeBucket;
+use crate::{Args, CliError, Result, usage_error};
+use bbolt_nub::components::backend::memmap::MemmapDb;
+use bbolt_nub::components::bucket_path::BucketPathBuf;
+use error_stack::ResultExt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const BENCH_BUCKET: &str = "bench";
+
+fn report(out: &mut impl Write, name: &str, elapsed: Duration, ops: u64) -> Result {
+  let per_op = elapsed / ops.max(1) as u32;
+  let ops_per_sec = ops as f64 / elapsed.as_secs_f64();
+  outln!(
+    out,
+    "# {name}\t{elapsed:?}\t({per_op:?}/op)\t({ops_per_sec:.0} op/sec)"
+  );
+  Ok(())
+}
+
+/// `bbolt bench [-count N] [-batch-size N] [-value-size N] [-path PATH]`
+///
+/// Writes `count` sequential keys in transactions of `batch-size`, then reads them back. The
+/// database is a temporary file unless `path` is given.
+pub fn bench(args: &Args) -> Result {
+  let count = args.flag("count", 1000u32)?;
+  let batch_size = args.flag("batch-size", 0u32)?;
+  let value_size = args.flag("value-size", 8usize)?;
+  let batch_size = if batch_size == 0 { count } else { batch_size };
+  let (path, temporary) = match args.flag("path", String::new())? {
+    path if path.is_empty() => (
+      std::env::temp_dir().join(format!("bbolt-bench-{}.db", std::process::id())),
+      true,
+    ),
+    path => (PathBuf::from(path), false),
+  };
+  if path.exists() {
+    return Err(usage_error("bench database already exists"));
+  }
+  let result = run(&path, count, batch_size.max(1), value_size);
+  if temporary {
+    let _ = std::fs::remove_file(&path);
+  }
+  result
+}
+
+fn run(path: &PathBuf, count: u32, batch_size: u32, value_size: usize) -> Result {
+  let db = MemmapDb::builder()
+    .open_path(path)
+    .change_context_lazy(|| CliError::Open(path.clone()))?;
+  let bucket = BucketPathBuf::root(BENCH_BUCKET);
+  let value = vec![0u8; value_size];
+  let mut out = io::stdout().lock();
+
+  let start = Instant::now();
+  let mut key = 0u32;
+  while key < count {
+    let tx = db.begin_mut().change_context(CliError::Write)?;
+    if key == 0 {
+      tx.create_bucket(&bucket).change_context(CliError::Write)?;
+    }
+    let end = count.min(key + batch_size);
+    for k in key..end {
+      tx.put(&bucket, &k.to_be_bytes(), &value)
+        .change_context(CliError::Write)?;
+    }
+    tx.commit().change_context(CliError::Write)?;
+    key = end;
+  }
+  report(&mut out, "Write", start.elapsed(), count as u64)?;
+
+  let start = Instant::now();
+  let tx = db.begin();
+  let mut read = 0u64;
+  TreeBucket::root(&tx, db.meta().root)?
+    .open_path(&tx, &[BENCH_BUCKET.to_string()])?
+    .for_each(|_, _, _| {
+      read += 1;
+      Ok(())
+    })?;
+  report(&mut out, "Read", start.elapsed(), read)
+}
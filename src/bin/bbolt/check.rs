@@ -0,0 +1,22 @@
+use crate::tree::open_db;
+use crate::{Args, CliError, Result};
+use error_stack::Report;
+use std::io::{self, Write};
+
+/// `bbolt check PATH`
+pub fn check(args: &Args) -> Result {
+  let db = open_db(&args.path()?, true)?;
+  let mut out = io::stdout().lock();
+  let mut problems = 0;
+  for error in db.check() {
+    outln!(out, "{}", error.current_context());
+    problems += 1;
+  }
+  match problems {
+    0 => {
+      outln!(out, "OK");
+      Ok(())
+    }
+    problems => Err(Report::new(CliError::CheckFailed(problems))),
+  }
+}
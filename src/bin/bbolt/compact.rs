@@ -0,0 +1,37 @@
+use crate::tree::open_db;
+use crate::{Args, CliError, Result, usage_error};
+use error_stack::ResultExt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// `bbolt compact [-tx-max-size BYTES] -o DST SRC`
+pub fn compact(args: &Args) -> Result {
+  let src_path = args.path()?;
+  let dst_path = PathBuf::from(args.flag("o", String::new())?);
+  if dst_path.as_os_str().is_empty() {
+    return Err(usage_error("output file required"));
+  }
+  if dst_path.exists() {
+    return Err(usage_error("output file already exists"));
+  }
+  let tx_max_size = args.flag("tx-max-size", 65536u64)?;
+
+  let src = open_db(&src_path, true)?;
+  src
+    .compact()
+    .tx_max_size(tx_max_size)
+    .compact_into(dst_path.clone())
+    .change_context(CliError::Write)?;
+  let dst = open_db(&dst_path, true)?;
+
+  // The files are preallocated, so compare the pages in use
+  let src_size = src.meta().eof_id.0.0 * src.page_size() as u64;
+  let dst_size = dst.meta().eof_id.0.0 * dst.page_size() as u64;
+  let mut out = io::stdout().lock();
+  outln!(
+    out,
+    "{src_size} -> {dst_size} bytes (gain={:.2}x)",
+    src_size as f64 / dst_size as f64
+  );
+  Ok(())
+}
@@ -0,0 +1,196 @@
+//! `bbolt` inspects and maintains database files, like the `bbolt` tool of Go BBolt
+
+/// `writeln!` to a command's output, returning [`CliError::Output`] if it fails
+macro_rules! outln {
+  ($out:expr $(, $($arg:tt)*)?) => {
+    error_stack::ResultExt::change_context(writeln!($out $(, $($arg)*)?), $crate::CliError::Output)?
+  };
+}
+
+mod bench;
+mod check;
+mod compact;
+mod pages;
+mod tree;
+
+use error_stack::Report;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CliError {
+  #[error("{0}")]
+  Usage(String),
+  #[error("Unable to open database `{0}`")]
+  Open(PathBuf),
+  #[error("Unable to read the database")]
+  Read,
+  #[error("Unable to write the database")]
+  Write,
+  #[error("Unable to write output")]
+  Output,
+  #[error("Bucket not found")]
+  BucketNotFound,
+  #[error("Key not found")]
+  KeyNotFound,
+  #[error("Page `{0}` is past the end of the database")]
+  PageOutOfBounds(u64),
+  #[error("Found {0} problems")]
+  CheckFailed(usize),
+}
+
+pub type Result<T = ()> = error_stack::Result<T, CliError>;
+
+const USAGE: &str = "\
+The bbolt command inspects and maintains bbolt databases.
+
+Usage:
+
+\tbbolt command [arguments]
+
+The commands are:
+
+    bench       run synthetic benchmark against bbolt
+    buckets     print a list of buckets
+    check       verifies integrity of bbolt database
+    compact     copies a bbolt database, compacting it in the process
+    dump        print a hexadecimal dump of a single page
+    get         print the value of a key in a bucket
+    help        print this screen
+    info        print basic info
+    keys        print a list of keys in a bucket
+    page        print one or more pages in human readable format
+    pages       print list of pages with their types
+    stats       iterate over all pages and generate usage stats
+";
+
+/// A command's arguments, split into `-name value` flags and positional arguments
+pub struct Args {
+  flags: Vec<(String, String)>,
+  positional: Vec<String>,
+}
+
+impl Args {
+  /// Splits `args`, taking the value of each flag in `flag_names` from the following argument
+  /// unless it's given as `-name=value`. Everything after `--` is positional.
+  fn parse(args: impl IntoIterator<Item = String>, flag_names: &[&str]) -> Result<Args> {
+    let mut flags = Vec::new();
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      if arg == "--" {
+        positional.extend(args.by_ref());
+        break;
+      }
+      let Some(flag) = arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) else {
+        positional.push(arg);
+        continue;
+      };
+      let (name, value) = match flag.split_once('=') {
+        Some((name, value)) => (name.to_string(), Some(value.to_string())),
+        None => (flag.to_string(), None),
+      };
+      if !flag_names.contains(&name.as_str()) {
+        return Err(usage_error(&format!(
+          "flag provided but not defined: -{name}"
+        )));
+      }
+      let value = match value.or_else(|| args.next()) {
+        Some(value) => value,
+        None => {
+          return Err(usage_error(&format!("flag needs an argument: -{name}")));
+        }
+      };
+      flags.push((name, value));
+    }
+    Ok(Args { flags, positional })
+  }
+
+  /// The last value given for flag `name`, or `default`
+  pub fn flag<T: FromStr>(&self, name: &str, default: T) -> Result<T> {
+    match self.flags.iter().rev().find(|(flag, _)| flag == name) {
+      Some((_, value)) => value
+        .parse()
+        .map_err(|_| usage_error(&format!("invalid value {value:?} for flag -{name}"))),
+      None => Ok(default),
+    }
+  }
+
+  /// The database path, which is the first positional argument
+  pub fn path(&self) -> Result<PathBuf> {
+    match self.positional.first() {
+      Some(path) => Ok(PathBuf::from(path)),
+      None => Err(usage_error("path required")),
+    }
+  }
+
+  /// The positional arguments after the database path
+  pub fn rest(&self) -> &[String] {
+    self.positional.get(1..).unwrap_or_default()
+  }
+}
+
+pub fn usage_error(message: &str) -> Report<CliError> {
+  Report::new(CliError::Usage(message.to_string()))
+}
+
+fn run(command: &str, args: Vec<String>) -> Result {
+  match command {
+    "bench" => {
+      let args = Args::parse(args, &["count", "batch-size", "value-size", "path"])?;
+      bench::bench(&args)
+    }
+    "buckets" => tree::buckets(&Args::parse(args, &[])?),
+    "check" => check::check(&Args::parse(args, &[])?),
+    "compact" => {
+      let args = Args::parse(args, &["o", "tx-max-size"])?;
+      compact::compact(&args)
+    }
+    "dump" => pages::dump(&Args::parse(args, &[])?),
+    "get" => tree::get(&Args::parse(args, &[])?),
+    "info" => pages::info(&Args::parse(args, &[])?),
+    "keys" => tree::keys(&Args::parse(args, &[])?),
+    "page" => pages::page(&Args::parse(args, &[])?),
+    "pages" => pages::pages(&Args::parse(args, &[])?),
+    "stats" => tree::stats(&Args::parse(args, &[])?),
+    command => Err(usage_error(&format!("unknown command: {command}"))),
+  }
+}
+
+fn main() -> ExitCode {
+  let mut args = std::env::args().skip(1);
+  let command = match args.next() {
+    Some(command) if command != "help" && command != "-h" && command != "--help" => command,
+    _ => {
+      eprint!("{USAGE}");
+      return ExitCode::from(2);
+    }
+  };
+  match run(&command, args.collect()) {
+    Ok(()) => ExitCode::SUCCESS,
+    // The reader closing the output early, like `bbolt pages db | head`, isn't a failure
+    Err(report)
+      if report
+        .downcast_ref::<io::Error>()
+        .is_some_and(|error| error.kind() == io::ErrorKind::BrokenPipe) =>
+    {
+      ExitCode::SUCCESS
+    }
+    Err(report) => {
+      match report.current_context() {
+        CliError::Usage(message) => {
+          eprintln!("{message}");
+          eprintln!("Run 'bbolt help' for usage.");
+          return ExitCode::from(2);
+        }
+        // Only IO failures need the full report
+        CliError::Open(_) | CliError::Read | CliError::Write => eprintln!("{report:?}"),
+        context => eprintln!("{context}"),
+      }
+      ExitCode::FAILURE
+    }
+  }
+}
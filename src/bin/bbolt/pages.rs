@@ -0,0 +1,394 @@
+use crate::tree::{Tx, format_bytes, open_db};
+use crate::{Args, CliError, Result, usage_error};
+use bbolt_nub::common::consts::PGID_NO_FREELIST;
+use bbolt_nub::common::id::{
+  DbPageId, DiskPageId, FreelistPageId, MetaPageId, NodePageId, PageMapTranslator,
+};
+use bbolt_nub::common::layout::bucket::BucketHeader;
+use bbolt_nub::common::layout::meta::Meta;
+use bbolt_nub::common::layout::node::LeafFlag;
+use bbolt_nub::common::layout::page::PageHeader;
+use bbolt_nub::io::backends::meta_reader::MetaReader;
+use bbolt_nub::io::pages::TxReadPageIO;
+use bbolt_nub::io::pages::types::freelist::HasFreelist;
+use bbolt_nub::io::pages::types::meta::HasMeta;
+use bbolt_nub::io::pages::types::node::branch::HasNodes;
+use bbolt_nub::io::pages::types::node::leaf::HasValues;
+use bbolt_nub::io::pages::types::node::{HasElements, HasKeys, NodePage};
+use bbolt_nub::io::pages::types::page_map::HasPageMap;
+use error_stack::{Report, ResultExt};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync;
+
+/// Raw page access to a database file, independent of its format
+pub struct RawFile {
+  file: File,
+  meta: Meta,
+}
+
+impl RawFile {
+  pub fn open(path: &Path) -> Result<RawFile> {
+    let file = File::open(path).change_context_lazy(|| CliError::Open(path.to_path_buf()))?;
+    let reader = file
+      .try_clone()
+      .change_context_lazy(|| CliError::Open(path.to_path_buf()))?;
+    let meta = *MetaReader::new(BufReader::new(reader))
+      .determine_file_meta()
+      .change_context_lazy(|| CliError::Open(path.to_path_buf()))?
+      .meta();
+    Ok(RawFile { file, meta })
+  }
+
+  #[inline]
+  pub fn meta(&self) -> &Meta {
+    &self.meta
+  }
+
+  #[inline]
+  pub fn page_size(&self) -> usize {
+    self.meta.page_size as usize
+  }
+
+  /// The number of pages in the file according to the meta
+  #[inline]
+  pub fn eof(&self) -> u64 {
+    self.meta.eof_id.0.0
+  }
+
+  fn read_at(&mut self, page_id: u64, buf: &mut [u8]) -> Result {
+    if page_id >= self.eof() {
+      return Err(Report::new(CliError::PageOutOfBounds(page_id)));
+    }
+    self
+      .file
+      .seek(SeekFrom::Start(page_id * self.page_size() as u64))
+      .change_context(CliError::Read)?;
+    self.file.read_exact(buf).change_context(CliError::Read)
+  }
+
+  pub fn header(&mut self, page_id: u64) -> Result<PageHeader> {
+    let mut header = PageHeader::default();
+    self.read_at(page_id, bytemuck::bytes_of_mut(&mut header))?;
+    Ok(header)
+  }
+
+  /// The page's bytes including its overflow pages
+  pub fn read_page(&mut self, page_id: u64) -> Result<Vec<u8>> {
+    let overflow = self.header(page_id)?.get_overflow() as u64;
+    if page_id + overflow >= self.eof() {
+      return Err(Report::new(CliError::PageOutOfBounds(page_id + overflow)));
+    }
+    let mut page = vec![0u8; (overflow as usize + 1) * self.page_size()];
+    self.read_at(page_id, &mut page)?;
+    Ok(page)
+  }
+}
+
+/// The page's type, as printed by Go BBolt
+pub fn page_type(header: &PageHeader) -> String {
+  if header.is_branch() {
+    "branch".to_string()
+  } else if header.is_leaf() {
+    "leaf".to_string()
+  } else if header.is_meta() {
+    "meta".to_string()
+  } else if header.is_freelist() {
+    "freelist".to_string()
+  } else if header.is_page_map() {
+    "pagemap".to_string()
+  } else {
+    format!("unknown<{:02x}>", header.flags().bits())
+  }
+}
+
+/// The ids of the pages in the freelist, not counting the freelist pages themselves. In the
+/// version 3 format every page the page map doesn't use is free.
+fn free_pages(tx: &sync::Arc<Tx<'_>>, meta: &Meta) -> Result<HashSet<u64>> {
+  if meta.free_list.0.0 == PGID_NO_FREELIST {
+    return Ok(HashSet::new());
+  }
+  let free = tx.free_page_ids(meta).change_context(CliError::Read)?;
+  Ok(free.into_iter().map(|page_id| page_id.0).collect())
+}
+
+/// The pages holding the overflow of version 3 nodes, which may be scattered. A node's overflow
+/// is mapped under the node page ids right after its own.
+fn page_map_overflow(raw: &mut RawFile, page_map: &PageMapTranslator) -> Result<HashSet<u64>> {
+  let mut overflow_pages = HashSet::new();
+  let mut entries = page_map.entries();
+  while let Some(entry) = entries.next() {
+    let overflow = raw.header(entry.disk().0)?.get_overflow();
+    overflow_pages.extend(
+      entries
+        .by_ref()
+        .take(overflow as usize)
+        .map(|entry| entry.disk().0),
+    );
+  }
+  Ok(overflow_pages)
+}
+
+fn page_ids(args: &Args) -> Result<Vec<u64>> {
+  if args.rest().is_empty() {
+    return Err(usage_error("page id required"));
+  }
+  args
+    .rest()
+    .iter()
+    .map(|page_id| {
+      page_id
+        .parse()
+        .map_err(|_| usage_error(&format!("invalid page id: {page_id}")))
+    })
+    .collect()
+}
+
+/// `bbolt info PATH`
+pub fn info(args: &Args) -> Result {
+  let raw = RawFile::open(&args.path()?)?;
+  let mut out = io::stdout().lock();
+  outln!(out, "Page Size: {}", raw.page_size());
+  Ok(())
+}
+
+/// `bbolt pages PATH`
+pub fn pages(args: &Args) -> Result {
+  let path = args.path()?;
+  let mut raw = RawFile::open(&path)?;
+  let db = open_db(&path, true)?;
+  let tx = db.begin();
+  let free = free_pages(&tx, raw.meta())?;
+  let page_map = db.page_map();
+  let overflow_pages = match &page_map {
+    Some(page_map) => page_map_overflow(&mut raw, page_map)?,
+    None => HashSet::new(),
+  };
+  let mut out = io::stdout().lock();
+
+  outln!(out, "ID       TYPE       ITEMS  OVRFLW");
+  outln!(out, "======== ========== ====== ======");
+  let mut page_id = 0;
+  while page_id < raw.eof() {
+    if free.contains(&page_id) {
+      outln!(out, "{page_id:<8} free");
+      page_id += 1;
+      continue;
+    }
+    if overflow_pages.contains(&page_id) {
+      page_id += 1;
+      continue;
+    }
+    let header = raw.header(page_id)?;
+    let overflow = header.get_overflow();
+    let count =
+      if header.is_branch() || header.is_leaf() || header.is_freelist() || header.is_page_map() {
+        header.count().to_string()
+      } else {
+        String::new()
+      };
+    let overflow_str = if overflow > 0 {
+      overflow.to_string()
+    } else {
+      String::new()
+    };
+    outln!(
+      out,
+      "{page_id:<8} {:<10} {count:<6} {overflow_str:<6}",
+      page_type(&header)
+    );
+    // Version 3 node overflow is skipped page by page, as it may not follow the node
+    page_id += if page_map.is_some() && (header.is_branch() || header.is_leaf()) {
+      1
+    } else {
+      overflow as u64 + 1
+    };
+  }
+  Ok(())
+}
+
+/// `bbolt page PATH PAGEID [PAGEID...]`
+pub fn page(args: &Args) -> Result {
+  let path = args.path()?;
+  let page_ids = page_ids(args)?;
+  let mut raw = RawFile::open(&path)?;
+  let db = open_db(&path, true)?;
+  let page_map = db.page_map();
+  let tx = db.begin();
+  let mut out = io::stdout().lock();
+  for (i, page_id) in page_ids.into_iter().enumerate() {
+    if i > 0 {
+      outln!(out, "===============================================");
+    }
+    let header = raw.header(page_id)?;
+    let overflow = header.get_overflow() as usize;
+    outln!(out, "Page ID:    {page_id}");
+    outln!(out, "Page Type:  {}", page_type(&header));
+    outln!(
+      out,
+      "Total Size: {} bytes",
+      (overflow + 1) * raw.page_size()
+    );
+    outln!(out, "Overflow pages: {overflow}");
+    if header.is_meta() {
+      let page = tx
+        .read_meta_page(MetaPageId(DbPageId(page_id)))
+        .change_context(CliError::Read)?;
+      print_meta(&mut out, page.meta())?;
+    } else if header.is_freelist() {
+      let freelist = tx
+        .read_freelist_page(FreelistPageId(DbPageId(page_id)))
+        .change_context(CliError::Read)?;
+      outln!(out, "Item Count: {}", freelist.free_count());
+      outln!(out);
+      for free_id in freelist.freelist_iter() {
+        outln!(out, "{}", free_id.0);
+      }
+    } else if header.is_page_map() {
+      let page_map = tx
+        .read_page_map_page(FreelistPageId(DbPageId(page_id)))
+        .change_context(CliError::Read)?;
+      outln!(out, "Item Count: {}", page_map.entry_count());
+      outln!(out);
+      for entry in page_map.page_map_iter() {
+        outln!(out, "{} -> {}", entry.node().0.0, entry.disk().0);
+      }
+    } else if header.is_branch() || header.is_leaf() {
+      // Version 3 nodes are read by the id the page map stores them under
+      let node_page_id = match &page_map {
+        Some(page_map) => page_map
+          .node_at(DiskPageId(page_id))
+          .ok_or_else(|| Report::new(CliError::Read))?,
+        None => NodePageId(DbPageId(page_id)),
+      };
+      let node = tx
+        .read_node_page(node_page_id)
+        .change_context(CliError::Read)?;
+      print_node(&mut out, &node)?;
+    }
+    outln!(out);
+  }
+  Ok(())
+}
+
+fn print_meta(out: &mut impl Write, meta: &Meta) -> Result {
+  outln!(out, "Version:    {}", meta.version);
+  outln!(out, "Page Size:  {} bytes", meta.page_size);
+  outln!(out, "Flags:      {:08x}", meta.flags);
+  outln!(out, "Root:       <pgid={}>", meta.root.root().0.0);
+  outln!(out, "Freelist:   <pgid={}>", meta.free_list.0.0);
+  outln!(out, "HWM:        <pgid={}>", meta.eof_id.0.0);
+  outln!(out, "Txn ID:     {}", meta.tx_id.0.0);
+  outln!(out, "Checksum:   {:016x}", meta.checksum);
+  Ok(())
+}
+
+fn print_node<'tx>(
+  out: &mut impl Write,
+  node: &NodePage<
+    <Tx<'tx> as TxReadPageIO<'tx>>::BranchType,
+    <Tx<'tx> as TxReadPageIO<'tx>>::LeafType,
+  >,
+) -> Result {
+  match node {
+    NodePage::Branch(branch) => {
+      outln!(out, "Item Count: {}", branch.element_count());
+      outln!(out);
+      for index in 0..branch.element_count() {
+        let (Some(key), Some(child)) = (branch.key(index), branch.node(index)) else {
+          return Err(Report::new(CliError::Read));
+        };
+        outln!(out, "{}: <pgid={}>", format_bytes(&key), child.0.0);
+      }
+    }
+    NodePage::Leaf(leaf) => {
+      outln!(out, "Item Count: {}", leaf.element_count());
+      outln!(out);
+      for index in 0..leaf.element_count() {
+        let (Some((key, value)), Some(flag)) = (leaf.key_value(index), leaf.leaf_flag(index))
+        else {
+          return Err(Report::new(CliError::Read));
+        };
+        if flag.contains(LeafFlag::BUCKET) {
+          let header: BucketHeader = bytemuck::pod_read_unaligned(
+            value
+              .get(0..size_of::<BucketHeader>())
+              .ok_or_else(|| Report::new(CliError::Read))?,
+          );
+          outln!(
+            out,
+            "{}: <pgid={},seq={}>",
+            format_bytes(&key),
+            header.root().0.0,
+            header.sequence()
+          );
+        } else if flag.contains(LeafFlag::COMPRESSED) {
+          // The page is read with its values decompressed
+          outln!(
+            out,
+            "{}: {} <lz4>",
+            format_bytes(&key),
+            format_bytes(&value)
+          );
+        } else {
+          outln!(out, "{}: {}", format_bytes(&key), format_bytes(&value));
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// `bbolt dump PATH PAGEID [PAGEID...]`
+pub fn dump(args: &Args) -> Result {
+  let path = args.path()?;
+  let page_ids = page_ids(args)?;
+  let mut raw = RawFile::open(&path)?;
+  let mut out = io::stdout().lock();
+  for (i, page_id) in page_ids.into_iter().enumerate() {
+    if i > 0 {
+      outln!(out, "===============================================");
+    }
+    let page = raw.read_page(page_id)?;
+    hexdump(&mut out, &page, page_id * raw.page_size() as u64)?;
+  }
+  Ok(())
+}
+
+/// Prints `bytes` 16 to a line, collapsing repeated lines into `*`
+fn hexdump(out: &mut impl Write, bytes: &[u8], offset: u64) -> Result {
+  let mut prev: Option<&[u8]> = None;
+  let mut skipped = false;
+  for (i, line) in bytes.chunks(16).enumerate() {
+    if prev == Some(line) {
+      if !skipped {
+        outln!(out, "*");
+        skipped = true;
+      }
+      continue;
+    }
+    prev = Some(line);
+    skipped = false;
+    let hex: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+    let ascii: String = line
+      .iter()
+      .map(|&b| {
+        if b.is_ascii_graphic() || b == b' ' {
+          b as char
+        } else {
+          '.'
+        }
+      })
+      .collect();
+    outln!(
+      out,
+      "{:08x}  {:<47}  |{ascii}|",
+      offset + i as u64 * 16,
+      hex.join(" ")
+    );
+  }
+  outln!(out, "{:08x}", offset + bytes.len() as u64);
+  Ok(())
+}
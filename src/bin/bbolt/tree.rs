@@ -0,0 +1,384 @@
+use crate::{Args, CliError, Result, usage_error};
+use bbolt_nub::common::errors::BucketError;
+use bbolt_nub::common::id::NodePageId;
+use bbolt_nub::common::layout::bucket::{BucketHeader, inline_elements};
+use bbolt_nub::common::layout::compression::decompress_value;
+use bbolt_nub::common::layout::node::{BranchElement, LeafElement, LeafFlag};
+use bbolt_nub::common::layout::page::PageHeader;
+use bbolt_nub::common::vec_pool::VecPool;
+use bbolt_nub::components::backend::memmap::{MemmapDb, MemmapTx};
+use bbolt_nub::components::bucket::OnDiskBucket;
+use bbolt_nub::components::cursor::{CoreCursor, CoreCursorApi, CoreCursorMoveApi};
+use bbolt_nub::io::pages::types::node::branch::HasNodes;
+use bbolt_nub::io::pages::types::node::leaf::HasValues;
+use bbolt_nub::io::pages::types::node::{HasElements, HasKeys, NodePage};
+use bbolt_nub::io::pages::{Page, TxReadPageIO};
+use error_stack::{Report, ResultExt};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync;
+
+pub type Tx<'tx> = MemmapTx<'tx>;
+
+pub type Bucket<'tx> = OnDiskBucket<
+  <Tx<'tx> as TxReadPageIO<'tx>>::BranchType,
+  <Tx<'tx> as TxReadPageIO<'tx>>::LeafType,
+  Tx<'tx>,
+>;
+
+/// Opens the existing database at `path`
+pub fn open_db(path: &Path, read_only: bool) -> Result<MemmapDb> {
+  if !path.is_file() {
+    return Err(Report::new(CliError::Open(path.to_path_buf())));
+  }
+  MemmapDb::builder()
+    .read_only(read_only)
+    .open_path(path)
+    .change_context_lazy(|| CliError::Open(path.to_path_buf()))
+}
+
+/// Formats `bytes` as text when it's printable, otherwise as hex
+pub fn format_bytes(bytes: &[u8]) -> String {
+  match std::str::from_utf8(bytes) {
+    Ok(s) if s.chars().all(|c| !c.is_control()) => s.to_string(),
+    _ => bytes.iter().map(|b| format!("{b:02x}")).collect(),
+  }
+}
+
+/// A bucket, which is either a tree of pages or stored inline in its parent's leaf
+pub enum TreeBucket<'tx> {
+  Pages(Bucket<'tx>),
+  Inline(Vec<u8>),
+}
+
+impl<'tx> TreeBucket<'tx> {
+  /// The root bucket
+  pub fn root(tx: &sync::Arc<Tx<'tx>>, header: BucketHeader) -> Result<TreeBucket<'tx>> {
+    OnDiskBucket::new(tx.clone(), VecPool::new(0, 0, 16), header)
+      .map(TreeBucket::Pages)
+      .change_context(CliError::Read)
+  }
+
+  /// The bucket whose leaf value is `value`
+  pub fn open(tx: &sync::Arc<Tx<'tx>>, value: &[u8]) -> Result<TreeBucket<'tx>> {
+    let header: BucketHeader = bytemuck::pod_read_unaligned(&value[0..size_of::<BucketHeader>()]);
+    if header.root().0.0 == 0 {
+      Ok(TreeBucket::Inline(value.to_vec()))
+    } else {
+      Self::root(tx, header)
+    }
+  }
+
+  /// The bucket found by following `path` from this one
+  pub fn open_path(self, tx: &sync::Arc<Tx<'tx>>, path: &[String]) -> Result<TreeBucket<'tx>> {
+    let mut bucket = self;
+    for name in path {
+      if let TreeBucket::Pages(pages) = &bucket {
+        match pages.bucket_header(name.as_bytes()) {
+          Ok(Some(header)) if header.root().0.0 != 0 => {
+            bucket = Self::root(tx, header)?;
+            continue;
+          }
+          // Inline buckets are found below, with their whole value
+          Ok(Some(_)) => {}
+          Ok(None) => return Err(Report::new(CliError::BucketNotFound)),
+          Err(err) if matches!(err.current_context(), BucketError::ValueIsBytes) => {
+            return Err(err.change_context(CliError::BucketNotFound));
+          }
+          Err(err) => return Err(err.change_context(CliError::Read)),
+        }
+      }
+      let mut child = None;
+      bucket.for_each(|flag, key, value| {
+        if key == name.as_bytes() && flag.contains(LeafFlag::BUCKET) {
+          child = Some(value.to_vec());
+        }
+        Ok(())
+      })?;
+      match child {
+        Some(value) => bucket = TreeBucket::open(tx, &value)?,
+        None => return Err(Report::new(CliError::BucketNotFound)),
+      }
+    }
+    Ok(bucket)
+  }
+
+  /// Calls `f` with the flag, key and value of every element in key order
+  pub fn for_each<F>(&self, mut f: F) -> Result
+  where
+    F: FnMut(LeafFlag, &[u8], &[u8]) -> Result,
+  {
+    let bucket = match self {
+      TreeBucket::Pages(bucket) => bucket,
+      TreeBucket::Inline(value) => {
+        let elements = inline_elements(value).ok_or_else(|| Report::new(CliError::Read))?;
+        for (flag, key, value) in elements {
+          f(flag, key, value)?;
+        }
+        return Ok(());
+      }
+    };
+    let mut cursor = CoreCursor::new_with_stack(bucket, VecPool::new(0, 0, 16).pop());
+    let mut next = cursor
+      .move_to_first_element()
+      .change_context(CliError::Read)?;
+    while let Some(flag) = next {
+      let (key, value) = cursor.key_value().expect("cursor is on an element");
+      f(flag, &key, &value)?;
+      next = cursor
+        .move_to_next_element()
+        .change_context(CliError::Read)?;
+    }
+    Ok(())
+  }
+
+  /// The decompressed value stored under `key`
+  pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+    let bucket = match self {
+      TreeBucket::Pages(bucket) => bucket,
+      TreeBucket::Inline(_) => {
+        let mut found = None;
+        self.for_each(|flag, k, value| {
+          if k == key && !flag.contains(LeafFlag::BUCKET) {
+            found = Some(value_bytes(flag, value)?);
+          }
+          Ok(())
+        })?;
+        return found.ok_or_else(|| Report::new(CliError::KeyNotFound));
+      }
+    };
+    match bucket.get(key) {
+      Ok(Some(value)) => Ok(value.to_vec()),
+      Ok(None) => Err(Report::new(CliError::KeyNotFound)),
+      Err(err) if matches!(err.current_context(), BucketError::ValueIsABucket) => {
+        Err(err.change_context(CliError::KeyNotFound))
+      }
+      Err(err) => Err(err.change_context(CliError::Read)),
+    }
+  }
+}
+
+/// A leaf value's bytes, decompressed if it was stored compressed
+pub fn value_bytes(flag: LeafFlag, value: &[u8]) -> Result<Vec<u8>> {
+  if flag.contains(LeafFlag::COMPRESSED) {
+    decompress_value(value).change_context(CliError::Read)
+  } else {
+    Ok(value.to_vec())
+  }
+}
+
+/// `bbolt buckets PATH`
+pub fn buckets(args: &Args) -> Result {
+  let db = open_db(&args.path()?, true)?;
+  let tx = db.begin();
+  let root = TreeBucket::root(&tx, db.meta().root)?;
+  let mut out = io::stdout().lock();
+  root.for_each(|flag, key, _| {
+    if flag.contains(LeafFlag::BUCKET) {
+      outln!(out, "{}", format_bytes(key));
+    }
+    Ok(())
+  })
+}
+
+/// `bbolt keys PATH BUCKET [BUCKET...]`
+pub fn keys(args: &Args) -> Result {
+  let path = args.path()?;
+  if args.rest().is_empty() {
+    return Err(usage_error("bucket required"));
+  }
+  let db = open_db(&path, true)?;
+  let tx = db.begin();
+  let bucket = TreeBucket::root(&tx, db.meta().root)?.open_path(&tx, args.rest())?;
+  let mut out = io::stdout().lock();
+  bucket.for_each(|_, key, _| {
+    outln!(out, "{}", format_bytes(key));
+    Ok(())
+  })
+}
+
+/// `bbolt get PATH BUCKET [BUCKET...] KEY`
+pub fn get(args: &Args) -> Result {
+  let path = args.path()?;
+  let (key, buckets) = match args.rest().split_last() {
+    Some((key, buckets)) if !buckets.is_empty() => (key, buckets),
+    Some(_) => return Err(usage_error("key required")),
+    None => return Err(usage_error("bucket required")),
+  };
+  let db = open_db(&path, true)?;
+  let tx = db.begin();
+  let bucket = TreeBucket::root(&tx, db.meta().root)?.open_path(&tx, buckets)?;
+  let value = bucket.get(key.as_bytes())?;
+  let mut out = io::stdout().lock();
+  outln!(out, "{}", format_bytes(&value));
+  Ok(())
+}
+
+/// Page and usage statistics of one or more bucket trees.
+///
+/// `BucketStats` in Go BBolt
+#[derive(Debug, Default)]
+pub struct TreeStats {
+  pub branch_pages: usize,
+  pub branch_overflow: usize,
+  pub leaf_pages: usize,
+  pub leaf_overflow: usize,
+  pub key_n: usize,
+  pub depth: usize,
+  pub branch_alloc: usize,
+  pub branch_in_use: usize,
+  pub leaf_alloc: usize,
+  pub leaf_in_use: usize,
+  pub bucket_n: usize,
+  pub inline_bucket_n: usize,
+  pub inline_bucket_in_use: usize,
+}
+
+impl TreeStats {
+  /// Adds the stats of the bucket rooted at `page_id`, which is at `depth` in its tree
+  pub fn walk(
+    &mut self, tx: &sync::Arc<Tx<'_>>, page_id: NodePageId, depth: usize, page_size: usize,
+  ) -> Result {
+    let node = tx.read_node_page(page_id).change_context(CliError::Read)?;
+    let overflow = node.page_header().get_overflow() as usize;
+    self.depth = self.depth.max(depth);
+    match &node {
+      NodePage::Branch(branch) => {
+        self.branch_pages += 1;
+        self.branch_overflow += overflow;
+        self.branch_alloc += (overflow + 1) * page_size;
+        self.branch_in_use +=
+          size_of::<PageHeader>() + branch.element_count() * size_of::<BranchElement>();
+        for index in 0..branch.element_count() {
+          let (Some(key), Some(child)) = (branch.key(index), branch.node(index)) else {
+            return Err(Report::new(CliError::Read));
+          };
+          self.branch_in_use += key.len();
+          self.walk(tx, child, depth + 1, page_size)?;
+        }
+      }
+      NodePage::Leaf(leaf) => {
+        self.leaf_pages += 1;
+        self.leaf_overflow += overflow;
+        self.leaf_alloc += (overflow + 1) * page_size;
+        self.leaf_in_use +=
+          size_of::<PageHeader>() + leaf.element_count() * size_of::<LeafElement>();
+        for index in 0..leaf.element_count() {
+          let (Some((key, value)), Some(flag)) = (leaf.key_value(index), leaf.leaf_flag(index))
+          else {
+            return Err(Report::new(CliError::Read));
+          };
+          self.leaf_in_use += key.len() + value.len();
+          if flag.contains(LeafFlag::BUCKET) {
+            self.walk_bucket(tx, &value, page_size)?;
+          } else {
+            self.key_n += 1;
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Adds the stats of the bucket whose leaf value is `value`
+  pub fn walk_bucket(&mut self, tx: &sync::Arc<Tx<'_>>, value: &[u8], page_size: usize) -> Result {
+    let header: BucketHeader = bytemuck::pod_read_unaligned(&value[0..size_of::<BucketHeader>()]);
+    self.bucket_n += 1;
+    if header.root().0.0 == 0 {
+      self.inline_bucket_n += 1;
+      self.inline_bucket_in_use += value.len() - size_of::<BucketHeader>();
+      let elements = inline_elements(value).ok_or_else(|| Report::new(CliError::Read))?;
+      self.key_n += elements.len();
+      Ok(())
+    } else {
+      self.walk(tx, header.root().into(), 1, page_size)
+    }
+  }
+}
+
+fn percent(part: usize, whole: usize) -> usize {
+  (part * 100).checked_div(whole).unwrap_or(0)
+}
+
+/// `bbolt stats PATH [PREFIX]`
+pub fn stats(args: &Args) -> Result {
+  let db = open_db(&args.path()?, true)?;
+  let prefix = args
+    .rest()
+    .first()
+    .map(String::as_bytes)
+    .unwrap_or_default();
+  let tx = db.begin();
+  let page_size = db.page_size();
+  let root = TreeBucket::root(&tx, db.meta().root)?;
+  let mut stats = TreeStats::default();
+  let mut top_level = 0;
+  root.for_each(|flag, key, value| {
+    if flag.contains(LeafFlag::BUCKET) && key.starts_with(prefix) {
+      top_level += 1;
+      stats.walk_bucket(&tx, value, page_size)?;
+    }
+    Ok(())
+  })?;
+
+  let mut out = io::stdout().lock();
+  outln!(out, "Aggregate statistics for {top_level} buckets\n");
+  outln!(out, "Page count statistics");
+  outln!(
+    out,
+    "\tNumber of logical branch pages: {}",
+    stats.branch_pages
+  );
+  outln!(
+    out,
+    "\tNumber of physical branch overflow pages: {}",
+    stats.branch_overflow
+  );
+  outln!(out, "\tNumber of logical leaf pages: {}", stats.leaf_pages);
+  outln!(
+    out,
+    "\tNumber of physical leaf overflow pages: {}",
+    stats.leaf_overflow
+  );
+  outln!(out, "Tree statistics");
+  outln!(out, "\tNumber of keys/value pairs: {}", stats.key_n);
+  outln!(out, "\tNumber of levels in B+tree: {}", stats.depth);
+  outln!(out, "Page size utilization");
+  outln!(
+    out,
+    "\tBytes allocated for physical branch pages: {}",
+    stats.branch_alloc
+  );
+  outln!(
+    out,
+    "\tBytes actually used for branch data: {} ({}%)",
+    stats.branch_in_use,
+    percent(stats.branch_in_use, stats.branch_alloc)
+  );
+  outln!(
+    out,
+    "\tBytes allocated for physical leaf pages: {}",
+    stats.leaf_alloc
+  );
+  outln!(
+    out,
+    "\tBytes actually used for leaf data: {} ({}%)",
+    stats.leaf_in_use,
+    percent(stats.leaf_in_use, stats.leaf_alloc)
+  );
+  outln!(out, "Bucket statistics");
+  outln!(out, "\tTotal number of buckets: {}", stats.bucket_n);
+  outln!(
+    out,
+    "\tTotal number on inlined buckets: {} ({}%)",
+    stats.inline_bucket_n,
+    percent(stats.inline_bucket_n, stats.bucket_n)
+  );
+  outln!(
+    out,
+    "\tBytes used for inlined buckets: {} ({}%)",
+    stats.inline_bucket_in_use,
+    percent(stats.inline_bucket_in_use, stats.leaf_in_use)
+  );
+  Ok(())
+}